@@ -36,7 +36,7 @@ pub async fn benchmark_handler(
         ));
     }
 
-    if !db.get_config().models.contains_key(&model_name) {
+    if !db.has_model(&model_name) {
         return Ok(json_status(
             serde_json::json!({ "error": format!("Model '{}' not found", model_name) }),
             StatusCode::BAD_REQUEST,
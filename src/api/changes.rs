@@ -0,0 +1,155 @@
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::sse::Event;
+use warp::{reply, Reply};
+
+use crate::api::handlers::error_status;
+use crate::database::{ChangeGap, ChangePosition, NyroDB};
+use crate::models::ChangeEvent;
+use crate::utils::logger::Logger;
+
+const DEFAULT_CHANGE_LIMIT: usize = 1000;
+const MAX_WAIT_MS: u64 = 30_000;
+const SSE_BATCH_SIZE: usize = 1024;
+const SSE_IDLE_WAIT: Duration = Duration::from_secs(15);
+
+/// Query parameters of `GET /changes/:model`. `since` is the last sequence
+/// number the consumer has processed; `wait` long-polls for up to that many
+/// milliseconds when nothing newer exists.
+#[derive(Debug, Default, Deserialize)]
+pub struct ChangeParams {
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+    pub wait: Option<u64>,
+    pub format: Option<ChangeFormat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeFormat {
+    Json,
+    Sse,
+}
+
+#[derive(Debug, Serialize)]
+struct ChangePage {
+    events: Vec<ChangeEvent>,
+    /// Pass back as `since` to continue after this page.
+    next_since: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gap: Option<ChangeGap>,
+}
+
+pub async fn changes_handler(
+    model_name: String,
+    params: ChangeParams,
+    last_event_id: Option<String>,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    if params.limit == Some(0) {
+        return Ok(error_status(
+            "limit must be greater than 0".to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    if let Err(e) = db.published_seq(&model_name) {
+        return Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST));
+    }
+
+    if params.format == Some(ChangeFormat::Sse) {
+        // Reconnecting EventSource clients send the id of the last event.
+        let since = last_event_id
+            .and_then(|id| id.parse().ok())
+            .or(params.since)
+            .unwrap_or(0);
+        Logger::info_with_config(
+            &db.get_config().logging,
+            &format!("Streaming changes of '{}' after {}", model_name, since),
+        );
+        return Ok(sse_reply(db, model_name, since));
+    }
+
+    let since = params.since.unwrap_or(0);
+    let limit = params.limit.unwrap_or(DEFAULT_CHANGE_LIMIT);
+    let wait = Duration::from_millis(params.wait.unwrap_or(0).min(MAX_WAIT_MS));
+    let position = ChangePosition::AfterSeq(since);
+    let mut batch = db.read_changes(&model_name, position, limit).await;
+    if let Ok(empty) = &batch {
+        if empty.events.is_empty()
+            && empty.gap.is_none()
+            && !wait.is_zero()
+            && db
+                .wait_for_changes(&model_name, since, wait)
+                .await
+                .unwrap_or(false)
+        {
+            batch = db.read_changes(&model_name, position, limit).await;
+        }
+    }
+    match batch {
+        Ok(batch) => Ok(Box::new(reply::json(&ChangePage {
+            events: batch.events,
+            next_since: batch.next_after,
+            gap: batch.gap,
+        }))),
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to read changes of '{}': {}", model_name, e),
+            );
+            Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+/// Streams change events as Server-Sent Events, each with its `seq` as the
+/// event id, reading the log in batches and waiting for new changes once it
+/// is caught up.
+fn sse_reply(db: Arc<NyroDB>, model_name: String, since: u64) -> Box<dyn Reply> {
+    let events = stream::unfold(
+        (db, model_name, Some(since), VecDeque::new()),
+        |(db, model_name, mut cursor, mut pending)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((
+                        Ok::<_, Infallible>(event),
+                        (db, model_name, cursor, pending),
+                    ));
+                }
+                let after = cursor?;
+                let batch = match db
+                    .read_changes(&model_name, ChangePosition::AfterSeq(after), SSE_BATCH_SIZE)
+                    .await
+                {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        let event = Event::default().event("error").data(e.to_string());
+                        return Some((Ok(event), (db, model_name, None, pending)));
+                    }
+                };
+                if let Some(gap) = &batch.gap {
+                    pending.extend(sse_event(gap.resumed_after, gap));
+                }
+                for event in &batch.events {
+                    pending.extend(sse_event(event.seq, event));
+                }
+                cursor = Some(batch.next_after);
+                if pending.is_empty() {
+                    let _ = db
+                        .wait_for_changes(&model_name, batch.next_after, SSE_IDLE_WAIT)
+                        .await;
+                }
+            }
+        },
+    );
+    Box::new(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+fn sse_event(seq: u64, data: &impl Serialize) -> Option<Event> {
+    Event::default().id(seq.to_string()).json_data(data).ok()
+}
@@ -1,14 +1,94 @@
+use serde::Deserialize;
 use serde_json::Value;
 use std::sync::Arc;
+use std::time::Duration;
 use warp::http::StatusCode;
 use warp::{reply, Rejection, Reply};
 
-use crate::api::realtime::RealtimeServer;
-use crate::database::NyroDB;
+use crate::api::realtime::{RealtimeParams, RealtimeServer};
+use crate::config::{ConflictPolicy, IndexKind, ModelSchema, WebhookConfig};
+use crate::database::{
+    AggregateRequest, ConflictError, LogPosition, ModelExistsError, NyroDB, PositionTimeoutError,
+    QueryRequest, TransactionRequest, POSITION_HEADER, PRIMARY_HEADER,
+};
+use crate::storage::{RangeQuery, ReadOnlyError, SchemaChange};
 use crate::utils::logger::Logger;
 
+/// Body of `POST /admin/backup`; `target` is a new path below `backup.root`.
+#[derive(Debug, Deserialize)]
+pub struct BackupRequest {
+    pub target: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct InsertOptions {
+    pub on_conflict: Option<ConflictPolicy>,
+}
+
+const DEFAULT_PAGE_LIMIT: usize = 1000;
+const NDJSON_BATCH_SIZE: usize = 1024;
+const DEFAULT_POSITION_WAIT_MS: u64 = 1000;
+const MAX_POSITION_WAIT_MS: u64 = 30_000;
+
+/// Read-your-writes parameters of the read endpoints: `min_position` is a
+/// position returned by a write, and `wait` how long (in ms) to wait for
+/// this node to reach it.
+#[derive(Debug, Default, Deserialize)]
+pub struct PositionParams {
+    pub min_position: Option<String>,
+    pub wait: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PageParams {
+    pub limit: Option<usize>,
+    pub after: Option<u64>,
+    pub format: Option<QueryFormat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryFormat {
+    Json,
+    Ndjson,
+}
+
+/// Query-string form of [`RangeQuery`]; `between` is `low,high`.
+#[derive(Debug, Default, Deserialize)]
+pub struct RangeParams {
+    pub gt: Option<String>,
+    pub gte: Option<String>,
+    pub lt: Option<String>,
+    pub lte: Option<String>,
+    pub between: Option<String>,
+    pub prefix: Option<String>,
+}
+
+impl RangeParams {
+    fn into_query(self) -> Result<RangeQuery, String> {
+        let between = match self.between {
+            Some(between) => {
+                let (low, high) = between
+                    .split_once(',')
+                    .ok_or_else(|| "between expects 'low,high'".to_string())?;
+                Some((Value::from(low), Value::from(high)))
+            }
+            None => None,
+        };
+        Ok(RangeQuery {
+            gt: self.gt.map(Value::from),
+            gte: self.gte.map(Value::from),
+            lt: self.lt.map(Value::from),
+            lte: self.lte.map(Value::from),
+            between,
+            prefix: self.prefix,
+        })
+    }
+}
+
 pub async fn insert_handler(
     model_name: String,
+    options: InsertOptions,
     data: Value,
     db: Arc<NyroDB>,
 ) -> Result<Box<dyn Reply>, warp::Rejection> {
@@ -18,15 +98,19 @@ pub async fn insert_handler(
         .await
         .map_err(|_| warp::reject::reject())?;
 
-    match db.insert_raw(&model_name, data).await {
+    match db
+        .insert_raw_with_policy(&model_name, data, options.on_conflict)
+        .await
+    {
         Ok(id) => {
             Logger::info_with_config(
                 &db.get_config().logging,
                 &format!("Inserted into '{}': ID {}", model_name, id),
             );
-            Ok(json_status(
-                serde_json::json!({ "id": id }),
-                StatusCode::CREATED,
+            Ok(with_position(
+                &db,
+                [model_name.as_str()],
+                json_status(serde_json::json!({ "id": id }), StatusCode::CREATED),
             ))
         }
         Err(e) => {
@@ -34,13 +118,14 @@ pub async fn insert_handler(
                 &db.get_config().logging,
                 &format!("Failed to insert into '{}': {}", model_name, e),
             );
-            Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST))
+            Ok(write_error(&db, e))
         }
     }
 }
 
 pub async fn insert_many_handler(
     model_name: String,
+    options: InsertOptions,
     rows: Vec<Value>,
     db: Arc<NyroDB>,
 ) -> Result<Box<dyn Reply>, warp::Rejection> {
@@ -50,15 +135,36 @@ pub async fn insert_many_handler(
         .await
         .map_err(|_| warp::reject::reject())?;
 
-    match db.insert_many_raw(&model_name, rows).await {
-        Ok(ids) => {
+    match db
+        .insert_many_raw_with_policy(&model_name, rows, options.on_conflict)
+        .await
+    {
+        Ok(report) => {
             Logger::info_with_config(
                 &db.get_config().logging,
-                &format!("Inserted {} rows into '{}'", ids.len(), model_name),
+                &format!(
+                    "Inserted {} rows into '{}' ({} conflicts)",
+                    report.ids.len(),
+                    model_name,
+                    report.conflicts.len()
+                ),
             );
-            Ok(json_status(
-                serde_json::json!({ "count": ids.len(), "ids": ids }),
-                StatusCode::CREATED,
+            let status = if report.ids.is_empty() && !report.conflicts.is_empty() {
+                StatusCode::CONFLICT
+            } else {
+                StatusCode::CREATED
+            };
+            Ok(with_position(
+                &db,
+                [model_name.as_str()],
+                json_status(
+                    serde_json::json!({
+                        "count": report.ids.len(),
+                        "ids": report.ids,
+                        "conflicts": report.conflicts
+                    }),
+                    status,
+                ),
             ))
         }
         Err(e) => {
@@ -66,7 +172,7 @@ pub async fn insert_many_handler(
                 &db.get_config().logging,
                 &format!("Failed to insert many rows into '{}': {}", model_name, e),
             );
-            Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST))
+            Ok(write_error(&db, e))
         }
     }
 }
@@ -74,8 +180,12 @@ pub async fn insert_many_handler(
 pub async fn get_handler(
     model_name: String,
     id: u64,
+    position: PositionParams,
     db: Arc<NyroDB>,
 ) -> Result<Box<dyn Reply>, warp::Rejection> {
+    if let Err(reply) = wait_for_position(&db, position).await {
+        return Ok(reply);
+    }
     let limiter = db.get_concurrency_limiter();
     let _permit = limiter
         .acquire()
@@ -104,15 +214,163 @@ pub async fn get_handler(
     }
 }
 
+pub async fn update_handler(
+    model_name: String,
+    id: u64,
+    data: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let limiter = db.get_concurrency_limiter();
+    let _permit = limiter
+        .acquire()
+        .await
+        .map_err(|_| warp::reject::reject())?;
+
+    let result = db.update_raw(&model_name, id, data).await;
+    Ok(update_reply(&db, &model_name, id, result))
+}
+
+pub async fn patch_handler(
+    model_name: String,
+    id: u64,
+    patch: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let limiter = db.get_concurrency_limiter();
+    let _permit = limiter
+        .acquire()
+        .await
+        .map_err(|_| warp::reject::reject())?;
+
+    let result = db.patch_raw(&model_name, id, patch).await;
+    Ok(update_reply(&db, &model_name, id, result))
+}
+
+pub async fn delete_handler(
+    model_name: String,
+    id: u64,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let limiter = db.get_concurrency_limiter();
+    let _permit = limiter
+        .acquire()
+        .await
+        .map_err(|_| warp::reject::reject())?;
+
+    match db.delete_raw(&model_name, id).await {
+        Ok(true) => {
+            Logger::info_with_config(
+                &db.get_config().logging,
+                &format!("Deleted from '{}': ID {}", model_name, id),
+            );
+            Ok(with_position(
+                &db,
+                [model_name.as_str()],
+                json_status(
+                    serde_json::json!({ "id": id, "deleted": true }),
+                    StatusCode::OK,
+                ),
+            ))
+        }
+        Ok(false) => {
+            Logger::warn_with_config(
+                &db.get_config().logging,
+                &format!("'{}' not found: ID {}", model_name, id),
+            );
+            Ok(Box::new(reply::with_status(
+                "Not found",
+                StatusCode::NOT_FOUND,
+            )))
+        }
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to delete from '{}' ID {}: {}", model_name, id, e),
+            );
+            Ok(write_error(&db, e))
+        }
+    }
+}
+
+fn update_reply(
+    db: &NyroDB,
+    model_name: &str,
+    id: u64,
+    result: anyhow::Result<Option<Value>>,
+) -> Box<dyn Reply> {
+    match result {
+        Ok(Some(data)) => {
+            Logger::info_with_config(
+                &db.get_config().logging,
+                &format!("Updated '{}': ID {}", model_name, id),
+            );
+            with_position(db, [model_name], Box::new(reply::json(&data)))
+        }
+        Ok(None) => {
+            Logger::warn_with_config(
+                &db.get_config().logging,
+                &format!("'{}' not found: ID {}", model_name, id),
+            );
+            Box::new(reply::with_status("Not found", StatusCode::NOT_FOUND))
+        }
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to update '{}' ID {}: {}", model_name, id, e),
+            );
+            write_error(db, e)
+        }
+    }
+}
+
 pub async fn query_handler(
     model_name: String,
+    params: PageParams,
+    position: PositionParams,
     db: Arc<NyroDB>,
 ) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = wait_for_position(&db, position).await {
+        return Ok(reply);
+    }
     let limiter = db.get_concurrency_limiter();
     let _permit = limiter
         .acquire()
         .await
         .map_err(|_| warp::reject::reject())?;
+    if params.limit == Some(0) {
+        return Ok(error_status(
+            "limit must be greater than 0".to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    if params.format == Some(QueryFormat::Ndjson) {
+        return Ok(ndjson_reply(&db, &model_name, params));
+    }
+    if params.limit.is_some() || params.after.is_some() {
+        let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+        return Ok(
+            match db.query_page_raw(&model_name, params.after, limit).await {
+                Ok(page) => {
+                    Logger::info_with_config(
+                        &db.get_config().logging,
+                        &format!(
+                            "Queried page of {} items from '{}'",
+                            page.results.len(),
+                            model_name
+                        ),
+                    );
+                    Box::new(warp::reply::json(&page))
+                }
+                Err(e) => {
+                    Logger::error_with_config(
+                        &db.get_config().logging,
+                        &format!("Failed to query '{}': {}", model_name, e),
+                    );
+                    error_status(e.to_string(), StatusCode::BAD_REQUEST)
+                }
+            },
+        );
+    }
     match db.query_raw(&model_name).await {
         Ok(results) => {
             Logger::info_with_config(
@@ -131,12 +389,67 @@ pub async fn query_handler(
     }
 }
 
+/// Streams rows as newline-delimited JSON, reading the log one batch at a
+/// time so the full result is never buffered.
+fn ndjson_reply(db: &NyroDB, model_name: &str, params: PageParams) -> Box<dyn Reply> {
+    let storage = match db.get_storage(model_name) {
+        Ok(storage) => storage,
+        Err(e) => return error_status(e.to_string(), StatusCode::BAD_REQUEST),
+    };
+    Logger::info_with_config(
+        &db.get_config().logging,
+        &format!("Streaming items from '{}'", model_name),
+    );
+
+    let remaining = params.limit.unwrap_or(usize::MAX);
+    let rows = futures_util::stream::try_unfold(
+        (Some(params.after), remaining),
+        move |(cursor, remaining)| {
+            let storage = storage.clone();
+            async move {
+                let Some(after) = cursor else {
+                    return Ok(None);
+                };
+                if remaining == 0 {
+                    return Ok(None);
+                }
+                // Cold rows are read from the log, so each batch runs off
+                // the async workers.
+                let batch = tokio::task::spawn_blocking(move || {
+                    let (rows, next_after) = storage
+                        .page(after, remaining.min(NDJSON_BATCH_SIZE))
+                        .map_err(std::io::Error::other)?;
+                    let mut chunk = Vec::new();
+                    for row in &rows {
+                        serde_json::to_writer(&mut chunk, row)?;
+                        chunk.push(b'\n');
+                    }
+                    Ok::<_, std::io::Error>((chunk, next_after, rows.len()))
+                });
+                let (chunk, next_after, count) = batch.await.map_err(std::io::Error::other)??;
+                let state = (next_after.map(Some), remaining - count);
+                Ok::<_, std::io::Error>(Some((chunk, state)))
+            }
+        },
+    );
+    let mut response = warp::http::Response::new(warp::hyper::Body::wrap_stream(rows));
+    response.headers_mut().insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::HeaderValue::from_static("application/x-ndjson"),
+    );
+    Box::new(response)
+}
+
 pub async fn query_field_handler(
     model_name: String,
     field: String,
     value: String,
+    position: PositionParams,
     db: Arc<NyroDB>,
 ) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = wait_for_position(&db, position).await {
+        return Ok(reply);
+    }
     let limiter = db.get_concurrency_limiter();
     let _permit = limiter
         .acquire()
@@ -169,10 +482,300 @@ pub async fn query_field_handler(
     }
 }
 
-pub async fn realtime_handler(ws: warp::ws::Ws, db: Arc<NyroDB>) -> Result<impl Reply, Rejection> {
+pub async fn query_structured_handler(
+    model_name: String,
+    position: PositionParams,
+    body: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = wait_for_position(&db, position).await {
+        return Ok(reply);
+    }
+    let limiter = db.get_concurrency_limiter();
+    let _permit = limiter
+        .acquire()
+        .await
+        .map_err(|_| warp::reject::reject())?;
+    let request: QueryRequest = match serde_json::from_value(body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+    match db.query_structured_raw(&model_name, &request).await {
+        Ok(response) => {
+            Logger::info_with_config(
+                &db.get_config().logging,
+                &format!(
+                    "Queried {} of {} matching items from '{}' ({})",
+                    response.results.len(),
+                    response.total,
+                    model_name,
+                    response.plan
+                ),
+            );
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to query '{}': {}", model_name, e),
+            );
+            Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+pub async fn aggregate_handler(
+    model_name: String,
+    body: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let limiter = db.get_concurrency_limiter();
+    let _permit = limiter
+        .acquire()
+        .await
+        .map_err(|_| warp::reject::reject())?;
+    let request: AggregateRequest = match serde_json::from_value(body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+    match db.aggregate(&model_name, &request).await {
+        Ok(response) => {
+            Logger::info_with_config(
+                &db.get_config().logging,
+                &format!(
+                    "Aggregated {} groups from '{}' ({})",
+                    response.groups.len(),
+                    model_name,
+                    response.plan
+                ),
+            );
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to aggregate '{}': {}", model_name, e),
+            );
+            Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+pub async fn query_range_handler(
+    model_name: String,
+    field: String,
+    params: RangeParams,
+    position: PositionParams,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = wait_for_position(&db, position).await {
+        return Ok(reply);
+    }
+    let limiter = db.get_concurrency_limiter();
+    let _permit = limiter
+        .acquire()
+        .await
+        .map_err(|_| warp::reject::reject())?;
+    let query = match params.into_query() {
+        Ok(query) => query,
+        Err(message) => return Ok(error_status(message, StatusCode::BAD_REQUEST)),
+    };
+    match db.query_range_raw(&model_name, &field, &query).await {
+        Ok(results) => {
+            Logger::info_with_config(
+                &db.get_config().logging,
+                &format!(
+                    "Queried {} items from '{}' by range on '{}'",
+                    results.len(),
+                    model_name,
+                    field
+                ),
+            );
+            Ok(Box::new(warp::reply::json(&results)))
+        }
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!(
+                    "Failed to query '{}' by range on '{}': {}",
+                    model_name, field, e
+                ),
+            );
+            Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+pub async fn compact_handler(
+    model_name: String,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    match db.compact(&model_name).await {
+        Ok(report) => Ok(Box::new(reply::json(&report))),
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to compact '{}': {}", model_name, e),
+            );
+            Ok(write_error(&db, e))
+        }
+    }
+}
+
+pub async fn backup_handler(
+    request: BackupRequest,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let target = match db.backup_target(&request.target) {
+        Ok(target) => target,
+        Err(e) => return Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+    match db.backup(&target).await {
+        Ok(manifest) => Ok(Box::new(reply::json(&manifest))),
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to back up to {}: {}", request.target, e),
+            );
+            Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+pub async fn schema_handler(
+    model_name: String,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    match db.schema_versions(&model_name) {
+        Ok(versions) => Ok(Box::new(reply::json(&versions))),
+        Err(e) => Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+pub async fn migrate_schema_handler(
+    model_name: String,
+    body: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let migrated = match serde_json::from_value::<SchemaChange>(body) {
+        Ok(change) => db.migrate_schema(&model_name, change).await,
+        Err(e) => Err(e.into()),
+    };
+    match migrated {
+        Ok(versions) => Ok(Box::new(reply::json(&versions))),
+        Err(e) => Ok(write_error(&db, e)),
+    }
+}
+
+/// Body of `POST /admin/indexes/:model`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexRequest {
+    pub field: String,
+    #[serde(default)]
+    pub kind: IndexKind,
+}
+
+pub async fn add_index_handler(
+    model_name: String,
+    body: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let change = match serde_json::from_value::<IndexRequest>(body) {
+        Ok(request) => SchemaChange::AddIndex {
+            name: request.field,
+            kind: request.kind,
+        },
+        Err(e) => return Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+    index_change(model_name, change, db).await
+}
+
+pub async fn drop_index_handler(
+    model_name: String,
+    field: String,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    index_change(model_name, SchemaChange::DropIndex { name: field }, db).await
+}
+
+/// Answers an index change with the model's description, whose indexes
+/// show whether a new one is ready.
+async fn index_change(
+    model_name: String,
+    change: SchemaChange,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let changed = match db.migrate_schema(&model_name, change).await {
+        Ok(_) => db.describe_model(&model_name),
+        Err(e) => Err(e),
+    };
+    match changed {
+        Ok(model) => Ok(Box::new(reply::json(&model))),
+        Err(e) => Ok(write_error(&db, e)),
+    }
+}
+
+pub async fn webhooks_handler(db: Arc<NyroDB>) -> Result<impl Reply, Rejection> {
+    Ok(reply::json(&db.webhooks()))
+}
+
+pub async fn register_webhook_handler(
+    name: String,
+    body: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let registered = serde_json::from_value::<WebhookConfig>(body)
+        .map_err(anyhow::Error::from)
+        .and_then(|config| db.register_webhook(&name, config));
+    match registered {
+        Ok(status) => {
+            Logger::info_with_config(
+                &db.get_config().logging,
+                &format!("Registered webhook '{}' for '{}'", name, status.model),
+            );
+            Ok(Box::new(reply::json(&status)))
+        }
+        Err(e) => Ok(write_error(&db, e)),
+    }
+}
+
+pub async fn remove_webhook_handler(
+    name: String,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    match db.remove_webhook(&name) {
+        Ok(true) => Ok(json_status(
+            serde_json::json!({ "removed": name }),
+            StatusCode::OK,
+        )),
+        Ok(false) => Ok(error_status(
+            format!("Webhook '{}' not found", name),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => Ok(write_error(&db, e)),
+    }
+}
+
+pub async fn realtime_handler(
+    ws: warp::ws::Ws,
+    params: RealtimeParams,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let resume = match params.resume_positions().and_then(|resume| {
+        for (model_name, _) in &resume {
+            db.published_seq(model_name)?;
+        }
+        Ok(resume)
+    }) {
+        Ok(resume) => resume,
+        Err(e) => return Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    };
     let tx = db.real_time_tx.clone();
     Logger::info_with_config(&db.get_config().logging, "Realtime client connected");
-    Ok(ws.on_upgrade(move |socket| RealtimeServer::handle_client(socket, db, tx)))
+    Ok(Box::new(ws.on_upgrade(move |socket| {
+        RealtimeServer::handle_client(socket, db, tx, params.protocol, resume)
+    })))
 }
 
 pub async fn metrics_handler(db: Arc<NyroDB>) -> Result<Box<dyn Reply>, warp::Rejection> {
@@ -197,17 +800,187 @@ pub async fn config_handler(db: Arc<NyroDB>) -> Result<impl Reply, warp::Rejecti
 }
 
 pub async fn models_handler(db: Arc<NyroDB>) -> Result<impl Reply, warp::Rejection> {
-    let models: Vec<String> = db.get_config().models.keys().cloned().collect();
+    let models = db.model_names();
     Logger::info_with_config(&db.get_config().logging, "Models list requested");
     Ok(reply::json(&serde_json::json!({
         "models": models
     })))
 }
 
+/// Body of `POST /models`: the name with the fields and `on_conflict` of a
+/// `[models.<name>]` section.
+#[derive(Debug, Deserialize)]
+pub struct CreateModelRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub schema: ModelSchema,
+}
+
+pub async fn create_model_handler(
+    body: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let created = serde_json::from_value::<CreateModelRequest>(body)
+        .map_err(anyhow::Error::from)
+        .and_then(|request| db.create_model(&request.name, request.schema));
+    match created {
+        Ok(model) => Ok(Box::new(reply::with_status(
+            reply::json(&model),
+            StatusCode::CREATED,
+        ))),
+        Err(e) => Ok(write_error(&db, e)),
+    }
+}
+
+pub async fn describe_model_handler(
+    model_name: String,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if !db.has_model(&model_name) {
+        return Ok(error_status(
+            format!("Model '{}' not found", model_name),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+    match db.describe_model(&model_name) {
+        Ok(model) => Ok(Box::new(reply::json(&model))),
+        Err(e) => Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+pub async fn drop_model_handler(
+    model_name: String,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    match db.drop_model(&model_name) {
+        Ok(true) => Ok(json_status(
+            serde_json::json!({ "dropped": model_name }),
+            StatusCode::OK,
+        )),
+        Ok(false) => Ok(error_status(
+            format!("Model '{}' not found", model_name),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => Ok(write_error(&db, e)),
+    }
+}
+
 fn json_status(value: serde_json::Value, status: StatusCode) -> Box<dyn Reply> {
     Box::new(reply::with_status(reply::json(&value), status))
 }
 
-fn error_status(message: String, status: StatusCode) -> Box<dyn Reply> {
+pub(crate) fn error_status(message: String, status: StatusCode) -> Box<dyn Reply> {
     json_status(serde_json::json!({ "error": message }), status)
 }
+
+/// Adds the position of `models` after a write, for reads that must see it.
+fn with_position<'a>(
+    db: &NyroDB,
+    models: impl IntoIterator<Item = &'a str>,
+    reply: Box<dyn Reply>,
+) -> Box<dyn Reply> {
+    match db.log_position(models) {
+        Ok(position) => Box::new(reply::with_header(
+            reply,
+            POSITION_HEADER,
+            position.to_string(),
+        )),
+        Err(_) => reply,
+    }
+}
+
+/// Holds a read until this node has applied `min_position`. Fails with 504
+/// if a replica doesn't catch up within `wait`.
+async fn wait_for_position(db: &NyroDB, params: PositionParams) -> Result<(), Box<dyn Reply>> {
+    let Some(min_position) = params.min_position else {
+        return Ok(());
+    };
+    let position: LogPosition = min_position
+        .parse()
+        .map_err(|e: anyhow::Error| error_status(e.to_string(), StatusCode::BAD_REQUEST))?;
+    let wait = params
+        .wait
+        .unwrap_or(DEFAULT_POSITION_WAIT_MS)
+        .min(MAX_POSITION_WAIT_MS);
+    db.wait_for_position(&position, Duration::from_millis(wait))
+        .await
+        .map_err(|e| {
+            let status = if e.downcast_ref::<PositionTimeoutError>().is_some() {
+                StatusCode::GATEWAY_TIMEOUT
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            error_status(e.to_string(), status)
+        })
+}
+
+/// 409 for a rejected insert or a taken model name. A write sent to a replica
+/// gets 421 with the primary it follows, also in `x-nyrodb-primary`, so
+/// clients can retry there.
+fn write_error(db: &NyroDB, error: anyhow::Error) -> Box<dyn Reply> {
+    if error.downcast_ref::<ConflictError>().is_some()
+        || error.downcast_ref::<ModelExistsError>().is_some()
+    {
+        return error_status(error.to_string(), StatusCode::CONFLICT);
+    }
+    if error.downcast_ref::<ReadOnlyError>().is_none() {
+        return error_status(error.to_string(), StatusCode::BAD_REQUEST);
+    }
+    let primary = db.primary_url();
+    let body = json_status(
+        serde_json::json!({
+            "error": "This node is not the primary",
+            "primary": primary,
+            "epoch": db.epoch()
+        }),
+        StatusCode::MISDIRECTED_REQUEST,
+    );
+    match primary {
+        Some(primary) => Box::new(reply::with_header(body, PRIMARY_HEADER, primary)),
+        None => body,
+    }
+}
+
+pub async fn transaction_handler(
+    body: Value,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let limiter = db.get_concurrency_limiter();
+    let _permit = limiter
+        .acquire()
+        .await
+        .map_err(|_| warp::reject::reject())?;
+    let request: TransactionRequest = match serde_json::from_value(body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+    match db.commit_transaction(request.operations).await {
+        Ok(report) => {
+            Logger::info_with_config(
+                &db.get_config().logging,
+                &format!(
+                    "Committed transaction {} with {} operations",
+                    report.transaction_id,
+                    report.results.len()
+                ),
+            );
+            let models: std::collections::BTreeSet<&str> = report
+                .results
+                .iter()
+                .map(|result| result.model.as_str())
+                .collect();
+            Ok(with_position(
+                &db,
+                models,
+                Box::new(warp::reply::json(&report)),
+            ))
+        }
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Transaction aborted: {}", e),
+            );
+            Ok(write_error(&db, e))
+        }
+    }
+}
@@ -1,4 +1,9 @@
 pub mod benchmark;
+pub mod changes;
 pub mod handlers;
 pub mod realtime;
+pub mod replication;
 pub mod routes;
+mod subscription;
+#[cfg(test)]
+mod subscription_tests;
@@ -1,31 +1,276 @@
-use crate::database::NyroDB;
+use crate::api::subscription::Subscriptions;
+use crate::database::{ChangePosition, NyroDB};
+use crate::models::ChangeEvent;
 use crate::utils::logger::Logger;
+use anyhow::Result;
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
 use warp::ws::{Message, WebSocket};
 
+const REPLAY_BATCH_SIZE: usize = 1024;
+
+/// Realtime wire formats, picked with `?protocol=` when connecting. Clients
+/// that name none keep the original text frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum RealtimeProtocol {
+    /// `EVENT_TYPE:model:json` text frames, and nothing else.
+    #[default]
+    #[serde(rename = "1")]
+    Text,
+    /// JSON [`ChangeEvent`] envelopes.
+    #[serde(rename = "2")]
+    Json,
+}
+
+/// Query parameters of `/ws`. `after_seq` and `since` take comma separated
+/// `model:number` pairs, e.g. `after_seq=user:41,product:7`.
+#[derive(Debug, Default, Deserialize)]
+pub struct RealtimeParams {
+    #[serde(default)]
+    pub protocol: RealtimeProtocol,
+    pub after_seq: Option<String>,
+    pub since: Option<String>,
+}
+
 pub struct RealtimeServer;
 
+impl RealtimeProtocol {
+    fn version(self) -> u8 {
+        match self {
+            Self::Text => 1,
+            Self::Json => 2,
+        }
+    }
+
+    fn encode(self, event: &ChangeEvent) -> Option<String> {
+        match self {
+            Self::Text => Some(event.to_text()),
+            Self::Json => serde_json::to_string(event).ok(),
+        }
+    }
+}
+
+impl RealtimeParams {
+    /// The models to replay before streaming live changes, and where from.
+    pub fn resume_positions(&self) -> Result<Vec<(String, ChangePosition)>> {
+        let mut positions: Vec<(String, ChangePosition)> = Vec::new();
+        let pairs = [
+            (
+                self.after_seq.as_deref(),
+                ChangePosition::AfterSeq as fn(u64) -> _,
+            ),
+            (self.since.as_deref(), ChangePosition::Since),
+        ];
+        for (value, position) in pairs {
+            for pair in value
+                .unwrap_or_default()
+                .split(',')
+                .filter(|pair| !pair.is_empty())
+            {
+                let (model, number) = pair
+                    .split_once(':')
+                    .and_then(|(model, number)| Some((model, number.parse().ok()?)))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Invalid resume position '{}', expected model:number", pair)
+                    })?;
+                if positions.iter().any(|(existing, _)| existing == model) {
+                    return Err(anyhow::anyhow!(
+                        "Model '{}' is resumed more than once",
+                        model
+                    ));
+                }
+                positions.push((model.to_string(), position(number)));
+            }
+        }
+        if !positions.is_empty() && self.protocol == RealtimeProtocol::Text {
+            return Err(anyhow::anyhow!("Resuming requires protocol 2"));
+        }
+        Ok(positions)
+    }
+}
+
+/// The sending half of one connection. Tracks the last sequence number sent
+/// per model so replayed and live events never overlap.
+struct ClientStream {
+    ws_tx: SplitSink<WebSocket, Message>,
+    db: Arc<NyroDB>,
+    subscriptions: Arc<RwLock<Subscriptions>>,
+    protocol: RealtimeProtocol,
+    cursors: HashMap<String, u64>,
+}
+
+impl ClientStream {
+    async fn send(&mut self, msg: String) -> bool {
+        self.ws_tx.send(Message::text(msg)).await.is_ok()
+    }
+
+    async fn emit(&mut self, event: &ChangeEvent) -> bool {
+        if !self.subscriptions.read().wants(event) {
+            return true;
+        }
+        match self.protocol.encode(event) {
+            Some(msg) => self.send(msg).await,
+            None => true,
+        }
+    }
+
+    /// Sends a live event unless replay already covered it.
+    async fn deliver(&mut self, event: &ChangeEvent) -> bool {
+        let cursor = self.cursors.entry(event.model.clone()).or_default();
+        if event.seq <= *cursor {
+            return true;
+        }
+        *cursor = event.seq;
+        self.emit(event).await
+    }
+
+    /// Sends the model's stored changes from `position` up to the newest
+    /// published one, preceded by a gap notice if some were compacted away.
+    async fn replay(&mut self, model_name: &str, mut position: ChangePosition) -> bool {
+        loop {
+            let batch = match self
+                .db
+                .read_changes(model_name, position, REPLAY_BATCH_SIZE)
+                .await
+            {
+                Ok(batch) => batch,
+                Err(error) => {
+                    let message = format!("Failed to replay '{}': {}", model_name, error);
+                    Logger::error_with_config(&self.db.get_config().logging, &message);
+                    return self
+                        .send(
+                            serde_json::json!({ "type": "error", "message": message }).to_string(),
+                        )
+                        .await;
+                }
+            };
+            if let Some(gap) = &batch.gap {
+                if self.protocol == RealtimeProtocol::Json {
+                    let Ok(msg) = serde_json::to_string(gap) else {
+                        return false;
+                    };
+                    if !self.send(msg).await {
+                        return false;
+                    }
+                }
+            }
+            for event in &batch.events {
+                if !self.emit(event).await {
+                    return false;
+                }
+            }
+            self.cursors
+                .insert(model_name.to_string(), batch.next_after);
+            if !batch.more {
+                return true;
+            }
+            position = ChangePosition::AfterSeq(batch.next_after);
+        }
+    }
+
+    /// After the channel dropped events for this client, catches up on every
+    /// model from its cursor instead of losing them.
+    async fn recover(&mut self) -> bool {
+        let cursors: Vec<(String, u64)> = self
+            .cursors
+            .iter()
+            .map(|(model, seq)| (model.clone(), *seq))
+            .collect();
+        for (model_name, seq) in cursors {
+            if !self
+                .replay(&model_name, ChangePosition::AfterSeq(seq))
+                .await
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 impl RealtimeServer {
-    pub async fn handle_client(ws: WebSocket, _db: Arc<NyroDB>, tx: broadcast::Sender<String>) {
-        let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+    pub async fn handle_client(
+        ws: WebSocket,
+        db: Arc<NyroDB>,
+        tx: broadcast::Sender<Arc<ChangeEvent>>,
+        protocol: RealtimeProtocol,
+        resume: Vec<(String, ChangePosition)>,
+    ) {
+        let (user_ws_tx, mut user_ws_rx) = ws.split();
         let mut rx = tx.subscribe();
+        // Taken after subscribing, so every later change reaches `rx`.
+        let mut cursors = HashMap::new();
+        for model_name in db.model_names() {
+            if let Ok(seq) = db.published_seq(&model_name) {
+                cursors.insert(model_name, seq);
+            }
+        }
+        let subscriptions = Arc::new(RwLock::new(Subscriptions::default()));
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<String>();
+        // Protocol 1 clients split every frame on `:`, so they get no
+        // welcome or subscription replies.
+        let replies = protocol == RealtimeProtocol::Json;
+        if replies {
+            let _ = reply_tx.send(
+                serde_json::json!({ "type": "welcome", "protocol": protocol.version() })
+                    .to_string(),
+            );
+        }
 
-        // Spawn a task to listen for broadcast messages and send to this client
+        // Forward replies to subscription messages and the events this client
+        // subscribed to; filtering happens here so clients never see the rest.
+        let mut stream = ClientStream {
+            ws_tx: user_ws_tx,
+            db,
+            subscriptions: subscriptions.clone(),
+            protocol,
+            cursors,
+        };
         let send_task = tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                if user_ws_tx.send(Message::text(msg)).await.is_err() {
+            if let Ok(welcome) = reply_rx.try_recv() {
+                if !stream.send(welcome).await {
+                    return;
+                }
+            }
+            for (model_name, position) in resume {
+                if !stream.replay(&model_name, position).await {
+                    return;
+                }
+            }
+            loop {
+                let sent = tokio::select! {
+                    reply = reply_rx.recv() => match reply {
+                        Some(reply) => stream.send(reply).await,
+                        None => break,
+                    },
+                    event = rx.recv() => match event {
+                        Ok(event) => stream.deliver(&event).await,
+                        Err(RecvError::Lagged(_)) => stream.recover().await,
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+                if !sent {
                     break;
                 }
             }
         });
 
-        // Listen for messages from client (e.g. subscribe to specific models)
         while let Some(result) = user_ws_rx.next().await {
             match result {
-                Ok(_) => {
-                    // We could handle subscription logic here
+                Ok(message) => {
+                    let Ok(text) = message.to_str() else {
+                        continue;
+                    };
+                    let reply = subscriptions.write().handle_message(text);
+                    if replies && reply_tx.send(reply).is_err() {
+                        break;
+                    }
                 }
                 Err(e) => {
                     Logger::error(&format!("WebSocket error: {}", e));
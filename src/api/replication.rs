@@ -0,0 +1,166 @@
+use serde::Deserialize;
+use std::sync::Arc;
+use warp::http::{Response, StatusCode};
+use warp::{reply, Rejection, Reply};
+
+use crate::api::handlers::error_status;
+use crate::database::{
+    format_generation, FenceRequest, NyroDB, StaleEpochError, EPOCH_HEADER, LOG_END_HEADER,
+    LOG_GENERATION_HEADER, PRIMARY_HEADER, SCHEMA_VERSION_HEADER,
+};
+use crate::utils::logger::Logger;
+
+const DEFAULT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+
+/// Query parameters of the replication endpoints: where the replica's copy
+/// ends and how much it wants at once.
+#[derive(Debug, Default, Deserialize)]
+pub struct ReplicationParams {
+    #[serde(default)]
+    pub offset: u64,
+    pub max_bytes: Option<usize>,
+}
+
+/// `GET /replication/log/:model`: raw frames from `offset`, with the log
+/// end and generation in headers.
+pub async fn log_handler(
+    model_name: String,
+    params: ReplicationParams,
+    epoch: Option<u64>,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(e) = db.observe_epoch(epoch.unwrap_or_default()) {
+        return Ok(epoch_error(&db, e));
+    }
+    let max_bytes = params.max_bytes.unwrap_or(DEFAULT_CHUNK_BYTES);
+    match db
+        .read_log_chunk(&model_name, params.offset, max_bytes)
+        .await
+    {
+        Ok(chunk) => Ok(binary_reply(
+            Response::builder()
+                .header(EPOCH_HEADER, chunk.epoch)
+                .header(LOG_END_HEADER, chunk.end)
+                .header(LOG_GENERATION_HEADER, format_generation(&chunk.generation))
+                .header(SCHEMA_VERSION_HEADER, chunk.schema_version),
+            chunk.bytes,
+        )),
+        Err(e) => Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+/// `GET /replication/transactions`: transaction log records from `offset`.
+pub async fn transactions_handler(
+    params: ReplicationParams,
+    epoch: Option<u64>,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(e) = db.observe_epoch(epoch.unwrap_or_default()) {
+        return Ok(epoch_error(&db, e));
+    }
+    match db.read_transaction_records(params.offset) {
+        Ok((records, end)) => Ok(binary_reply(
+            Response::builder()
+                .header(EPOCH_HEADER, db.epoch())
+                .header(LOG_END_HEADER, end),
+            records,
+        )),
+        Err(e) => Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+/// `GET /replication/schema/:model`: the schema versions a replica needs
+/// to decode frames stamped with a newer version than its own.
+pub async fn schema_handler(
+    model_name: String,
+    epoch: Option<u64>,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(e) = db.observe_epoch(epoch.unwrap_or_default()) {
+        return Ok(epoch_error(&db, e));
+    }
+    match db.schema_versions(&model_name) {
+        Ok(versions) => Ok(Box::new(reply::with_header(
+            reply::json(&versions),
+            EPOCH_HEADER,
+            db.epoch().to_string(),
+        ))),
+        Err(e) => Ok(error_status(e.to_string(), StatusCode::BAD_REQUEST)),
+    }
+}
+
+/// `GET /replication/catalog`: every model the primary serves, which a
+/// replica creates or drops to match.
+pub async fn catalog_handler(
+    epoch: Option<u64>,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(e) = db.observe_epoch(epoch.unwrap_or_default()) {
+        return Ok(epoch_error(&db, e));
+    }
+    Ok(Box::new(reply::with_header(
+        reply::json(&db.models()),
+        EPOCH_HEADER,
+        db.epoch().to_string(),
+    )))
+}
+
+pub async fn status_handler(db: Arc<NyroDB>) -> Result<impl Reply, Rejection> {
+    Ok(reply::json(&db.replication_status()))
+}
+
+/// `POST /replication/fence`: sent by a new primary to the node it replaced.
+pub async fn fence_handler(
+    request: FenceRequest,
+    db: Arc<NyroDB>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    match db.fence(request) {
+        Ok(status) => Ok(Box::new(reply::json(&status))),
+        Err(e) => Ok(epoch_error(&db, e)),
+    }
+}
+
+/// `POST /admin/promote`: makes this replica the primary of a new epoch.
+pub async fn promote_handler(db: Arc<NyroDB>) -> Result<Box<dyn Reply>, Rejection> {
+    match db.promote().await {
+        Ok(status) => Ok(Box::new(reply::json(&status))),
+        Err(e) => {
+            Logger::error_with_config(
+                &db.get_config().logging,
+                &format!("Failed to promote: {}", e),
+            );
+            Ok(error_status(e.to_string(), StatusCode::CONFLICT))
+        }
+    }
+}
+
+/// 409 with this node's epoch and the primary it knows for epoch
+/// conflicts, 400 otherwise.
+fn epoch_error(db: &NyroDB, error: anyhow::Error) -> Box<dyn Reply> {
+    if error.downcast_ref::<StaleEpochError>().is_none() {
+        return error_status(error.to_string(), StatusCode::BAD_REQUEST);
+    }
+    let primary = match db.is_replica() {
+        true => db.primary_url().unwrap_or_default(),
+        false => db.advertise_url(),
+    };
+    Box::new(reply::with_header(
+        reply::with_header(
+            error_status(error.to_string(), StatusCode::CONFLICT),
+            EPOCH_HEADER,
+            db.epoch().to_string(),
+        ),
+        PRIMARY_HEADER,
+        primary,
+    ))
+}
+
+fn binary_reply(builder: warp::http::response::Builder, bytes: Vec<u8>) -> Box<dyn Reply> {
+    match builder
+        .header("content-type", "application/octet-stream")
+        .body(bytes)
+    {
+        Ok(response) => Box::new(response),
+        Err(e) => error_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
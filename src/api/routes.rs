@@ -3,8 +3,11 @@ use std::sync::Arc;
 use warp::http::StatusCode;
 use warp::{Filter, Rejection, Reply};
 
-use crate::api::{benchmark, handlers};
-use crate::database::NyroDB;
+use crate::api::changes::ChangeParams;
+use crate::api::realtime::RealtimeParams;
+use crate::api::replication::ReplicationParams;
+use crate::api::{benchmark, changes, handlers, replication};
+use crate::database::{NyroDB, EPOCH_HEADER};
 
 #[derive(Debug)]
 struct AuthError;
@@ -67,26 +70,67 @@ pub fn create_routes(
 
     let insert_route = warp::path!("insert" / String)
         .and(warp::post())
+        .and(warp::query::<handlers::InsertOptions>())
         .and(warp::body::json())
         .and(db_filter.clone())
         .and_then(handlers::insert_handler);
 
     let insert_many_route = warp::path!("insert_many" / String)
         .and(warp::post())
+        .and(warp::query::<handlers::InsertOptions>())
         .and(warp::body::json())
         .and(db_filter.clone())
         .and_then(handlers::insert_many_handler);
 
     let get_route = warp::path!("get" / String / u64)
         .and(warp::get())
+        .and(warp::query::<handlers::PositionParams>())
         .and(db_filter.clone())
         .and_then(handlers::get_handler);
 
+    let update_route = warp::path!("update" / String / u64)
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::update_handler);
+
+    let patch_route = warp::path!("update" / String / u64)
+        .and(warp::patch())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::patch_handler);
+
+    let delete_route = warp::path!("delete" / String / u64)
+        .and(warp::delete())
+        .and(db_filter.clone())
+        .and_then(handlers::delete_handler);
+
     let query_route = warp::path!("query" / String)
         .and(warp::get())
+        .and(warp::query::<handlers::PageParams>())
+        .and(warp::query::<handlers::PositionParams>())
         .and(db_filter.clone())
         .and_then(handlers::query_handler);
 
+    let query_structured_route = warp::path!("query" / String)
+        .and(warp::post())
+        .and(warp::query::<handlers::PositionParams>())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::query_structured_handler);
+
+    let aggregate_route = warp::path!("aggregate" / String)
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::aggregate_handler);
+
+    let transaction_route = warp::path!("txn")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::transaction_handler);
+
     let metrics_route = warp::path!("metrics")
         .and(warp::get())
         .and(db_filter.clone())
@@ -107,28 +151,183 @@ pub fn create_routes(
         .and(db_filter.clone())
         .and_then(handlers::models_handler);
 
+    let create_model_route = warp::path!("models")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::create_model_handler);
+
+    let describe_model_route = warp::path!("models" / String)
+        .and(warp::get())
+        .and(db_filter.clone())
+        .and_then(handlers::describe_model_handler);
+
+    let drop_model_route = warp::path!("models" / String)
+        .and(warp::delete())
+        .and(db_filter.clone())
+        .and_then(handlers::drop_model_handler);
+
     let query_field_route = warp::path!("query" / String / String / String)
         .and(warp::get())
+        .and(warp::query::<handlers::PositionParams>())
         .and(db_filter.clone())
         .and_then(handlers::query_field_handler);
 
+    let query_range_route = warp::path!("query" / String / String)
+        .and(warp::get())
+        .and(warp::query::<handlers::RangeParams>())
+        .and(warp::query::<handlers::PositionParams>())
+        .and(db_filter.clone())
+        .and_then(handlers::query_range_handler);
+
+    let compact_route = warp::path!("admin" / "compact" / String)
+        .and(warp::post())
+        .and(db_filter.clone())
+        .and_then(handlers::compact_handler);
+
+    let backup_route = warp::path!("admin" / "backup")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::backup_handler);
+
+    let schema_route = warp::path!("admin" / "schema" / String)
+        .and(warp::get())
+        .and(db_filter.clone())
+        .and_then(handlers::schema_handler);
+
+    let migrate_schema_route = warp::path!("admin" / "schema" / String)
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::migrate_schema_handler);
+
+    let add_index_route = warp::path!("admin" / "indexes" / String)
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::add_index_handler);
+
+    let drop_index_route = warp::path!("admin" / "indexes" / String / String)
+        .and(warp::delete())
+        .and(db_filter.clone())
+        .and_then(handlers::drop_index_handler);
+
+    let replication_log_route = warp::path!("replication" / "log" / String)
+        .and(warp::get())
+        .and(warp::query::<ReplicationParams>())
+        .and(warp::header::optional::<u64>(EPOCH_HEADER))
+        .and(db_filter.clone())
+        .and_then(replication::log_handler);
+
+    let replication_transactions_route = warp::path!("replication" / "transactions")
+        .and(warp::get())
+        .and(warp::query::<ReplicationParams>())
+        .and(warp::header::optional::<u64>(EPOCH_HEADER))
+        .and(db_filter.clone())
+        .and_then(replication::transactions_handler);
+
+    let replication_schema_route = warp::path!("replication" / "schema" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<u64>(EPOCH_HEADER))
+        .and(db_filter.clone())
+        .and_then(replication::schema_handler);
+
+    let replication_catalog_route = warp::path!("replication" / "catalog")
+        .and(warp::get())
+        .and(warp::header::optional::<u64>(EPOCH_HEADER))
+        .and(db_filter.clone())
+        .and_then(replication::catalog_handler);
+
+    let replication_status_route = warp::path!("replication" / "status")
+        .and(warp::get())
+        .and(db_filter.clone())
+        .and_then(replication::status_handler);
+
+    let fence_route = warp::path!("replication" / "fence")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(replication::fence_handler);
+
+    let promote_route = warp::path!("admin" / "promote")
+        .and(warp::post())
+        .and(db_filter.clone())
+        .and_then(replication::promote_handler);
+
+    let webhooks_route = warp::path!("admin" / "webhooks")
+        .and(warp::get())
+        .and(db_filter.clone())
+        .and_then(handlers::webhooks_handler);
+
+    let register_webhook_route = warp::path!("admin" / "webhooks" / String)
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(handlers::register_webhook_handler);
+
+    let remove_webhook_route = warp::path!("admin" / "webhooks" / String)
+        .and(warp::delete())
+        .and(db_filter.clone())
+        .and_then(handlers::remove_webhook_handler);
+
+    let changes_route = warp::path!("changes" / String)
+        .and(warp::get())
+        .and(warp::query::<ChangeParams>())
+        .and(warp::header::optional::<String>("last-event-id"))
+        .and(db_filter.clone())
+        .and_then(changes::changes_handler);
+
     let realtime_route = warp::path("ws")
         .and(warp::ws())
+        .and(warp::query::<RealtimeParams>())
         .and(db_filter.clone())
         .and_then(handlers::realtime_handler);
 
     let auth = with_auth(db.clone());
 
-    let routes = insert_route
+    // Grouped so the nested filter types stay shallow enough to compile.
+    let data_routes = insert_route
         .or(insert_many_route)
         .or(get_route)
+        .or(update_route)
+        .or(patch_route)
+        .or(delete_route)
         .or(query_route)
+        .or(query_structured_route)
         .or(query_field_route)
-        .or(realtime_route)
+        .or(query_range_route)
+        .or(aggregate_route)
+        .or(transaction_route)
+        .or(changes_route)
+        .or(realtime_route);
+
+    let admin_routes = webhooks_route
+        .or(register_webhook_route)
+        .or(remove_webhook_route)
         .or(metrics_route)
         .or(benchmark_route)
         .or(config_route)
-        .or(models_route);
+        .or(models_route)
+        .or(create_model_route)
+        .or(describe_model_route)
+        .or(drop_model_route)
+        .or(compact_route)
+        .or(backup_route)
+        .or(schema_route)
+        .or(migrate_schema_route)
+        .or(add_index_route)
+        .or(drop_index_route);
+
+    let replication_routes = replication_log_route
+        .or(replication_transactions_route)
+        .or(replication_schema_route)
+        .or(replication_catalog_route)
+        .or(replication_status_route)
+        .or(fence_route)
+        .or(promote_route);
+
+    let routes = data_routes.or(admin_routes).or(replication_routes);
 
     auth.and(routes).recover(handle_rejection)
 }
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::database::Filter;
+use crate::models::ChangeEvent;
+
+const EVENT_TYPES: [&str; 3] = ["INSERT", "UPDATE", "DELETE"];
+
+/// A control message sent by a realtime client, e.g.
+/// `{ "action": "subscribe", "id": "vip", "model": "user", "operations": ["insert"] }`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub(crate) enum ClientMessage {
+    Subscribe(SubscribeRequest),
+    Unsubscribe { id: String },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubscribeRequest {
+    pub(crate) id: String,
+    /// All models when omitted.
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+    /// All operations when empty.
+    #[serde(default)]
+    pub(crate) operations: Vec<String>,
+    /// Same document as the `POST /query/:model` filter, applied to the payload.
+    #[serde(default)]
+    pub(crate) filter: Option<Value>,
+}
+
+struct Subscription {
+    model: Option<String>,
+    operations: Vec<&'static str>,
+    filter: Option<Filter>,
+}
+
+/// The subscriptions of one connection. A connection without any
+/// subscription receives every event.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    by_id: BTreeMap<String, Subscription>,
+}
+
+impl Subscriptions {
+    /// Applies a client message and returns the JSON reply for the client.
+    pub(crate) fn handle_message(&mut self, text: &str) -> String {
+        let reply = serde_json::from_str::<ClientMessage>(text)
+            .map_err(Into::into)
+            .and_then(|message| self.apply(message));
+        match reply {
+            Ok(reply) => reply.to_string(),
+            Err(error) => {
+                serde_json::json!({ "type": "error", "message": error.to_string() }).to_string()
+            }
+        }
+    }
+
+    fn apply(&mut self, message: ClientMessage) -> Result<Value> {
+        match message {
+            ClientMessage::Subscribe(request) => {
+                let operations = request
+                    .operations
+                    .iter()
+                    .map(|operation| {
+                        let upper = operation.to_ascii_uppercase();
+                        EVENT_TYPES
+                            .into_iter()
+                            .find(|event_type| *event_type == upper)
+                            .ok_or_else(|| anyhow::anyhow!("Unknown operation '{}'", operation))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let filter = request.filter.as_ref().map(Filter::parse).transpose()?;
+                self.by_id.insert(
+                    request.id.clone(),
+                    Subscription {
+                        model: request.model,
+                        operations,
+                        filter,
+                    },
+                );
+                Ok(serde_json::json!({ "type": "subscribed", "id": request.id }))
+            }
+            ClientMessage::Unsubscribe { id } => {
+                if self.by_id.remove(&id).is_none() {
+                    return Err(anyhow::anyhow!("No subscription with id '{}'", id));
+                }
+                Ok(serde_json::json!({ "type": "unsubscribed", "id": id }))
+            }
+        }
+    }
+
+    pub(crate) fn wants(&self, event: &ChangeEvent) -> bool {
+        if self.by_id.is_empty() {
+            return true;
+        }
+        let event_type = event.operation.event_type();
+        self.by_id.values().any(|subscription| {
+            subscription
+                .model
+                .as_ref()
+                .is_none_or(|model| *model == event.model)
+                && (subscription.operations.is_empty()
+                    || subscription.operations.contains(&event_type))
+                && subscription
+                    .filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.matches(&event.data))
+        })
+    }
+}
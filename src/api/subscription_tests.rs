@@ -0,0 +1,90 @@
+use serde_json::{json, Value};
+
+use super::subscription::Subscriptions;
+use crate::models::{ChangeEvent, Operation};
+
+#[test]
+fn connections_without_subscriptions_receive_every_event() {
+    let subscriptions = Subscriptions::default();
+
+    assert!(subscriptions.wants(&event("user", Operation::Insert, json!({ "id": 1 }))));
+    assert!(subscriptions.wants(&event("product", Operation::Delete, json!({ "id": 2 }))));
+}
+
+#[test]
+fn subscriptions_filter_by_model_operation_and_predicate() -> anyhow::Result<()> {
+    let mut subscriptions = Subscriptions::default();
+    let reply = subscriptions.handle_message(
+        &json!({
+            "action": "subscribe",
+            "id": "vip",
+            "model": "user",
+            "operations": ["insert", "update"],
+            "filter": { "field": "tier", "eq": "vip" }
+        })
+        .to_string(),
+    );
+    assert_eq!(
+        serde_json::from_str::<Value>(&reply)?,
+        json!({ "type": "subscribed", "id": "vip" })
+    );
+
+    let vip = json!({ "id": 1, "tier": "vip" });
+    assert!(subscriptions.wants(&event("user", Operation::Insert, vip.clone())));
+    assert!(subscriptions.wants(&event("user", Operation::Update, vip.clone())));
+    assert!(!subscriptions.wants(&event(
+        "user",
+        Operation::Insert,
+        json!({ "id": 2, "tier": "free" })
+    )));
+    assert!(!subscriptions.wants(&event("user", Operation::Delete, json!({ "id": 1 }))));
+    assert!(!subscriptions.wants(&event("product", Operation::Insert, vip.clone())));
+
+    subscriptions.handle_message(
+        &json!({ "action": "subscribe", "id": "deletes", "operations": ["delete"] }).to_string(),
+    );
+    assert!(subscriptions.wants(&event("product", Operation::Delete, json!({ "id": 3 }))));
+
+    let reply = subscriptions.handle_message(r#"{"action":"unsubscribe","id":"vip"}"#);
+    assert!(reply.contains("unsubscribed"));
+    assert!(!subscriptions.wants(&event("user", Operation::Insert, vip)));
+
+    let reply = subscriptions
+        .handle_message(r#"{"action":"subscribe","id":"bad","operations":["upsert"]}"#);
+    assert!(reply.contains("Unknown operation 'upsert'"));
+    Ok(())
+}
+
+#[test]
+fn events_encode_as_json_envelope_or_legacy_text() -> anyhow::Result<()> {
+    let mut update = event("user", Operation::Update, json!({ "id": 1, "tier": "vip" }));
+    update.previous = Some(json!({ "id": 1, "tier": "free" }));
+
+    assert_eq!(
+        serde_json::to_value(&update)?,
+        json!({
+            "type": "event",
+            "model": "user",
+            "operation": "UPDATE",
+            "id": 1,
+            "timestamp": 5,
+            "seq": 9,
+            "data": { "id": 1, "tier": "vip" },
+            "previous": { "id": 1, "tier": "free" }
+        })
+    );
+    assert_eq!(update.to_text(), r#"UPDATE:user:{"id":1,"tier":"vip"}"#);
+    Ok(())
+}
+
+fn event(model: &str, operation: Operation, data: Value) -> ChangeEvent {
+    ChangeEvent {
+        model: model.to_string(),
+        operation,
+        id: data.get("id").and_then(Value::as_u64).unwrap_or_default(),
+        timestamp: 5,
+        seq: 9,
+        data,
+        previous: None,
+    }
+}
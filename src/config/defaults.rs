@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
 use crate::config::{
-    LoggingConfig, MetricsConfig, ModelField, ModelSchema, NyroConfig, PerformanceConfig,
-    SecurityConfig, ServerConfig, StorageConfig,
+    BackupConfig, ConflictPolicy, LoggingConfig, MetricsConfig, ModelField, ModelSchema,
+    NyroConfig, PerformanceConfig, ReplicationConfig, ReplicationRole, SecurityConfig,
+    ServerConfig, StorageConfig,
 };
 
 impl Default for NyroConfig {
@@ -18,6 +19,13 @@ impl Default for NyroConfig {
                 buffer_size: 8 * 1024 * 1024,
                 enable_mmap: true,
                 sync_interval: 1000,
+                compaction_min_bytes: compaction_min_bytes(),
+                compaction_garbage_ratio: compaction_garbage_ratio(),
+                compaction_check_interval: compaction_check_interval(),
+                strict_recovery: false,
+                index_checkpoint_bytes: index_checkpoint_bytes(),
+                index_checkpoint_interval: index_checkpoint_interval(),
+                row_cache_bytes: 0,
             },
             performance: PerformanceConfig {
                 batch_size: 1000,
@@ -39,10 +47,67 @@ impl Default for NyroConfig {
                 api_key: String::new(),
             },
             models: default_models(),
+            webhooks: HashMap::new(),
+            replication: ReplicationConfig::default(),
+            backup: BackupConfig::default(),
         }
     }
 }
 
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            root: backup_root(),
+        }
+    }
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            role: ReplicationRole::Primary,
+            primary_url: String::new(),
+            poll_interval: replication_poll_interval(),
+            batch_bytes: replication_batch_bytes(),
+            failover_timeout: 0,
+            sole_replica: false,
+            advertise_url: String::new(),
+        }
+    }
+}
+
+pub(super) fn compaction_min_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+pub(super) fn compaction_garbage_ratio() -> f64 {
+    0.5
+}
+
+pub(super) fn compaction_check_interval() -> u64 {
+    60_000
+}
+
+pub(super) fn index_checkpoint_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+pub(super) fn index_checkpoint_interval() -> u64 {
+    60_000
+}
+
+pub(super) fn replication_poll_interval() -> u64 {
+    500
+}
+
+pub(super) fn replication_batch_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+pub(super) fn backup_root() -> String {
+    "./backups".to_string()
+}
+
 fn default_models() -> HashMap<String, ModelSchema> {
     let mut models = HashMap::new();
     models.insert(
@@ -54,6 +119,8 @@ fn default_models() -> HashMap<String, ModelSchema> {
                 field("hash_password", "string", true),
                 field("created_at", "u64", true),
             ],
+            on_conflict: ConflictPolicy::default(),
+            cache_bytes: None,
         },
     );
     models.insert(
@@ -65,6 +132,8 @@ fn default_models() -> HashMap<String, ModelSchema> {
                 field("price", "u32", true),
                 field("category_id", "u64", true),
             ],
+            on_conflict: ConflictPolicy::default(),
+            cache_bytes: None,
         },
     );
     models
@@ -76,5 +145,6 @@ fn field(name: &str, field_type: &str, required: bool) -> ModelField {
         field_type: field_type.to_string(),
         required,
         indexed: false,
+        index: None,
     }
 }
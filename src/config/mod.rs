@@ -17,7 +17,15 @@ pub struct NyroConfig {
     pub logging: LoggingConfig,
     pub metrics: MetricsConfig,
     pub security: SecurityConfig,
+    /// Models created through the API are kept in the data directory.
+    #[serde(default)]
     pub models: HashMap<String, ModelSchema>,
+    #[serde(default)]
+    pub webhooks: HashMap<String, WebhookConfig>,
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +47,20 @@ pub struct StorageConfig {
     pub buffer_size: usize,
     pub enable_mmap: bool,
     pub sync_interval: u64,
+    #[serde(default = "defaults::compaction_min_bytes")]
+    pub compaction_min_bytes: u64,
+    #[serde(default = "defaults::compaction_garbage_ratio")]
+    pub compaction_garbage_ratio: f64,
+    #[serde(default = "defaults::compaction_check_interval")]
+    pub compaction_check_interval: u64,
+    #[serde(default)]
+    pub strict_recovery: bool,
+    #[serde(default = "defaults::index_checkpoint_bytes")]
+    pub index_checkpoint_bytes: u64,
+    #[serde(default = "defaults::index_checkpoint_interval")]
+    pub index_checkpoint_interval: u64,
+    #[serde(default)]
+    pub row_cache_bytes: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -65,6 +87,75 @@ pub struct MetricsConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelSchema {
     pub fields: Vec<ModelField>,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// Overrides `storage.row_cache_bytes` for this model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_bytes: Option<u64>,
+}
+
+/// POSTs a model's change events to `url`. The body is signed with `secret`
+/// when one is set; `operations` limits delivery to `insert`, `update` or
+/// `delete` events.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub model: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operations: Vec<String>,
+}
+
+/// `POST /admin/backup` only writes below `root`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+    #[serde(default = "defaults::backup_root")]
+    pub root: String,
+}
+
+/// A `replica` follows the log of the primary at `primary_url`, polling
+/// every `poll_interval` milliseconds for up to `batch_bytes` per model. With
+/// a `failover_timeout` it promotes itself after losing the primary for that
+/// many milliseconds, which needs `sole_replica`; `advertise_url` is how
+/// other nodes reach this one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    #[serde(default)]
+    pub role: ReplicationRole,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub primary_url: String,
+    #[serde(default = "defaults::replication_poll_interval")]
+    pub poll_interval: u64,
+    #[serde(default = "defaults::replication_batch_bytes")]
+    pub batch_bytes: usize,
+    #[serde(default)]
+    pub failover_timeout: u64,
+    #[serde(default)]
+    pub sole_replica: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub advertise_url: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationRole {
+    #[default]
+    Primary,
+    Replica,
+}
+
+/// What an insert does when a row with the same `id` already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    Reject,
+    #[default]
+    Overwrite,
+    UpsertMerge,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -76,6 +167,108 @@ pub struct ModelField {
     pub required: bool,
     #[serde(default)]
     pub indexed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<IndexKind>,
+}
+
+/// `hash` serves equality lookups, `ordered` also serves range queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    #[default]
+    Hash,
+    Ordered,
+}
+
+impl ModelField {
+    /// `indexed = true` without an explicit `index` is a hash index.
+    pub fn index_kind(&self) -> Option<IndexKind> {
+        self.index.or(self.indexed.then_some(IndexKind::Hash))
+    }
+}
+
+impl ModelSchema {
+    pub fn validate(&self, model_name: &str) -> Result<()> {
+        if self.fields.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Model '{}' has no fields defined",
+                model_name
+            ));
+        }
+        let mut field_names = HashSet::new();
+        if !self.fields.iter().any(|field| field.name == "id") {
+            return Err(anyhow::anyhow!(
+                "Model '{}' must have an 'id' field",
+                model_name
+            ));
+        }
+        for field in &self.fields {
+            if !field_names.insert(&field.name) {
+                return Err(anyhow::anyhow!(
+                    "Model '{}' has duplicate field '{}'",
+                    model_name,
+                    field.name
+                ));
+            }
+            if !crate::database::validation::validate_supported_field_type(&field.field_type) {
+                return Err(anyhow::anyhow!(
+                    "Model '{}' field '{}' has unsupported type '{}'",
+                    model_name,
+                    field.name,
+                    field.field_type
+                ));
+            }
+            if field.index_kind() == Some(IndexKind::Ordered)
+                && !crate::database::validation::validate_orderable_field_type(&field.field_type)
+            {
+                return Err(anyhow::anyhow!(
+                    "Model '{}' field '{}' of type '{}' cannot have an ordered index",
+                    model_name,
+                    field.name,
+                    field.field_type
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WebhookConfig {
+    pub fn validate(&self, name: &str, models: &HashMap<String, ModelSchema>) -> Result<()> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow::anyhow!(
+                "Webhook name '{}' may only contain letters, digits, '-' and '_'",
+                name
+            ));
+        }
+        if !models.contains_key(&self.model) {
+            return Err(anyhow::anyhow!(
+                "Webhook '{}' targets undefined model '{}'",
+                name,
+                self.model
+            ));
+        }
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(anyhow::anyhow!(
+                "Webhook '{}' URL must start with http:// or https://",
+                name
+            ));
+        }
+        for operation in &self.operations {
+            if !matches!(operation.as_str(), "insert" | "update" | "delete") {
+                return Err(anyhow::anyhow!(
+                    "Webhook '{}' has unknown operation '{}'",
+                    name,
+                    operation
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl NyroConfig {
@@ -142,6 +335,23 @@ impl NyroConfig {
         if self.storage.data_dir.is_empty() {
             return Err(anyhow::anyhow!("Data directory cannot be empty"));
         }
+        if !(self.storage.compaction_garbage_ratio > 0.0
+            && self.storage.compaction_garbage_ratio <= 1.0)
+        {
+            return Err(anyhow::anyhow!(
+                "Compaction garbage ratio must be in (0, 1]"
+            ));
+        }
+        if self.storage.compaction_min_bytes > 0 && self.storage.compaction_check_interval == 0 {
+            return Err(anyhow::anyhow!(
+                "Compaction check interval cannot be 0 when automatic compaction is enabled"
+            ));
+        }
+        if self.storage.index_checkpoint_bytes > 0 && self.storage.index_checkpoint_interval == 0 {
+            return Err(anyhow::anyhow!(
+                "Index checkpoint interval cannot be 0 when index checkpoints are enabled"
+            ));
+        }
         if self.performance.batch_size == 0 {
             return Err(anyhow::anyhow!("Batch size cannot be 0"));
         }
@@ -167,40 +377,47 @@ impl NyroConfig {
         if self.metrics.max_samples == 0 {
             return Err(anyhow::anyhow!("Max samples cannot be 0"));
         }
-        if self.models.is_empty() {
-            return Err(anyhow::anyhow!("No models defined in configuration"));
-        }
         for (model_name, schema) in &self.models {
-            if schema.fields.is_empty() {
-                return Err(anyhow::anyhow!(
-                    "Model '{}' has no fields defined",
-                    model_name
-                ));
-            }
-            let mut field_names = HashSet::new();
-            if !schema.fields.iter().any(|field| field.name == "id") {
-                return Err(anyhow::anyhow!(
-                    "Model '{}' must have an 'id' field",
-                    model_name
-                ));
-            }
-            for field in &schema.fields {
-                if !field_names.insert(&field.name) {
-                    return Err(anyhow::anyhow!(
-                        "Model '{}' has duplicate field '{}'",
-                        model_name,
-                        field.name
-                    ));
-                }
-                if !crate::database::validation::validate_supported_field_type(&field.field_type) {
-                    return Err(anyhow::anyhow!(
-                        "Model '{}' field '{}' has unsupported type '{}'",
-                        model_name,
-                        field.name,
-                        field.field_type
-                    ));
-                }
-            }
+            schema.validate(model_name)?;
+        }
+        for (name, webhook) in &self.webhooks {
+            webhook.validate(name, &self.models)?;
+        }
+        if self.replication.role == ReplicationRole::Replica
+            && !self.replication.primary_url.starts_with("http://")
+        {
+            return Err(anyhow::anyhow!(
+                "A replica needs a primary_url starting with http://"
+            ));
+        }
+        if self.replication.poll_interval == 0 || self.replication.batch_bytes == 0 {
+            return Err(anyhow::anyhow!(
+                "Replication poll interval and batch bytes cannot be 0"
+            ));
+        }
+        if self.replication.failover_timeout > 0
+            && self.replication.failover_timeout <= self.replication.poll_interval
+        {
+            return Err(anyhow::anyhow!(
+                "Replication failover timeout must be longer than the poll interval"
+            ));
+        }
+        // Two replicas that lose the primary together would both promote
+        // themselves into the same epoch.
+        if self.replication.failover_timeout > 0 && !self.replication.sole_replica {
+            return Err(anyhow::anyhow!(
+                "Replication failover_timeout needs sole_replica = true"
+            ));
+        }
+        if !self.replication.advertise_url.is_empty()
+            && !self.replication.advertise_url.starts_with("http://")
+        {
+            return Err(anyhow::anyhow!(
+                "Replication advertise_url must start with http://"
+            ));
+        }
+        if self.backup.root.is_empty() {
+            return Err(anyhow::anyhow!("Backup root cannot be empty"));
         }
         Ok(())
     }
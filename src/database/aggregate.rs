@@ -0,0 +1,256 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::database::filter::{compare_values, sort_order, value_at_path, Filter};
+use crate::database::query::for_each_match;
+use crate::database::types::NyroDB;
+use crate::database::validation::{NumericKind, SchemaPlan};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AggregateRequest {
+    #[serde(default)]
+    pub filter: Option<Value>,
+    #[serde(default)]
+    pub group_by: Option<String>,
+    /// Output name to aggregate, e.g. `"revenue": { "op": "sum", "field": "price" }`.
+    pub aggregates: BTreeMap<String, AggregateSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AggregateSpec {
+    pub op: AggregateOp,
+    #[serde(default)]
+    pub field: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AggregateOp {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AggregateResponse {
+    pub groups: Vec<AggregateGroup>,
+    pub plan: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AggregateGroup {
+    /// The `group_by` value, or `null` when not grouping.
+    pub key: Value,
+    pub values: Map<String, Value>,
+}
+
+#[derive(Clone)]
+struct Accumulator {
+    name: String,
+    op: AggregateOp,
+    field: Option<String>,
+    kind: NumericKind,
+    count: u64,
+    integer_sum: i128,
+    float_sum: f64,
+    extreme: Option<Value>,
+}
+
+impl NyroDB {
+    pub async fn aggregate(
+        &self,
+        model_name: &str,
+        request: &AggregateRequest,
+    ) -> Result<AggregateResponse> {
+        let runtime = self.get_runtime(model_name)?;
+        let accumulators = accumulators(&runtime.schema_plan(), request)?;
+        if let Some(group_by) = &request.group_by {
+            if !runtime.schema_plan().has_field(group_by) {
+                return Err(anyhow::anyhow!(
+                    "Cannot group by '{}': not a field of '{}'",
+                    group_by,
+                    model_name
+                ));
+            }
+        }
+        let filter = request.filter.as_ref().map(Filter::parse).transpose()?;
+        let storage = &runtime.storage;
+
+        let counts_only = accumulators
+            .iter()
+            .all(|accumulator| accumulator.op == AggregateOp::Count && accumulator.field.is_none());
+        if let (None, Some(group_by), true) = (&filter, &request.group_by, counts_only) {
+            if let Some(buckets) = storage.index_bucket_sizes(group_by) {
+                let indexed_rows: usize = buckets.iter().map(|(_, size)| size).sum();
+                let mut groups = buckets;
+                let unindexed_rows = storage.len().saturating_sub(indexed_rows);
+                if unindexed_rows > 0 {
+                    groups.push((Value::Null, unindexed_rows));
+                }
+                let mut groups = groups
+                    .into_iter()
+                    .map(|(key, size)| AggregateGroup {
+                        key,
+                        values: accumulators
+                            .iter()
+                            .map(|accumulator| (accumulator.name.clone(), size.into()))
+                            .collect(),
+                    })
+                    .collect::<Vec<_>>();
+                groups.sort_by(|left, right| sort_order(Some(&left.key), Some(&right.key)));
+                let plan = if storage.has_ordered_index(group_by) {
+                    format!("range({})", group_by)
+                } else {
+                    format!("index({})", group_by)
+                };
+                self.metrics.record_query();
+                return Ok(AggregateResponse { groups, plan });
+            }
+        }
+
+        let mut groups: HashMap<String, (Value, Vec<Accumulator>)> = HashMap::new();
+        let plan = for_each_match(storage, filter.as_ref(), |row| {
+            let key = request
+                .group_by
+                .as_ref()
+                .and_then(|group_by| row.get(group_by).cloned())
+                .unwrap_or(Value::Null);
+            let (_, group) = groups
+                .entry(key.to_string())
+                .or_insert_with(|| (key, accumulators.clone()));
+            for accumulator in group {
+                accumulator.add(&row);
+            }
+        })?;
+        if groups.is_empty() && request.group_by.is_none() {
+            groups.insert(String::new(), (Value::Null, accumulators));
+        }
+
+        let mut groups = groups
+            .into_values()
+            .map(|(key, accumulators)| AggregateGroup {
+                key,
+                values: accumulators
+                    .iter()
+                    .map(|accumulator| (accumulator.name.clone(), accumulator.finish()))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|left, right| sort_order(Some(&left.key), Some(&right.key)));
+        self.metrics.record_query();
+        Ok(AggregateResponse { groups, plan })
+    }
+}
+
+fn accumulators(schema_plan: &SchemaPlan, request: &AggregateRequest) -> Result<Vec<Accumulator>> {
+    if request.aggregates.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Aggregate request needs at least one aggregate"
+        ));
+    }
+    request
+        .aggregates
+        .iter()
+        .map(|(name, spec)| {
+            let kind = match (spec.op, &spec.field) {
+                (AggregateOp::Count, _) => NumericKind::Integer,
+                (_, Some(field)) => schema_plan.numeric_kind(field).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Aggregate '{}' needs a numeric field, '{}' is not one",
+                        name,
+                        field
+                    )
+                })?,
+                (_, None) => {
+                    return Err(anyhow::anyhow!("Aggregate '{}' needs a 'field'", name));
+                }
+            };
+            Ok(Accumulator {
+                name: name.clone(),
+                op: spec.op,
+                field: spec.field.clone(),
+                kind,
+                count: 0,
+                integer_sum: 0,
+                float_sum: 0.0,
+                extreme: None,
+            })
+        })
+        .collect()
+}
+
+impl Accumulator {
+    fn add(&mut self, row: &Value) {
+        let Some(field) = &self.field else {
+            self.count += 1;
+            return;
+        };
+        let value = match value_at_path(row, field) {
+            Some(Value::Null) | None => return,
+            Some(value) => value,
+        };
+        if self.op == AggregateOp::Count {
+            self.count += 1;
+            return;
+        }
+        let Value::Number(number) = value else {
+            return;
+        };
+        self.count += 1;
+        match self.kind {
+            NumericKind::Integer => {
+                self.integer_sum += number
+                    .as_i64()
+                    .map(i128::from)
+                    .or_else(|| number.as_u64().map(i128::from))
+                    .unwrap_or_default();
+            }
+            NumericKind::Float => self.float_sum += number.as_f64().unwrap_or_default(),
+        }
+        let wanted = match self.op {
+            AggregateOp::Min => Ordering::Less,
+            AggregateOp::Max => Ordering::Greater,
+            _ => return,
+        };
+        if self
+            .extreme
+            .as_ref()
+            .is_none_or(|extreme| compare_values(value, extreme) == Some(wanted))
+        {
+            self.extreme = Some(value.clone());
+        }
+    }
+
+    fn finish(&self) -> Value {
+        match self.op {
+            AggregateOp::Count => self.count.into(),
+            AggregateOp::Sum => self.sum(),
+            AggregateOp::Min | AggregateOp::Max => self.extreme.clone().unwrap_or(Value::Null),
+            AggregateOp::Avg if self.count == 0 => Value::Null,
+            AggregateOp::Avg => {
+                let sum = match self.kind {
+                    NumericKind::Integer => self.integer_sum as f64,
+                    NumericKind::Float => self.float_sum,
+                };
+                (sum / self.count as f64).into()
+            }
+        }
+    }
+
+    fn sum(&self) -> Value {
+        match self.kind {
+            NumericKind::Float => self.float_sum.into(),
+            NumericKind::Integer => i64::try_from(self.integer_sum)
+                .map(Value::from)
+                .or_else(|_| u64::try_from(self.integer_sum).map(Value::from))
+                .unwrap_or_else(|_| (self.integer_sum as f64).into()),
+        }
+    }
+}
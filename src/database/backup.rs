@@ -0,0 +1,552 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config::{ModelSchema, NyroConfig};
+use crate::database::catalog::{CatalogFile, CATALOG_FILE};
+use crate::database::helpers::current_unix_millis;
+use crate::database::types::{ModelRuntime, NyroDB};
+use crate::database::webhooks::{hex, WEBHOOK_DIR};
+use crate::storage::{
+    copy_log_until, transaction_log_path, FrozenLog, LogSnapshot, TRANSACTION_LOG_FILE,
+};
+use crate::utils::logger::Logger;
+use crate::utils::tar::{read_index, TarEntry, TarWriter};
+
+pub const BACKUP_FORMAT: &str = "nyrodb-backup";
+pub const BACKUP_VERSION: u32 = 1;
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes a backup. Every file is stored under the same relative path it
+/// has in the data directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub format: String,
+    pub version: u32,
+    pub nyrodb_version: String,
+    pub created_at: u64,
+    pub models: BTreeMap<String, BackupModel>,
+    pub files: BTreeMap<String, BackupFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupModel {
+    pub schema: ModelSchema,
+    pub log: String,
+    /// Sequence number of the last entry in the copied log.
+    pub last_seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+impl NyroDB {
+    /// Writes a consistent snapshot of every model to `target`, a new or
+    /// empty directory, or a tar archive when it ends in `.tar`.
+    pub async fn backup(&self, target: impl AsRef<Path>) -> Result<BackupManifest> {
+        let target = target.as_ref().to_path_buf();
+        let (models, catalog) = self.catalog.snapshot()?;
+        let runtimes = models
+            .into_iter()
+            .map(|(model_name, schema)| {
+                let runtime = self.get_runtime(&model_name)?;
+                Ok((model_name, schema, runtime))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let data_dir = PathBuf::from(&self.config.storage.data_dir);
+        let backup_target = target.clone();
+        let manifest = tokio::task::spawn_blocking(move || {
+            write_backup(&backup_target, &data_dir, &runtimes, catalog)
+        })
+        .await
+        .map_err(|error| anyhow::anyhow!("Backup task failed: {}", error))??;
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!(
+                "Backed up {} models to {}",
+                manifest.models.len(),
+                target.display()
+            ),
+        );
+        Ok(manifest)
+    }
+
+    /// Resolves a target sent to `POST /admin/backup` below `backup.root`.
+    /// Only new relative paths without `..` or symbolic links are taken, so
+    /// the API cannot write anywhere else or over an existing file.
+    pub fn backup_target(&self, target: &str) -> Result<PathBuf> {
+        let relative = Path::new(target);
+        let normal = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if target.is_empty() || !normal {
+            return Err(anyhow::anyhow!(
+                "Backup target '{}' must be a relative path without '..'",
+                target
+            ));
+        }
+        let mut path = PathBuf::from(&self.config.backup.root);
+        for component in relative.components() {
+            path.push(component);
+            match std::fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    return Err(anyhow::anyhow!(
+                        "Backup target '{}' passes through a symbolic link",
+                        target
+                    ));
+                }
+                _ => {}
+            }
+        }
+        if std::fs::symlink_metadata(&path).is_ok() {
+            return Err(anyhow::anyhow!(
+                "Backup target {} already exists",
+                path.display()
+            ));
+        }
+        Ok(path)
+    }
+}
+
+/// What [`restore_backup`] put back.
+#[derive(Debug, Serialize)]
+pub struct RestoreReport {
+    pub backup_created_at: u64,
+    pub until: Option<u64>,
+    pub files: usize,
+    /// Entries per model left out for being written after `until`.
+    pub dropped_entries: BTreeMap<String, u64>,
+}
+
+/// Rebuilds the configured data directory from a backup directory or
+/// archive. The data directory must be missing or empty. Every file is
+/// checked against the manifest in a staging directory that is only moved
+/// into place once all of them match. With `until` (UNIX millis), model logs
+/// keep only the entries written at or before it.
+pub fn restore_backup(
+    snapshot: impl AsRef<Path>,
+    config: &NyroConfig,
+    until: Option<u64>,
+) -> Result<RestoreReport> {
+    let snapshot = snapshot.as_ref();
+    let data_dir = PathBuf::from(&config.storage.data_dir);
+    if data_dir.exists() && std::fs::read_dir(&data_dir)?.next().is_some() {
+        return Err(anyhow::anyhow!(
+            "Data directory {} is not empty; move it aside before restoring",
+            data_dir.display()
+        ));
+    }
+
+    let source = BackupSource::open(snapshot)?;
+    let manifest = source.manifest()?;
+    let models = if manifest.files.contains_key(CATALOG_FILE) {
+        let mut bytes = Vec::new();
+        source.file(CATALOG_FILE)?.read_to_end(&mut bytes)?;
+        serde_json::from_slice::<CatalogFile>(&bytes)
+            .map_err(|error| anyhow::anyhow!("Invalid {} in the backup: {}", CATALOG_FILE, error))?
+            .models(&config.models)
+    } else {
+        config.models.clone().into_iter().collect()
+    };
+    for (model_name, model) in &manifest.models {
+        let message = match models.get(model_name) {
+            None => format!(
+                "Backup holds model '{}', which is not configured",
+                model_name
+            ),
+            Some(schema)
+                if serde_json::to_value(schema)? != serde_json::to_value(&model.schema)? =>
+            {
+                format!("Schema of '{}' differs from the backup", model_name)
+            }
+            Some(_) => continue,
+        };
+        Logger::warn_with_config(&config.logging, &message);
+    }
+
+    let mut staging = data_dir.as_os_str().to_owned();
+    staging.push(".restoring");
+    let staging = PathBuf::from(staging);
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    let dropped_entries = match restore_files(&source, &manifest, &staging, until) {
+        Ok(dropped_entries) => dropped_entries,
+        Err(error) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(error);
+        }
+    };
+    if data_dir.exists() {
+        std::fs::remove_dir(&data_dir)?;
+    }
+    std::fs::rename(&staging, &data_dir)?;
+
+    Ok(RestoreReport {
+        backup_created_at: manifest.created_at,
+        until,
+        files: manifest.files.len(),
+        dropped_entries,
+    })
+}
+
+fn restore_files(
+    source: &BackupSource,
+    manifest: &BackupManifest,
+    staging: &Path,
+    until: Option<u64>,
+) -> Result<BTreeMap<String, u64>> {
+    let mut dropped_entries = BTreeMap::new();
+    for (path, expected) in &manifest.files {
+        let target = staging.join(path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create_new(&target)?);
+        let mut reader = Sha256Reader::new(source.file(path)?);
+        let model_name = manifest
+            .models
+            .iter()
+            .find(|(_, model)| &model.log == path)
+            .map(|(model_name, _)| model_name);
+        match (model_name, until) {
+            (Some(model_name), Some(until)) => {
+                let cut = copy_log_until(&mut reader, &mut writer, until).map_err(|error| {
+                    anyhow::anyhow!("Cannot restore '{}': {}", model_name, error)
+                })?;
+                io::copy(&mut reader, &mut io::sink())?;
+                dropped_entries.insert(model_name.clone(), cut.dropped_entries);
+            }
+            _ => {
+                io::copy(&mut reader, &mut writer)?;
+            }
+        }
+        writer.into_inner()?.sync_all()?;
+        if &reader.finish() != expected {
+            return Err(anyhow::anyhow!(
+                "{} in the backup does not match its manifest checksum",
+                path
+            ));
+        }
+    }
+    Ok(dropped_entries)
+}
+
+fn write_backup(
+    target: &Path,
+    data_dir: &Path,
+    runtimes: &[(String, ModelSchema, Arc<ModelRuntime>)],
+    catalog: Option<Vec<u8>>,
+) -> Result<BackupManifest> {
+    // Freezing every log at once, in the same name order transactions lock
+    // them, means no transaction is half written while offsets are taken.
+    let (snapshots, schemas, transactions) = {
+        let frozen = runtimes
+            .iter()
+            .map(|(_, _, runtime)| runtime.storage.freeze())
+            .collect::<Result<Vec<_>>>()?;
+        let snapshots = frozen
+            .iter()
+            .map(FrozenLog::snapshot)
+            .collect::<Result<Vec<_>>>()?;
+        // Migrations take the writer lock, so these match the frozen logs.
+        let schemas: Vec<_> = runtimes
+            .iter()
+            .map(|(_, _, runtime)| runtime.storage.schema_versions())
+            .collect();
+        (
+            snapshots,
+            schemas,
+            read_if_exists(&transaction_log_path(data_dir))?,
+        )
+    };
+
+    let mut sink = BackupSink::create(target)?;
+    let mut manifest = BackupManifest {
+        format: BACKUP_FORMAT.to_string(),
+        version: BACKUP_VERSION,
+        nyrodb_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: current_unix_millis()?,
+        models: BTreeMap::new(),
+        files: BTreeMap::new(),
+    };
+    for (((model_name, schema, _), snapshot), versions) in
+        runtimes.iter().zip(snapshots).zip(schemas)
+    {
+        let LogSnapshot {
+            file,
+            len,
+            last_seq,
+        } = snapshot;
+        let log = format!("{}.log", model_name);
+        let entry = sink.add(&log, len, file)?;
+        manifest.files.insert(log.clone(), entry);
+        if versions.len() > 1 {
+            let path = format!("{}.schema.json", model_name);
+            let contents = serde_json::to_vec_pretty(&versions)?;
+            let entry = sink.add(&path, contents.len() as u64, contents.as_slice())?;
+            manifest.files.insert(path, entry);
+        }
+        manifest.models.insert(
+            model_name.clone(),
+            BackupModel {
+                schema: schema.clone(),
+                log,
+                last_seq,
+            },
+        );
+    }
+    if let Some(transactions) = transactions {
+        let entry = sink.add(
+            TRANSACTION_LOG_FILE,
+            transactions.len() as u64,
+            transactions.as_slice(),
+        )?;
+        manifest
+            .files
+            .insert(TRANSACTION_LOG_FILE.to_string(), entry);
+    }
+    if let Some(catalog) = catalog {
+        let entry = sink.add(CATALOG_FILE, catalog.len() as u64, catalog.as_slice())?;
+        manifest.files.insert(CATALOG_FILE.to_string(), entry);
+    }
+    for (path, contents) in webhook_files(data_dir)? {
+        let entry = sink.add(&path, contents.len() as u64, contents.as_slice())?;
+        manifest.files.insert(path, entry);
+    }
+
+    // Written last, so a backup without a manifest is known to be incomplete.
+    let manifest_bytes = serde_json::to_vec_pretty(&manifest)?;
+    sink.add(
+        MANIFEST_FILE,
+        manifest_bytes.len() as u64,
+        manifest_bytes.as_slice(),
+    )?;
+    sink.finish()?;
+    Ok(manifest)
+}
+
+/// The webhook registry and delivery cursors, so restored webhooks resume
+/// where they were.
+fn webhook_files(data_dir: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let dir = data_dir.join(WEBHOOK_DIR);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".tmp") || !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(contents) = read_if_exists(&entry.path())? {
+            files.push((format!("{}/{}", WEBHOOK_DIR, name), contents));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+enum BackupSource {
+    Directory(PathBuf),
+    Archive {
+        path: PathBuf,
+        entries: HashMap<String, TarEntry>,
+    },
+}
+
+impl BackupSource {
+    fn open(snapshot: &Path) -> Result<Self> {
+        if snapshot.is_dir() {
+            return Ok(Self::Directory(snapshot.to_path_buf()));
+        }
+        let file = File::open(snapshot).map_err(|error| {
+            anyhow::anyhow!("Cannot open backup {}: {}", snapshot.display(), error)
+        })?;
+        let entries = read_index(BufReader::new(file))?
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        Ok(Self::Archive {
+            path: snapshot.to_path_buf(),
+            entries,
+        })
+    }
+
+    fn file(&self, path: &str) -> Result<Box<dyn Read>> {
+        let missing = || anyhow::anyhow!("Backup is missing {}", path);
+        match self {
+            Self::Directory(dir) => {
+                let file = File::open(dir.join(path)).map_err(|_| missing())?;
+                Ok(Box::new(BufReader::new(file)))
+            }
+            Self::Archive {
+                path: archive,
+                entries,
+            } => {
+                let entry = entries.get(path).ok_or_else(missing)?;
+                let mut file = File::open(archive)?;
+                file.seek(SeekFrom::Start(entry.offset))?;
+                Ok(Box::new(BufReader::new(file).take(entry.size)))
+            }
+        }
+    }
+
+    /// Reads and checks the manifest; the files are checked as they are
+    /// restored.
+    fn manifest(&self) -> Result<BackupManifest> {
+        let mut bytes = Vec::new();
+        self.file(MANIFEST_FILE)
+            .map_err(|_| anyhow::anyhow!("Backup has no {}; it is incomplete", MANIFEST_FILE))?
+            .read_to_end(&mut bytes)?;
+        let manifest: BackupManifest = serde_json::from_slice(&bytes)
+            .map_err(|error| anyhow::anyhow!("Invalid backup manifest: {}", error))?;
+        if manifest.format != BACKUP_FORMAT || manifest.version > BACKUP_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported backup format {} version {}",
+                manifest.format,
+                manifest.version
+            ));
+        }
+        for path in manifest.files.keys() {
+            let relative = Path::new(path)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            if !relative || path.is_empty() || path == MANIFEST_FILE {
+                return Err(anyhow::anyhow!(
+                    "Invalid path '{}' in backup manifest",
+                    path
+                ));
+            }
+        }
+        for (model_name, model) in &manifest.models {
+            if !manifest.files.contains_key(&model.log) {
+                return Err(anyhow::anyhow!(
+                    "Backup manifest lists no log file for '{}'",
+                    model_name
+                ));
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+enum BackupSink {
+    Directory(PathBuf),
+    Archive(TarWriter<BufWriter<File>>),
+}
+
+impl BackupSink {
+    fn create(target: &Path) -> Result<Self> {
+        let is_archive = target
+            .extension()
+            .is_some_and(|extension| extension == "tar");
+        if is_archive {
+            if target.exists() {
+                return Err(anyhow::anyhow!(
+                    "Backup target {} already exists",
+                    target.display()
+                ));
+            }
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = File::create_new(target)?;
+            return Ok(Self::Archive(TarWriter::new(BufWriter::new(file))));
+        }
+        if target.exists() && std::fs::read_dir(target)?.next().is_some() {
+            return Err(anyhow::anyhow!(
+                "Backup target {} is not empty",
+                target.display()
+            ));
+        }
+        std::fs::create_dir_all(target)?;
+        Ok(Self::Directory(target.to_path_buf()))
+    }
+
+    /// Copies `size` bytes of `data` into the backup and checksums them.
+    fn add(&mut self, path: &str, size: u64, data: impl Read) -> Result<BackupFile> {
+        let mut data = Sha256Reader::new(data.take(size));
+        match self {
+            Self::Directory(dir) => {
+                let path = dir.join(path);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create_new(&path)?;
+                let copied = io::copy(&mut data, &mut file)?;
+                if copied != size {
+                    return Err(anyhow::anyhow!(
+                        "{} ended after {} of {} bytes",
+                        path.display(),
+                        copied,
+                        size
+                    ));
+                }
+                file.sync_all()?;
+            }
+            Self::Archive(archive) => archive.append(path, size, &mut data)?,
+        }
+        Ok(data.finish())
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Self::Archive(archive) = self {
+            archive.finish()?.into_inner()?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts and checksums everything read through it.
+struct Sha256Reader<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<R: Read> Sha256Reader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    fn finish(self) -> BackupFile {
+        BackupFile {
+            bytes: self.bytes,
+            sha256: hex(&self.hasher.finalize()),
+        }
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::config::{IndexKind, ModelSchema, NyroConfig};
+use crate::database::helpers::write_atomically;
+use crate::database::types::NyroDB;
+use crate::storage::{LogStorage, ReadOnlyError};
+use crate::utils::logger::Logger;
+
+pub(crate) const CATALOG_FILE: &str = "catalog.json";
+
+type Models = BTreeMap<String, ModelSchema>;
+
+/// Models created or dropped through the API, kept in `catalog.json` in the
+/// data directory and laid over the configured models on every start.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct CatalogFile {
+    #[serde(default)]
+    pub(crate) created: Models,
+    /// Configured models that were dropped.
+    #[serde(default)]
+    pub(crate) dropped: BTreeSet<String>,
+}
+
+impl CatalogFile {
+    pub(crate) fn models(&self, configured: &HashMap<String, ModelSchema>) -> Models {
+        let mut models: Models = configured
+            .iter()
+            .filter(|(name, _)| !self.dropped.contains(*name))
+            .map(|(name, schema)| (name.clone(), schema.clone()))
+            .collect();
+        models.extend(self.created.clone());
+        models
+    }
+}
+
+/// Every model this node serves.
+pub(crate) struct Catalog {
+    models: RwLock<Models>,
+    /// Serializes changes, which rewrite `catalog.json`.
+    file: Mutex<CatalogFile>,
+}
+
+impl Catalog {
+    /// An unreadable `catalog.json` leaves only the configured models, so
+    /// the logs of created models stay on disk until it is fixed.
+    pub(crate) fn load(config: &NyroConfig) -> Self {
+        let path = catalog_path(config);
+        let file = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|error| {
+                Logger::error_with_config(
+                    &config.logging,
+                    &format!("Failed to parse {}: {}", path.display(), error),
+                );
+                CatalogFile::default()
+            }),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => CatalogFile::default(),
+            Err(error) => {
+                Logger::error_with_config(
+                    &config.logging,
+                    &format!("Failed to read {}: {}", path.display(), error),
+                );
+                CatalogFile::default()
+            }
+        };
+        Self {
+            models: RwLock::new(file.models(&config.models)),
+            file: Mutex::new(file),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.models.read().len()
+    }
+
+    /// The models with the contents of `catalog.json`, or `None` while the
+    /// configuration alone defines them.
+    pub(crate) fn snapshot(&self) -> Result<(Models, Option<Vec<u8>>)> {
+        let file = self.file.lock();
+        let contents = if file.created.is_empty() && file.dropped.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_vec_pretty(&*file)?)
+        };
+        Ok((self.models.read().clone(), contents))
+    }
+
+    pub(crate) fn get(&self, model_name: &str) -> Option<ModelSchema> {
+        self.models.read().get(model_name).cloned()
+    }
+}
+
+/// What `GET /models/:name` reports about a model.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelDescription {
+    pub name: String,
+    /// The fields as of the newest schema version.
+    pub schema: ModelSchema,
+    pub schema_version: u32,
+    pub rows: usize,
+    pub log_bytes: u64,
+    /// Log bytes of the rows held by a bounded row cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached_bytes: Option<u64>,
+    pub indexes: Vec<IndexDescription>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexDescription {
+    pub field: String,
+    pub kind: IndexKind,
+    /// False while the index is being filled; queries do not use it yet.
+    pub ready: bool,
+}
+
+/// Returned when creating a model under a name that is taken.
+#[derive(Debug)]
+pub struct ModelExistsError {
+    pub model: String,
+}
+
+impl std::fmt::Display for ModelExistsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Model '{}' already exists", self.model)
+    }
+}
+
+impl std::error::Error for ModelExistsError {}
+
+impl NyroDB {
+    /// Names of every model, configured or created, in order.
+    pub fn model_names(&self) -> Vec<String> {
+        self.catalog.models.read().keys().cloned().collect()
+    }
+
+    pub fn has_model(&self, model_name: &str) -> bool {
+        self.catalog.models.read().contains_key(model_name)
+    }
+
+    pub(crate) fn models(&self) -> Models {
+        self.catalog.models.read().clone()
+    }
+
+    /// Adds a model with an empty log. It is kept in `catalog.json` and
+    /// served after restarts whatever the configuration says.
+    pub fn create_model(&self, model_name: &str, schema: ModelSchema) -> Result<ModelDescription> {
+        if self.is_replica() {
+            return Err(ReadOnlyError.into());
+        }
+        if model_name.is_empty()
+            || !model_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow::anyhow!(
+                "Model name '{}' may only contain letters, digits, '-' and '_'",
+                model_name
+            ));
+        }
+        schema.validate(model_name)?;
+        {
+            let mut file = self.catalog.file.lock();
+            if self.catalog.models.read().contains_key(model_name) {
+                return Err(ModelExistsError {
+                    model: model_name.to_string(),
+                }
+                .into());
+            }
+            let log_path = LogStorage::log_path(&self.config.storage.data_dir, model_name);
+            if Path::new(&log_path).exists() {
+                return Err(anyhow::anyhow!(
+                    "{} already exists; move it aside to create '{}'",
+                    log_path,
+                    model_name
+                ));
+            }
+            let mut next = file.clone();
+            next.dropped.remove(model_name);
+            next.created.insert(model_name.to_string(), schema.clone());
+            self.save_catalog(&next)?;
+            *file = next;
+            self.catalog
+                .models
+                .write()
+                .insert(model_name.to_string(), schema);
+        }
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!("Created model '{}'", model_name),
+        );
+        self.describe_model(model_name)
+    }
+
+    pub fn describe_model(&self, model_name: &str) -> Result<ModelDescription> {
+        let runtime = self.get_runtime(model_name)?;
+        let versions = runtime.storage.schema_versions();
+        let fields = versions
+            .last()
+            .map(|version| version.fields.clone())
+            .unwrap_or_default();
+        let indexes = fields
+            .iter()
+            .filter(|field| field.name != "id")
+            .filter_map(|field| {
+                Some(IndexDescription {
+                    field: field.name.clone(),
+                    kind: field.index_kind()?,
+                    ready: runtime.storage.index_ready(&field.name),
+                })
+            })
+            .collect();
+        Ok(ModelDescription {
+            name: model_name.to_string(),
+            schema: ModelSchema {
+                fields,
+                on_conflict: runtime.conflict_policy,
+                cache_bytes: self
+                    .catalog
+                    .get(model_name)
+                    .and_then(|schema| schema.cache_bytes),
+            },
+            schema_version: versions.len() as u32,
+            rows: runtime.storage.len(),
+            log_bytes: runtime.storage.log_bytes(),
+            cached_bytes: runtime.storage.cached_bytes(),
+            indexes,
+        })
+    }
+
+    /// Removes a model and deletes its log. Models that webhooks deliver
+    /// from are refused. Returns whether the model existed.
+    pub fn drop_model(&self, model_name: &str) -> Result<bool> {
+        if self.is_replica() {
+            return Err(ReadOnlyError.into());
+        }
+        if let Some(webhook) = self
+            .webhooks()
+            .into_iter()
+            .find(|webhook| webhook.model == model_name)
+        {
+            return Err(anyhow::anyhow!(
+                "Model '{}' is used by webhook '{}'",
+                model_name,
+                webhook.name
+            ));
+        }
+        self.remove_model(model_name)
+    }
+
+    fn remove_model(&self, model_name: &str) -> Result<bool> {
+        {
+            let mut file = self.catalog.file.lock();
+            if !self.catalog.models.read().contains_key(model_name) {
+                return Ok(false);
+            }
+            let mut next = file.clone();
+            next.created.remove(model_name);
+            if self.config.models.contains_key(model_name) {
+                next.dropped.insert(model_name.to_string());
+            }
+            self.save_catalog(&next)?;
+            *file = next;
+            self.catalog.models.write().remove(model_name);
+        }
+        if let Some((_, runtime)) = self.runtimes.remove(model_name) {
+            // Lets writes that already passed validation finish first.
+            let _schema = runtime.schema_gate.write();
+            runtime.storage.shutdown(&self.config.logging)?;
+        }
+        self.replication.forget_model(model_name);
+        LogStorage::remove_files(&self.config.storage.data_dir, model_name)?;
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!("Dropped model '{}'", model_name),
+        );
+        Ok(true)
+    }
+
+    /// Makes a replica serve the primary's models, dropping the rest.
+    pub(crate) fn adopt_catalog(&self, models: Models) -> Result<()> {
+        let current = self.models();
+        if serde_json::to_value(&current)? == serde_json::to_value(&models)? {
+            return Ok(());
+        }
+        for model_name in current.keys() {
+            if !models.contains_key(model_name) {
+                self.remove_model(model_name)?;
+            }
+        }
+        let mut file = self.catalog.file.lock();
+        let next = CatalogFile {
+            dropped: self
+                .config
+                .models
+                .keys()
+                .filter(|model_name| !models.contains_key(*model_name))
+                .cloned()
+                .collect(),
+            created: models,
+        };
+        self.save_catalog(&next)?;
+        *self.catalog.models.write() = next.models(&self.config.models);
+        *file = next;
+        Ok(())
+    }
+
+    fn save_catalog(&self, file: &CatalogFile) -> Result<()> {
+        write_atomically(
+            &catalog_path(&self.config),
+            &serde_json::to_vec_pretty(file)?,
+        )
+    }
+}
+
+fn catalog_path(config: &NyroConfig) -> PathBuf {
+    Path::new(&config.storage.data_dir).join(CATALOG_FILE)
+}
@@ -0,0 +1,325 @@
+use anyhow::Result;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch};
+
+use crate::database::types::{ModelRuntime, NyroDB};
+use crate::models::{ChangeEvent, LogEntry, Operation};
+use crate::storage::LogStorage;
+
+/// Where a replay of a model's changes starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangePosition {
+    /// Changes with a greater sequence number.
+    AfterSeq(u64),
+    /// Changes written at or after this UNIX millisecond timestamp.
+    Since(u64),
+}
+
+/// Reported instead of changes that cannot be replayed, because a compaction
+/// dropped them or the requested sequence number is ahead of the log.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "gap")]
+pub struct ChangeGap {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after_seq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    pub resumed_after: u64,
+}
+
+#[derive(Debug)]
+pub struct ChangeBatch {
+    pub events: Vec<ChangeEvent>,
+    pub gap: Option<ChangeGap>,
+    /// Sequence number the next batch continues after.
+    pub next_after: u64,
+    /// Whether published changes remain after `next_after`.
+    pub more: bool,
+}
+
+/// Realtime sequencing state of one model. Entries are numbered under the
+/// log's writer lock but published after it is released, so a batch that
+/// arrives early waits here until every lower number has been broadcast.
+pub(crate) struct ChangeFeed {
+    state: Mutex<FeedState>,
+    /// The published sequence number, for readers waiting on new changes.
+    published: watch::Sender<u64>,
+}
+
+struct FeedState {
+    published_seq: u64,
+    /// Batches by first sequence number, with their entry count.
+    pending: BTreeMap<u64, (u64, Vec<Arc<ChangeEvent>>)>,
+}
+
+impl ChangeFeed {
+    pub(crate) fn new(published_seq: u64) -> Self {
+        Self {
+            state: Mutex::new(FeedState {
+                published_seq,
+                pending: BTreeMap::new(),
+            }),
+            published: watch::Sender::new(published_seq),
+        }
+    }
+
+    fn published_seq(&self) -> u64 {
+        self.state.lock().published_seq
+    }
+
+    /// Restarts numbering at `published_seq` after the log was replaced.
+    pub(crate) fn reset(&self, published_seq: u64) {
+        let mut state = self.state.lock();
+        state.pending.clear();
+        state.published_seq = published_seq;
+        self.published.send_replace(published_seq);
+    }
+
+    /// Sends every batch that is next in line. The lock is held while sending
+    /// so the model's events enter the channel in sequence order.
+    fn publish(
+        &self,
+        tx: &broadcast::Sender<Arc<ChangeEvent>>,
+        first_seq: u64,
+        count: u64,
+        events: Vec<Arc<ChangeEvent>>,
+    ) {
+        if count == 0 {
+            return;
+        }
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        state.pending.insert(first_seq, (count, events));
+        while let Some(batch) = state.pending.first_entry() {
+            if *batch.key() != state.published_seq + 1 {
+                break;
+            }
+            let (count, events) = batch.remove();
+            for event in events {
+                let _ = tx.send(event);
+            }
+            state.published_seq += count;
+        }
+        if *self.published.borrow() != state.published_seq {
+            self.published.send_replace(state.published_seq);
+        }
+    }
+}
+
+impl NyroDB {
+    /// The stored rows that the updates in `entries` replace, read before the
+    /// entries are applied. Empty when nobody is listening.
+    pub(crate) fn previous_rows(
+        &self,
+        storage: &LogStorage,
+        entries: &[LogEntry<Value>],
+    ) -> Result<Vec<Option<Value>>> {
+        if self.real_time_tx.receiver_count() == 0 {
+            return Ok(Vec::new());
+        }
+        let mut batch_rows: HashMap<u64, Option<Value>> = HashMap::new();
+        entries
+            .iter()
+            .map(|entry| {
+                let id = entry_id(entry);
+                let previous = match batch_rows.get(&id) {
+                    Some(row) => row.clone(),
+                    None => storage.get_value(id)?,
+                };
+                let is_delete = matches!(entry.operation, Operation::Delete);
+                batch_rows.insert(id, (!is_delete).then(|| entry.data.clone()));
+                Ok(previous.filter(|_| matches!(entry.operation, Operation::Update)))
+            })
+            .collect()
+    }
+
+    /// Broadcasts entries the log numbered from `first_seq` onwards.
+    pub(crate) fn publish_changes(
+        &self,
+        runtime: &ModelRuntime,
+        model_name: &str,
+        first_seq: u64,
+        entries: &[LogEntry<Value>],
+        previous: Vec<Option<Value>>,
+    ) {
+        let events = if self.real_time_tx.receiver_count() == 0 {
+            Vec::new()
+        } else {
+            let mut previous = previous.into_iter();
+            entries
+                .iter()
+                .zip(first_seq..)
+                .map(|(entry, seq)| {
+                    let mut event = change_event(model_name, seq, entry.clone());
+                    event.previous = previous.next().flatten();
+                    Arc::new(event)
+                })
+                .collect()
+        };
+        runtime
+            .changes
+            .publish(&self.real_time_tx, first_seq, entries.len() as u64, events);
+    }
+
+    /// Advances the feed past changes written while nobody was listening.
+    pub(crate) fn skip_changes(&self, runtime: &ModelRuntime, first_seq: u64, count: usize) {
+        runtime
+            .changes
+            .publish(&self.real_time_tx, first_seq, count as u64, Vec::new());
+    }
+
+    pub(crate) fn finish_bulk_insert(
+        &self,
+        runtime: &ModelRuntime,
+        model_name: &str,
+        first_seq: u64,
+        entries: &[LogEntry<Value>],
+        previous: Vec<Option<Value>>,
+        start: Instant,
+    ) {
+        if self.metrics.enabled {
+            self.metrics.record_inserts(
+                entries.len() as u64,
+                start.elapsed(),
+                self.config.metrics.max_samples,
+            );
+        }
+        self.publish_changes(runtime, model_name, first_seq, entries, previous);
+    }
+
+    /// Sequence number of the newest change broadcast for `model_name`.
+    pub fn published_seq(&self, model_name: &str) -> Result<u64> {
+        Ok(self.get_runtime(model_name)?.changes.published_seq())
+    }
+
+    /// Waits up to `timeout` for a change after `after_seq` to be published
+    /// and returns whether one was.
+    pub async fn wait_for_changes(
+        &self,
+        model_name: &str,
+        after_seq: u64,
+        timeout: Duration,
+    ) -> Result<bool> {
+        Ok(self.changes_after(model_name, after_seq, timeout)?.await)
+    }
+
+    /// [`Self::wait_for_changes`] as a future that holds no reference to the
+    /// database, and ends early once it is dropped.
+    pub(crate) fn changes_after(
+        &self,
+        model_name: &str,
+        after_seq: u64,
+        timeout: Duration,
+    ) -> Result<impl std::future::Future<Output = bool>> {
+        let mut published = self.get_runtime(model_name)?.changes.published.subscribe();
+        Ok(async move {
+            tokio::time::timeout(timeout, published.wait_for(|seq| *seq > after_seq))
+                .await
+                .is_ok_and(|changed| changed.is_ok())
+        })
+    }
+
+    /// Reads up to `limit` already published changes from the model's log.
+    /// Replayed events carry no `previous` row.
+    pub async fn read_changes(
+        &self,
+        model_name: &str,
+        position: ChangePosition,
+        limit: usize,
+    ) -> Result<ChangeBatch> {
+        let runtime = self.get_runtime(model_name)?;
+        let model_name = model_name.to_string();
+        tokio::task::spawn_blocking(move || read_changes(&runtime, &model_name, position, limit))
+            .await
+            .map_err(|error| anyhow::anyhow!("Change replay task failed: {}", error))?
+    }
+}
+
+fn read_changes(
+    runtime: &ModelRuntime,
+    model_name: &str,
+    position: ChangePosition,
+    limit: usize,
+) -> Result<ChangeBatch> {
+    let head = runtime.changes.published_seq();
+    let marker = runtime.storage.sequence_marker();
+    let gap = |resumed_after: u64| {
+        let (after_seq, since) = match position {
+            ChangePosition::AfterSeq(seq) => (Some(seq), None),
+            ChangePosition::Since(timestamp) => (None, Some(timestamp)),
+        };
+        Some(ChangeGap {
+            model: model_name.to_string(),
+            after_seq,
+            since,
+            resumed_after,
+        })
+    };
+
+    let (after, gap) = match position {
+        ChangePosition::AfterSeq(seq) if seq > head => (head, gap(head)),
+        ChangePosition::AfterSeq(seq) if seq < marker.compacted_through => {
+            (marker.compacted_through, gap(marker.compacted_through))
+        }
+        ChangePosition::AfterSeq(seq) => (seq, None),
+        ChangePosition::Since(timestamp)
+            if marker.compacted_through > 0 && timestamp < marker.compacted_at =>
+        {
+            (marker.compacted_through, gap(marker.compacted_through))
+        }
+        ChangePosition::Since(timestamp) => {
+            let after = match runtime.storage.first_seq_since(timestamp, head)? {
+                Some(seq) => (seq - 1).max(marker.compacted_through),
+                None => head,
+            };
+            (after, None)
+        }
+    };
+
+    let events: Vec<ChangeEvent> = runtime
+        .storage
+        .read_entries(after, head, limit)?
+        .into_iter()
+        .map(|sequenced| change_event(model_name, sequenced.seq, sequenced.entry))
+        .collect();
+    // A short batch scanned everything up to `head`, including entries that
+    // could not be decoded.
+    let more = events.len() == limit;
+    let next_after = match events.last() {
+        Some(event) if more => event.seq,
+        _ => after.max(head),
+    };
+    let more = more && next_after < head;
+    Ok(ChangeBatch {
+        events,
+        gap,
+        next_after,
+        more,
+    })
+}
+
+fn change_event(model_name: &str, seq: u64, entry: LogEntry<Value>) -> ChangeEvent {
+    ChangeEvent {
+        model: model_name.to_string(),
+        id: entry_id(&entry),
+        operation: entry.operation,
+        timestamp: entry.timestamp,
+        seq,
+        data: entry.data,
+        previous: None,
+    }
+}
+
+fn entry_id(entry: &LogEntry<Value>) -> u64 {
+    entry
+        .data
+        .get("id")
+        .and_then(Value::as_u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::database::types::NyroDB;
+use crate::storage::LogStorage;
+use crate::utils::logger::Logger;
+
+impl NyroDB {
+    /// Checkpoints the indexes of a model whenever its log has grown by
+    /// `index_checkpoint_bytes` since the last checkpoint.
+    pub(crate) fn spawn_index_checkpointer(&self, model_name: &str, storage: &Arc<LogStorage>) {
+        let storage_config = &self.config.storage;
+        if storage_config.index_checkpoint_bytes == 0 {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let storage = Arc::downgrade(storage);
+        let model_name = model_name.to_string();
+        let log_config = self.config.logging.clone();
+        let min_bytes = storage_config.index_checkpoint_bytes;
+        let check_interval = Duration::from_millis(storage_config.index_checkpoint_interval);
+        handle.spawn(async move {
+            let mut interval = tokio::time::interval(check_interval);
+            loop {
+                interval.tick().await;
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+                if storage
+                    .log_bytes()
+                    .saturating_sub(storage.checkpointed_bytes())
+                    < min_bytes
+                {
+                    continue;
+                }
+                match tokio::task::spawn_blocking(move || storage.checkpoint_indexes()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(error)) => Logger::error_with_config(
+                        &log_config,
+                        &format!("Index checkpoint failed for '{}': {}", model_name, error),
+                    ),
+                    Err(error) => Logger::error_with_config(
+                        &log_config,
+                        &format!(
+                            "Index checkpoint task failed for '{}': {}",
+                            model_name, error
+                        ),
+                    ),
+                }
+            }
+        });
+    }
+
+    /// Checkpoints every open model whose log grew since its last checkpoint,
+    /// so the next start replays nothing.
+    pub(crate) fn checkpoint_all_indexes(&self) {
+        if self.config.storage.index_checkpoint_bytes == 0 {
+            return;
+        }
+        for runtime in self.runtimes.iter() {
+            if let Err(error) = runtime.storage.checkpoint_indexes() {
+                Logger::error_with_config(
+                    &self.config.logging,
+                    &format!("Index checkpoint failed for '{}': {}", runtime.key(), error),
+                );
+            }
+        }
+    }
+}
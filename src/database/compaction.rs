@@ -0,0 +1,73 @@
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::database::types::NyroDB;
+use crate::storage::{CompactionReport, LogStorage};
+use crate::utils::logger::Logger;
+
+impl NyroDB {
+    pub async fn compact(&self, model_name: &str) -> Result<CompactionReport> {
+        let storage = self.get_storage(model_name)?;
+        let report = tokio::task::spawn_blocking(move || storage.compact())
+            .await
+            .map_err(|error| anyhow::anyhow!("Compaction task failed: {}", error))??;
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!(
+                "Compacted '{}': {} live entries, {} -> {} bytes",
+                model_name, report.live_entries, report.bytes_before, report.bytes_after
+            ),
+        );
+        Ok(report)
+    }
+
+    pub(crate) fn spawn_compaction_watcher(&self, model_name: &str, storage: &Arc<LogStorage>) {
+        let storage_config = &self.config.storage;
+        if storage_config.compaction_min_bytes == 0 {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let storage = Arc::downgrade(storage);
+        let model_name = model_name.to_string();
+        let log_config = self.config.logging.clone();
+        let min_bytes = storage_config.compaction_min_bytes;
+        let garbage_ratio = storage_config.compaction_garbage_ratio;
+        let check_interval = Duration::from_millis(storage_config.compaction_check_interval);
+        handle.spawn(async move {
+            let mut interval = tokio::time::interval(check_interval);
+            loop {
+                interval.tick().await;
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+                if storage.is_read_only() || !storage.should_compact(min_bytes, garbage_ratio) {
+                    continue;
+                }
+                match tokio::task::spawn_blocking(move || storage.compact()).await {
+                    Ok(Ok(report)) => Logger::info_with_config(
+                        &log_config,
+                        &format!(
+                            "Auto-compacted '{}': {} -> {} bytes",
+                            model_name, report.bytes_before, report.bytes_after
+                        ),
+                    ),
+                    Ok(Err(error)) => Logger::error_with_config(
+                        &log_config,
+                        &format!("Auto-compaction failed for '{}': {}", model_name, error),
+                    ),
+                    Err(error) => Logger::error_with_config(
+                        &log_config,
+                        &format!(
+                            "Auto-compaction task failed for '{}': {}",
+                            model_name, error
+                        ),
+                    ),
+                }
+            }
+        });
+    }
+}
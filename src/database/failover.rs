@@ -0,0 +1,409 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use warp::hyper::{self, Body, Client, Request};
+
+use crate::config::{NyroConfig, ReplicationRole};
+use crate::database::helpers::write_atomically;
+use crate::database::replication::{send, HttpClient, ReplicationStatus};
+use crate::database::types::NyroDB;
+use crate::utils::logger::Logger;
+
+pub(crate) const ROLE_FILE: &str = "replication.json";
+pub const EPOCH_HEADER: &str = "x-nyrodb-epoch";
+/// The primary a node follows, sent with misdirected writes and refused
+/// fences.
+pub const PRIMARY_HEADER: &str = "x-nyrodb-primary";
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(5);
+const FENCE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// This node's role and epoch. Kept in `replication.json` in the data
+/// directory once a promotion or fence changed them, and preferred over the
+/// configuration from then on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RoleState {
+    pub(crate) role: ReplicationRole,
+    /// Raised by every promotion.
+    pub(crate) epoch: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) primary_url: String,
+    /// Set when the local logs may have diverged from the primary's, so the
+    /// next pass copies every log whole.
+    #[serde(default)]
+    pub(crate) resync: bool,
+}
+
+impl RoleState {
+    /// An unreadable `replication.json` leaves the node a replica without a
+    /// primary rather than risk a second primary.
+    pub(crate) fn load(config: &NyroConfig) -> Self {
+        let fenced = || Self {
+            role: ReplicationRole::Replica,
+            epoch: 0,
+            primary_url: String::new(),
+            resync: true,
+        };
+        let path = role_path(config);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Self {
+                    role: config.replication.role,
+                    epoch: 0,
+                    primary_url: config.replication.primary_url.clone(),
+                    resync: false,
+                };
+            }
+            Err(error) => {
+                Logger::error_with_config(
+                    &config.logging,
+                    &format!(
+                        "Failed to read {}, refusing writes: {}",
+                        path.display(),
+                        error
+                    ),
+                );
+                return fenced();
+            }
+        };
+        serde_json::from_slice(&bytes).unwrap_or_else(|error| {
+            Logger::error_with_config(
+                &config.logging,
+                &format!(
+                    "Failed to parse {}, refusing writes: {}",
+                    path.display(),
+                    error
+                ),
+            );
+            fenced()
+        })
+    }
+}
+
+/// Body of `POST /replication/fence`, sent by a new primary to the one it
+/// replaced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FenceRequest {
+    pub epoch: u64,
+    pub primary_url: String,
+}
+
+/// Returned when a request carries an epoch this node has moved past, or
+/// when this node learns that it has been superseded.
+#[derive(Debug)]
+pub struct StaleEpochError {
+    pub epoch: u64,
+    pub current: u64,
+}
+
+impl std::fmt::Display for StaleEpochError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Epoch {} is not newer than the current epoch {}",
+            self.epoch, self.current
+        )
+    }
+}
+
+impl std::error::Error for StaleEpochError {}
+
+impl NyroDB {
+    pub fn epoch(&self) -> u64 {
+        self.replication.role.read().epoch
+    }
+
+    /// The primary a replica follows, when it knows one.
+    pub fn primary_url(&self) -> Option<String> {
+        let role = self.replication.role.read();
+        (role.role == ReplicationRole::Replica && !role.primary_url.is_empty())
+            .then(|| role.primary_url.clone())
+    }
+
+    /// How other nodes reach this one: `advertise_url`, or the bind address.
+    pub fn advertise_url(&self) -> String {
+        if !self.config.replication.advertise_url.is_empty() {
+            return self.config.replication.advertise_url.clone();
+        }
+        let host = match self.config.server.host.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => "127.0.0.1".to_string(),
+            _ => self.config.server.host.clone(),
+        };
+        format!("http://{}:{}", host, self.config.server.port)
+    }
+
+    /// Makes this replica the primary of the next epoch. It first tries for
+    /// a few seconds to copy what the old primary still has, then tells the
+    /// old primary to stop taking writes.
+    pub async fn promote(self: &Arc<Self>) -> Result<ReplicationStatus> {
+        let (primary_known, resync, epoch) = {
+            let role = self.replication.role.read();
+            if role.role == ReplicationRole::Primary {
+                return Err(anyhow::anyhow!("This node is already the primary"));
+            }
+            (!role.primary_url.is_empty(), role.resync, role.epoch)
+        };
+        self.stop_replication();
+        if primary_known && !resync {
+            let client = Client::new();
+            let catch_up = async { while let Ok(true) = self.replicate_once(&client).await {} };
+            let _ = tokio::time::timeout(CATCH_UP_TIMEOUT, catch_up).await;
+        }
+        self.take_over(epoch)?;
+        Ok(self.replication_status())
+    }
+
+    /// Switches to primary in the epoch after `from_epoch` and starts
+    /// fencing the previous primary. Fails if a fence or an adopted epoch
+    /// moved this node past `from_epoch` in the meantime.
+    pub(crate) fn take_over(self: &Arc<Self>, from_epoch: u64) -> Result<()> {
+        let (epoch, previous_primary) = {
+            let mut role = self.replication.role.write();
+            if role.role == ReplicationRole::Primary || role.epoch != from_epoch {
+                return Err(anyhow::anyhow!(
+                    "Not promoting: this node moved to epoch {} as {:?}",
+                    role.epoch,
+                    role.role
+                ));
+            }
+            let state = RoleState {
+                role: ReplicationRole::Primary,
+                epoch: role.epoch + 1,
+                primary_url: String::new(),
+                resync: false,
+            };
+            self.save_role(&state)?;
+            let previous = std::mem::replace(&mut *role, state);
+            (role.epoch, previous.primary_url)
+        };
+        self.replication.reset_progress();
+        *self.transaction_log.lock() = None;
+        for runtime in self.runtimes.iter() {
+            runtime.storage.set_read_only(false);
+        }
+        self.start_webhooks()?;
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!("Promoted to primary at epoch {}", epoch),
+        );
+
+        if !previous_primary.is_empty() {
+            let request = FenceRequest {
+                epoch,
+                primary_url: self.advertise_url(),
+            };
+            let task = tokio::spawn(fence_previous(
+                Arc::downgrade(self),
+                previous_primary,
+                request,
+            ));
+            if let Some(previous) = self.replication.fence_task.lock().replace(task) {
+                previous.abort();
+            }
+        }
+        Ok(())
+    }
+
+    /// Demotes this node to a replica of `request.primary_url`. Nodes are
+    /// ordered by epoch, then by URL: within one epoch the node only steps
+    /// down for, or moves to, a primary whose URL orders after the one it
+    /// is or follows, so two primaries of an epoch cannot both stay.
+    pub fn fence(self: &Arc<Self>, request: FenceRequest) -> Result<ReplicationStatus> {
+        let repeated = {
+            let role = self.replication.role.read();
+            let leader = match role.role {
+                ReplicationRole::Primary => self.advertise_url(),
+                ReplicationRole::Replica => role.primary_url.clone(),
+            };
+            let superseded = request.epoch > role.epoch
+                || (request.epoch == role.epoch
+                    && (request.primary_url > leader
+                        || (role.role == ReplicationRole::Replica
+                            && request.primary_url == leader)));
+            if !superseded {
+                return Err(StaleEpochError {
+                    epoch: request.epoch,
+                    current: role.epoch,
+                }
+                .into());
+            }
+            request.epoch == role.epoch
+                && role.role == ReplicationRole::Replica
+                && request.primary_url == leader
+        };
+        if !repeated {
+            self.demote(request.epoch, request.primary_url)?;
+        }
+        Ok(self.replication_status())
+    }
+
+    /// Checks the epoch sent with a replication request. A newer one means a
+    /// promotion happened elsewhere, so a primary stops taking writes until
+    /// the new primary fences it.
+    pub fn observe_epoch(self: &Arc<Self>, epoch: u64) -> Result<()> {
+        let current = self.epoch();
+        if epoch <= current {
+            return Ok(());
+        }
+        if !self.is_replica() {
+            self.demote(epoch, String::new())?;
+        }
+        Err(StaleEpochError {
+            epoch: current,
+            current: epoch,
+        }
+        .into())
+    }
+
+    fn demote(self: &Arc<Self>, epoch: u64, primary_url: String) -> Result<()> {
+        {
+            let mut role = self.replication.role.write();
+            let state = RoleState {
+                role: ReplicationRole::Replica,
+                epoch,
+                primary_url,
+                resync: true,
+            };
+            self.save_role(&state)?;
+            *role = state;
+        }
+        for runtime in self.runtimes.iter() {
+            runtime.storage.set_read_only(true);
+        }
+        *self.transaction_log.lock() = None;
+        self.stop_webhooks();
+        if let Some(task) = self.replication.fence_task.lock().take() {
+            task.abort();
+        }
+        Logger::warn_with_config(
+            &self.config.logging,
+            &format!(
+                "Demoted to replica at epoch {}; primary is {}",
+                epoch,
+                self.primary_url().as_deref().unwrap_or("unknown")
+            ),
+        );
+        self.start_replication()
+    }
+
+    /// Takes the epoch of a primary that is further along.
+    pub(crate) fn adopt_epoch(&self, epoch: u64) -> Result<()> {
+        let mut role = self.replication.role.write();
+        if epoch > role.epoch {
+            let mut state = role.clone();
+            state.epoch = epoch;
+            self.save_role(&state)?;
+            *role = state;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish_resync(&self) -> Result<()> {
+        let mut role = self.replication.role.write();
+        if role.resync {
+            let mut state = role.clone();
+            state.resync = false;
+            self.save_role(&state)?;
+            *role = state;
+        }
+        Ok(())
+    }
+
+    fn save_role(&self, state: &RoleState) -> Result<()> {
+        write_atomically(&role_path(&self.config), &serde_json::to_vec_pretty(state)?)
+    }
+}
+
+fn role_path(config: &NyroConfig) -> PathBuf {
+    Path::new(&config.storage.data_dir).join(ROLE_FILE)
+}
+
+/// Retries until the old primary accepts, so one that was unreachable during
+/// the failover is fenced as soon as it is back.
+async fn fence_previous(db: Weak<NyroDB>, url: String, request: FenceRequest) {
+    let client = Client::new();
+    loop {
+        let Some(db) = db.upgrade() else {
+            return;
+        };
+        if db.is_replica() || db.epoch() != request.epoch {
+            return;
+        }
+        match post_fence(&db, &client, &url, &request).await {
+            Ok(None) => {
+                Logger::info_with_config(
+                    &db.config.logging,
+                    &format!("Fenced the previous primary at {}", url),
+                );
+                return;
+            }
+            Ok(Some(rival)) => {
+                // The old primary knows a newer epoch, or a primary of ours
+                // that orders after this node: follow it if it wins.
+                if let Err(error) = db.fence(rival) {
+                    Logger::error_with_config(
+                        &db.config.logging,
+                        &format!("Failed to step down: {}", error),
+                    );
+                }
+                return;
+            }
+            Err(error) => {
+                db.replication.record_error(format!(
+                    "Fencing the previous primary at {} failed: {}",
+                    url, error
+                ));
+            }
+        }
+        drop(db);
+        tokio::time::sleep(FENCE_RETRY_INTERVAL).await;
+    }
+}
+
+/// Sends `request` to the node at `url`. A refusal comes back as the epoch
+/// and primary that node answered with.
+async fn post_fence(
+    db: &NyroDB,
+    client: &HttpClient,
+    url: &str,
+    request: &FenceRequest,
+) -> Result<Option<FenceRequest>> {
+    let mut builder = Request::post(format!("{}/replication/fence", url.trim_end_matches('/')))
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(EPOCH_HEADER, request.epoch);
+    if !db.config.security.api_key.is_empty() {
+        builder = builder.header("x-api-key", &db.config.security.api_key);
+    }
+    let (parts, body) = send(
+        client,
+        builder.body(Body::from(serde_json::to_vec(request)?))?,
+    )
+    .await?;
+    if parts.status == hyper::StatusCode::CONFLICT {
+        let epoch = parts
+            .headers
+            .get(EPOCH_HEADER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or_default();
+        if epoch >= request.epoch {
+            let primary_url = parts
+                .headers
+                .get(PRIMARY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            return Ok(Some(FenceRequest { epoch, primary_url }));
+        }
+    }
+    if !parts.status.is_success() {
+        return Err(anyhow::anyhow!(
+            "Answered {}: {}",
+            parts.status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    Ok(None)
+}
@@ -0,0 +1,221 @@
+use anyhow::Result;
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+
+/// A parsed filter document. `field` paths use `.` to reach into `object`
+/// fields; all operators of one condition must hold.
+#[derive(Debug, Clone)]
+pub(crate) enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Condition(Box<Condition>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Condition {
+    pub(crate) field: String,
+    pub(crate) eq: Option<Value>,
+    pub(crate) ne: Option<Value>,
+    pub(crate) gt: Option<Value>,
+    pub(crate) gte: Option<Value>,
+    pub(crate) lt: Option<Value>,
+    pub(crate) lte: Option<Value>,
+    pub(crate) any_of: Option<Vec<Value>>,
+    pub(crate) exists: Option<bool>,
+}
+
+impl Filter {
+    pub(crate) fn parse(value: &Value) -> Result<Self> {
+        let object = value
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("Filter must be a JSON object, got {}", value))?;
+
+        if object.len() == 1 {
+            if let Some(branches) = object.get("and") {
+                return Ok(Self::And(parse_branches("and", branches)?));
+            }
+            if let Some(branches) = object.get("or") {
+                return Ok(Self::Or(parse_branches("or", branches)?));
+            }
+            if let Some(inner) = object.get("not") {
+                return Ok(Self::Not(Box::new(Self::parse(inner)?)));
+            }
+        }
+        if object.contains_key("field") {
+            return Condition::parse(object).map(|condition| Self::Condition(Box::new(condition)));
+        }
+        Err(anyhow::anyhow!(
+            "Filter needs 'and', 'or', 'not' or a 'field' condition, got {}",
+            value
+        ))
+    }
+
+    pub(crate) fn matches(&self, row: &Value) -> bool {
+        match self {
+            Self::And(filters) => filters.iter().all(|filter| filter.matches(row)),
+            Self::Or(filters) => filters.iter().any(|filter| filter.matches(row)),
+            Self::Not(filter) => !filter.matches(row),
+            Self::Condition(condition) => condition.matches(row),
+        }
+    }
+}
+
+fn parse_branches(operator: &str, value: &Value) -> Result<Vec<Filter>> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("'{}' expects an array of filters", operator))?
+        .iter()
+        .map(Filter::parse)
+        .collect()
+}
+
+impl Condition {
+    fn parse(object: &Map<String, Value>) -> Result<Self> {
+        let mut condition = Self::default();
+        let mut has_operator = false;
+        for (key, value) in object {
+            let slot = match key.as_str() {
+                "field" => {
+                    condition.field = value
+                        .as_str()
+                        .filter(|field| !field.is_empty())
+                        .ok_or_else(|| anyhow::anyhow!("'field' must be a non-empty string"))?
+                        .to_string();
+                    continue;
+                }
+                "in" => {
+                    let values = value
+                        .as_array()
+                        .ok_or_else(|| anyhow::anyhow!("'in' expects an array"))?;
+                    condition.any_of = Some(values.clone());
+                    has_operator = true;
+                    continue;
+                }
+                "exists" => {
+                    let exists = value
+                        .as_bool()
+                        .ok_or_else(|| anyhow::anyhow!("'exists' expects a boolean"))?;
+                    condition.exists = Some(exists);
+                    has_operator = true;
+                    continue;
+                }
+                "eq" => &mut condition.eq,
+                "ne" => &mut condition.ne,
+                "gt" => &mut condition.gt,
+                "gte" => &mut condition.gte,
+                "lt" => &mut condition.lt,
+                "lte" => &mut condition.lte,
+                unknown => {
+                    return Err(anyhow::anyhow!("Unknown filter operator '{}'", unknown));
+                }
+            };
+            *slot = Some(value.clone());
+            has_operator = true;
+        }
+        if !has_operator {
+            return Err(anyhow::anyhow!(
+                "Condition on '{}' has no operator",
+                condition.field
+            ));
+        }
+        Ok(condition)
+    }
+
+    fn matches(&self, row: &Value) -> bool {
+        let value = value_at_path(row, &self.field);
+        if let Some(exists) = self.exists {
+            if value.is_some() != exists {
+                return false;
+            }
+        }
+        if let Some(expected) = &self.ne {
+            if value.is_some_and(|value| values_equal(value, expected)) {
+                return false;
+            }
+        }
+        if self.eq.is_none()
+            && self.any_of.is_none()
+            && self.gt.is_none()
+            && self.gte.is_none()
+            && self.lt.is_none()
+            && self.lte.is_none()
+        {
+            return true;
+        }
+
+        let Some(value) = value else {
+            return false;
+        };
+        let compares = |bound: &Option<Value>, accept: fn(Ordering) -> bool| {
+            bound
+                .as_ref()
+                .is_none_or(|bound| compare_values(value, bound).is_some_and(accept))
+        };
+        self.eq
+            .as_ref()
+            .is_none_or(|expected| values_equal(value, expected))
+            && self.any_of.as_ref().is_none_or(|candidates| {
+                candidates
+                    .iter()
+                    .any(|candidate| values_equal(value, candidate))
+            })
+            && compares(&self.gt, Ordering::is_gt)
+            && compares(&self.gte, Ordering::is_ge)
+            && compares(&self.lt, Ordering::is_lt)
+            && compares(&self.lte, Ordering::is_le)
+    }
+}
+
+pub(crate) fn value_at_path<'a>(row: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(row, |value, segment| value.as_object()?.get(segment))
+}
+
+pub(crate) fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => {
+            compare_numbers(left, right) == Some(Ordering::Equal)
+        }
+        _ => left == right,
+    }
+}
+
+/// Orders two values of the same JSON type; mixed types are incomparable.
+pub(crate) fn compare_values(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => compare_numbers(left, right),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        (Value::Bool(left), Value::Bool(right)) => Some(left.cmp(right)),
+        _ => None,
+    }
+}
+
+/// Total order used for sorting: missing and `null` first, then booleans,
+/// numbers, strings, arrays and objects.
+pub(crate) fn sort_order(left: Option<&Value>, right: Option<&Value>) -> Ordering {
+    let rank = |value: Option<&Value>| match value {
+        None | Some(Value::Null) => 0,
+        Some(Value::Bool(_)) => 1,
+        Some(Value::Number(_)) => 2,
+        Some(Value::String(_)) => 3,
+        Some(Value::Array(_)) => 4,
+        Some(Value::Object(_)) => 5,
+    };
+    match (left, right) {
+        (Some(left_value), Some(right_value)) => {
+            compare_values(left_value, right_value).unwrap_or_else(|| rank(left).cmp(&rank(right)))
+        }
+        _ => rank(left).cmp(&rank(right)),
+    }
+}
+
+fn compare_numbers(left: &Number, right: &Number) -> Option<Ordering> {
+    if let (Some(left), Some(right)) = (left.as_u64(), right.as_u64()) {
+        return Some(left.cmp(&right));
+    }
+    if let (Some(left), Some(right)) = (left.as_i64(), right.as_i64()) {
+        return Some(left.cmp(&right));
+    }
+    left.as_f64()?.partial_cmp(&right.as_f64()?)
+}
@@ -1,11 +1,7 @@
 use anyhow::Result;
-use serde_json::Value;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
-
-use crate::config::LoggingConfig;
-use crate::models::LogEntry;
-use crate::utils::logger::Logger;
-use crate::utils::metrics::Metrics;
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub(crate) fn field_matches(data: &Value, field: &str, expected: &str) -> bool {
     data.get(field)
@@ -27,45 +23,45 @@ pub(crate) fn current_unix_millis() -> Result<u64> {
         .map_err(|_| anyhow::anyhow!("Current UNIX timestamp does not fit into u64 millis"))
 }
 
-pub(crate) fn publish_insert_event(
-    real_time_tx: &tokio::sync::broadcast::Sender<String>,
-    log_config: &LoggingConfig,
-    model_name: &str,
-    entry: &LogEntry<Value>,
-) {
-    if real_time_tx.receiver_count() == 0 {
-        return;
-    }
-
-    match serde_json::to_string(&entry.data) {
-        Ok(data) => {
-            let _ = real_time_tx.send(format!("INSERT:{}:{}", model_name, data));
+pub(crate) fn merge_patch_object(target: &mut Map<String, Value>, patch: Map<String, Value>) {
+    for (field, patch_value) in patch {
+        match patch_value {
+            Value::Null => {
+                target.remove(&field);
+            }
+            Value::Object(patch_object) => match target.get_mut(&field) {
+                Some(Value::Object(target_object)) => {
+                    merge_patch_object(target_object, patch_object);
+                }
+                _ => {
+                    let mut merged = Map::new();
+                    merge_patch_object(&mut merged, patch_object);
+                    target.insert(field, Value::Object(merged));
+                }
+            },
+            value => {
+                target.insert(field, value);
+            }
         }
-        Err(error) => Logger::error_with_config(
-            log_config,
-            &format!(
-                "Failed to serialize realtime event for {}: {}",
-                model_name, error
-            ),
-        ),
     }
 }
 
-pub(crate) fn finish_bulk_insert(
-    metrics: &Metrics,
-    max_samples: usize,
-    real_time_tx: &tokio::sync::broadcast::Sender<String>,
-    log_config: &LoggingConfig,
-    model_name: &str,
-    entries: &[LogEntry<Value>],
-    start: Instant,
-) {
-    if metrics.enabled {
-        metrics.record_inserts(entries.len() as u64, start.elapsed(), max_samples);
+/// Shallow upsert merge: top-level fields of `incoming` replace the stored ones.
+pub(crate) fn merge_fields(existing: &mut Map<String, Value>, incoming: Map<String, Value>) {
+    for (field, value) in incoming {
+        existing.insert(field, value);
     }
-    if real_time_tx.receiver_count() > 0 {
-        entries
-            .iter()
-            .for_each(|entry| publish_insert_event(real_time_tx, log_config, model_name, entry));
+}
+
+/// Replaces `path` through a synced temporary file.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
     }
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, contents)?;
+    std::fs::File::open(&temp_path)?.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
 }
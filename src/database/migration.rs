@@ -0,0 +1,195 @@
+use anyhow::Result;
+use std::sync::Arc;
+
+use crate::config::{IndexKind, ModelField};
+use crate::database::types::NyroDB;
+use crate::database::validation::{
+    validate_field_value, validate_orderable_field_type, SchemaPlan,
+};
+use crate::storage::{LogStorage, SchemaChange, SchemaVersion};
+use crate::utils::logger::Logger;
+
+/// Numeric changes that keep every stored value representable.
+const WIDENINGS: &[(&str, &str)] = &[
+    ("u32", "u64"),
+    ("u32", "i64"),
+    ("u32", "f64"),
+    ("u64", "f64"),
+    ("i64", "f64"),
+];
+
+impl NyroDB {
+    /// Every schema version of a model, oldest first.
+    pub fn schema_versions(&self, model_name: &str) -> Result<Vec<SchemaVersion>> {
+        Ok(self.get_storage(model_name)?.schema_versions())
+    }
+
+    /// Applies `change` as the model's next schema version without touching
+    /// logged rows, which are read through the versions they were written
+    /// under. Returns every version.
+    pub async fn migrate_schema(
+        &self,
+        model_name: &str,
+        change: SchemaChange,
+    ) -> Result<Vec<SchemaVersion>> {
+        let runtime = self.get_runtime(model_name)?;
+        let _schema = runtime.schema_gate.write();
+        let fields = migrated_fields(runtime.storage.schema().fields(), &change)?;
+        let schema_plan = SchemaPlan::from_fields(&fields)?;
+        let versions = runtime.storage.add_schema_version(change, fields)?;
+        *runtime.schema_plan.write() = Arc::new(schema_plan);
+        self.spawn_index_backfill(model_name, &runtime.storage);
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!(
+                "Migrated '{}' to schema version {}",
+                model_name,
+                versions.len()
+            ),
+        );
+        Ok(versions)
+    }
+
+    /// Takes the schema versions of the primary on a replica.
+    pub(crate) fn adopt_schema_versions(
+        &self,
+        model_name: &str,
+        versions: Vec<SchemaVersion>,
+    ) -> Result<()> {
+        let runtime = self.get_runtime(model_name)?;
+        let _schema = runtime.schema_gate.write();
+        if runtime.storage.adopt_schema_versions(versions)? {
+            let schema_plan = SchemaPlan::from_fields(runtime.storage.schema().fields())?;
+            *runtime.schema_plan.write() = Arc::new(schema_plan);
+            self.spawn_index_backfill(model_name, &runtime.storage);
+        }
+        Ok(())
+    }
+
+    /// Fills indexes added by a schema change in the background, or right
+    /// away outside a Tokio runtime.
+    fn spawn_index_backfill(&self, model_name: &str, storage: &Arc<LogStorage>) {
+        if storage.index_builds().is_empty() {
+            return;
+        }
+        let storage = Arc::clone(storage);
+        let model_name = model_name.to_string();
+        let log_config = self.config.logging.clone();
+        let backfill = move || match storage.backfill_indexes() {
+            Ok(()) => Logger::info_with_config(
+                &log_config,
+                &format!("Finished building indexes of '{}'", model_name),
+            ),
+            Err(error) => Logger::error_with_config(
+                &log_config,
+                &format!("Failed to build indexes of '{}': {}", model_name, error),
+            ),
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(backfill);
+            }
+            Err(_) => backfill(),
+        }
+    }
+}
+
+/// The fields after `change`, or why it cannot be applied to `fields`.
+fn migrated_fields(fields: &[ModelField], change: &SchemaChange) -> Result<Vec<ModelField>> {
+    let mut fields = fields.to_vec();
+    let position = |name: &str| fields.iter().position(|field| field.name == name);
+    match change {
+        SchemaChange::AddField {
+            name,
+            field_type,
+            required,
+            default,
+        } => {
+            if name.is_empty() {
+                return Err(anyhow::anyhow!("Field name must not be empty"));
+            }
+            if position(name).is_some() {
+                return Err(anyhow::anyhow!("Field '{}' already exists", name));
+            }
+            let field = ModelField {
+                name: name.clone(),
+                field_type: field_type.clone(),
+                required: *required,
+                indexed: false,
+                index: None,
+            };
+            if default.is_null() {
+                if *required {
+                    return Err(anyhow::anyhow!(
+                        "Required field '{}' needs a default for existing rows",
+                        name
+                    ));
+                }
+                SchemaPlan::from_fields(std::slice::from_ref(&field))?;
+            } else {
+                validate_field_value(&field, default)?;
+            }
+            fields.push(field);
+        }
+        SchemaChange::DropField { name } => {
+            let index = existing_field(position(name), name)?;
+            fields.remove(index);
+        }
+        SchemaChange::RetypeField { name, field_type } => {
+            let index = existing_field(position(name), name)?;
+            let field = &mut fields[index];
+            let widening = (field.field_type.as_str(), field_type.as_str());
+            if !WIDENINGS.contains(&widening) {
+                return Err(anyhow::anyhow!(
+                    "Field '{}' cannot change from {} to {}",
+                    name,
+                    field.field_type,
+                    field_type
+                ));
+            }
+            // Index keys of u32 and u64 values are the same; other widenings
+            // would leave existing keys unreachable.
+            if field.index_kind().is_some() && widening != ("u32", "u64") {
+                return Err(anyhow::anyhow!(
+                    "Indexed field '{}' can only change from u32 to u64",
+                    name
+                ));
+            }
+            field.field_type = field_type.clone();
+        }
+        SchemaChange::AddIndex { name, kind } => {
+            let index = existing_field(position(name), name)?;
+            let field = &mut fields[index];
+            if field.index_kind().is_some() {
+                return Err(anyhow::anyhow!("Field '{}' is already indexed", name));
+            }
+            if *kind == IndexKind::Ordered && !validate_orderable_field_type(&field.field_type) {
+                return Err(anyhow::anyhow!(
+                    "Field '{}' of type '{}' cannot have an ordered index",
+                    name,
+                    field.field_type
+                ));
+            }
+            field.indexed = false;
+            field.index = Some(*kind);
+        }
+        SchemaChange::DropIndex { name } => {
+            let index = existing_field(position(name), name)?;
+            let field = &mut fields[index];
+            if field.index_kind().is_none() {
+                return Err(anyhow::anyhow!("Field '{}' is not indexed", name));
+            }
+            field.indexed = false;
+            field.index = None;
+        }
+    }
+    Ok(fields)
+}
+
+fn existing_field(position: Option<usize>, name: &str) -> Result<usize> {
+    match position {
+        Some(_) if name == "id" => Err(anyhow::anyhow!("The 'id' field cannot be changed")),
+        Some(index) => Ok(index),
+        None => Err(anyhow::anyhow!("Field '{}' does not exist", name)),
+    }
+}
@@ -1,9 +1,24 @@
+mod aggregate;
+mod backup;
+mod catalog;
+mod changes;
+mod checkpoint;
+mod compaction;
+mod failover;
+mod filter;
 mod helpers;
+mod migration;
+mod mutations;
+mod position;
+mod query;
+mod replication;
 mod runtime;
 #[cfg(test)]
 mod tests;
+mod transaction;
 mod types;
 pub(crate) mod validation;
+mod webhooks;
 
 use anyhow::Result;
 use dashmap::DashMap;
@@ -14,16 +29,43 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Semaphore;
 
-pub use types::NyroDB;
-
-use crate::config::NyroConfig;
+pub use aggregate::{
+    AggregateGroup, AggregateOp, AggregateRequest, AggregateResponse, AggregateSpec,
+};
+pub use backup::{
+    restore_backup, BackupFile, BackupManifest, BackupModel, RestoreReport, MANIFEST_FILE,
+};
+pub use catalog::{IndexDescription, ModelDescription, ModelExistsError};
+pub use changes::{ChangeBatch, ChangeGap, ChangePosition};
+pub use failover::{FenceRequest, StaleEpochError, EPOCH_HEADER, PRIMARY_HEADER};
+pub(crate) use filter::Filter;
+pub use position::{LogPosition, PositionTimeoutError, POSITION_HEADER};
+pub use query::{QueryPage, QueryRequest, QueryResponse, SortField, SortOrder};
+pub use replication::{
+    format_generation, ModelReplicationStatus, ReplicationStatus, LOG_END_HEADER,
+    LOG_GENERATION_HEADER, SCHEMA_VERSION_HEADER,
+};
+pub use transaction::{
+    Transaction, TransactionOperation, TransactionReport, TransactionRequest, TransactionResult,
+};
+pub use types::{ConflictError, InsertConflict, InsertManyReport, NyroDB};
+pub use webhooks::WebhookStatus;
+
+use crate::config::{ConflictPolicy, NyroConfig};
+use crate::database::catalog::Catalog;
+use crate::database::replication::Replication;
+use crate::database::types::ModelRuntime;
 use crate::database::validation::SchemaPlan;
 use crate::models::{LogEntry, Operation};
+use crate::storage::RangeQuery;
 use crate::utils::logger::Logger;
-use crate::utils::metrics::{Metrics, MetricsReport};
-use helpers::{current_unix_millis, field_matches, finish_bulk_insert, publish_insert_event};
+use crate::utils::metrics::{IndexBuildReport, Metrics, MetricsReport};
+use helpers::{current_unix_millis, field_matches, merge_fields};
+use serde_json::Map;
+use std::collections::HashMap;
 
 const PARALLEL_PREPARE_THRESHOLD: usize = 16_384;
+const REALTIME_CHANNEL_CAPACITY: usize = 10_000;
 
 impl NyroDB {
     pub fn new(config: NyroConfig) -> Self {
@@ -33,6 +75,7 @@ impl NyroDB {
         let log_config = config.logging.clone();
 
         Logger::info_with_config(&log_config, "Initializing NyroDB engine");
+        let catalog = Catalog::load(&config);
         Logger::info_with_config(
             &log_config,
             &format!(
@@ -41,11 +84,12 @@ impl NyroDB {
                 config.server.port,
                 config.performance.max_concurrent_ops,
                 config.storage.buffer_size / 1024,
-                config.models.len()
+                catalog.len()
             ),
         );
 
-        let (real_time_tx, _) = tokio::sync::broadcast::channel(10000);
+        let (real_time_tx, _) = tokio::sync::broadcast::channel(REALTIME_CHANNEL_CAPACITY);
+        let replication = Replication::new(&config);
 
         Logger::info_with_config(&log_config, "NyroDB engine initialized successfully");
 
@@ -56,62 +100,214 @@ impl NyroDB {
             concurrency_limiter: Arc::new(Semaphore::new(config.performance.max_concurrent_ops)),
             config,
             real_time_tx,
+            transaction_log: parking_lot::Mutex::new(None),
+            webhooks: Default::default(),
+            replication,
+            catalog,
         }
     }
 
     pub async fn insert_raw(&self, model_name: &str, data: Value) -> Result<u64> {
+        self.insert_raw_with_policy(model_name, data, None).await
+    }
+
+    /// Inserts one row, resolving an existing `id` with `policy` or the
+    /// model's `on_conflict` setting. `reject` fails with [`ConflictError`].
+    pub async fn insert_raw_with_policy(
+        &self,
+        model_name: &str,
+        data: Value,
+        policy: Option<ConflictPolicy>,
+    ) -> Result<u64> {
         let start = Instant::now();
         let runtime = self.get_runtime(model_name)?;
-        let (id, log_entry) = Self::prepare_insert_entry_for_schema(
-            &runtime.schema_plan,
-            data,
-            current_unix_millis()?,
-        )?;
+        let _schema = runtime.schema_gate.read();
+        let policy = policy.unwrap_or(runtime.conflict_policy);
+        let timestamp = current_unix_millis()?;
+        let (id, log_entry) = if policy == ConflictPolicy::UpsertMerge {
+            Self::prepare_upsert_entry(&runtime, None, data, timestamp)?
+        } else {
+            Self::prepare_insert_entry_for_schema(&runtime.schema_plan(), data, timestamp)?
+        };
         let realtime_entry = if self.real_time_tx.receiver_count() > 0 {
-            Some(log_entry.clone())
+            let previous = if policy == ConflictPolicy::UpsertMerge {
+                self.previous_rows(&runtime.storage, std::slice::from_ref(&log_entry))?
+            } else {
+                Vec::new()
+            };
+            Some((log_entry.clone(), previous))
         } else {
             None
         };
 
-        runtime.storage.append_owned(log_entry)?;
+        let seq = if policy == ConflictPolicy::Reject {
+            runtime
+                .storage
+                .append_owned_if_absent(log_entry)?
+                .ok_or_else(|| ConflictError {
+                    model: model_name.to_string(),
+                    id,
+                })?
+        } else {
+            runtime.storage.append_owned(log_entry)?
+        };
         self.metrics
             .record_insert(start.elapsed(), self.config.metrics.max_samples);
-        if let Some(entry) = realtime_entry {
-            self.publish_insert(model_name, &entry);
+        match realtime_entry {
+            Some((entry, previous)) => {
+                self.publish_changes(&runtime, model_name, seq, &[entry], previous);
+            }
+            None => self.skip_changes(&runtime, seq, 1),
         }
 
         Ok(id)
     }
 
+    /// Inserts rows with the model's `on_conflict` policy. Rows skipped by
+    /// `reject` are left out of the returned ids; use
+    /// [`NyroDB::insert_many_raw_with_policy`] to see them.
     pub async fn insert_many_raw(&self, model_name: &str, rows: Vec<Value>) -> Result<Vec<u64>> {
+        Ok(self
+            .insert_many_raw_with_policy(model_name, rows, None)
+            .await?
+            .ids)
+    }
+
+    pub async fn insert_many_raw_with_policy(
+        &self,
+        model_name: &str,
+        rows: Vec<Value>,
+        policy: Option<ConflictPolicy>,
+    ) -> Result<InsertManyReport> {
         let start = Instant::now();
         let timestamp = current_unix_millis()?;
         let runtime = self.get_runtime(model_name)?;
-        let (ids, entries) = Self::prepare_insert_entries(&runtime.schema_plan, rows, timestamp)?;
+        let _schema = runtime.schema_gate.read();
+
+        match policy.unwrap_or(runtime.conflict_policy) {
+            ConflictPolicy::Overwrite => {
+                let (ids, entries) =
+                    Self::prepare_insert_entries(&runtime.schema_plan(), rows, timestamp)?;
+                self.append_bulk(model_name, &runtime, entries, Vec::new(), start)?;
+                Ok(InsertManyReport {
+                    ids,
+                    conflicts: Vec::new(),
+                })
+            }
+            ConflictPolicy::Reject => {
+                let (ids, entries) =
+                    Self::prepare_insert_entries(&runtime.schema_plan(), rows, timestamp)?;
+                let (first_seq, skipped) = runtime.storage.append_entries_if_absent(&entries)?;
+                let mut report = InsertManyReport::default();
+                let mut written = Vec::with_capacity(entries.len() - skipped.len());
+                let mut skipped = skipped.into_iter().peekable();
+                for (index, (id, entry)) in ids.into_iter().zip(entries).enumerate() {
+                    if skipped.next_if_eq(&index).is_some() {
+                        report.conflicts.push(InsertConflict { index, id });
+                    } else {
+                        report.ids.push(id);
+                        written.push(entry);
+                    }
+                }
+                self.finish_bulk_insert(
+                    &runtime,
+                    model_name,
+                    first_seq,
+                    &written,
+                    Vec::new(),
+                    start,
+                );
+                Ok(report)
+            }
+            ConflictPolicy::UpsertMerge => {
+                let mut batch_rows = HashMap::new();
+                let mut ids = Vec::with_capacity(rows.len());
+                let mut entries = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let (id, entry) =
+                        Self::prepare_upsert_entry(&runtime, Some(&batch_rows), row, timestamp)?;
+                    if let Value::Object(data) = &entry.data {
+                        batch_rows.insert(id, data.clone());
+                    }
+                    ids.push(id);
+                    entries.push(entry);
+                }
+                let previous = self.previous_rows(&runtime.storage, &entries)?;
+                self.append_bulk(model_name, &runtime, entries, previous, start)?;
+                Ok(InsertManyReport {
+                    ids,
+                    conflicts: Vec::new(),
+                })
+            }
+        }
+    }
 
+    fn append_bulk(
+        &self,
+        model_name: &str,
+        runtime: &ModelRuntime,
+        entries: Vec<LogEntry<Value>>,
+        previous: Vec<Option<Value>>,
+        start: Instant,
+    ) -> Result<()> {
         if self.real_time_tx.receiver_count() == 0 {
-            runtime.storage.append_entries_owned(entries)?;
+            let count = entries.len();
+            let first_seq = runtime.storage.append_entries_owned(entries)?;
             if self.metrics.enabled {
                 self.metrics.record_inserts(
-                    ids.len() as u64,
+                    count as u64,
                     start.elapsed(),
                     self.config.metrics.max_samples,
                 );
             }
+            self.skip_changes(runtime, first_seq, count);
         } else {
-            runtime.storage.append_entries(&entries)?;
-            finish_bulk_insert(
-                &self.metrics,
-                self.config.metrics.max_samples,
-                &self.real_time_tx,
-                &self.config.logging,
-                model_name,
-                &entries,
-                start,
-            );
+            let first_seq = runtime.storage.append_entries(&entries)?;
+            self.finish_bulk_insert(runtime, model_name, first_seq, &entries, previous, start);
         }
+        Ok(())
+    }
+
+    /// Merges `data` over the stored row (or the latest version of the same id
+    /// earlier in the batch) and logs the result as an update when a row
+    /// existed.
+    fn prepare_upsert_entry(
+        runtime: &ModelRuntime,
+        batch_rows: Option<&HashMap<u64, Map<String, Value>>>,
+        data: Value,
+        timestamp: u64,
+    ) -> Result<(u64, LogEntry<Value>)> {
+        let obj = match data {
+            Value::Object(obj) => obj,
+            _ => return Err(anyhow::anyhow!("Data must be a JSON object")),
+        };
+        let id = obj
+            .get("id")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow::anyhow!("Missing or invalid 'id' field"))?;
+        let existing = match batch_rows.and_then(|rows| rows.get(&id)) {
+            Some(row) => Some(row.clone()),
+            None => match runtime.storage.get_value(id)? {
+                Some(Value::Object(row)) => Some(row),
+                _ => None,
+            },
+        };
 
-        Ok(ids)
+        let Some(mut merged) = existing else {
+            return Self::prepare_insert_entry_for_schema(
+                &runtime.schema_plan(),
+                Value::Object(obj),
+                timestamp,
+            );
+        };
+        merge_fields(&mut merged, obj);
+        let (id, mut entry) = Self::prepare_insert_entry_for_schema(
+            &runtime.schema_plan(),
+            Value::Object(merged),
+            timestamp,
+        )?;
+        entry.operation = Operation::Update;
+        Ok((id, entry))
     }
 
     fn prepare_insert_entry_for_schema(
@@ -201,14 +397,14 @@ impl NyroDB {
         let storage = self.get_storage(model_name)?;
         let mut results = Vec::new();
 
-        if let Some(field_index) = storage.secondary_indices.get(field) {
-            if let Some(ids) = field_index.get(value) {
-                for id in ids.value() {
-                    if let Some(entry) = storage.get::<Value>(*id)? {
-                        results.push(entry.data);
-                    }
+        if let Some(ids) = storage.hash_index_ids(field, value) {
+            for id in ids {
+                if let Some(entry) = storage.get::<Value>(id)? {
+                    results.push(entry.data);
                 }
             }
+        } else if storage.has_ordered_index(field) {
+            results = storage.query_range(field, &RangeQuery::equal_to(value.into()))?;
         } else {
             for entry in storage.get_all::<Value>()? {
                 if field_matches(&entry.data, field, value) {
@@ -221,17 +417,51 @@ impl NyroDB {
         Ok(results)
     }
 
+    pub async fn query_range_raw(
+        &self,
+        model_name: &str,
+        field: &str,
+        query: &RangeQuery,
+    ) -> Result<Vec<Value>> {
+        let storage = self.get_storage(model_name)?;
+        let results = storage.query_range(field, query)?;
+        self.metrics.record_query();
+        Ok(results)
+    }
+
     pub fn get_metrics(&self) -> MetricsReport {
-        self.metrics.get_stats()
+        let mut report = self.metrics.get_stats();
+        if self.is_replica() {
+            let replication = self.replication_status();
+            report.replication_lag_bytes = Some(replication.lag_bytes);
+            report.replication_lag_ms = replication.lag_ms;
+        }
+        for runtime in self.runtimes.iter() {
+            for build in runtime.storage.index_builds() {
+                report.index_builds.push(IndexBuildReport {
+                    model: runtime.key().clone(),
+                    field: build.field,
+                    indexed_rows: build.indexed_rows,
+                    total_rows: build.total_rows,
+                });
+            }
+        }
+        report
+            .index_builds
+            .sort_by(|left, right| (&left.model, &left.field).cmp(&(&right.model, &right.field)));
+        report
     }
 
     pub async fn shutdown(&self) -> Result<()> {
         Logger::shutdown_with_config(&self.config.logging, "Initiating graceful shutdown");
         self.shutdown_flag.store(true, Ordering::Relaxed);
+        self.stop_webhooks();
+        self.stop_replication();
 
         let timeout = Duration::from_secs(self.config.server.graceful_shutdown_timeout);
         tokio::time::sleep(timeout).await;
 
+        self.checkpoint_all_indexes();
         let mut shutdown_count = 0;
         for item in self.runtimes.iter() {
             let (model_name, runtime) = item.pair();
@@ -268,8 +498,4 @@ impl NyroDB {
     pub fn get_concurrency_limiter(&self) -> Arc<Semaphore> {
         Arc::clone(&self.concurrency_limiter)
     }
-
-    fn publish_insert(&self, model_name: &str, entry: &LogEntry<Value>) {
-        publish_insert_event(&self.real_time_tx, &self.config.logging, model_name, entry);
-    }
 }
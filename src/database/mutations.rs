@@ -0,0 +1,118 @@
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use crate::database::helpers::{current_unix_millis, merge_patch_object};
+use crate::database::types::{ModelRuntime, NyroDB};
+use crate::models::{LogEntry, Operation};
+
+impl NyroDB {
+    pub async fn update_raw(
+        &self,
+        model_name: &str,
+        id: u64,
+        data: Value,
+    ) -> Result<Option<Value>> {
+        let runtime = self.get_runtime(model_name)?;
+        let mut obj = match data {
+            Value::Object(obj) => obj,
+            _ => return Err(anyhow::anyhow!("Data must be a JSON object")),
+        };
+
+        match obj.get("id") {
+            None => {
+                obj.insert("id".to_string(), Value::from(id));
+            }
+            Some(value) if value.as_u64() == Some(id) => {}
+            Some(_) => {
+                return Err(anyhow::anyhow!(
+                    "Body 'id' does not match requested ID {}",
+                    id
+                ))
+            }
+        }
+
+        if !runtime.storage.contains(id) {
+            return Ok(None);
+        }
+
+        self.write_update(model_name, &runtime, obj, None)
+    }
+
+    pub async fn patch_raw(
+        &self,
+        model_name: &str,
+        id: u64,
+        patch: Value,
+    ) -> Result<Option<Value>> {
+        let runtime = self.get_runtime(model_name)?;
+        let patch = match patch {
+            Value::Object(patch) => patch,
+            _ => return Err(anyhow::anyhow!("Patch must be a JSON object")),
+        };
+
+        // A write to the row between the read and the append moves its
+        // frame; the patch is then applied again to the newer row.
+        loop {
+            let (offset, mut obj) = match runtime.storage.get_value_with_offset(id)? {
+                Some((offset, Value::Object(obj))) => (offset, obj),
+                Some(_) => return Err(anyhow::anyhow!("Stored row {} is not a JSON object", id)),
+                None => return Ok(None),
+            };
+            merge_patch_object(&mut obj, patch.clone());
+            if obj.get("id").and_then(Value::as_u64) != Some(id) {
+                return Err(anyhow::anyhow!("Patch cannot change the 'id' field"));
+            }
+
+            if let Some(data) = self.write_update(model_name, &runtime, obj, Some(offset))? {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    pub async fn delete_raw(&self, model_name: &str, id: u64) -> Result<bool> {
+        let runtime = self.get_runtime(model_name)?;
+        let entry = LogEntry {
+            timestamp: current_unix_millis()?,
+            operation: Operation::Delete,
+            data: serde_json::json!({ "id": id }),
+        };
+        let Some(seq) = runtime.storage.append_if_present(&entry, None)? else {
+            return Ok(false);
+        };
+        self.metrics.record_delete();
+        self.publish_changes(&runtime, model_name, seq, &[entry], Vec::new());
+
+        Ok(true)
+    }
+
+    /// Appends the update unless the row is gone or, with `expected_offset`,
+    /// was written since it was read.
+    fn write_update(
+        &self,
+        model_name: &str,
+        runtime: &ModelRuntime,
+        obj: Map<String, Value>,
+        expected_offset: Option<u64>,
+    ) -> Result<Option<Value>> {
+        let _schema = runtime.schema_gate.read();
+        let (_, data) = runtime.schema_plan().validate_and_filter_owned(obj)?;
+        let entry = LogEntry {
+            timestamp: current_unix_millis()?,
+            operation: Operation::Update,
+            data: Value::Object(data),
+        };
+        let read_previous = self.real_time_tx.receiver_count() > 0;
+        let Some((seq, previous)) =
+            runtime
+                .storage
+                .replace_if_present(&entry, expected_offset, read_previous)?
+        else {
+            return Ok(None);
+        };
+        self.metrics.record_update();
+        let data = entry.data.clone();
+        self.publish_changes(runtime, model_name, seq, &[entry], vec![previous]);
+
+        Ok(Some(data))
+    }
+}
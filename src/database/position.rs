@@ -0,0 +1,105 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::database::types::NyroDB;
+
+pub const POSITION_HEADER: &str = "x-nyrodb-position";
+
+/// The newest sequence number of each listed model, written as
+/// `model:seq` pairs separated by commas. Sequence numbers are the same on
+/// the primary and its replicas and survive compactions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogPosition(pub BTreeMap<String, u64>);
+
+impl fmt::Display for LogPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (model, seq)) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}:{}", model, seq)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for LogPosition {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut position = BTreeMap::new();
+        for part in value.split(',') {
+            let (model, seq) = part
+                .rsplit_once(':')
+                .filter(|(model, _)| !model.is_empty())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Invalid position '{}': expected model:seq", part)
+                })?;
+            let seq = seq
+                .parse::<u64>()
+                .map_err(|_| anyhow::anyhow!("Invalid sequence number in position '{}'", part))?;
+            let seq = position
+                .get(model)
+                .map_or(seq, |other: &u64| seq.max(*other));
+            position.insert(model.to_string(), seq);
+        }
+        Ok(Self(position))
+    }
+}
+
+/// Returned when this node did not reach a requested position in time.
+#[derive(Debug)]
+pub struct PositionTimeoutError {
+    pub model: String,
+    pub seq: u64,
+    pub applied: u64,
+}
+
+impl fmt::Display for PositionTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Timed out waiting for '{}' to reach {} (applied up to {})",
+            self.model, self.seq, self.applied
+        )
+    }
+}
+
+impl std::error::Error for PositionTimeoutError {}
+
+impl NyroDB {
+    /// Position of the newest entries of `models`. A read that waits for it
+    /// sees every write to them that completed before this call.
+    pub fn log_position<'a>(
+        &self,
+        models: impl IntoIterator<Item = &'a str>,
+    ) -> Result<LogPosition> {
+        models
+            .into_iter()
+            .map(|model| Ok((model.to_string(), self.get_storage(model)?.last_seq())))
+            .collect::<Result<_>>()
+            .map(LogPosition)
+    }
+
+    /// Waits up to `timeout` until every entry up to `position` has been
+    /// applied and published here.
+    pub async fn wait_for_position(&self, position: &LogPosition, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        for (model, &seq) in &position.0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if seq > 0 && !self.wait_for_changes(model, seq - 1, remaining).await? {
+                return Err(PositionTimeoutError {
+                    model: model.clone(),
+                    seq,
+                    applied: self.published_seq(model)?,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
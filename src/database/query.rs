@@ -0,0 +1,301 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+
+use crate::database::filter::{sort_order, value_at_path, Condition, Filter};
+use crate::database::types::NyroDB;
+use crate::storage::{LogStorage, RangeQuery};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueryRequest {
+    #[serde(default)]
+    pub filter: Option<Value>,
+    /// Projection; dotted paths keep their nesting in the output.
+    #[serde(default)]
+    pub fields: Option<Vec<String>>,
+    #[serde(default)]
+    pub sort: Vec<SortField>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SortField {
+    pub field: String,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryResponse {
+    pub results: Vec<Value>,
+    /// Matching rows before `offset` and `limit` were applied.
+    pub total: usize,
+    /// `scan`, or the indexes the candidate rows were read from.
+    pub plan: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryPage {
+    pub results: Vec<Value>,
+    /// Pass as `after` to fetch the next page; `None` on the last page.
+    pub next_after: Option<u64>,
+}
+
+struct IndexPlan {
+    ids: Vec<u64>,
+    description: String,
+}
+
+impl NyroDB {
+    pub async fn query_page_raw(
+        &self,
+        model_name: &str,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<QueryPage> {
+        let storage = self.get_storage(model_name)?;
+        // Cold rows are read from the log.
+        let (results, next_after) = tokio::task::spawn_blocking(move || storage.page(after, limit))
+            .await
+            .map_err(|error| anyhow::anyhow!("Page read task failed: {}", error))??;
+        self.metrics.record_query();
+        Ok(QueryPage {
+            results,
+            next_after,
+        })
+    }
+
+    pub async fn query_structured_raw(
+        &self,
+        model_name: &str,
+        request: &QueryRequest,
+    ) -> Result<QueryResponse> {
+        let storage = self.get_storage(model_name)?;
+        let filter = request.filter.as_ref().map(Filter::parse).transpose()?;
+
+        let mut rows = Vec::new();
+        let plan = for_each_match(&storage, filter.as_ref(), |row| rows.push(row))?;
+
+        if !request.sort.is_empty() {
+            rows.sort_by(|left, right| {
+                request
+                    .sort
+                    .iter()
+                    .map(|sort| {
+                        let ordering = sort_order(
+                            value_at_path(left, &sort.field),
+                            value_at_path(right, &sort.field),
+                        );
+                        match sort.order {
+                            SortOrder::Asc => ordering,
+                            SortOrder::Desc => ordering.reverse(),
+                        }
+                    })
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+
+        let total = rows.len();
+        let results = rows
+            .into_iter()
+            .skip(request.offset)
+            .take(request.limit.unwrap_or(usize::MAX))
+            .map(|row| match &request.fields {
+                Some(fields) => project(&row, fields),
+                None => row,
+            })
+            .collect();
+
+        self.metrics.record_query();
+        Ok(QueryResponse {
+            results,
+            total,
+            plan,
+        })
+    }
+}
+
+/// Feeds every row matching `filter` to `visit` and returns the plan used.
+pub(crate) fn for_each_match(
+    storage: &LogStorage,
+    filter: Option<&Filter>,
+    mut visit: impl FnMut(Value),
+) -> Result<String> {
+    let matches = |row: &Value| filter.is_none_or(|filter| filter.matches(row));
+    match filter.and_then(|filter| plan(storage, filter)) {
+        Some(index_plan) => {
+            for id in index_plan.ids {
+                if let Some(row) = storage.get_value(id)? {
+                    if matches(&row) {
+                        visit(row);
+                    }
+                }
+            }
+            Ok(index_plan.description)
+        }
+        None => {
+            storage.scan(|row| {
+                if matches(&row) {
+                    visit(row);
+                }
+            })?;
+            Ok("scan".to_string())
+        }
+    }
+}
+
+/// Picks candidate ids from secondary indexes. Every candidate is still
+/// checked against the full filter, so a plan only has to be a superset.
+fn plan(storage: &LogStorage, filter: &Filter) -> Option<IndexPlan> {
+    match filter {
+        Filter::Condition(condition) => plan_condition(storage, condition),
+        Filter::And(filters) => filters
+            .iter()
+            .filter_map(|filter| plan(storage, filter))
+            .min_by_key(|index_plan| index_plan.ids.len()),
+        Filter::Or(filters) => {
+            let plans = filters
+                .iter()
+                .map(|filter| plan(storage, filter))
+                .collect::<Option<Vec<_>>>()?;
+            Some(union(plans))
+        }
+        Filter::Not(_) => None,
+    }
+}
+
+fn plan_condition(storage: &LogStorage, condition: &Condition) -> Option<IndexPlan> {
+    let field = condition.field.as_str();
+    if field.contains('.') {
+        return None;
+    }
+
+    if storage.has_ordered_index(field) {
+        let description = format!("range({})", field);
+        if let Some(value) = &condition.eq {
+            let ids = storage.ordered_index_ids(field, &RangeQuery::equal_to(value.clone()))?;
+            return Some(IndexPlan { ids, description });
+        }
+        if let Some(values) = &condition.any_of {
+            let plans = values
+                .iter()
+                .map(|value| {
+                    let ids =
+                        storage.ordered_index_ids(field, &RangeQuery::equal_to(value.clone()))?;
+                    Some(IndexPlan {
+                        ids,
+                        description: description.clone(),
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?;
+            return Some(IndexPlan {
+                ids: union(plans).ids,
+                description,
+            });
+        }
+        if condition.gt.is_some() && condition.gte.is_some()
+            || condition.lt.is_some() && condition.lte.is_some()
+        {
+            return None;
+        }
+        let range = RangeQuery {
+            gt: condition.gt.clone(),
+            gte: condition.gte.clone(),
+            lt: condition.lt.clone(),
+            lte: condition.lte.clone(),
+            ..RangeQuery::default()
+        };
+        let ids = storage.ordered_index_ids(field, &range)?;
+        return Some(IndexPlan { ids, description });
+    }
+
+    let description = format!("index({})", field);
+    let values = match (&condition.eq, &condition.any_of) {
+        (Some(value), _) => std::slice::from_ref(value),
+        (None, Some(values)) => values.as_slice(),
+        (None, None) => return None,
+    };
+    let mut ids = Vec::new();
+    for value in values {
+        ids.extend(storage.hash_index_ids(field, &hash_index_key(value)?)?);
+    }
+    Some(IndexPlan {
+        ids: dedup(ids),
+        description,
+    })
+}
+
+/// Mirrors how hash indexes stringify values. Floats are left to a scan
+/// because `1` and `1.0` stringify differently.
+fn hash_index_key(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Bool(_) => Some(value.to_string()),
+        Value::Number(number) if !number.is_f64() => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+fn union(plans: Vec<IndexPlan>) -> IndexPlan {
+    let mut descriptions = Vec::with_capacity(plans.len());
+    let mut ids = Vec::new();
+    for index_plan in plans {
+        if !descriptions.contains(&index_plan.description) {
+            descriptions.push(index_plan.description);
+        }
+        ids.extend(index_plan.ids);
+    }
+    IndexPlan {
+        ids: dedup(ids),
+        description: if descriptions.len() == 1 {
+            descriptions.remove(0)
+        } else {
+            format!("union({})", descriptions.join(", "))
+        },
+    }
+}
+
+fn dedup(ids: Vec<u64>) -> Vec<u64> {
+    let mut seen = HashSet::with_capacity(ids.len());
+    ids.into_iter().filter(|id| seen.insert(*id)).collect()
+}
+
+fn project(row: &Value, fields: &[String]) -> Value {
+    let mut projected = Map::new();
+    for path in fields {
+        let Some(value) = value_at_path(row, path) else {
+            continue;
+        };
+        let mut target = &mut projected;
+        let mut segments = path.split('.').peekable();
+        while let Some(segment) = segments.next() {
+            if segments.peek().is_none() {
+                target.insert(segment.to_string(), value.clone());
+                break;
+            }
+            let child = target
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            let Value::Object(child) = child else {
+                break;
+            };
+            target = child;
+        }
+    }
+    Value::Object(projected)
+}
@@ -0,0 +1,585 @@
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use warp::hyper::{self, Body, Client, Request};
+
+use crate::config::{NyroConfig, ReplicationRole};
+use crate::database::failover::{RoleState, StaleEpochError, EPOCH_HEADER};
+use crate::database::helpers::current_unix_millis;
+use crate::database::types::{ModelRuntime, NyroDB};
+use crate::models::LogEntry;
+use crate::storage::{
+    read_transaction_records, transaction_log_path, write_transaction_records, LogChunk,
+    SequenceMarker,
+};
+use crate::utils::logger::Logger;
+
+pub const LOG_END_HEADER: &str = "x-nyrodb-log-end";
+pub const LOG_GENERATION_HEADER: &str = "x-nyrodb-log-generation";
+pub const SCHEMA_VERSION_HEADER: &str = "x-nyrodb-schema-version";
+/// Upper bound on one chunk, whatever a replica asks for.
+pub const MAX_CHUNK_BYTES: usize = 64 * 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Role and progress of this node, shared between its replication task and
+/// the API.
+pub(crate) struct Replication {
+    pub(super) role: RwLock<RoleState>,
+    state: Mutex<ReplicationState>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    /// Fences the previous primary after a promotion.
+    pub(super) fence_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct ReplicationState {
+    models: BTreeMap<String, ModelReplicationStatus>,
+    started_at: u64,
+    /// Last pass that reached the primary.
+    contacted_at: u64,
+    caught_up_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Replication {
+    pub(crate) fn new(config: &NyroConfig) -> Self {
+        Self {
+            role: RwLock::new(RoleState::load(config)),
+            state: Mutex::default(),
+            task: Mutex::default(),
+            fence_task: Mutex::default(),
+        }
+    }
+
+    pub(super) fn reset_progress(&self) {
+        *self.state.lock() = ReplicationState::default();
+    }
+
+    pub(super) fn forget_model(&self, model_name: &str) {
+        self.state.lock().models.remove(model_name);
+    }
+
+    pub(super) fn record_error(&self, error: String) {
+        self.state.lock().last_error = Some(error);
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModelReplicationStatus {
+    /// Bytes of the primary's log applied locally.
+    pub applied_offset: u64,
+    /// Length of the primary's log when last asked.
+    pub primary_offset: u64,
+    pub lag_bytes: u64,
+    /// Complete copies of the log taken after the primary compacted it.
+    pub resyncs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicationStatus {
+    pub role: ReplicationRole,
+    pub epoch: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary_url: Option<String>,
+    pub models: BTreeMap<String, ModelReplicationStatus>,
+    pub lag_bytes: u64,
+    /// Milliseconds since the replica last held everything the primary had.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag_ms: Option<u64>,
+    pub caught_up_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl NyroDB {
+    pub fn is_replica(&self) -> bool {
+        self.replication.role.read().role == ReplicationRole::Replica
+    }
+
+    /// Starts following the primary when this node is a replica that knows
+    /// one.
+    pub fn start_replication(self: &Arc<Self>) -> Result<()> {
+        let Some(primary_url) = self.primary_url() else {
+            return Ok(());
+        };
+        for model_name in self.model_names() {
+            self.get_runtime(&model_name)?;
+        }
+        {
+            let mut state = self.replication.state.lock();
+            state.started_at = current_unix_millis()?;
+            state.contacted_at = state.started_at;
+        }
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!("Replicating from primary at {}", primary_url),
+        );
+        let task = tokio::spawn(replicate(Arc::downgrade(self)));
+        if let Some(previous) = self.replication.task.lock().replace(task) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stop_replication(&self) {
+        if let Some(task) = self.replication.task.lock().take() {
+            task.abort();
+        }
+    }
+
+    pub fn replication_status(&self) -> ReplicationStatus {
+        let role = self.replication.role.read().clone();
+        let state = self.replication.state.lock();
+        let replica = role.role == ReplicationRole::Replica;
+        let lag_ms = replica.then(|| {
+            let since = state.caught_up_at.unwrap_or(state.started_at);
+            current_unix_millis()
+                .unwrap_or_default()
+                .saturating_sub(since)
+        });
+        ReplicationStatus {
+            role: role.role,
+            epoch: role.epoch,
+            primary_url: (replica && !role.primary_url.is_empty()).then_some(role.primary_url),
+            models: state.models.clone(),
+            lag_bytes: state.models.values().map(|model| model.lag_bytes).sum(),
+            lag_ms,
+            caught_up_at: state.caught_up_at,
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    /// Whole frames of a model's log from `offset`, for a replica, stamped
+    /// with the epoch they were read under.
+    pub async fn read_log_chunk(
+        &self,
+        model_name: &str,
+        offset: u64,
+        max_bytes: usize,
+    ) -> Result<LogChunk> {
+        let storage = self.get_storage(model_name)?;
+        let max_bytes = max_bytes.clamp(1, MAX_CHUNK_BYTES);
+        let epoch = self.epoch();
+        let mut chunk =
+            tokio::task::spawn_blocking(move || storage.read_log_chunk(offset, max_bytes))
+                .await
+                .map_err(|error| anyhow::anyhow!("Log read task failed: {}", error))??;
+        chunk.epoch = epoch;
+        Ok(chunk)
+    }
+
+    /// Transaction log records after `offset`, with the length of the log.
+    pub fn read_transaction_records(&self, offset: u64) -> Result<(Vec<u8>, u64)> {
+        read_transaction_records(Path::new(&self.config.storage.data_dir), offset)
+    }
+
+    /// One pass over every model. Returns whether any model is still behind.
+    pub(crate) async fn replicate_once(self: &Arc<Self>, client: &HttpClient) -> Result<bool> {
+        let batch_bytes = self.config.replication.batch_bytes;
+        let (_, catalog) = self.fetch(client, "/replication/catalog").await?;
+        self.adopt_catalog(serde_json::from_slice(&catalog)?)?;
+        let models = self.model_names();
+        if self.replication.role.read().resync {
+            self.sync_transaction_log(client, true).await?;
+            for model_name in &models {
+                let runtime = self.get_runtime(model_name)?;
+                self.resync_model(client, model_name, &runtime).await?;
+            }
+            self.finish_resync()?;
+            return Ok(true);
+        }
+
+        // The primary publishes a transaction frame only after its commit
+        // record is durable, so fetching the transaction log after the chunks
+        // covers every transaction they contain.
+        let mut chunks = Vec::with_capacity(models.len());
+        for model_name in models {
+            let runtime = self.get_runtime(&model_name)?;
+            let offset = runtime.storage.log_bytes();
+            let chunk = self
+                .fetch_log_chunk(client, &model_name, offset, batch_bytes)
+                .await?;
+            chunks.push((model_name, runtime, chunk));
+        }
+        self.sync_transaction_log(client, false).await?;
+
+        let mut behind = false;
+        for (model_name, runtime, chunk) in chunks {
+            let chunk = if chunk.generation != runtime.storage.sequence_marker()
+                || chunk.offset > chunk.end
+            {
+                self.resync_model(client, &model_name, &runtime).await?
+            } else if !chunk.bytes.is_empty() {
+                let db = Arc::clone(self);
+                let runtime = Arc::clone(&runtime);
+                let model = model_name.clone();
+                tokio::task::spawn_blocking(move || db.apply_chunk(&model, &runtime, chunk))
+                    .await
+                    .map_err(|error| anyhow::anyhow!("Replication task failed: {}", error))??
+            } else {
+                chunk
+            };
+
+            let applied_offset = runtime.storage.log_bytes();
+            let lag_bytes = chunk.end.saturating_sub(applied_offset);
+            behind |= lag_bytes > 0;
+            let mut state = self.replication.state.lock();
+            let status = state.models.entry(model_name).or_default();
+            status.applied_offset = applied_offset;
+            status.primary_offset = chunk.end;
+            status.lag_bytes = lag_bytes;
+        }
+        Ok(behind)
+    }
+
+    /// Appends and indexes a chunk, then publishes its entries to the local
+    /// change feed. Hands the chunk back without its bytes.
+    fn apply_chunk(
+        &self,
+        model_name: &str,
+        runtime: &ModelRuntime,
+        mut chunk: LogChunk,
+    ) -> Result<LogChunk> {
+        let replicated = runtime
+            .storage
+            .decode_log_chunk(chunk.offset, &chunk.bytes)?;
+        // Holding the role keeps a fence or promotion from raising the epoch
+        // between the check and the append.
+        let role = self.replication.role.read();
+        if self.real_time_tx.receiver_count() == 0 {
+            let count = replicated.len();
+            let first_seq = runtime
+                .storage
+                .apply_log_chunk(&chunk, role.epoch, replicated)?;
+            self.skip_changes(runtime, first_seq, count);
+        } else {
+            let entries: Vec<LogEntry<Value>> = replicated
+                .iter()
+                .map(|replicated| replicated.entry.clone())
+                .collect();
+            let previous = self.previous_rows(&runtime.storage, &entries)?;
+            let first_seq = runtime
+                .storage
+                .apply_log_chunk(&chunk, role.epoch, replicated)?;
+            self.publish_changes(runtime, model_name, first_seq, &entries, previous);
+        }
+        drop(role);
+        chunk.bytes = Vec::new();
+        Ok(chunk)
+    }
+
+    /// Replaces the local log with a complete copy of the primary's.
+    async fn resync_model(
+        &self,
+        client: &HttpClient,
+        model_name: &str,
+        runtime: &ModelRuntime,
+    ) -> Result<LogChunk> {
+        let mut copy_path = runtime.storage.file_path.clone();
+        copy_path.push_str(".replica");
+        let path = copy_path.clone();
+        let mut copy = run_blocking(move || Ok(std::fs::File::create(path)?)).await?;
+        let mut generation = None;
+        let mut last = LogChunk::default();
+        while generation.is_none() || last.offset + (last.bytes.len() as u64) < last.end {
+            let offset = last.offset + last.bytes.len() as u64;
+            let mut chunk = self
+                .fetch_log_chunk(
+                    client,
+                    model_name,
+                    offset,
+                    self.config.replication.batch_bytes,
+                )
+                .await?;
+            if generation.is_some_and(|generation| generation != chunk.generation) {
+                // Compacted again while copying; start over.
+                copy = run_blocking(move || {
+                    copy.set_len(0)?;
+                    copy.rewind()?;
+                    Ok(copy)
+                })
+                .await?;
+                generation = None;
+                last = LogChunk::default();
+                continue;
+            }
+            if chunk.bytes.is_empty() && offset < chunk.end {
+                return Err(anyhow::anyhow!(
+                    "Primary returned no frames for '{}' at offset {}",
+                    model_name,
+                    offset
+                ));
+            }
+            let bytes = std::mem::take(&mut chunk.bytes);
+            let (file, bytes) = run_blocking(move || {
+                copy.write_all(&bytes)?;
+                Ok((copy, bytes))
+            })
+            .await?;
+            copy = file;
+            chunk.bytes = bytes;
+            generation = Some(chunk.generation);
+            last = chunk;
+        }
+        run_blocking(move || Ok(copy.sync_all()?)).await?;
+        self.sync_transaction_log(client, false).await?;
+
+        let storage = Arc::clone(&runtime.storage);
+        let strict = self.config.storage.strict_recovery;
+        let log_config = self.config.logging.clone();
+        tokio::task::spawn_blocking(move || {
+            storage.replace_log(Path::new(&copy_path), strict, &log_config)
+        })
+        .await
+        .map_err(|error| anyhow::anyhow!("Log replacement task failed: {}", error))??;
+        runtime.changes.reset(runtime.storage.last_seq());
+
+        self.replication
+            .state
+            .lock()
+            .models
+            .entry(model_name.to_string())
+            .or_default()
+            .resyncs += 1;
+        Logger::info_with_config(
+            &self.config.logging,
+            &format!(
+                "Copied the log of '{}' from the primary ({} bytes)",
+                model_name, last.end
+            ),
+        );
+        last.bytes = Vec::new();
+        last.offset = last.end;
+        Ok(last)
+    }
+
+    async fn fetch_log_chunk(
+        &self,
+        client: &HttpClient,
+        model_name: &str,
+        offset: u64,
+        max_bytes: usize,
+    ) -> Result<LogChunk> {
+        let (headers, bytes) = self
+            .fetch(
+                client,
+                &format!(
+                    "/replication/log/{}?offset={}&max_bytes={}",
+                    model_name, offset, max_bytes
+                ),
+            )
+            .await?;
+        // Frames of a newer schema version only decode with its history.
+        let schema_version = match headers.get(SCHEMA_VERSION_HEADER) {
+            Some(_) => header_u64(&headers, SCHEMA_VERSION_HEADER)? as u32,
+            None => 1,
+        };
+        if schema_version > self.get_storage(model_name)?.schema().version() {
+            let (_, body) = self
+                .fetch(client, &format!("/replication/schema/{}", model_name))
+                .await?;
+            self.adopt_schema_versions(model_name, serde_json::from_slice(&body)?)?;
+        }
+        Ok(LogChunk {
+            epoch: header_u64(&headers, EPOCH_HEADER).unwrap_or_default(),
+            offset,
+            end: header_u64(&headers, LOG_END_HEADER)?,
+            generation: parse_generation(header_str(&headers, LOG_GENERATION_HEADER)?)?,
+            schema_version,
+            bytes,
+        })
+    }
+
+    /// Appends the primary's new transaction records, starting over when
+    /// the local copy is longer than the primary's or `from_start` is set.
+    async fn sync_transaction_log(&self, client: &HttpClient, from_start: bool) -> Result<()> {
+        let data_dir = PathBuf::from(&self.config.storage.data_dir);
+        let local_len = match std::fs::metadata(transaction_log_path(&data_dir)) {
+            Ok(_) if from_start => 0,
+            Ok(metadata) => metadata.len(),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error.into()),
+        };
+        let path = format!("/replication/transactions?offset={}", local_len);
+        let (headers, records) = self.fetch(client, &path).await?;
+        let end = header_u64(&headers, LOG_END_HEADER)?;
+        if local_len > end {
+            let (_, records) = self
+                .fetch(client, "/replication/transactions?offset=0")
+                .await?;
+            return run_blocking(move || write_transaction_records(&data_dir, 0, &records)).await;
+        }
+        if !records.is_empty() || from_start {
+            run_blocking(move || write_transaction_records(&data_dir, local_len, &records)).await?;
+        }
+        Ok(())
+    }
+
+    /// GETs `path` from the primary. Both sides send their epoch: a primary
+    /// behind ours is refused, and a newer epoch is taken over.
+    async fn fetch(&self, client: &HttpClient, path: &str) -> Result<(hyper::HeaderMap, Vec<u8>)> {
+        let (primary_url, epoch) = {
+            let role = self.replication.role.read();
+            (role.primary_url.clone(), role.epoch)
+        };
+        let url = format!("{}{}", primary_url.trim_end_matches('/'), path);
+        let mut request = Request::get(&url).header(EPOCH_HEADER, epoch);
+        if !self.config.security.api_key.is_empty() {
+            request = request.header("x-api-key", &self.config.security.api_key);
+        }
+        let (parts, body) = send(client, request.body(Body::empty())?).await?;
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!(
+                "Primary answered {} for {}: {}",
+                parts.status,
+                path,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        let primary_epoch = match parts.headers.get(EPOCH_HEADER) {
+            Some(_) => header_u64(&parts.headers, EPOCH_HEADER)?,
+            None => 0,
+        };
+        if primary_epoch < epoch {
+            return Err(StaleEpochError {
+                epoch: primary_epoch,
+                current: epoch,
+            }
+            .into());
+        }
+        self.adopt_epoch(primary_epoch)?;
+        Ok((parts.headers, body.to_vec()))
+    }
+}
+
+pub(crate) type HttpClient = Client<hyper::client::HttpConnector>;
+
+pub(crate) async fn send(
+    client: &HttpClient,
+    request: Request<Body>,
+) -> Result<(hyper::http::response::Parts, hyper::body::Bytes)> {
+    tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let response = client.request(request).await?;
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok((parts, body))
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Timed out after {}s", REQUEST_TIMEOUT.as_secs()))?
+}
+
+/// Polls the primary until this node is promoted, dropped or shut down,
+/// without waiting between passes while any model is behind. With a
+/// `failover_timeout` on a `sole_replica`, losing the primary for that long
+/// promotes the node.
+async fn replicate(db: Weak<NyroDB>) {
+    let client = Client::new();
+    loop {
+        let Some(db) = db.upgrade() else {
+            return;
+        };
+        if db.shutdown_flag.load(Ordering::Relaxed) || !db.is_replica() {
+            return;
+        }
+        let poll_interval = Duration::from_millis(db.config.replication.poll_interval);
+        let result = db.replicate_once(&client).await;
+        let now = current_unix_millis().unwrap_or_default();
+        match result {
+            Ok(behind) => {
+                let mut state = db.replication.state.lock();
+                state.contacted_at = now;
+                state.last_error = None;
+                if behind {
+                    continue;
+                }
+                state.caught_up_at = Some(now);
+            }
+            Err(error) => {
+                Logger::error_with_config(
+                    &db.config.logging,
+                    &format!("Replication from the primary failed: {}", error),
+                );
+                let contacted_at = {
+                    let mut state = db.replication.state.lock();
+                    state.last_error = Some(error.to_string());
+                    state.contacted_at
+                };
+                let failover_timeout = db.config.replication.failover_timeout;
+                let (epoch, diverged) = {
+                    let role = db.replication.role.read();
+                    (role.epoch, role.resync)
+                };
+                if failover_timeout > 0
+                    && db.config.replication.sole_replica
+                    && !diverged
+                    && now.saturating_sub(contacted_at) > failover_timeout
+                {
+                    // Detach rather than abort this task before promoting.
+                    drop(db.replication.task.lock().take());
+                    if let Err(error) = db.take_over(epoch) {
+                        Logger::error_with_config(
+                            &db.config.logging,
+                            &format!("Automatic promotion failed: {}", error),
+                        );
+                    }
+                    return;
+                }
+            }
+        }
+        drop(db);
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Runs file work of the replication task off the async workers.
+async fn run_blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|error| anyhow::anyhow!("Replication file task failed: {}", error))?
+}
+
+/// `base:compacted_through:compacted_at`, as sent in [`LOG_GENERATION_HEADER`].
+pub fn format_generation(marker: &SequenceMarker) -> String {
+    format!(
+        "{}:{}:{}",
+        marker.base, marker.compacted_through, marker.compacted_at
+    )
+}
+
+fn parse_generation(value: &str) -> Result<SequenceMarker> {
+    let parts: Vec<u64> = value
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| anyhow::anyhow!("Invalid log generation '{}'", value))?;
+    match parts[..] {
+        [base, compacted_through, compacted_at] => Ok(SequenceMarker {
+            base,
+            compacted_through,
+            compacted_at,
+        }),
+        _ => Err(anyhow::anyhow!("Invalid log generation '{}'", value)),
+    }
+}
+
+fn header_str<'a>(headers: &'a hyper::HeaderMap, name: &str) -> Result<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| anyhow::anyhow!("Primary response has no {} header", name))
+}
+
+fn header_u64(headers: &hyper::HeaderMap, name: &str) -> Result<u64> {
+    header_str(headers, name)?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid {} header", name))
+}
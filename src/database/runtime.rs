@@ -1,7 +1,9 @@
 use anyhow::Result;
 use dashmap::mapref::entry::Entry;
+use parking_lot::RwLock;
 use std::sync::Arc;
 
+use crate::database::changes::ChangeFeed;
 use crate::database::types::{ModelRuntime, NyroDB};
 use crate::database::validation::SchemaPlan;
 use crate::storage::LogStorage;
@@ -16,24 +18,27 @@ impl NyroDB {
             Entry::Occupied(existing_runtime) => Ok(existing_runtime.get().clone()),
             Entry::Vacant(empty_slot) => {
                 let schema = self
-                    .config
-                    .models
+                    .catalog
                     .get(model_name)
-                    .ok_or_else(|| {
-                        anyhow::anyhow!("Model '{}' not defined in configuration", model_name)
-                    })?
-                    .clone();
-                let schema = Arc::new(schema);
-                let schema_plan = Arc::new(SchemaPlan::from_schema(&schema)?);
+                    .ok_or_else(|| anyhow::anyhow!("Model '{}' does not exist", model_name))?;
                 let storage = Arc::new(LogStorage::new(
                     model_name,
                     &self.config.storage,
                     &self.config.logging,
                     &schema,
                 )?);
+                // Migrations may have moved the fields past the configuration.
+                let schema_plan = SchemaPlan::from_fields(storage.schema().fields())?;
+                storage.set_read_only(self.is_replica());
+                self.spawn_compaction_watcher(model_name, &storage);
+                self.spawn_index_checkpointer(model_name, &storage);
+                let last_seq = storage.last_seq();
                 let runtime = Arc::new(ModelRuntime {
-                    schema_plan,
+                    schema_plan: RwLock::new(Arc::new(schema_plan)),
+                    schema_gate: RwLock::new(()),
                     storage,
+                    conflict_policy: schema.on_conflict,
+                    changes: ChangeFeed::new(last_seq),
                 });
                 empty_slot.insert(runtime.clone());
                 Ok(runtime)
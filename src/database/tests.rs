@@ -1,8 +1,20 @@
 use serde_json::json;
+use sha2::Digest;
 use std::sync::Arc;
+use std::time::Duration;
+use warp::Filter;
 
-use crate::config::NyroConfig;
-use crate::database::NyroDB;
+use crate::config::{
+    ConflictPolicy, IndexKind, ModelField, ModelSchema, NyroConfig, ReplicationRole, WebhookConfig,
+};
+use crate::database::webhooks::{hex, sign};
+use crate::database::{
+    restore_backup, AggregateRequest, ChangeBatch, ChangePosition, ConflictError, FenceRequest,
+    LogPosition, ModelExistsError, NyroDB, PositionTimeoutError, QueryRequest, StaleEpochError,
+    MANIFEST_FILE, POSITION_HEADER,
+};
+use crate::models::{LogEntry, Operation};
+use crate::storage::{RangeQuery, ReadOnlyError, SchemaChange};
 
 #[tokio::test]
 async fn insert_is_committed_before_returning() -> anyhow::Result<()> {
@@ -178,10 +190,2335 @@ async fn insert_many_returns_readable_persisted_rows() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn update_and_patch_keep_secondary_index_consistent() -> anyhow::Result<()> {
+    let mut config = test_config("update_patch");
+    index_field(&mut config, "user", "email");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_raw("user", user_row(5, "old@test.com")).await?;
+
+    let replaced = db
+        .update_raw(
+            "user",
+            5,
+            json!({
+                "email": "new@test.com",
+                "hash_password": "hash_5",
+                "created_at": 6
+            }),
+        )
+        .await?;
+    assert_eq!(
+        replaced,
+        Some(json!({
+            "id": 5,
+            "email": "new@test.com",
+            "hash_password": "hash_5",
+            "created_at": 6
+        }))
+    );
+    assert!(db
+        .query_by_field_raw("user", "email", "old@test.com")
+        .await?
+        .is_empty());
+
+    let patched = db
+        .patch_raw("user", 5, json!({ "email": "patched@test.com" }))
+        .await?;
+    assert_eq!(
+        patched.as_ref().and_then(|row| row.get("created_at")),
+        Some(&json!(6))
+    );
+    assert_eq!(
+        db.query_by_field_raw("user", "email", "patched@test.com")
+            .await?
+            .len(),
+        1
+    );
+    assert!(db.patch_raw("user", 5, json!({ "id": 6 })).await.is_err());
+    assert_eq!(
+        db.update_raw("user", 99, user_row(99, "x@test.com"))
+            .await?,
+        None
+    );
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert!(reopened
+        .query_by_field_raw("user", "email", "new@test.com")
+        .await?
+        .is_empty());
+    assert_eq!(
+        reopened
+            .query_by_field_raw("user", "email", "patched@test.com")
+            .await?
+            .len(),
+        1
+    );
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn deleted_rows_stay_deleted_after_restart() -> anyhow::Result<()> {
+    let mut config = test_config("delete_restart");
+    index_field(&mut config, "user", "email");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_many_raw(
+        "user",
+        vec![user_row(1, "one@test.com"), user_row(2, "two@test.com")],
+    )
+    .await?;
+
+    assert!(db.delete_raw("user", 1).await?);
+    assert!(!db.delete_raw("user", 1).await?);
+    assert_eq!(db.get_raw("user", 1).await?, None);
+    assert_eq!(db.query_raw("user").await?.len(), 1);
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.get_raw("user", 1).await?, None);
+    assert_eq!(
+        reopened.get_raw("user", 2).await?,
+        Some(user_row(2, "two@test.com"))
+    );
+    assert!(reopened
+        .query_by_field_raw("user", "email", "one@test.com")
+        .await?
+        .is_empty());
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn updates_are_only_appended_for_rows_still_present() -> anyhow::Result<()> {
+    let config = test_config("append_if_present");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_raw("user", user_row(1, "one@test.com")).await?;
+    let storage = db.get_storage("user")?;
+    let update = |email: &str| LogEntry {
+        timestamp: 1,
+        operation: Operation::Update,
+        data: user_row(1, email),
+    };
+    let read_offset = storage.get_value_with_offset(1)?.map(|(offset, _)| offset);
+    assert!(storage
+        .append_if_present(&update("two@test.com"), read_offset)?
+        .is_some());
+    // The row moved since `read_offset` was read.
+    assert!(storage
+        .append_if_present(&update("lost@test.com"), read_offset)?
+        .is_none());
+    // The replaced row is read under the same lock as the append.
+    let (_, previous) = storage
+        .replace_if_present(&update("two@test.com"), None, true)?
+        .expect("row is present");
+    assert_eq!(
+        previous.map(|row| row["email"].clone()),
+        Some(json!("two@test.com"))
+    );
+    db.patch_raw("user", 1, json!({ "hash_password": "patched" }))
+        .await?;
+    assert_eq!(
+        db.get_raw("user", 1).await?.map(|row| row["email"].clone()),
+        Some(json!("two@test.com"))
+    );
+
+    assert!(db.delete_raw("user", 1).await?);
+    assert!(storage
+        .append_if_present(&update("revived@test.com"), None)?
+        .is_none());
+    assert!(db
+        .patch_raw("user", 1, json!({ "hash_password": "x" }))
+        .await?
+        .is_none());
+    drop(storage);
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.get_raw("user", 1).await?, None);
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn reinserting_an_id_moves_it_between_index_buckets() -> anyhow::Result<()> {
+    let mut config = test_config("reinsert_index");
+    index_field(&mut config, "user", "email");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_raw("user", user_row(1, "first@test.com")).await?;
+    db.insert_raw("user", user_row(1, "second@test.com"))
+        .await?;
+    db.insert_many_raw(
+        "user",
+        vec![
+            user_row(1, "second@test.com"),
+            user_row(2, "second@test.com"),
+            user_row(2, "second@test.com"),
+        ],
+    )
+    .await?;
+
+    assert!(db
+        .query_by_field_raw("user", "email", "first@test.com")
+        .await?
+        .is_empty());
+    assert_eq!(
+        db.query_by_field_raw("user", "email", "second@test.com")
+            .await?
+            .len(),
+        2
+    );
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert!(reopened
+        .query_by_field_raw("user", "email", "first@test.com")
+        .await?
+        .is_empty());
+    assert_eq!(
+        reopened
+            .query_by_field_raw("user", "email", "second@test.com")
+            .await?
+            .len(),
+        2
+    );
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn ordered_indexes_answer_range_queries_across_updates_and_restart() -> anyhow::Result<()> {
+    let mut config = test_config("ordered_index");
+    order_field(&mut config, "user", "created_at");
+    order_field(&mut config, "user", "email");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    for (id, email) in [
+        (1, "ann@test.com"),
+        (2, "anna@test.com"),
+        (3, "bob@test.com"),
+    ] {
+        db.insert_raw("user", user_row(id, email)).await?;
+    }
+    db.insert_many_raw(
+        "user",
+        vec![user_row(4, "cy@test.com"), user_row(5, "dan@test.com")],
+    )
+    .await?;
+    let window = RangeQuery {
+        gte: Some(json!(2)),
+        lt: Some(json!("4")),
+        ..RangeQuery::default()
+    };
+    assert_eq!(range_ids(&db, "created_at", &window).await?, vec![2, 3]);
+    let between = RangeQuery {
+        between: Some((json!(4), json!(5))),
+        ..RangeQuery::default()
+    };
+    assert_eq!(range_ids(&db, "created_at", &between).await?, vec![4, 5]);
+    let prefix = RangeQuery {
+        prefix: Some("ann".to_string()),
+        ..RangeQuery::default()
+    };
+    assert_eq!(range_ids(&db, "email", &prefix).await?, vec![1, 2]);
+
+    db.patch_raw("user", 3, json!({ "created_at": 10 })).await?;
+    db.delete_raw("user", 2).await?;
+    assert!(range_ids(&db, "created_at", &window).await?.is_empty());
+    assert_eq!(
+        db.query_by_field_raw("user", "created_at", "10")
+            .await?
+            .len(),
+        1
+    );
+    let unindexed = RangeQuery {
+        prefix: Some("hash_".to_string()),
+        ..RangeQuery::default()
+    };
+    assert_eq!(
+        range_ids(&db, "hash_password", &unindexed).await?,
+        vec![1, 3, 4, 5]
+    );
+    let two_lower_bounds = RangeQuery {
+        gt: Some(json!(1)),
+        gte: Some(json!(2)),
+        ..RangeQuery::default()
+    };
+    assert!(db
+        .query_range_raw("user", "created_at", &two_lower_bounds)
+        .await
+        .is_err());
+    assert!(db
+        .query_range_raw("user", "created_at", &prefix)
+        .await
+        .is_err());
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    let recent = RangeQuery {
+        gt: Some(json!(4)),
+        ..RangeQuery::default()
+    };
+    assert_eq!(
+        range_ids(&reopened, "created_at", &recent).await?,
+        vec![3, 5]
+    );
+    assert_eq!(range_ids(&reopened, "email", &prefix).await?, vec![1]);
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn structured_queries_filter_sort_project_and_use_indexes() -> anyhow::Result<()> {
+    let mut config = test_config("structured_query");
+    config.models.insert(
+        "account".to_string(),
+        ModelSchema {
+            fields: vec![
+                model_field("id", "u64", None),
+                model_field("city", "string", Some(IndexKind::Hash)),
+                model_field("age", "u64", Some(IndexKind::Ordered)),
+                model_field("profile", "object", None),
+            ],
+            on_conflict: ConflictPolicy::default(),
+            cache_bytes: None,
+        },
+    );
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_many_raw(
+        "account",
+        vec![
+            json!({ "id": 1, "city": "Oslo", "age": 31, "profile": { "tier": "gold" } }),
+            json!({ "id": 2, "city": "Oslo", "age": 19, "profile": { "tier": "free" } }),
+            json!({ "id": 3, "city": "Rome", "age": 45, "profile": { "tier": "gold" } }),
+            json!({ "id": 4, "city": "Lima", "age": 27, "profile": {} }),
+        ],
+    )
+    .await?;
+
+    let request: QueryRequest = serde_json::from_value(json!({
+        "filter": { "and": [
+            { "field": "age", "gte": 20 },
+            { "not": { "field": "city", "eq": "Lima" } }
+        ] },
+        "fields": ["id", "profile.tier"],
+        "sort": [{ "field": "age", "order": "desc" }]
+    }))?;
+    let response = db.query_structured_raw("account", &request).await?;
+    assert_eq!(response.plan, "range(age)");
+    assert_eq!(response.total, 2);
+    assert_eq!(
+        response.results,
+        vec![
+            json!({ "id": 3, "profile": { "tier": "gold" } }),
+            json!({ "id": 1, "profile": { "tier": "gold" } }),
+        ]
+    );
+
+    let request: QueryRequest = serde_json::from_value(json!({
+        "filter": { "or": [
+            { "field": "city", "in": ["Rome", "Lima"] },
+            { "field": "age", "lt": 20 }
+        ] },
+        "sort": [{ "field": "id" }],
+        "offset": 1,
+        "limit": 1
+    }))?;
+    let response = db.query_structured_raw("account", &request).await?;
+    assert_eq!(response.plan, "union(index(city), range(age))");
+    assert_eq!(response.total, 3);
+    assert_eq!(response.results[0]["id"], 3);
+
+    let request: QueryRequest = serde_json::from_value(json!({
+        "filter": { "field": "profile.tier", "exists": false }
+    }))?;
+    let response = db.query_structured_raw("account", &request).await?;
+    assert_eq!(response.plan, "scan");
+    assert_eq!(
+        response.results,
+        vec![json!({ "id": 4, "city": "Lima", "age": 27, "profile": {} })]
+    );
+
+    let unknown_operator: QueryRequest = serde_json::from_value(json!({
+        "filter": { "field": "age", "between": [1, 2] }
+    }))?;
+    assert!(db
+        .query_structured_raw("account", &unknown_operator)
+        .await
+        .is_err());
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn cursor_pages_walk_ids_in_order_including_sparse_ids() -> anyhow::Result<()> {
+    let config = test_config("cursor_pages");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    let sparse_id = 50_000_000;
+    db.insert_raw("user", user_row(sparse_id, "sparse@test.com"))
+        .await?;
+    db.insert_many_raw(
+        "user",
+        (1..=5)
+            .rev()
+            .map(|id| user_row(id, &format!("user{}@test.com", id)))
+            .collect(),
+    )
+    .await?;
+    db.delete_raw("user", 3).await?;
+    for id in [sparse_id + 7, sparse_id + 3] {
+        db.insert_raw("user", user_row(id, &format!("user{}@test.com", id)))
+            .await?;
+    }
+    db.delete_raw("user", sparse_id + 3).await?;
+
+    let mut pages = Vec::new();
+    let mut after = None;
+    loop {
+        let page = db.query_page_raw("user", after, 2).await?;
+        pages.push(
+            page.results
+                .iter()
+                .filter_map(|row| row["id"].as_u64())
+                .collect::<Vec<_>>(),
+        );
+        match page.next_after {
+            Some(next_after) => after = Some(next_after),
+            None => break,
+        }
+    }
+    assert_eq!(
+        pages,
+        vec![vec![1, 2], vec![4, 5], vec![sparse_id, sparse_id + 7]]
+    );
+    assert!(db
+        .query_page_raw("user", Some(sparse_id + 7), 2)
+        .await?
+        .results
+        .is_empty());
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn aggregates_group_by_indexed_fields_with_and_without_scanning() -> anyhow::Result<()> {
+    let mut config = test_config("aggregate");
+    index_field(&mut config, "product", "category_id");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_many_raw(
+        "product",
+        [(1, 10, 1), (2, 30, 1), (3, 5, 2), (4, 7, 2), (5, 100, 3)]
+            .into_iter()
+            .map(|(id, price, category_id)| {
+                json!({ "id": id, "name": format!("p{}", id), "price": price, "category_id": category_id })
+            })
+            .collect(),
+    )
+    .await?;
+
+    let request: AggregateRequest = serde_json::from_value(json!({
+        "group_by": "category_id",
+        "aggregates": {
+            "n": { "op": "count" },
+            "revenue": { "op": "sum", "field": "price" },
+            "cheapest": { "op": "min", "field": "price" },
+            "priciest": { "op": "max", "field": "price" },
+            "mean": { "op": "avg", "field": "price" }
+        }
+    }))?;
+    let response = db.aggregate("product", &request).await?;
+    assert_eq!(response.plan, "scan");
+    let groups = serde_json::to_value(&response.groups)?;
+    assert_eq!(
+        groups[0],
+        json!({ "key": 1, "values": { "n": 2, "revenue": 40, "cheapest": 10, "priciest": 30, "mean": 20.0 } })
+    );
+    assert_eq!(groups[2]["values"]["revenue"], 100);
+
+    let counts: AggregateRequest = serde_json::from_value(json!({
+        "group_by": "category_id",
+        "aggregates": { "n": { "op": "count" } }
+    }))?;
+    let response = db.aggregate("product", &counts).await?;
+    assert_eq!(response.plan, "index(category_id)");
+    assert_eq!(
+        serde_json::to_value(&response.groups)?,
+        json!([
+            { "key": 1, "values": { "n": 2 } },
+            { "key": 2, "values": { "n": 2 } },
+            { "key": 3, "values": { "n": 1 } }
+        ])
+    );
+
+    let filtered: AggregateRequest = serde_json::from_value(json!({
+        "filter": { "field": "price", "lt": 20 },
+        "aggregates": { "n": { "op": "count" }, "revenue": { "op": "sum", "field": "price" } }
+    }))?;
+    let response = db.aggregate("product", &filtered).await?;
+    assert_eq!(
+        serde_json::to_value(&response.groups)?,
+        json!([{ "key": null, "values": { "n": 3, "revenue": 22 } }])
+    );
+
+    let not_numeric: AggregateRequest = serde_json::from_value(json!({
+        "aggregates": { "total": { "op": "sum", "field": "name" } }
+    }))?;
+    assert!(db.aggregate("product", &not_numeric).await.is_err());
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn conflict_policies_reject_or_merge_existing_ids() -> anyhow::Result<()> {
+    let mut config = test_config("conflict_policy");
+    if let Some(schema) = config.models.get_mut("user") {
+        schema.on_conflict = ConflictPolicy::Reject;
+    }
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_raw("user", user_row(1, "one@test.com")).await?;
+    let error = db
+        .insert_raw("user", user_row(1, "dup@test.com"))
+        .await
+        .err()
+        .ok_or_else(|| anyhow::anyhow!("expected conflict"))?;
+    assert!(error.downcast_ref::<ConflictError>().is_some());
+
+    let report = db
+        .insert_many_raw_with_policy(
+            "user",
+            vec![
+                user_row(1, "dup@test.com"),
+                user_row(2, "two@test.com"),
+                user_row(2, "dup@test.com"),
+            ],
+            None,
+        )
+        .await?;
+    assert_eq!(report.ids, vec![2]);
+    let conflicts = report
+        .conflicts
+        .iter()
+        .map(|conflict| (conflict.index, conflict.id))
+        .collect::<Vec<_>>();
+    assert_eq!(conflicts, vec![(0, 1), (2, 2)]);
+    assert_eq!(
+        db.get_raw("user", 2).await?,
+        Some(user_row(2, "two@test.com"))
+    );
+
+    db.insert_raw_with_policy(
+        "user",
+        json!({ "id": 1, "email": "merged@test.com" }),
+        Some(ConflictPolicy::UpsertMerge),
+    )
+    .await?;
+    assert_eq!(
+        db.get_raw("user", 1).await?,
+        Some(json!({
+            "id": 1,
+            "email": "merged@test.com",
+            "hash_password": "hash_1",
+            "created_at": 1
+        }))
+    );
+    let report = db
+        .insert_many_raw_with_policy(
+            "user",
+            vec![
+                json!({ "id": 2, "created_at": 20 }),
+                json!({ "id": 2, "hash_password": "rotated" }),
+            ],
+            Some(ConflictPolicy::UpsertMerge),
+        )
+        .await?;
+    assert_eq!(report.ids, vec![2, 2]);
+    assert_eq!(
+        db.get_raw("user", 2).await?,
+        Some(json!({
+            "id": 2,
+            "email": "two@test.com",
+            "hash_password": "rotated",
+            "created_at": 20
+        }))
+    );
+    assert!(db
+        .insert_raw_with_policy(
+            "user",
+            json!({ "id": 3, "email": "partial@test.com" }),
+            Some(ConflictPolicy::UpsertMerge),
+        )
+        .await
+        .is_err());
+
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn transactions_apply_across_models_or_not_at_all() -> anyhow::Result<()> {
+    let mut config = test_config("transactions");
+    if let Some(schema) = config.models.get_mut("user") {
+        schema.on_conflict = ConflictPolicy::Reject;
+    }
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_raw("user", user_row(1, "one@test.com")).await?;
+    let mut txn = db.begin();
+    txn.insert("user", user_row(2, "two@test.com"))
+        .insert("product", product_row(10, 2))
+        .patch("product", 10, json!({ "price": 25 }))
+        .delete("user", 1);
+    let report = txn.commit().await?;
+    let results = report
+        .results
+        .iter()
+        .map(|result| (result.model.as_str(), result.id))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        vec![("user", 2), ("product", 10), ("product", 10), ("user", 1)]
+    );
+
+    let mut txn = db.begin();
+    txn.insert("product", product_row(11, 2))
+        .insert("user", user_row(2, "dup@test.com"));
+    let error = txn
+        .commit()
+        .await
+        .err()
+        .ok_or_else(|| anyhow::anyhow!("expected conflict"))?;
+    assert!(error.downcast_ref::<ConflictError>().is_some());
+    let mut txn = db.begin();
+    txn.insert("product", product_row(12, 2)).delete("user", 99);
+    assert!(txn.commit().await.is_err());
+    assert_eq!(db.get_raw("product", 11).await?, None);
+    assert_eq!(db.get_raw("product", 12).await?, None);
+
+    db.insert_raw("user", user_row(3, "three@test.com")).await?;
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.get_raw("user", 1).await?, None);
+    assert_eq!(
+        reopened.get_raw("user", 2).await?,
+        Some(user_row(2, "two@test.com"))
+    );
+    assert_eq!(
+        reopened.get_raw("product", 10).await?,
+        Some(json!({ "id": 10, "name": "product_10", "price": 25, "category_id": 2 }))
+    );
+    assert_eq!(reopened.query_raw("product").await?.len(), 1);
+    assert!(reopened.get_raw("user", 3).await?.is_some());
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn uncommitted_transaction_frames_are_discarded_on_replay() -> anyhow::Result<()> {
+    let config = test_config("uncommitted_transaction");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_raw("user", user_row(1, "one@test.com")).await?;
+    let storage = db.get_storage("user")?;
+    let orphan_id = db.transaction_log()?.begin()?;
+    let encoded = storage.encode_transaction_entries(&[LogEntry {
+        timestamp: 1,
+        operation: Operation::Insert,
+        data: user_row(2, "orphan@test.com"),
+    }])?;
+    let mut frame = storage.lock_for_transaction()?;
+    frame.write(orphan_id, encoded)?;
+    drop(frame);
+    drop(storage);
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.get_raw("user", 2).await?, None);
+    let mut txn = reopened.begin();
+    txn.insert("user", user_row(3, "three@test.com"));
+    let report = txn.commit().await?;
+    assert!(report.transaction_id > orphan_id);
+    reopened.shutdown().await?;
+    drop(reopened);
+
+    let reopened = NyroDB::new(config.clone());
+    assert!(reopened.get_raw("user", 1).await?.is_some());
+    assert_eq!(reopened.get_raw("user", 2).await?, None);
+    assert!(reopened.get_raw("user", 3).await?.is_some());
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn realtime_events_carry_per_model_sequence_and_previous_rows() -> anyhow::Result<()> {
+    let config = test_config("realtime_events");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_raw("user", user_row(1, "one@test.com")).await?;
+    let mut events = db.real_time_tx.subscribe();
+    db.insert_raw("product", product_row(10, 2)).await?;
+    db.patch_raw("user", 1, json!({ "email": "new@test.com" }))
+        .await?;
+    db.delete_raw("user", 1).await?;
+
+    let product = events.recv().await?;
+    assert_eq!((product.model.as_str(), product.seq), ("product", 1));
+    let update = events.recv().await?;
+    assert_eq!(
+        (update.model.as_str(), update.id, update.seq),
+        ("user", 1, 2)
+    );
+    assert!(matches!(update.operation, Operation::Update));
+    assert_eq!(update.previous, Some(user_row(1, "one@test.com")));
+    assert_eq!(update.data["email"], "new@test.com");
+    let delete = events.recv().await?;
+    assert!(matches!(delete.operation, Operation::Delete));
+    assert_eq!((delete.seq, delete.previous.clone()), (3, None));
+
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn realtime_clients_without_a_protocol_keep_text_frames() -> anyhow::Result<()> {
+    let config = test_config("realtime_protocols");
+    cleanup_path(&config.storage.data_dir)?;
+    let db = Arc::new(NyroDB::new(config.clone()));
+    let routes = crate::api::routes::create_routes(db.clone());
+
+    let mut legacy = warp::test::ws()
+        .path("/ws")
+        .handshake(routes.clone())
+        .await?;
+    legacy.send_text("not a subscription").await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    db.insert_raw("user", user_row(1, "one@test.com")).await?;
+    let frame = legacy.recv().await?;
+    assert!(frame
+        .to_str()
+        .unwrap_or_default()
+        .starts_with("INSERT:user:{"));
+
+    let mut json = warp::test::ws()
+        .path("/ws?protocol=2")
+        .handshake(routes)
+        .await?;
+    let welcome: serde_json::Value =
+        serde_json::from_str(json.recv().await?.to_str().unwrap_or_default())?;
+    assert_eq!(welcome, json!({ "type": "welcome", "protocol": 2 }));
+    db.insert_raw("user", user_row(2, "two@test.com")).await?;
+    let event: serde_json::Value =
+        serde_json::from_str(json.recv().await?.to_str().unwrap_or_default())?;
+    assert_eq!(
+        (event["type"].clone(), event["id"].clone()),
+        (json!("event"), json!(2))
+    );
+
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn changes_replay_by_sequence_or_timestamp_across_restart_and_compaction(
+) -> anyhow::Result<()> {
+    let config = test_config("change_replay");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_many_raw(
+        "user",
+        (1..=3).map(|id| user_row(id, "a@test.com")).collect(),
+    )
+    .await?;
+    db.patch_raw("user", 2, json!({ "email": "b@test.com" }))
+        .await?;
+    db.delete_raw("user", 3).await?;
+    let mut transaction = db.begin();
+    transaction.insert("user", user_row(4, "c@test.com"));
+    transaction.commit().await?;
+
+    let seqs = |batch: &ChangeBatch| batch.events.iter().map(|e| e.seq).collect::<Vec<_>>();
+    let batch = db
+        .read_changes("user", ChangePosition::AfterSeq(3), 10)
+        .await?;
+    assert_eq!(seqs(&batch), vec![4, 5, 6]);
+    assert!(matches!(batch.events[0].operation, Operation::Update));
+    assert!(matches!(batch.events[1].operation, Operation::Delete));
+    assert_eq!(batch.events[2].id, 4);
+    assert!(batch.gap.is_none() && !batch.more);
+    let page = db
+        .read_changes("user", ChangePosition::AfterSeq(3), 2)
+        .await?;
+    assert_eq!(
+        (seqs(&page), page.next_after, page.more),
+        (vec![4, 5], 5, true)
+    );
+    let all = db
+        .read_changes("user", ChangePosition::Since(0), 10)
+        .await?;
+    assert_eq!(seqs(&all), vec![1, 2, 3, 4, 5, 6]);
+    let future = db
+        .read_changes("user", ChangePosition::Since(u64::MAX), 10)
+        .await?;
+    assert_eq!((future.events.len(), future.next_after), (0, 6));
+    db.insert_many_raw(
+        "product",
+        (1..=5000).map(|id| product_row(id, id % 7)).collect(),
+    )
+    .await?;
+    db.shutdown().await?;
+
+    let db = NyroDB::new(config.clone());
+    assert_eq!(db.published_seq("user")?, 6);
+    let products = db
+        .read_changes("product", ChangePosition::AfterSeq(4500), 3)
+        .await?;
+    assert_eq!(seqs(&products), vec![4501, 4502, 4503]);
+    assert_eq!(products.events[0].id, 4501);
+    let batch = db
+        .read_changes("user", ChangePosition::AfterSeq(4), 10)
+        .await?;
+    assert_eq!(seqs(&batch), vec![5, 6]);
+
+    db.compact("user").await?;
+    db.insert_raw("user", user_row(5, "d@test.com")).await?;
+    let batch = db
+        .read_changes("user", ChangePosition::AfterSeq(4), 10)
+        .await?;
+    assert_eq!(batch.gap.as_ref().map(|gap| gap.resumed_after), Some(6));
+    assert_eq!(seqs(&batch), vec![7]);
+    let since = db
+        .read_changes("user", ChangePosition::Since(0), 10)
+        .await?;
+    assert!(since.gap.is_some());
+    let ahead = db
+        .read_changes("user", ChangePosition::AfterSeq(99), 10)
+        .await?;
+    assert_eq!(ahead.gap.map(|gap| gap.resumed_after), Some(7));
+    db.shutdown().await?;
+
+    let db = NyroDB::new(config.clone());
+    assert_eq!(db.published_seq("user")?, 7);
+    let batch = db
+        .read_changes("user", ChangePosition::AfterSeq(6), 10)
+        .await?;
+    assert_eq!((seqs(&batch), batch.gap.is_none()), (vec![7], true));
+    assert_eq!(batch.events[0].data, user_row(5, "d@test.com"));
+
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn waiting_for_changes_wakes_on_publish_or_times_out() -> anyhow::Result<()> {
+    let config = test_config("change_wait");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = Arc::new(NyroDB::new(config.clone()));
+    let idle = Duration::from_millis(20);
+    assert!(!db.wait_for_changes("user", 0, idle).await?);
+
+    let writer = db.clone();
+    let insert = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        writer.insert_raw("user", user_row(1, "a@test.com")).await
+    });
+    assert!(
+        db.wait_for_changes("user", 0, Duration::from_secs(5))
+            .await?
+    );
+    insert.await??;
+    let batch = db
+        .read_changes("user", ChangePosition::AfterSeq(0), 10)
+        .await?;
+    assert_eq!((batch.events.len(), batch.next_after), (1, 1));
+    assert!(!db.wait_for_changes("user", 1, idle).await?);
+
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn webhooks_retry_sign_and_resume_from_their_persisted_cursor() -> anyhow::Result<()> {
+    let received = Arc::new(parking_lot::Mutex::new(Vec::new()));
+    let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let stand_in = {
+        let received = received.clone();
+        let attempts = attempts.clone();
+        warp::post()
+            .and(warp::header::optional::<String>("x-nyrodb-signature"))
+            .and(warp::header::<u64>("x-nyrodb-timestamp"))
+            .and(warp::body::bytes())
+            .map(
+                move |signature: Option<String>, timestamp: u64, body: warp::hyper::body::Bytes| {
+                    if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                        return warp::http::StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                    received.lock().push((signature, timestamp, body.to_vec()));
+                    warp::http::StatusCode::OK
+                },
+            )
+    };
+    let (address, server) = warp::serve(stand_in).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let mut config = test_config("webhooks");
+    cleanup_path(&config.storage.data_dir)?;
+    config.webhooks.insert(
+        "audit".to_string(),
+        WebhookConfig {
+            model: "user".to_string(),
+            url: format!("http://{}/hook", address),
+            secret: "s3cret".to_string(),
+            operations: vec!["insert".to_string()],
+        },
+    );
+    let mut tls_hook = config.webhooks["audit"].clone();
+    tls_hook.url = "https://hooks.example.com/nyrodb".to_string();
+    tls_hook.validate("tls", &config.models)?;
+    tls_hook.url = "ftp://hooks.example.com/nyrodb".to_string();
+    assert!(tls_hook.validate("tls", &config.models).is_err());
+    let delivered = |count: usize| {
+        let received = received.clone();
+        async move {
+            for _ in 0..250 {
+                if received.lock().len() >= count {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Err(anyhow::anyhow!("webhook delivery timed out"))
+        }
+    };
+    let delivered_ids = |index: usize| -> anyhow::Result<Vec<u64>> {
+        let (_, _, body) = &received.lock()[index];
+        let payload: serde_json::Value = serde_json::from_slice(body)?;
+        Ok(payload["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|event| event["id"].as_u64())
+            .collect())
+    };
+
+    let db = Arc::new(NyroDB::new(config.clone()));
+    db.insert_raw("user", user_row(1, "before@test.com"))
+        .await?;
+    db.start_webhooks()?;
+    db.insert_raw("user", user_row(2, "two@test.com")).await?;
+    db.patch_raw("user", 2, json!({ "email": "patched@test.com" }))
+        .await?;
+    delivered(1).await?;
+    assert!(attempts.load(std::sync::atomic::Ordering::SeqCst) >= 2);
+    assert_eq!(delivered_ids(0)?, vec![2]);
+    assert_eq!(
+        sign(b"Jefe", 1_700_000_000_000, b"what do ya want for nothing?"),
+        "sha256=67c32eec367f08da9b08bed2fa1ad06d820a1b6cf0f7dc32c293102c301c776c"
+    );
+    let (signature, timestamp, body) = received.lock()[0].clone();
+    assert!(timestamp.abs_diff(super::helpers::current_unix_millis()?) < 60_000);
+    assert_eq!(signature, Some(sign(b"s3cret", timestamp, &body)));
+    db.shutdown().await?;
+
+    let db = Arc::new(NyroDB::new(config.clone()));
+    db.insert_raw("user", user_row(3, "three@test.com")).await?;
+    db.start_webhooks()?;
+    delivered(2).await?;
+    assert_eq!(delivered_ids(1)?, vec![3]);
+    assert_eq!(db.webhooks()[0].failures, 0);
+    db.shutdown().await?;
+    drop(db);
+
+    // A delivery backing off from a dead endpoint does not keep the
+    // database alive.
+    if let Some(hook) = config.webhooks.get_mut("audit") {
+        hook.url = "http://127.0.0.1:1/hook".to_string();
+    }
+    let db = Arc::new(NyroDB::new(config.clone()));
+    db.start_webhooks()?;
+    db.insert_raw("user", user_row(4, "four@test.com")).await?;
+    for _ in 0..250 {
+        if db.webhooks()[0].failures > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(db.webhooks()[0].failures > 0);
+    let weak = Arc::downgrade(&db);
+    drop(db);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(weak.upgrade().is_none());
+
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn backups_copy_consistent_logs_with_a_checksummed_manifest() -> anyhow::Result<()> {
+    let mut config = test_config("backup");
+    cleanup_path(&config.storage.data_dir)?;
+    let root = parent_temp_dir().join("backup-root");
+    cleanup_path(&root.to_string_lossy())?;
+    config.backup.root = root.to_string_lossy().into_owned();
+    let backup_dir = parent_temp_dir().join("backup-target");
+    let archive = parent_temp_dir().join("backup-target.tar");
+    cleanup_path(&backup_dir.to_string_lossy())?;
+    cleanup_path(&archive.to_string_lossy())?;
+
+    let db = NyroDB::new(config.clone());
+    for id in 1..=3 {
+        db.insert_raw("user", user_row(id, "backup@test.com"))
+            .await?;
+    }
+    let mut txn = db.begin();
+    txn.insert("user", user_row(4, "tx@test.com"))
+        .insert("product", product_row(10, 2));
+    txn.commit().await?;
+
+    let manifest = db.backup(&backup_dir).await?;
+    db.insert_raw("user", user_row(5, "later@test.com")).await?;
+    assert_eq!(manifest.format, "nyrodb-backup");
+    assert_eq!(manifest.models["user"].last_seq, 4);
+    assert_eq!(manifest.models["product"].last_seq, 1);
+    assert_eq!(
+        manifest.models["user"].schema.fields.len(),
+        config.models["user"].fields.len()
+    );
+    assert!(manifest.files.contains_key("transactions.txlog"));
+    for (path, file) in &manifest.files {
+        let bytes = std::fs::read(backup_dir.join(path))?;
+        assert_eq!(bytes.len() as u64, file.bytes);
+        assert_eq!(hex(&sha2::Sha256::digest(&bytes)), file.sha256);
+    }
+    let written: crate::database::BackupManifest =
+        serde_json::from_slice(&std::fs::read(backup_dir.join(MANIFEST_FILE))?)?;
+    assert_eq!(written.files, manifest.files);
+    assert!(db.backup(&backup_dir).await.is_err());
+
+    let archived = db.backup(&archive).await?;
+    assert_eq!(archived.models["user"].last_seq, 5);
+    let tar = std::fs::read(&archive)?;
+    assert_eq!(tar.len() % 512, 0);
+    assert_eq!(&tar[..11], b"product.log");
+    assert!(db.backup(&archive).await.is_err());
+
+    // Backups requested over the API stay below backup.root.
+    for target in [
+        "",
+        "/tmp/nyrodb-backup",
+        "../escape",
+        "nightly/../../escape",
+    ] {
+        assert!(db.backup_target(target).is_err(), "{}", target);
+    }
+    let api_target = db.backup_target("nightly/first.tar")?;
+    assert_eq!(api_target, root.join("nightly").join("first.tar"));
+    db.backup(&api_target).await?;
+    assert!(db.backup_target("nightly/first.tar").is_err());
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(parent_temp_dir(), root.join("outside"))?;
+        assert!(db.backup_target("outside/escape.tar").is_err());
+    }
+    db.shutdown().await?;
+
+    // A directory backup opens as a data directory of its own.
+    let mut restored_config = config.clone();
+    restored_config.storage.data_dir = backup_dir.to_string_lossy().into_owned();
+    let restored = NyroDB::new(restored_config);
+    assert_eq!(restored.query_raw("user").await?.len(), 4);
+    assert_eq!(restored.get_raw("user", 5).await?, None);
+    assert!(restored.get_raw("product", 10).await?.is_some());
+    restored.shutdown().await?;
+
+    cleanup_data_dir(&config.storage.data_dir)?;
+    cleanup_path(&backup_dir.to_string_lossy())?;
+    cleanup_path(&archive.to_string_lossy())?;
+    cleanup_path(&root.to_string_lossy())?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn restores_verify_checksums_and_cut_logs_at_a_point_in_time() -> anyhow::Result<()> {
+    let config = test_config("restore_source");
+    cleanup_path(&config.storage.data_dir)?;
+    let backup_dir = parent_temp_dir().join("restore-backup");
+    let archive = parent_temp_dir().join("restore-backup.tar");
+    cleanup_path(&backup_dir.to_string_lossy())?;
+    cleanup_path(&archive.to_string_lossy())?;
+
+    let db = NyroDB::new(config.clone());
+    for id in 1..=3 {
+        db.insert_raw("user", user_row(id, "before@test.com"))
+            .await?;
+    }
+    let mut txn = db.begin();
+    txn.insert("user", user_row(4, "tx@test.com"))
+        .insert("product", product_row(10, 2));
+    txn.commit().await?;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let cutoff = super::helpers::current_unix_millis()?;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    db.insert_raw("user", user_row(5, "after@test.com")).await?;
+    db.patch_raw("user", 1, json!({ "email": "garbage@test.com" }))
+        .await?;
+    db.delete_raw("user", 2).await?;
+    let mut txn = db.begin();
+    txn.insert("user", user_row(6, "tx@test.com"))
+        .insert("product", product_row(11, 2));
+    txn.commit().await?;
+    db.backup(&backup_dir).await?;
+    db.backup(&archive).await?;
+    db.shutdown().await?;
+
+    let full = test_config("restore_full");
+    cleanup_path(&full.storage.data_dir)?;
+    let report = restore_backup(&backup_dir, &full, None)?;
+    assert!(report.dropped_entries.is_empty());
+    let restored = NyroDB::new(full.clone());
+    assert_eq!(restored.query_raw("user").await?.len(), 5);
+    assert_eq!(restored.query_raw("product").await?.len(), 2);
+    restored.shutdown().await?;
+    assert!(restore_backup(&backup_dir, &full, None).is_err());
+
+    let point_in_time = test_config("restore_until");
+    cleanup_path(&point_in_time.storage.data_dir)?;
+    let report = restore_backup(&archive, &point_in_time, Some(cutoff))?;
+    assert_eq!(report.dropped_entries["user"], 4);
+    assert_eq!(report.dropped_entries["product"], 1);
+    let restored = NyroDB::new(point_in_time.clone());
+    assert_eq!(restored.query_raw("user").await?.len(), 4);
+    assert_eq!(
+        restored.get_raw("user", 1).await?,
+        Some(user_row(1, "before@test.com"))
+    );
+    assert!(restored.get_raw("user", 2).await?.is_some());
+    assert_eq!(restored.get_raw("user", 6).await?, None);
+    assert_eq!(restored.query_raw("product").await?.len(), 1);
+    restored
+        .insert_raw("user", user_row(7, "new@test.com"))
+        .await?;
+    restored.shutdown().await?;
+
+    let log = backup_dir.join("user.log");
+    let mut bytes = std::fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&log, &bytes)?;
+    let corrupt = test_config("restore_corrupt");
+    cleanup_path(&corrupt.storage.data_dir)?;
+    let error = restore_backup(&backup_dir, &corrupt, None)
+        .err()
+        .ok_or_else(|| anyhow::anyhow!("corrupt backup was restored"))?;
+    assert!(error.to_string().contains("user.log"));
+    assert!(!std::path::Path::new(&corrupt.storage.data_dir).exists());
+
+    for data_dir in [
+        &config.storage.data_dir,
+        &full.storage.data_dir,
+        &point_in_time.storage.data_dir,
+    ] {
+        cleanup_data_dir(data_dir)?;
+    }
+    cleanup_path(&backup_dir.to_string_lossy())?;
+    cleanup_path(&archive.to_string_lossy())?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn replicas_follow_the_primary_log_and_refuse_writes() -> anyhow::Result<()> {
+    let mut primary_config = test_config("replication_primary");
+    cleanup_path(&primary_config.storage.data_dir)?;
+    index_field(&mut primary_config, "user", "email");
+    let primary = Arc::new(NyroDB::new(primary_config.clone()));
+    primary
+        .insert_raw("user", user_row(1, "one@test.com"))
+        .await?;
+    primary
+        .insert_raw("user", user_row(2, "two@test.com"))
+        .await?;
+    primary.insert_raw("product", product_row(10, 3)).await?;
+    let (address, server) = warp::serve(crate::api::routes::create_routes(primary.clone()))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let mut replica_config = test_config("replication_replica");
+    cleanup_path(&replica_config.storage.data_dir)?;
+    index_field(&mut replica_config, "user", "email");
+    replica_config.replication.role = ReplicationRole::Replica;
+    replica_config.replication.primary_url = format!("http://{}", address);
+    replica_config.replication.poll_interval = 10;
+    replica_config.replication.batch_bytes = 64;
+    replica_config.validate()?;
+    let replica = Arc::new(NyroDB::new(replica_config.clone()));
+    replica.start_replication()?;
+    let caught_up = || {
+        let primary = primary.clone();
+        let replica = replica.clone();
+        async move {
+            for _ in 0..500 {
+                let status = replica.replication_status();
+                let mut matched = true;
+                for model_name in ["user", "product"] {
+                    let end = primary.get_storage(model_name)?.log_bytes();
+                    matched &= status
+                        .models
+                        .get(model_name)
+                        .is_some_and(|model| model.applied_offset == end && model.lag_bytes == 0);
+                }
+                if matched {
+                    return Ok(status);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Err(anyhow::anyhow!("replica did not catch up"))
+        }
+    };
+
+    caught_up().await?;
+    assert_eq!(
+        replica.get_raw("user", 2).await?,
+        primary.get_raw("user", 2).await?
+    );
+    assert_eq!(replica.query_raw("product").await?.len(), 1);
+
+    primary
+        .patch_raw("user", 1, json!({ "email": "patched@test.com" }))
+        .await?;
+    primary.delete_raw("user", 2).await?;
+    let mut txn = primary.begin();
+    txn.insert("user", user_row(3, "three@test.com"))
+        .insert("product", product_row(11, 3));
+    txn.commit().await?;
+    caught_up().await?;
+    assert_eq!(replica.get_raw("user", 2).await?, None);
+    assert_eq!(
+        replica.get_raw("user", 3).await?,
+        Some(user_row(3, "three@test.com"))
+    );
+    let patched = replica
+        .query_by_field_raw("user", "email", "patched@test.com")
+        .await?;
+    assert_eq!(patched.len(), 1);
+    assert!(replica
+        .query_by_field_raw("user", "email", "one@test.com")
+        .await?
+        .is_empty());
+    assert_eq!(replica.query_raw("product").await?.len(), 2);
+    assert_eq!(
+        replica.published_seq("user")?,
+        primary.published_seq("user")?
+    );
+
+    let error = replica
+        .insert_raw("user", user_row(4, "four@test.com"))
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<ReadOnlyError>().is_some());
+    let mut txn = replica.begin();
+    txn.delete("user", 1);
+    assert!(txn.commit().await.is_err());
+    assert!(replica.compact("user").await.is_err());
+
+    primary.compact("user").await?;
+    primary
+        .insert_raw("user", user_row(5, "five@test.com"))
+        .await?;
+    let status = caught_up().await?;
+    assert_eq!(status.models["user"].resyncs, 1);
+    assert_eq!(
+        replica.get_storage("user")?.sequence_marker(),
+        primary.get_storage("user")?.sequence_marker()
+    );
+    let mut ids: Vec<u64> = replica
+        .query_raw("user")
+        .await?
+        .iter()
+        .filter_map(|row| row["id"].as_u64())
+        .collect();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 3, 5]);
+    assert_eq!(replica.get_metrics().replication_lag_bytes, Some(0));
+
+    replica.shutdown().await?;
+    drop(replica);
+    let reopened = NyroDB::new(replica_config.clone());
+    assert_eq!(
+        reopened.get_raw("user", 5).await?,
+        Some(user_row(5, "five@test.com"))
+    );
+    assert_eq!(
+        reopened.get_raw("product", 11).await?,
+        Some(product_row(11, 3))
+    );
+
+    primary.shutdown().await?;
+    cleanup_data_dir(&primary_config.storage.data_dir)?;
+    cleanup_data_dir(&replica_config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn promotion_fences_the_old_primary_with_a_new_epoch() -> anyhow::Result<()> {
+    let old_config = test_config("failover_old_primary");
+    cleanup_path(&old_config.storage.data_dir)?;
+    let old = Arc::new(NyroDB::new(old_config.clone()));
+    old.insert_raw("user", user_row(1, "one@test.com")).await?;
+    let (old_address, server) = warp::serve(crate::api::routes::create_routes(old.clone()))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let new_port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let mut new_config = test_config("failover_new_primary");
+    cleanup_path(&new_config.storage.data_dir)?;
+    new_config.replication.role = ReplicationRole::Replica;
+    new_config.replication.primary_url = format!("http://{}", old_address);
+    new_config.replication.poll_interval = 10;
+    new_config.replication.advertise_url = format!("http://127.0.0.1:{}", new_port);
+    let promoted = Arc::new(NyroDB::new(new_config.clone()));
+    let (_, server) = warp::serve(crate::api::routes::create_routes(promoted.clone()))
+        .bind_ephemeral(([127, 0, 0, 1], new_port));
+    tokio::spawn(server);
+    promoted.start_replication()?;
+    let replicated = |db: Arc<NyroDB>, id: u64| async move {
+        for _ in 0..500 {
+            if db.get_raw("user", id).await?.is_some() {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Err(anyhow::anyhow!("row {} was not replicated", id))
+    };
+    replicated(promoted.clone(), 1).await?;
+
+    let status = promoted.promote().await?;
+    assert_eq!((status.role, status.epoch), (ReplicationRole::Primary, 1));
+    assert!(promoted.promote().await.is_err());
+    promoted
+        .insert_raw("user", user_row(2, "two@test.com"))
+        .await?;
+
+    replicated(old.clone(), 2).await?;
+    assert!(old.is_replica());
+    assert_eq!(old.epoch(), 1);
+    assert_eq!(
+        old.primary_url(),
+        Some(new_config.replication.advertise_url.clone())
+    );
+    let error = old
+        .insert_raw("user", user_row(3, "three@test.com"))
+        .await
+        .unwrap_err();
+    assert!(error.downcast_ref::<ReadOnlyError>().is_some());
+    let response = warp::hyper::Client::new()
+        .request(
+            warp::hyper::Request::post(format!("http://{}/insert/user", old_address))
+                .header("content-type", "application/json")
+                .body(user_row(3, "three@test.com").to_string().into())?,
+        )
+        .await?;
+    assert_eq!(
+        response.status(),
+        warp::http::StatusCode::MISDIRECTED_REQUEST
+    );
+    assert_eq!(
+        response.headers()["x-nyrodb-primary"],
+        new_config.replication.advertise_url.as_str()
+    );
+
+    let stale = promoted.fence(FenceRequest {
+        epoch: 0,
+        primary_url: format!("http://{}", old_address),
+    });
+    assert!(stale
+        .unwrap_err()
+        .downcast_ref::<StaleEpochError>()
+        .is_some());
+    old.shutdown().await?;
+    drop(old);
+    let reopened = NyroDB::new(old_config.clone());
+    assert!(reopened.is_replica());
+    assert_eq!(reopened.epoch(), 1);
+
+    let mut orphan_config = test_config("failover_orphan");
+    cleanup_path(&orphan_config.storage.data_dir)?;
+    orphan_config.replication.role = ReplicationRole::Replica;
+    orphan_config.replication.primary_url = "http://127.0.0.1:1".to_string();
+    orphan_config.replication.poll_interval = 10;
+    orphan_config.replication.failover_timeout = 50;
+    assert!(orphan_config.validate().is_err());
+    orphan_config.replication.sole_replica = true;
+    orphan_config.validate()?;
+    let orphan = Arc::new(NyroDB::new(orphan_config.clone()));
+    orphan.start_replication()?;
+    for _ in 0..500 {
+        if !orphan.is_replica() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(orphan.epoch(), 1);
+    orphan
+        .insert_raw("user", user_row(4, "four@test.com"))
+        .await?;
+
+    promoted.shutdown().await?;
+    orphan.shutdown().await?;
+    cleanup_data_dir(&old_config.storage.data_dir)?;
+    cleanup_data_dir(&new_config.storage.data_dir)?;
+    cleanup_data_dir(&orphan_config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn primaries_of_one_epoch_are_ordered_by_url() -> anyhow::Result<()> {
+    let mut low_config = test_config("failover_low_url");
+    cleanup_path(&low_config.storage.data_dir)?;
+    low_config.replication.advertise_url = "http://127.0.0.1:1".to_string();
+    let low = Arc::new(NyroDB::new(low_config.clone()));
+    let mut high_config = test_config("failover_high_url");
+    cleanup_path(&high_config.storage.data_dir)?;
+    high_config.replication.advertise_url = "http://127.0.0.1:2".to_string();
+    let high = Arc::new(NyroDB::new(high_config.clone()));
+    high.insert_raw("user", user_row(1, "one@test.com")).await?;
+
+    let refused = high.fence(FenceRequest {
+        epoch: 0,
+        primary_url: low_config.replication.advertise_url.clone(),
+    });
+    assert!(refused
+        .unwrap_err()
+        .downcast_ref::<StaleEpochError>()
+        .is_some());
+    assert!(!high.is_replica());
+
+    low.fence(FenceRequest {
+        epoch: 0,
+        primary_url: high_config.replication.advertise_url.clone(),
+    })?;
+    assert!(low.is_replica());
+    assert_eq!(low.epoch(), 0);
+    assert_eq!(
+        low.primary_url(),
+        Some(high_config.replication.advertise_url.clone())
+    );
+    assert!(low
+        .fence(FenceRequest {
+            epoch: 0,
+            primary_url: low_config.replication.advertise_url.clone(),
+        })
+        .is_err());
+    assert!(low.take_over(1).is_err());
+
+    // A chunk served under an older epoch is not appended.
+    let chunk = high.read_log_chunk("user", 0, 1 << 20).await?;
+    assert_eq!(chunk.epoch, 0);
+    low.adopt_epoch(1)?;
+    let storage = low.get_storage("user")?;
+    let entries = storage.decode_log_chunk(chunk.offset, &chunk.bytes)?;
+    let error = storage
+        .apply_log_chunk(&chunk, low.epoch(), entries)
+        .unwrap_err();
+    assert!(error.to_string().contains("from epoch 0"));
+    assert!(low.get_raw("user", 1).await?.is_none());
+
+    low.shutdown().await?;
+    high.shutdown().await?;
+    cleanup_data_dir(&low_config.storage.data_dir)?;
+    cleanup_data_dir(&high_config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn replica_reads_wait_for_the_position_of_a_write() -> anyhow::Result<()> {
+    let primary_config = test_config("position_primary");
+    cleanup_path(&primary_config.storage.data_dir)?;
+    let primary = Arc::new(NyroDB::new(primary_config.clone()));
+    let (primary_address, server) = warp::serve(crate::api::routes::create_routes(primary.clone()))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    let mut replica_config = test_config("position_replica");
+    cleanup_path(&replica_config.storage.data_dir)?;
+    replica_config.replication.role = ReplicationRole::Replica;
+    replica_config.replication.primary_url = format!("http://{}", primary_address);
+    replica_config.replication.poll_interval = 200;
+    let replica = Arc::new(NyroDB::new(replica_config.clone()));
+    let (replica_address, server) = warp::serve(crate::api::routes::create_routes(replica.clone()))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    replica.start_replication()?;
+
+    let client = warp::hyper::Client::new();
+    let response = client
+        .request(
+            warp::hyper::Request::post(format!("http://{}/insert/user", primary_address))
+                .header("content-type", "application/json")
+                .body(user_row(1, "one@test.com").to_string().into())?,
+        )
+        .await?;
+    assert_eq!(response.status(), warp::http::StatusCode::CREATED);
+    let position = response.headers()[POSITION_HEADER].to_str()?.to_string();
+    assert_eq!(position, "user:1");
+
+    let response = client
+        .get(
+            format!(
+                "http://{}/get/user/1?min_position={}&wait=5000",
+                replica_address, position
+            )
+            .parse()?,
+        )
+        .await?;
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+    let body = warp::hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&body)?["email"],
+        "one@test.com"
+    );
+
+    let mut transaction = primary.begin();
+    transaction
+        .insert("user", user_row(2, "two@test.com"))
+        .insert("product", product_row(10, 3));
+    let report = transaction.commit().await?;
+    let position = primary.log_position(report.results.iter().map(|r| r.model.as_str()))?;
+    assert_eq!(position.to_string(), "product:1,user:2");
+    assert_eq!(position.to_string().parse::<LogPosition>()?, position);
+    replica
+        .wait_for_position(&position, Duration::from_secs(5))
+        .await?;
+    assert!(replica.get_raw("user", 2).await?.is_some());
+    assert!(replica.get_raw("product", 10).await?.is_some());
+
+    let ahead: LogPosition = "user:5".parse()?;
+    let error = replica
+        .wait_for_position(&ahead, Duration::from_millis(50))
+        .await
+        .unwrap_err();
+    let timeout = error.downcast_ref::<PositionTimeoutError>().unwrap();
+    assert_eq!((timeout.seq, timeout.applied), (5, 2));
+    let response = client
+        .get(
+            format!(
+                "http://{}/query/user?min_position=user:5&wait=10",
+                replica_address
+            )
+            .parse()?,
+        )
+        .await?;
+    assert_eq!(response.status(), warp::http::StatusCode::GATEWAY_TIMEOUT);
+    assert!("user".parse::<LogPosition>().is_err());
+    assert!("user:one".parse::<LogPosition>().is_err());
+    assert!(replica
+        .wait_for_position(&"missing:1".parse()?, Duration::ZERO)
+        .await
+        .is_err());
+
+    primary.shutdown().await?;
+    replica.shutdown().await?;
+    cleanup_data_dir(&primary_config.storage.data_dir)?;
+    cleanup_data_dir(&replica_config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn schema_migrations_upgrade_logged_rows_on_read() -> anyhow::Result<()> {
+    let mut config = test_config("schema_migrations");
+    index_field(&mut config, "user", "email");
+    cleanup_path(&config.storage.data_dir)?;
+    let db = Arc::new(NyroDB::new(config.clone()));
+    for id in 1..=3 {
+        db.insert_raw("user", user_row(id, "old@test.com")).await?;
+    }
+    let (address, server) = warp::serve(crate::api::routes::create_routes(db.clone()))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let mut replica_config = test_config("schema_migrations_replica");
+    cleanup_path(&replica_config.storage.data_dir)?;
+    replica_config.replication.role = ReplicationRole::Replica;
+    replica_config.replication.primary_url = format!("http://{}", address);
+    replica_config.replication.poll_interval = 10;
+    let replica = Arc::new(NyroDB::new(replica_config.clone()));
+    replica.start_replication()?;
+
+    let changes: Vec<SchemaChange> = serde_json::from_value(json!([
+        { "op": "add_field", "name": "plan", "type": "string", "required": true, "default": "free" },
+        { "op": "drop_field", "name": "hash_password" },
+        { "op": "retype_field", "name": "created_at", "type": "f64" }
+    ]))?;
+    for change in changes {
+        db.migrate_schema("user", change).await?;
+    }
+    for invalid in [
+        json!({ "op": "add_field", "name": "plan", "type": "string" }),
+        json!({ "op": "add_field", "name": "tier", "type": "u64", "required": true }),
+        json!({ "op": "add_field", "name": "tier", "type": "u64", "default": "gold" }),
+        json!({ "op": "drop_field", "name": "id" }),
+        json!({ "op": "drop_field", "name": "missing" }),
+        json!({ "op": "retype_field", "name": "created_at", "type": "u64" }),
+        json!({ "op": "retype_field", "name": "email", "type": "u64" }),
+    ] {
+        let change: SchemaChange = serde_json::from_value(invalid)?;
+        assert!(db.migrate_schema("user", change).await.is_err());
+    }
+    assert!(db
+        .insert_raw("user", user_row(4, "new@test.com"))
+        .await
+        .is_err());
+    let new_row = json!({ "id": 4, "email": "new@test.com", "created_at": 4.5, "plan": "pro" });
+    db.insert_raw("user", new_row.clone()).await?;
+    let old_row = json!({ "id": 1, "email": "old@test.com", "created_at": 1.0, "plan": "free" });
+    assert_eq!(db.get_raw("user", 1).await?, Some(old_row.clone()));
+    assert_eq!(
+        db.query_by_field_raw("user", "email", "old@test.com")
+            .await?
+            .len(),
+        3
+    );
+
+    let mut replicated = None;
+    for _ in 0..500 {
+        replicated = replica.get_raw("user", 4).await?;
+        if replicated.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(replicated, Some(new_row.clone()));
+    assert_eq!(replica.get_raw("user", 1).await?, Some(old_row.clone()));
+    assert_eq!(replica.schema_versions("user")?.len(), 4);
+    replica.stop_replication();
+
+    db.compact("user").await?;
+    db.shutdown().await?;
+    let reopened = NyroDB::new(config.clone());
+    let versions = reopened.schema_versions("user")?;
+    assert_eq!(versions.len(), 4);
+    assert!(versions[3].fields.iter().any(|field| field.name == "plan"));
+    assert_eq!(reopened.get_raw("user", 1).await?, Some(old_row));
+    assert_eq!(reopened.get_raw("user", 4).await?, Some(new_row));
+    assert!(reopened
+        .insert_raw(
+            "user",
+            json!({ "id": 5, "email": "x@test.com", "created_at": 5 })
+        )
+        .await
+        .is_err());
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    cleanup_data_dir(&replica_config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn models_created_and_dropped_at_runtime_survive_restarts() -> anyhow::Result<()> {
+    let config = test_config("model_catalog");
+    cleanup_path(&config.storage.data_dir)?;
+    let db = Arc::new(NyroDB::new(config.clone()));
+    let (address, server) = warp::serve(crate::api::routes::create_routes(db.clone()))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let mut replica_config = test_config("model_catalog_replica");
+    cleanup_path(&replica_config.storage.data_dir)?;
+    replica_config.replication.role = ReplicationRole::Replica;
+    replica_config.replication.primary_url = format!("http://{}", address);
+    replica_config.replication.poll_interval = 10;
+    let replica = Arc::new(NyroDB::new(replica_config.clone()));
+    replica.start_replication()?;
+
+    let order = ModelSchema {
+        fields: vec![
+            model_field("id", "u64", None),
+            model_field("total", "f64", Some(IndexKind::Ordered)),
+            model_field("status", "string", Some(IndexKind::Hash)),
+        ],
+        on_conflict: ConflictPolicy::Reject,
+        cache_bytes: None,
+    };
+    let created = db.create_model("order", order.clone())?;
+    assert_eq!((created.rows, created.schema_version), (0, 1));
+    assert_eq!(created.indexes.len(), 2);
+    assert!(db
+        .create_model("order", order.clone())
+        .is_err_and(|error| error.downcast_ref::<ModelExistsError>().is_some()));
+    assert!(db.create_model("bad name", order.clone()).is_err());
+    db.insert_raw("order", json!({ "id": 1, "total": 9.5, "status": "open" }))
+        .await?;
+    assert!(db
+        .insert_raw("order", json!({ "id": 1, "total": 1.0, "status": "open" }))
+        .await
+        .is_err());
+    assert!(db.drop_model("product")?);
+    assert!(!db.drop_model("product")?);
+    assert!(db.get_raw("product", 1).await.is_err());
+    assert_eq!(db.model_names(), ["order", "user"]);
+
+    let mut replicated = None;
+    for _ in 0..500 {
+        if replica.model_names() == ["order", "user"] {
+            replicated = replica.get_raw("order", 1).await?;
+            if replicated.is_some() {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        replicated.map(|row| row["status"].clone()),
+        Some(json!("open"))
+    );
+    assert!(replica
+        .create_model("other", order.clone())
+        .is_err_and(|error| error.downcast_ref::<ReadOnlyError>().is_some()));
+    replica.stop_replication();
+
+    db.shutdown().await?;
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.model_names(), ["order", "user"]);
+    let described = reopened.describe_model("order")?;
+    assert_eq!(described.rows, 1);
+    assert!(described.log_bytes > 0);
+    assert!(reopened
+        .insert_raw("order", json!({ "id": 1, "total": 1.0, "status": "open" }))
+        .await
+        .is_err());
+
+    assert!(reopened.drop_model("order")?);
+    let order_log = format!("{}/order.log", config.storage.data_dir);
+    assert!(!std::path::Path::new(&order_log).exists());
+    let product = config.models["product"].clone();
+    assert_eq!(reopened.create_model("product", product)?.rows, 0);
+    reopened.shutdown().await?;
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.model_names(), ["product", "user"]);
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    cleanup_data_dir(&replica_config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn indexes_added_to_live_models_are_backfilled_under_concurrent_writes() -> anyhow::Result<()>
+{
+    let config = test_config("online_indexes");
+    cleanup_path(&config.storage.data_dir)?;
+    let db = Arc::new(NyroDB::new(config.clone()));
+    let rows: Vec<_> = (1..=5_000)
+        .map(|id| user_row(id, &format!("user{}@test.com", id % 10)))
+        .collect();
+    db.insert_many_raw("user", rows).await?;
+
+    let writer = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            for id in 5_001..=5_500 {
+                db.insert_raw("user", user_row(id, &format!("user{}@test.com", id % 10)))
+                    .await?;
+            }
+            anyhow::Ok(())
+        })
+    };
+    for change in [
+        json!({ "op": "add_index", "name": "email" }),
+        json!({ "op": "add_index", "name": "created_at", "kind": "ordered" }),
+    ] {
+        let change: SchemaChange = serde_json::from_value(change)?;
+        db.migrate_schema("user", change).await?;
+    }
+    writer.await??;
+    // Rows moved to another value while the index is filled must leave
+    // their old bucket.
+    db.update_raw("user", 7, user_row(7, "moved@test.com"))
+        .await?;
+    for _ in 0..500 {
+        if db.get_metrics().index_builds.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(db.get_metrics().index_builds.is_empty());
+    let storage = db.get_storage("user")?;
+    let expected = (1..=5_500u64).filter(|id| id % 10 == 7 && *id != 7).count();
+    assert_eq!(
+        storage
+            .hash_index_ids("email", "user7@test.com")
+            .map(|ids| ids.len()),
+        Some(expected)
+    );
+    assert_eq!(
+        storage.hash_index_ids("email", "moved@test.com"),
+        Some(vec![7])
+    );
+    assert_eq!(
+        storage
+            .ordered_index_ids(
+                "created_at",
+                &RangeQuery {
+                    between: Some((json!(100), json!(5_499))),
+                    ..RangeQuery::default()
+                },
+            )
+            .map(|ids| ids.len()),
+        Some(5_400)
+    );
+    assert!(db
+        .describe_model("user")?
+        .indexes
+        .iter()
+        .all(|index| index.ready));
+
+    for invalid in [
+        json!({ "op": "add_index", "name": "email" }),
+        json!({ "op": "add_index", "name": "id" }),
+        json!({ "op": "add_index", "name": "missing" }),
+        json!({ "op": "drop_index", "name": "hash_password" }),
+    ] {
+        let change: SchemaChange = serde_json::from_value(invalid)?;
+        assert!(db.migrate_schema("user", change).await.is_err());
+    }
+    let change = serde_json::from_value(json!({ "op": "drop_index", "name": "email" }))?;
+    db.migrate_schema("user", change).await?;
+    assert_eq!(storage.hash_index_ids("email", "user7@test.com"), None);
+
+    db.shutdown().await?;
+    let reopened = NyroDB::new(config.clone());
+    let storage = reopened.get_storage("user")?;
+    assert_eq!(storage.hash_index_ids("email", "user7@test.com"), None);
+    assert!(storage.has_ordered_index("created_at"));
+    assert_eq!(
+        reopened
+            .query_range_raw(
+                "user",
+                "created_at",
+                &RangeQuery {
+                    between: Some((json!(1), json!(10))),
+                    ..RangeQuery::default()
+                },
+            )
+            .await?
+            .len(),
+        10
+    );
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn index_checkpoints_load_on_restart_and_replay_only_the_tail() -> anyhow::Result<()> {
+    let mut config = test_config("index_checkpoint");
+    order_field(&mut config, "user", "created_at");
+    // Checkpoints are only taken explicitly here, not on shutdown.
+    config.storage.index_checkpoint_bytes = 0;
+    cleanup_path(&config.storage.data_dir)?;
+    let checkpoint_path = std::path::Path::new(&config.storage.data_dir).join("user.index");
+
+    let db = NyroDB::new(config.clone());
+    for id in 1..=3 {
+        db.insert_raw("user", user_row(id, &format!("u{}@test.com", id)))
+            .await?;
+    }
+    db.delete_raw("user", 2).await?;
+    let storage = db.get_storage("user")?;
+    assert!(storage.checkpoint_indexes()?);
+    assert!(!storage.checkpoint_indexes()?);
+    assert_eq!(storage.checkpointed_bytes(), storage.log_bytes());
+    db.insert_raw("user", user_row(4, "u4@test.com")).await?;
+    db.patch_raw(
+        "user",
+        1,
+        json!({ "email": "moved@test.com", "created_at": 9 }),
+    )
+    .await?;
+    let last_seq = storage.last_seq();
+    drop(storage);
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    let storage = reopened.get_storage("user")?;
+    assert!(storage.checkpointed_bytes() > 0);
+    assert!(storage.checkpointed_bytes() < storage.log_bytes());
+    assert_eq!(storage.last_seq(), last_seq);
+    assert_eq!(storage.len(), 3);
+    assert!(reopened.get_raw("user", 2).await?.is_none());
+    assert_eq!(
+        reopened
+            .get_raw("user", 3)
+            .await?
+            .map(|row| row["email"].clone()),
+        Some(json!("u3@test.com"))
+    );
+    assert!(reopened
+        .query_by_field_raw("user", "email", "u1@test.com")
+        .await?
+        .is_empty());
+    assert_eq!(
+        reopened
+            .query_by_field_raw("user", "email", "moved@test.com")
+            .await?
+            .len(),
+        1
+    );
+    let recent = RangeQuery {
+        gte: Some(json!(3)),
+        ..RangeQuery::default()
+    };
+    assert_eq!(
+        range_ids(&reopened, "created_at", &recent).await?,
+        vec![1, 3, 4]
+    );
+
+    // A checkpoint taken before a compaction no longer matches the log.
+    assert!(storage.checkpoint_indexes()?);
+    let stale = std::fs::read(&checkpoint_path)?;
+    reopened.compact("user").await?;
+    assert!(!checkpoint_path.exists());
+    std::fs::write(&checkpoint_path, stale)?;
+    drop(storage);
+    reopened.shutdown().await?;
+
+    let recovered = NyroDB::new(config.clone());
+    let storage = recovered.get_storage("user")?;
+    assert_eq!(storage.checkpointed_bytes(), 0);
+    assert_eq!(storage.len(), 3);
+    assert_eq!(
+        range_ids(&recovered, "created_at", &recent).await?,
+        vec![1, 3, 4]
+    );
+    drop(storage);
+    recovered.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn bounded_row_cache_reads_evicted_rows_from_the_log() -> anyhow::Result<()> {
+    let mut config = test_config("row_cache");
+    index_field(&mut config, "user", "email");
+    config.storage.enable_mmap = false;
+    config.storage.row_cache_bytes = 2048;
+    config.storage.index_checkpoint_bytes = 0;
+    if let Some(product) = config.models.get_mut("product") {
+        product.cache_bytes = Some(0);
+    }
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    let rows = (1..=200)
+        .map(|id| user_row(id, &format!("u{}@test.com", id)))
+        .collect();
+    db.insert_many_raw("user", rows).await?;
+    db.insert_raw("product", product_row(1, 7)).await?;
+    let cached = db.describe_model("user")?.cached_bytes;
+    assert!(cached.is_some_and(|bytes| bytes <= 2048), "{:?}", cached);
+    assert_eq!(db.describe_model("product")?.cached_bytes, None);
+
+    db.patch_raw("user", 5, json!({ "email": "patched@test.com" }))
+        .await?;
+    db.delete_raw("user", 6).await?;
+    for id in [1, 100, 200] {
+        assert_eq!(
+            db.get_raw("user", id)
+                .await?
+                .map(|row| row["email"].clone()),
+            Some(json!(format!("u{}@test.com", id)))
+        );
+    }
+    assert_eq!(
+        db.query_by_field_raw("user", "email", "patched@test.com")
+            .await?
+            .len(),
+        1
+    );
+    db.compact("user").await?;
+    assert_eq!(db.query_raw("user").await?.len(), 199);
+    assert!(db.get_storage("user")?.checkpoint_indexes()?);
+    db.shutdown().await?;
+
+    // The checkpoint loads every row cold; reads bring them back.
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.describe_model("user")?.cached_bytes, Some(0));
+    assert_eq!(
+        reopened
+            .get_raw("user", 5)
+            .await?
+            .map(|row| row["email"].clone()),
+        Some(json!("patched@test.com"))
+    );
+    assert!(reopened.get_raw("user", 6).await?.is_none());
+    let rows = reopened.query_raw("user").await?;
+    assert_eq!(rows.len(), 199);
+    assert!(rows
+        .iter()
+        .all(|row| row["hash_password"] == json!(format!("hash_{}", row["id"]))));
+    let cached = reopened.describe_model("user")?.cached_bytes;
+    assert!(cached.is_some_and(|bytes| bytes <= 2048), "{:?}", cached);
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn tiny_row_cache_serves_rows_written_during_reads() -> anyhow::Result<()> {
+    let mut config = test_config("row_cache_race");
+    config.storage.enable_mmap = false;
+    config.storage.row_cache_bytes = 128;
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = Arc::new(NyroDB::new(config.clone()));
+    db.insert_many_raw(
+        "user",
+        (1..=8).map(|id| user_row(id, "v0@test.com")).collect(),
+    )
+    .await?;
+    let writer = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            for version in 1..=300 {
+                let id = version % 8 + 1;
+                db.update_raw("user", id, user_row(id, &format!("v{}@test.com", version)))
+                    .await?;
+            }
+            anyhow::Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for round in 0..600u64 {
+                    let id = round % 8 + 1;
+                    let row = db.get_raw("user", id).await?;
+                    let row = row.ok_or_else(|| anyhow::anyhow!("row {} went missing", id))?;
+                    assert_eq!(row["hash_password"], json!(format!("hash_{}", id)));
+                }
+                anyhow::Ok(())
+            })
+        })
+        .collect();
+    writer.await??;
+    for reader in readers {
+        reader.await??;
+    }
+    let cached = db.describe_model("user")?.cached_bytes;
+    assert!(cached.is_some_and(|bytes| bytes <= 128), "{:?}", cached);
+
+    db.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn compaction_keeps_latest_live_rows_across_restart() -> anyhow::Result<()> {
+    let mut config = test_config("compaction");
+    index_field(&mut config, "user", "email");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    db.insert_many_raw(
+        "user",
+        (0..32).map(|id| user_row(id, "a@test.com")).collect(),
+    )
+    .await?;
+    db.insert_raw("user", user_row(100, "sparse@test.com"))
+        .await?;
+    for id in 0..16 {
+        db.patch_raw("user", id, json!({ "email": "b@test.com" }))
+            .await?;
+    }
+    for id in 16..24 {
+        db.delete_raw("user", id).await?;
+    }
+
+    let storage = db.get_storage("user")?;
+    assert!(storage.should_compact(0, 0.3));
+    let report = db.compact("user").await?;
+    assert_eq!(report.live_entries, 25);
+    assert!(report.bytes_after < report.bytes_before);
+    assert_eq!(storage.garbage_bytes(), 0);
+    assert_eq!(
+        std::fs::metadata(&storage.file_path)?.len(),
+        report.bytes_after
+    );
+
+    db.insert_raw("user", user_row(200, "after@test.com"))
+        .await?;
+    assert_eq!(
+        db.get_raw("user", 3).await?,
+        Some(json!({
+            "id": 3,
+            "email": "b@test.com",
+            "hash_password": "hash_3",
+            "created_at": 3
+        }))
+    );
+    db.shutdown().await?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.query_raw("user").await?.len(), 26);
+    assert_eq!(reopened.get_raw("user", 20).await?, None);
+    assert_eq!(
+        reopened.get_raw("user", 200).await?,
+        Some(user_row(200, "after@test.com"))
+    );
+    assert_eq!(
+        reopened
+            .query_by_field_raw("user", "email", "a@test.com")
+            .await?
+            .len(),
+        8
+    );
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn torn_tail_is_truncated_on_open() -> anyhow::Result<()> {
+    let config = test_config("torn_tail");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    for id in 1..=3 {
+        db.insert_raw("user", user_row(id, "tail@test.com")).await?;
+    }
+    let log_path = db.get_storage("user")?.file_path.clone();
+    db.shutdown().await?;
+
+    let committed_len = std::fs::metadata(&log_path)?.len();
+    let mut torn_frame = 64u32.to_le_bytes().to_vec();
+    torn_frame.extend_from_slice(b"NYR4 partial");
+    append_bytes(&log_path, &torn_frame)?;
+
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(reopened.query_raw("user").await?.len(), 3);
+    assert_eq!(std::fs::metadata(&log_path)?.len(), committed_len);
+    reopened
+        .insert_raw("user", user_row(4, "tail@test.com"))
+        .await?;
+    reopened.shutdown().await?;
+    drop(reopened);
+
+    let reopened = NyroDB::new(config.clone());
+    assert_eq!(
+        reopened.get_raw("user", 4).await?,
+        Some(user_row(4, "tail@test.com"))
+    );
+    reopened.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn mid_file_corruption_is_skipped_or_refused_in_strict_mode() -> anyhow::Result<()> {
+    let mut config = test_config("mid_file_corruption");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    for id in 1..=3 {
+        db.insert_raw("user", user_row(id, "mid@test.com")).await?;
+    }
+    let log_path = db.get_storage("user")?.file_path.clone();
+    db.shutdown().await?;
+
+    let mut bytes = std::fs::read(&log_path)?;
+    let first_frame_len = 4 + u32::from_le_bytes(bytes[0..4].try_into()?) as usize;
+    let last_byte_of_second_frame = first_frame_len
+        + 4
+        + u32::from_le_bytes(bytes[first_frame_len..first_frame_len + 4].try_into()?) as usize
+        - 1;
+    bytes[last_byte_of_second_frame] ^= 0xff;
+    std::fs::write(&log_path, &bytes)?;
+
+    config.storage.strict_recovery = true;
+    let strict = NyroDB::new(config.clone());
+    assert!(strict.get_raw("user", 1).await.is_err());
+    drop(strict);
+
+    config.storage.strict_recovery = false;
+    let lenient = NyroDB::new(config.clone());
+    assert!(lenient.get_raw("user", 1).await?.is_some());
+    assert_eq!(lenient.get_raw("user", 2).await?, None);
+    assert!(lenient.get_raw("user", 3).await?.is_some());
+    assert_eq!(std::fs::metadata(&log_path)?.len(), bytes.len() as u64);
+    lenient.shutdown().await?;
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn corrupt_frame_length_keeps_the_frames_after_it() -> anyhow::Result<()> {
+    let mut config = test_config("corrupt_length");
+    cleanup_path(&config.storage.data_dir)?;
+
+    let db = NyroDB::new(config.clone());
+    for id in 1..=3 {
+        db.insert_raw("user", user_row(id, "length@test.com"))
+            .await?;
+    }
+    let log_path = db.get_storage("user")?.file_path.clone();
+    db.shutdown().await?;
+    let original = std::fs::read(&log_path)?;
+    let second_frame = 4 + u32::from_le_bytes(original[0..4].try_into()?) as usize;
+    let second_size = u32::from_le_bytes(original[second_frame..second_frame + 4].try_into()?);
+
+    // A size past the end of the log, then one that cuts the frame short.
+    for corrupt_size in [u32::MAX - 16, second_size - 10] {
+        let mut bytes = original.clone();
+        bytes[second_frame..second_frame + 4].copy_from_slice(&corrupt_size.to_le_bytes());
+        std::fs::write(&log_path, &bytes)?;
+
+        config.storage.strict_recovery = true;
+        let strict = NyroDB::new(config.clone());
+        assert!(strict.get_raw("user", 1).await.is_err());
+        drop(strict);
+        assert_eq!(std::fs::read(&log_path)?, bytes);
+
+        config.storage.strict_recovery = false;
+        let lenient = NyroDB::new(config.clone());
+        assert!(lenient.get_raw("user", 1).await?.is_some());
+        assert_eq!(lenient.get_raw("user", 2).await?, None);
+        assert_eq!(
+            lenient.get_raw("user", 3).await?,
+            Some(user_row(3, "length@test.com"))
+        );
+        assert_eq!(std::fs::metadata(&log_path)?.len(), bytes.len() as u64);
+        lenient.shutdown().await?;
+    }
+
+    // Frames of the older formats after the corrupt length have no checksum
+    // to resume from, so the log is refused rather than truncated.
+    let mut legacy = original[..second_frame].to_vec();
+    let mut position = second_frame;
+    while position < original.len() {
+        let size = u32::from_le_bytes(original[position..position + 4].try_into()?) as usize;
+        let payload = &original[position + 4..position + 4 + size];
+        assert_eq!(&payload[..4], b"NYR4");
+        legacy.extend_from_slice(&(size as u32 - 8).to_le_bytes());
+        legacy.extend_from_slice(&payload[8..]);
+        position += 4 + size;
+    }
+    legacy[second_frame..second_frame + 4].copy_from_slice(&(u32::MAX - 16).to_le_bytes());
+    std::fs::write(&log_path, &legacy)?;
+    let refused = NyroDB::new(config.clone());
+    assert!(refused.get_raw("user", 1).await.is_err());
+    drop(refused);
+    assert_eq!(std::fs::read(&log_path)?, legacy);
+    cleanup_data_dir(&config.storage.data_dir)?;
+    Ok(())
+}
+
+fn append_bytes(path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+fn user_row(id: u64, email: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "email": email,
+        "hash_password": format!("hash_{}", id),
+        "created_at": id
+    })
+}
+
+fn product_row(id: u64, category_id: u64) -> serde_json::Value {
+    json!({
+        "id": id,
+        "name": format!("product_{}", id),
+        "price": 20,
+        "category_id": category_id
+    })
+}
+
+fn index_field(config: &mut NyroConfig, model_name: &str, field_name: &str) {
+    if let Some(schema) = config.models.get_mut(model_name) {
+        for field in &mut schema.fields {
+            if field.name == field_name {
+                field.indexed = true;
+            }
+        }
+    }
+}
+
+fn model_field(name: &str, field_type: &str, index: Option<IndexKind>) -> ModelField {
+    ModelField {
+        name: name.to_string(),
+        field_type: field_type.to_string(),
+        required: true,
+        indexed: false,
+        index,
+    }
+}
+
+fn order_field(config: &mut NyroConfig, model_name: &str, field_name: &str) {
+    if let Some(schema) = config.models.get_mut(model_name) {
+        for field in &mut schema.fields {
+            if field.name == field_name {
+                field.index = Some(IndexKind::Ordered);
+            }
+        }
+    }
+}
+
+async fn range_ids(db: &NyroDB, field: &str, query: &RangeQuery) -> anyhow::Result<Vec<u64>> {
+    let mut ids: Vec<u64> = db
+        .query_range_raw("user", field, query)
+        .await?
+        .iter()
+        .filter_map(|row| row["id"].as_u64())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
 fn test_config(name: &str) -> NyroConfig {
     let mut config = NyroConfig::default();
     config.storage.data_dir = parent_temp_dir().join(name).to_string_lossy().into_owned();
     config.storage.sync_interval = 0;
+    config.server.graceful_shutdown_timeout = 0;
     config.logging.level = "error".to_string();
     config.metrics.enable = false;
     config
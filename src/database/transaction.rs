@@ -0,0 +1,345 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::ConflictPolicy;
+use crate::database::helpers::{current_unix_millis, merge_fields, merge_patch_object};
+use crate::database::types::{ConflictError, ModelRuntime, NyroDB};
+use crate::models::{LogEntry, Operation};
+use crate::storage::{StagedFrame, TransactionLog};
+
+/// One staged write. Over REST these are tagged by `op`, e.g.
+/// `{ "op": "delete", "model": "user", "id": 7 }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum TransactionOperation {
+    Insert { model: String, data: Value },
+    Update { model: String, id: u64, data: Value },
+    Patch { model: String, id: u64, data: Value },
+    Delete { model: String, id: u64 },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransactionRequest {
+    pub operations: Vec<TransactionOperation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransactionReport {
+    pub transaction_id: u64,
+    pub results: Vec<TransactionResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransactionResult {
+    pub model: String,
+    pub id: u64,
+    pub operation: Operation,
+}
+
+/// Writes staged with [`NyroDB::begin`]. Nothing touches storage until
+/// [`Transaction::commit`]; dropping or aborting discards everything.
+pub struct Transaction<'a> {
+    db: &'a NyroDB,
+    operations: Vec<TransactionOperation>,
+}
+
+impl TransactionOperation {
+    fn model(&self) -> &str {
+        match self {
+            Self::Insert { model, .. }
+            | Self::Update { model, .. }
+            | Self::Patch { model, .. }
+            | Self::Delete { model, .. } => model,
+        }
+    }
+}
+
+impl Transaction<'_> {
+    pub fn insert(&mut self, model_name: &str, data: Value) -> &mut Self {
+        self.stage(TransactionOperation::Insert {
+            model: model_name.to_string(),
+            data,
+        })
+    }
+
+    pub fn update(&mut self, model_name: &str, id: u64, data: Value) -> &mut Self {
+        self.stage(TransactionOperation::Update {
+            model: model_name.to_string(),
+            id,
+            data,
+        })
+    }
+
+    pub fn patch(&mut self, model_name: &str, id: u64, patch: Value) -> &mut Self {
+        self.stage(TransactionOperation::Patch {
+            model: model_name.to_string(),
+            id,
+            data: patch,
+        })
+    }
+
+    pub fn delete(&mut self, model_name: &str, id: u64) -> &mut Self {
+        self.stage(TransactionOperation::Delete {
+            model: model_name.to_string(),
+            id,
+        })
+    }
+
+    pub fn stage(&mut self, operation: TransactionOperation) -> &mut Self {
+        self.operations.push(operation);
+        self
+    }
+
+    pub async fn commit(self) -> Result<TransactionReport> {
+        self.db.commit_transaction(self.operations).await
+    }
+
+    pub fn abort(self) {}
+}
+
+impl NyroDB {
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            db: self,
+            operations: Vec::new(),
+        }
+    }
+
+    /// Applies every operation or none. Each touched model's writer lock is
+    /// held, in model name order, from the first read until the commit record
+    /// is durable, so concurrent writers never interleave with a transaction.
+    pub async fn commit_transaction(
+        &self,
+        operations: Vec<TransactionOperation>,
+    ) -> Result<TransactionReport> {
+        let start = Instant::now();
+        if operations.is_empty() {
+            return Err(anyhow::anyhow!("Transaction has no operations"));
+        }
+        let mut runtimes = BTreeMap::new();
+        for operation in &operations {
+            if !runtimes.contains_key(operation.model()) {
+                let runtime = self.get_runtime(operation.model())?;
+                runtimes.insert(operation.model().to_string(), runtime);
+            }
+        }
+        let transaction_log = self.transaction_log()?;
+        let timestamp = current_unix_millis()?;
+
+        let _schemas: Vec<_> = runtimes
+            .values()
+            .map(|runtime| runtime.schema_gate.read())
+            .collect();
+        let mut frames = BTreeMap::new();
+        for (model_name, runtime) in &runtimes {
+            frames.insert(model_name.as_str(), runtime.storage.lock_for_transaction()?);
+        }
+        let resolved = match resolve_operations(&runtimes, operations, timestamp) {
+            Ok(resolved) => resolved,
+            Err(error) => return Err(abort_frames(frames, error)),
+        };
+
+        let transaction_id = transaction_log.begin()?;
+        let mut entries_by_model: BTreeMap<&str, Vec<LogEntry<Value>>> = BTreeMap::new();
+        for (model_name, _, entry) in &resolved {
+            entries_by_model
+                .entry(model_name.as_str())
+                .or_default()
+                .push(entry.clone());
+        }
+        for (model_name, entries) in &entries_by_model {
+            let written = runtimes[*model_name]
+                .storage
+                .encode_transaction_entries(entries)
+                .and_then(|encoded| {
+                    let frame = frames
+                        .get_mut(model_name)
+                        .expect("every transaction model holds a frame");
+                    frame.write(transaction_id, encoded)
+                });
+            if let Err(error) = written {
+                return Err(abort_frames(frames, error));
+            }
+        }
+        let mut previous_by_model = BTreeMap::new();
+        for (model_name, entries) in &entries_by_model {
+            match self.previous_rows(&runtimes[*model_name].storage, entries) {
+                Ok(previous) => previous_by_model.insert(*model_name, previous),
+                Err(error) => return Err(abort_frames(frames, error)),
+            };
+        }
+        if let Err(error) = transaction_log.commit(transaction_id) {
+            return Err(abort_frames(frames, error));
+        }
+        let first_seqs: BTreeMap<&str, u64> = frames
+            .into_iter()
+            .map(|(model_name, frame)| (model_name, frame.publish()))
+            .collect();
+
+        let mut results = Vec::with_capacity(resolved.len());
+        for (model_name, id, entry) in &resolved {
+            match entry.operation {
+                Operation::Insert => self
+                    .metrics
+                    .record_insert(start.elapsed(), self.config.metrics.max_samples),
+                Operation::Update => self.metrics.record_update(),
+                Operation::Delete => self.metrics.record_delete(),
+            }
+            results.push(TransactionResult {
+                model: model_name.clone(),
+                id: *id,
+                operation: entry.operation.clone(),
+            });
+        }
+        for (model_name, entries) in &entries_by_model {
+            let previous = previous_by_model.remove(model_name).unwrap_or_default();
+            self.publish_changes(
+                &runtimes[*model_name],
+                model_name,
+                first_seqs[model_name],
+                entries,
+                previous,
+            );
+        }
+        Ok(TransactionReport {
+            transaction_id,
+            results,
+        })
+    }
+
+    pub(crate) fn transaction_log(&self) -> Result<Arc<TransactionLog>> {
+        let mut transaction_log = self.transaction_log.lock();
+        if let Some(transaction_log) = transaction_log.as_ref() {
+            return Ok(transaction_log.clone());
+        }
+        let opened = Arc::new(TransactionLog::open(&self.config.storage.data_dir)?);
+        *transaction_log = Some(opened.clone());
+        Ok(opened)
+    }
+}
+
+/// Turns operations into log entries, reading rows written earlier in the
+/// same transaction before falling back to storage.
+fn resolve_operations(
+    runtimes: &BTreeMap<String, Arc<ModelRuntime>>,
+    operations: Vec<TransactionOperation>,
+    timestamp: u64,
+) -> Result<Vec<(String, u64, LogEntry<Value>)>> {
+    let mut staged_rows: HashMap<(String, u64), Option<Map<String, Value>>> = HashMap::new();
+    let mut resolved = Vec::with_capacity(operations.len());
+
+    for operation in operations {
+        let model_name = operation.model().to_string();
+        let runtime = &runtimes[&model_name];
+        let current_row = |id: u64| -> Result<Option<Map<String, Value>>> {
+            if let Some(row) = staged_rows.get(&(model_name.clone(), id)) {
+                return Ok(row.clone());
+            }
+            match runtime.storage.get_value(id)? {
+                Some(Value::Object(row)) => Ok(Some(row)),
+                Some(_) => Err(anyhow::anyhow!("Stored row {} is not a JSON object", id)),
+                None => Ok(None),
+            }
+        };
+        let missing = |id: u64| anyhow::anyhow!("Row {} not found in '{}'", id, model_name);
+
+        let (operation, row) = match operation {
+            TransactionOperation::Insert { data, .. } => {
+                let Value::Object(row) = data else {
+                    return Err(anyhow::anyhow!("Data must be a JSON object"));
+                };
+                let id = row
+                    .get("id")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| anyhow::anyhow!("Missing or invalid 'id' field"))?;
+                match (current_row(id)?, runtime.conflict_policy) {
+                    (Some(_), ConflictPolicy::Reject) => {
+                        return Err(ConflictError {
+                            model: model_name,
+                            id,
+                        }
+                        .into());
+                    }
+                    (Some(mut existing), ConflictPolicy::UpsertMerge) => {
+                        merge_fields(&mut existing, row);
+                        (Operation::Update, existing)
+                    }
+                    _ => (Operation::Insert, row),
+                }
+            }
+            TransactionOperation::Update { id, data, .. } => {
+                let Value::Object(mut row) = data else {
+                    return Err(anyhow::anyhow!("Data must be a JSON object"));
+                };
+                match row.get("id") {
+                    None => {
+                        row.insert("id".to_string(), Value::from(id));
+                    }
+                    Some(value) if value.as_u64() == Some(id) => {}
+                    Some(_) => {
+                        return Err(anyhow::anyhow!(
+                            "Body 'id' does not match requested ID {}",
+                            id
+                        ))
+                    }
+                }
+                current_row(id)?.ok_or_else(|| missing(id))?;
+                (Operation::Update, row)
+            }
+            TransactionOperation::Patch { id, data, .. } => {
+                let Value::Object(patch) = data else {
+                    return Err(anyhow::anyhow!("Patch must be a JSON object"));
+                };
+                let mut row = current_row(id)?.ok_or_else(|| missing(id))?;
+                merge_patch_object(&mut row, patch);
+                if row.get("id").and_then(Value::as_u64) != Some(id) {
+                    return Err(anyhow::anyhow!("Patch cannot change the 'id' field"));
+                }
+                (Operation::Update, row)
+            }
+            TransactionOperation::Delete { id, .. } => {
+                current_row(id)?.ok_or_else(|| missing(id))?;
+                let entry = LogEntry {
+                    timestamp,
+                    operation: Operation::Delete,
+                    data: serde_json::json!({ "id": id }),
+                };
+                staged_rows.insert((model_name.clone(), id), None);
+                resolved.push((model_name, id, entry));
+                continue;
+            }
+        };
+
+        let (id, data) = runtime.schema_plan().validate_and_filter_owned(row)?;
+        staged_rows.insert((model_name.clone(), id), Some(data.clone()));
+        resolved.push((
+            model_name,
+            id,
+            LogEntry {
+                timestamp,
+                operation,
+                data: Value::Object(data),
+            },
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Rolls back every frame and returns `error`, or the rollback failure if
+/// one leaves a log in an unknown state.
+fn abort_frames(frames: BTreeMap<&str, StagedFrame<'_>>, error: anyhow::Error) -> anyhow::Error {
+    for (model_name, frame) in frames {
+        if let Err(rollback_error) = frame.rollback() {
+            return rollback_error.context(format!(
+                "Failed to roll back transaction frame for '{}' after: {}",
+                model_name, error
+            ));
+        }
+    }
+    error
+}
@@ -1,11 +1,18 @@
 use dashmap::DashMap;
+use parking_lot::RwLock;
+use serde::Serialize;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
 
-use crate::config::NyroConfig;
+use crate::config::{ConflictPolicy, NyroConfig};
+use crate::database::catalog::Catalog;
+use crate::database::changes::ChangeFeed;
+use crate::database::replication::Replication;
 use crate::database::validation::SchemaPlan;
-use crate::storage::LogStorage;
+use crate::database::webhooks::Webhooks;
+use crate::models::ChangeEvent;
+use crate::storage::{LogStorage, TransactionLog};
 use crate::utils::metrics::Metrics;
 
 pub struct NyroDB {
@@ -14,10 +21,57 @@ pub struct NyroDB {
     pub(crate) shutdown_flag: Arc<AtomicBool>,
     pub(crate) config: NyroConfig,
     pub(crate) concurrency_limiter: Arc<Semaphore>,
-    pub real_time_tx: tokio::sync::broadcast::Sender<String>,
+    pub real_time_tx: tokio::sync::broadcast::Sender<Arc<ChangeEvent>>,
+    /// Opened on the first transaction.
+    pub(crate) transaction_log: parking_lot::Mutex<Option<Arc<TransactionLog>>>,
+    pub(crate) webhooks: Webhooks,
+    pub(crate) replication: Replication,
+    pub(crate) catalog: Catalog,
 }
 
 pub(crate) struct ModelRuntime {
-    pub(crate) schema_plan: Arc<SchemaPlan>,
+    pub(crate) schema_plan: RwLock<Arc<SchemaPlan>>,
+    /// Shared by writes from validation until their entries are logged and
+    /// held alone by schema changes, so no write straddles two versions.
+    pub(crate) schema_gate: RwLock<()>,
     pub(crate) storage: Arc<LogStorage>,
+    pub(crate) conflict_policy: ConflictPolicy,
+    pub(crate) changes: ChangeFeed,
 }
+
+impl ModelRuntime {
+    pub(crate) fn schema_plan(&self) -> Arc<SchemaPlan> {
+        Arc::clone(&self.schema_plan.read())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct InsertManyReport {
+    pub ids: Vec<u64>,
+    pub conflicts: Vec<InsertConflict>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InsertConflict {
+    pub index: usize,
+    pub id: u64,
+}
+
+/// Returned when the `reject` conflict policy refuses an insert.
+#[derive(Debug)]
+pub struct ConflictError {
+    pub model: String,
+    pub id: u64,
+}
+
+impl std::fmt::Display for ConflictError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Row with ID {} already exists in '{}'",
+            self.id, self.model
+        )
+    }
+}
+
+impl std::error::Error for ConflictError {}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use serde_json::{Map, Value};
 
-use crate::config::ModelSchema;
+use crate::config::ModelField;
 
 pub(crate) struct SchemaPlan {
     fields: Vec<FieldPlan>,
@@ -13,6 +13,12 @@ struct FieldPlan {
     kind: FieldKind,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NumericKind {
+    Integer,
+    Float,
+}
+
 #[derive(Clone, Copy)]
 enum FieldKind {
     String,
@@ -26,9 +32,8 @@ enum FieldKind {
 }
 
 impl SchemaPlan {
-    pub(crate) fn from_schema(schema: &ModelSchema) -> Result<Self> {
-        let fields = schema
-            .fields
+    pub(crate) fn from_fields(fields: &[ModelField]) -> Result<Self> {
+        let fields = fields
             .iter()
             .map(|field| {
                 Ok(FieldPlan {
@@ -42,6 +47,19 @@ impl SchemaPlan {
         Ok(Self { fields })
     }
 
+    pub(crate) fn numeric_kind(&self, field_name: &str) -> Option<NumericKind> {
+        let field = self.fields.iter().find(|field| field.name == field_name)?;
+        match field.kind {
+            FieldKind::U64 | FieldKind::U32 | FieldKind::I64 => Some(NumericKind::Integer),
+            FieldKind::F64 => Some(NumericKind::Float),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn has_field(&self, field_name: &str) -> bool {
+        self.fields.iter().any(|field| field.name == field_name)
+    }
+
     pub(crate) fn validate_and_filter_owned(
         &self,
         mut obj: Map<String, Value>,
@@ -117,9 +135,17 @@ impl FieldKind {
     }
 }
 
+pub(crate) fn validate_field_value(field: &ModelField, value: &Value) -> Result<()> {
+    FieldKind::from_str(&field.field_type)?.validate(&field.name, value)
+}
+
 pub(crate) fn validate_supported_field_type(field_type: &str) -> bool {
     matches!(
         field_type,
         "string" | "bool" | "u64" | "u32" | "i64" | "f64" | "object" | "array"
     )
 }
+
+pub(crate) fn validate_orderable_field_type(field_type: &str) -> bool {
+    matches!(field_type, "string" | "u64" | "u32" | "i64" | "f64")
+}
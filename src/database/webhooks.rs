@@ -0,0 +1,430 @@
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use warp::hyper::{self, Body, Client, Request};
+
+use crate::config::{LoggingConfig, WebhookConfig};
+use crate::database::helpers::{current_unix_millis, write_atomically};
+use crate::database::types::NyroDB;
+use crate::database::{ChangeGap, ChangePosition};
+use crate::models::ChangeEvent;
+use crate::storage::ReadOnlyError;
+use crate::utils::logger::Logger;
+
+pub(crate) const WEBHOOK_DIR: &str = "webhooks";
+const REGISTRY_FILE: &str = "registry.json";
+const DELIVERY_BATCH_SIZE: usize = 100;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const IDLE_WAIT: Duration = Duration::from_secs(15);
+
+/// Webhooks from the configuration and the admin API, with their delivery
+/// tasks. API registrations are kept in `webhooks/registry.json` and every
+/// webhook's cursor in `webhooks/<name>.cursor` under the data directory.
+#[derive(Default)]
+pub(crate) struct Webhooks {
+    hooks: Mutex<BTreeMap<String, Arc<Webhook>>>,
+    /// Serializes registry read-modify-writes.
+    registry: Mutex<()>,
+}
+
+struct Webhook {
+    name: String,
+    config: WebhookConfig,
+    from_config: bool,
+    state: Mutex<DeliveryState>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct DeliveryState {
+    cursor: u64,
+    failures: u64,
+    last_error: Option<String>,
+    last_delivered_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookStatus {
+    pub name: String,
+    pub model: String,
+    pub url: String,
+    pub operations: Vec<String>,
+    pub from_config: bool,
+    /// Sequence number of the last change delivered or skipped.
+    pub cursor: u64,
+    /// Failed attempts since the last successful delivery.
+    pub failures: u64,
+    pub last_error: Option<String>,
+    pub last_delivered_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    webhook: &'a str,
+    model: &'a str,
+    events: Vec<ChangeEvent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gap: Option<ChangeGap>,
+}
+
+impl Webhook {
+    fn status(&self) -> WebhookStatus {
+        let state = self.state.lock();
+        WebhookStatus {
+            name: self.name.clone(),
+            model: self.config.model.clone(),
+            url: self.config.url.clone(),
+            operations: self.config.operations.clone(),
+            from_config: self.from_config,
+            cursor: state.cursor,
+            failures: state.failures,
+            last_error: state.last_error.clone(),
+            last_delivered_at: state.last_delivered_at,
+        }
+    }
+
+    fn stop(&self) {
+        if let Some(task) = self.task.lock().take() {
+            task.abort();
+        }
+    }
+
+    fn wants(&self, event: &ChangeEvent) -> bool {
+        self.config.operations.is_empty()
+            || self
+                .config
+                .operations
+                .iter()
+                .any(|operation| operation.eq_ignore_ascii_case(event.operation.event_type()))
+    }
+}
+
+impl NyroDB {
+    /// Starts delivering every configured and registered webhook. New
+    /// webhooks begin at the newest change. Replicas leave delivery to the
+    /// primary.
+    pub fn start_webhooks(self: &Arc<Self>) -> Result<()> {
+        if self.is_replica() {
+            return Ok(());
+        }
+        let mut definitions: Vec<(String, WebhookConfig, bool)> = self
+            .config
+            .webhooks
+            .iter()
+            .map(|(name, config)| (name.clone(), config.clone(), true))
+            .collect();
+        for (name, config) in self.load_webhook_registry()? {
+            if !self.config.webhooks.contains_key(&name) {
+                definitions.push((name, config, false));
+            }
+        }
+        for (name, config, from_config) in definitions {
+            if !self.webhooks.hooks.lock().contains_key(&name) {
+                self.spawn_webhook(name, config, from_config)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn webhooks(&self) -> Vec<WebhookStatus> {
+        self.webhooks
+            .hooks
+            .lock()
+            .values()
+            .map(|hook| hook.status())
+            .collect()
+    }
+
+    /// Adds or replaces a webhook and persists it. A replaced webhook keeps
+    /// its cursor while it targets the same model.
+    pub fn register_webhook(
+        self: &Arc<Self>,
+        name: &str,
+        config: WebhookConfig,
+    ) -> Result<WebhookStatus> {
+        if self.is_replica() {
+            return Err(ReadOnlyError.into());
+        }
+        config.validate(name, &self.models().into_iter().collect())?;
+        if self.config.webhooks.contains_key(name) {
+            return Err(anyhow::anyhow!(
+                "Webhook '{}' is defined in the configuration",
+                name
+            ));
+        }
+        let _registry = self.webhooks.registry.lock();
+        let mut registry = self.load_webhook_registry()?;
+        if let Some(previous) = registry.insert(name.to_string(), config.clone()) {
+            if previous.model != config.model {
+                remove_file_if_exists(&self.webhook_cursor_path(name))?;
+            }
+        }
+        self.save_webhook_registry(&registry)?;
+        if let Some(previous) = self.webhooks.hooks.lock().remove(name) {
+            previous.stop();
+        }
+        let hook = self.spawn_webhook(name.to_string(), config, false)?;
+        Ok(hook.status())
+    }
+
+    /// Stops and forgets a webhook registered through the API.
+    pub fn remove_webhook(&self, name: &str) -> Result<bool> {
+        if self.is_replica() {
+            return Err(ReadOnlyError.into());
+        }
+        if self.config.webhooks.contains_key(name) {
+            return Err(anyhow::anyhow!(
+                "Webhook '{}' is defined in the configuration",
+                name
+            ));
+        }
+        let _registry = self.webhooks.registry.lock();
+        let mut registry = self.load_webhook_registry()?;
+        if registry.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save_webhook_registry(&registry)?;
+        if let Some(hook) = self.webhooks.hooks.lock().remove(name) {
+            hook.stop();
+        }
+        remove_file_if_exists(&self.webhook_cursor_path(name))?;
+        Ok(true)
+    }
+
+    /// Stops and forgets every delivery task; `start_webhooks` starts them
+    /// again from their persisted cursors.
+    pub(crate) fn stop_webhooks(&self) {
+        for hook in std::mem::take(&mut *self.webhooks.hooks.lock()).into_values() {
+            hook.stop();
+        }
+    }
+
+    fn spawn_webhook(
+        self: &Arc<Self>,
+        name: String,
+        config: WebhookConfig,
+        from_config: bool,
+    ) -> Result<Arc<Webhook>> {
+        let cursor_path = self.webhook_cursor_path(&name);
+        let cursor = match std::fs::read_to_string(&cursor_path) {
+            Ok(cursor) => cursor.trim().parse()?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                let cursor = self.published_seq(&config.model)?;
+                write_atomically(&cursor_path, cursor.to_string().as_bytes())?;
+                cursor
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let hook = Arc::new(Webhook {
+            name: name.clone(),
+            config,
+            from_config,
+            state: Mutex::new(DeliveryState {
+                cursor,
+                ..DeliveryState::default()
+            }),
+            task: Mutex::new(None),
+        });
+        let task = tokio::spawn(deliver(Arc::downgrade(self), hook.clone(), cursor_path));
+        *hook.task.lock() = Some(task);
+        self.webhooks.hooks.lock().insert(name, hook.clone());
+        Ok(hook)
+    }
+
+    fn webhook_dir(&self) -> PathBuf {
+        Path::new(&self.config.storage.data_dir).join(WEBHOOK_DIR)
+    }
+
+    fn webhook_cursor_path(&self, name: &str) -> PathBuf {
+        self.webhook_dir().join(format!("{}.cursor", name))
+    }
+
+    fn load_webhook_registry(&self) -> Result<BTreeMap<String, WebhookConfig>> {
+        match std::fs::read(self.webhook_dir().join(REGISTRY_FILE)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn save_webhook_registry(&self, registry: &BTreeMap<String, WebhookConfig>) -> Result<()> {
+        write_atomically(
+            &self.webhook_dir().join(REGISTRY_FILE),
+            &serde_json::to_vec_pretty(registry)?,
+        )
+    }
+}
+
+/// Tails the model's change log from the webhook's cursor. A batch is retried
+/// with exponential backoff until the endpoint accepts it, and the cursor is
+/// persisted only afterwards, so every change is delivered at least once. The
+/// database is only held for each call into it, never across a request or a
+/// backoff, so a failing endpoint cannot keep it alive after shutdown.
+async fn deliver(db: Weak<NyroDB>, hook: Arc<Webhook>, cursor_path: PathBuf) {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build(connector);
+    let model_name = hook.config.model.clone();
+    let Some(logging) = db.upgrade().map(|db| db.get_config().logging.clone()) else {
+        return;
+    };
+    loop {
+        let Some(live) = db.upgrade() else {
+            return;
+        };
+        let cursor = hook.state.lock().cursor;
+        let batch = live
+            .read_changes(
+                &model_name,
+                ChangePosition::AfterSeq(cursor),
+                DELIVERY_BATCH_SIZE,
+            )
+            .await;
+        drop(live);
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(error) => {
+                record_failure(&logging, &hook, error, INITIAL_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let events: Vec<ChangeEvent> = batch
+            .events
+            .into_iter()
+            .filter(|event| hook.wants(event))
+            .collect();
+        if !events.is_empty() || batch.gap.is_some() {
+            let payload = WebhookPayload {
+                webhook: &hook.name,
+                model: &model_name,
+                events,
+                gap: batch.gap,
+            };
+            let body = match serde_json::to_vec(&payload) {
+                Ok(body) => body,
+                Err(error) => {
+                    record_failure(&logging, &hook, error.into(), MAX_BACKOFF).await;
+                    continue;
+                }
+            };
+            let mut backoff = INITIAL_BACKOFF;
+            while let Err(error) = post(&client, &hook, body.clone()).await {
+                record_failure(&logging, &hook, error, backoff).await;
+                if db.strong_count() == 0 {
+                    return;
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            let mut state = hook.state.lock();
+            state.failures = 0;
+            state.last_error = None;
+            state.last_delivered_at = current_unix_millis().ok();
+        }
+
+        if batch.next_after != cursor {
+            if let Err(error) =
+                write_atomically(&cursor_path, batch.next_after.to_string().as_bytes())
+            {
+                record_failure(&logging, &hook, error, INITIAL_BACKOFF).await;
+                continue;
+            }
+            hook.state.lock().cursor = batch.next_after;
+        }
+        if !batch.more {
+            let Some(live) = db.upgrade() else {
+                return;
+            };
+            let changed = live.changes_after(&model_name, batch.next_after, IDLE_WAIT);
+            drop(live);
+            if let Ok(changed) = changed {
+                changed.await;
+            }
+        }
+    }
+}
+
+async fn record_failure(
+    logging: &LoggingConfig,
+    hook: &Webhook,
+    error: anyhow::Error,
+    backoff: Duration,
+) {
+    Logger::error_with_config(
+        logging,
+        &format!(
+            "Webhook '{}' delivery failed, retrying in {}ms: {}",
+            hook.name,
+            backoff.as_millis(),
+            error
+        ),
+    );
+    {
+        let mut state = hook.state.lock();
+        state.failures += 1;
+        state.last_error = Some(error.to_string());
+    }
+    tokio::time::sleep(backoff).await;
+}
+
+/// Every attempt is stamped with the time it was sent, which the signature
+/// covers, so receivers can refuse replayed deliveries.
+async fn post(
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+    hook: &Webhook,
+    body: Vec<u8>,
+) -> Result<()> {
+    let timestamp = current_unix_millis()?;
+    let mut request = Request::post(&hook.config.url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header("x-nyrodb-webhook", &hook.name)
+        .header("x-nyrodb-timestamp", timestamp);
+    if !hook.config.secret.is_empty() {
+        request = request.header(
+            "x-nyrodb-signature",
+            sign(hook.config.secret.as_bytes(), timestamp, &body),
+        );
+    }
+    let request = request.body(Body::from(body))?;
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {}s", REQUEST_TIMEOUT.as_secs()))??;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!("Endpoint answered {}", response.status()));
+    }
+    Ok(())
+}
+
+/// `x-nyrodb-signature` of a delivery: HMAC-SHA256 of `<timestamp>.<body>`.
+pub(crate) fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error.into()),
+    }
+}
@@ -1,12 +1,14 @@
 use anyhow::Result;
 use nyrodb::api;
 use nyrodb::config::NyroConfig;
-use nyrodb::database::NyroDB;
+use nyrodb::database::{restore_backup, NyroDB};
 use nyrodb::utils::logger::Logger;
-use std::net::SocketAddr;
+use serde_json::Value;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::signal;
+use warp::hyper::{self, Body, Client, Request};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -16,11 +18,19 @@ async fn main() -> Result<()> {
         println!("Default configuration generated: nyrodb.toml");
         return Ok(());
     }
+    if args.len() > 1 && args[1] == "backup" {
+        return backup_command(&args[2..]).await;
+    }
+    if args.len() > 1 && args[1] == "restore" {
+        return restore_command(&args[2..]).await;
+    }
 
     let config = NyroConfig::load()?;
     config.validate()?;
 
     let db = Arc::new(NyroDB::new(config.clone()));
+    db.start_webhooks()?;
+    db.start_replication()?;
     let db_shutdown = db.clone();
 
     let routes = api::routes::create_routes(db.clone());
@@ -77,3 +87,94 @@ async fn main() -> Result<()> {
     warp::serve(routes).run(bind_addr).await;
     Ok(())
 }
+
+/// `NyroDB backup [--offline] <directory|file.tar>` asks the running server
+/// for a backup below its `backup.root`, or with `--offline` reads the data
+/// directory of a stopped one into any path.
+async fn backup_command(args: &[String]) -> Result<()> {
+    let offline = args.iter().any(|arg| arg == "--offline");
+    let targets: Vec<&String> = args.iter().filter(|arg| *arg != "--offline").collect();
+    let [target] = targets.as_slice() else {
+        return Err(anyhow::anyhow!(
+            "Usage: NyroDB backup [--offline] <directory|file.tar>"
+        ));
+    };
+    let config = NyroConfig::load()?;
+    config.validate()?;
+    let manifest = if offline {
+        let db = NyroDB::new(config);
+        let manifest = db.backup(std::path::absolute(target)?).await?;
+        db.shutdown().await?;
+        serde_json::to_value(manifest)?
+    } else {
+        request_backup(&config, target).await?
+    };
+    println!("{}", serde_json::to_string_pretty(&manifest)?);
+    Ok(())
+}
+
+/// `NyroDB restore <directory|file.tar> [--until <unix-millis>]` rebuilds
+/// the configured data directory from a backup, then opens every model to
+/// check that its log replays.
+async fn restore_command(args: &[String]) -> Result<()> {
+    let usage =
+        || anyhow::anyhow!("Usage: NyroDB restore <directory|file.tar> [--until <unix-millis>]");
+    let (snapshot, until) = match args {
+        [snapshot] => (snapshot, None),
+        [snapshot, flag, until] if flag == "--until" => {
+            (snapshot, Some(until.parse::<u64>().map_err(|_| usage())?))
+        }
+        _ => return Err(usage()),
+    };
+
+    let config = NyroConfig::load()?;
+    config.validate()?;
+    let report = restore_backup(snapshot, &config, until)?;
+    println!(
+        "Restored {} files from {} into {}",
+        report.files, snapshot, config.storage.data_dir
+    );
+
+    let db = NyroDB::new(config);
+    for model_name in db.model_names() {
+        let rows = db.get_storage(&model_name)?.len();
+        match report.dropped_entries.get(&model_name) {
+            Some(dropped) => println!(
+                "  {}: {} rows, {} later entries dropped",
+                model_name, rows, dropped
+            ),
+            None => println!("  {}: {} rows", model_name, rows),
+        }
+    }
+    db.shutdown().await?;
+    Ok(())
+}
+
+async fn request_backup(config: &NyroConfig, target: &str) -> Result<Value> {
+    let mut addr: SocketAddr = format!("{}:{}", config.server.host, config.server.port)
+        .parse()
+        .map_err(|error| anyhow::anyhow!("Invalid server address: {}", error))?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    let mut request = Request::post(format!("http://{}/admin/backup", addr))
+        .header("content-type", "application/json");
+    if config.security.enable_auth {
+        request = request.header("x-api-key", &config.security.api_key);
+    }
+    let body = serde_json::to_vec(&serde_json::json!({ "target": target }))?;
+    let response = Client::new()
+        .request(request.body(Body::from(body))?)
+        .await
+        .map_err(|error| anyhow::anyhow!("Server at {} is not reachable: {}", addr, error))?;
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await?;
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "Backup failed ({}): {}",
+            status,
+            String::from_utf8_lossy(&bytes)
+        ));
+    }
+    Ok(serde_json::from_slice(&bytes)?)
+}
@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LogEntry<T> {
@@ -13,3 +14,49 @@ pub enum Operation {
     Update,
     Delete,
 }
+
+impl Operation {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Operation::Insert => "INSERT",
+            Operation::Update => "UPDATE",
+            Operation::Delete => "DELETE",
+        }
+    }
+}
+
+/// A committed change as broadcast to realtime clients.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename = "event")]
+pub struct ChangeEvent {
+    pub model: String,
+    #[serde(serialize_with = "serialize_event_type")]
+    pub operation: Operation,
+    pub id: u64,
+    pub timestamp: u64,
+    /// Increases by one with every change published for `model`.
+    pub seq: u64,
+    pub data: Value,
+    /// The stored row an update replaced.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous: Option<Value>,
+}
+
+impl ChangeEvent {
+    /// The protocol 1 text form, `EVENT_TYPE:model:json`.
+    pub fn to_text(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.operation.event_type(),
+            self.model,
+            self.data
+        )
+    }
+}
+
+fn serialize_event_type<S: Serializer>(
+    operation: &Operation,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(operation.event_type())
+}
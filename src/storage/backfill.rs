@@ -0,0 +1,108 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::encoding::build_index_data;
+use super::LogStorage;
+
+/// Rows indexed per hold of the writer lock.
+const BACKFILL_BATCH_ROWS: usize = 1024;
+
+/// A secondary index added to a live log. Queries ignore it until the rows
+/// logged before it existed are indexed.
+pub(crate) struct IndexBuild {
+    indexed_rows: AtomicU64,
+    total_rows: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexBuildProgress {
+    pub field: String,
+    pub indexed_rows: u64,
+    pub total_rows: u64,
+}
+
+impl LogStorage {
+    /// Whether queries may use the secondary index of `field`.
+    pub(crate) fn index_ready(&self, field: &str) -> bool {
+        !self.index_builds.contains_key(field)
+    }
+
+    pub(super) fn start_index_build(&self, field: &str) {
+        let build = IndexBuild {
+            indexed_rows: AtomicU64::new(0),
+            total_rows: self.index.len() as u64,
+        };
+        self.index_builds.insert(field.to_string(), Arc::new(build));
+    }
+
+    pub fn index_builds(&self) -> Vec<IndexBuildProgress> {
+        let mut builds: Vec<IndexBuildProgress> = self
+            .index_builds
+            .iter()
+            .map(|build| IndexBuildProgress {
+                field: build.key().clone(),
+                indexed_rows: build
+                    .indexed_rows
+                    .load(Ordering::Relaxed)
+                    .min(build.total_rows),
+                total_rows: build.total_rows,
+            })
+            .collect();
+        builds.sort_by(|left, right| left.field.cmp(&right.field));
+        builds
+    }
+
+    /// Indexes every row for the index builds pending when called, a batch
+    /// at a time under the writer lock. Writes index their own rows and
+    /// cannot interleave with a batch, so a batch always sees the newest
+    /// version of each row.
+    pub fn backfill_indexes(&self) -> Result<()> {
+        let builds: Vec<(String, Arc<IndexBuild>)> = self
+            .index_builds
+            .iter()
+            .map(|build| (build.key().clone(), Arc::clone(build.value())))
+            .collect();
+        if builds.is_empty() {
+            return Ok(());
+        }
+
+        let mut after = None;
+        loop {
+            let writer = self.file.write();
+            let reader = self.reader.read();
+            let ids = self.index.ids_after(after, BACKFILL_BATCH_ROWS);
+            let schema = self.schema();
+            for &id in &ids {
+                let Some(entry) = self.index.get(id) else {
+                    continue;
+                };
+                let cache = self.cached_entry(&reader, id, entry)?;
+                let row = self.cached_value(&cache)?;
+                if let Some(index_data) = build_index_data(&row, &schema.indexed_fields) {
+                    self.reindex_secondary(id, index_data.values);
+                }
+            }
+            drop(reader);
+            drop(writer);
+
+            for (_, build) in &builds {
+                build
+                    .indexed_rows
+                    .fetch_add(ids.len() as u64, Ordering::Relaxed);
+            }
+            match ids.last() {
+                Some(&last) if ids.len() == BACKFILL_BATCH_ROWS => after = Some(last),
+                _ => break,
+            }
+        }
+
+        // A field dropped and indexed again meanwhile has a newer build.
+        for (field, build) in builds {
+            self.index_builds
+                .remove_if(&field, |_, current| Arc::ptr_eq(current, &build));
+        }
+        Ok(())
+    }
+}
@@ -77,16 +77,10 @@ pub fn run_storage_stage_benchmark(
 }
 
 fn encode_entries(storage: &LogStorage, entries: &[LogEntry<Value>]) -> Result<Vec<EncodedEntry>> {
+    let schema = storage.schema();
     entries
         .iter()
-        .map(|entry| {
-            encoding::encode_entry(
-                entry,
-                &storage.indexed_fields,
-                &storage.field_codecs,
-                CacheMode::EncodedFrame,
-            )
-        })
+        .map(|entry| encoding::encode_entry(entry, &schema, CacheMode::EncodedFrame))
         .collect()
 }
 
@@ -119,7 +113,7 @@ fn publish_index_only(encoded_entries: &[EncodedEntry]) {
                     offset,
                     size: entry.size,
                 },
-                cache: entry.cache_entry.clone(),
+                cache: Some(entry.cache_entry.clone()),
             };
             offset += 4 + entry.size as u64;
             Some((index_data.id, indexed_entry))
@@ -0,0 +1,168 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use super::index::EntryLocation;
+use super::sequence::SequenceMarker;
+use super::LogStorage;
+
+#[derive(Debug, Serialize)]
+pub struct CompactionReport {
+    pub live_entries: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+pub(super) fn compaction_path(file_path: &str) -> String {
+    format!("{}.compact", file_path)
+}
+
+/// A leftover compaction file means the process died before the swap, so the
+/// original log is still authoritative.
+pub(super) fn remove_stale_compaction(file_path: &str) -> Result<()> {
+    match std::fs::remove_file(compaction_path(file_path)) {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+impl LogStorage {
+    pub fn log_bytes(&self) -> u64 {
+        self.current_offset.load(Ordering::Acquire)
+    }
+
+    pub fn garbage_bytes(&self) -> u64 {
+        self.log_bytes()
+            .saturating_sub(self.index.live_bytes() + self.sequence.marker_bytes())
+    }
+
+    pub fn should_compact(&self, min_bytes: u64, garbage_ratio: f64) -> bool {
+        let log_bytes = self.log_bytes();
+        if log_bytes == 0 || log_bytes < min_bytes {
+            return false;
+        }
+        self.garbage_bytes() as f64 / log_bytes as f64 >= garbage_ratio
+    }
+
+    /// Rewrites the log with only the latest live frame of every id and swaps
+    /// it in place. Writers are blocked for the duration; readers keep using
+    /// the in-memory cache while the mmap is being replaced. The new log opens
+    /// with a sequence marker so the kept frames keep the newest numbers.
+    pub fn compact(&self) -> Result<CompactionReport> {
+        self.ensure_writable()?;
+        let mut writer = self.file.write();
+        writer.flush()?;
+        let bytes_before = self.current_offset.load(Ordering::Acquire);
+
+        let mut locations = self.index.locations();
+        locations.sort_unstable_by_key(|(_, location)| location.offset);
+
+        let last_seq = self.sequence.last_seq();
+        let marker = SequenceMarker {
+            base: last_seq.saturating_sub(locations.len() as u64),
+            compacted_through: last_seq,
+            compacted_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_millis() as u64,
+        };
+        let compact_path = compaction_path(&self.file_path);
+        let (relocated, bytes_after) =
+            match self.write_compacted_log(&compact_path, &marker, &locations) {
+                Ok(written) => written,
+                Err(error) => {
+                    let _ = std::fs::remove_file(&compact_path);
+                    return Err(error);
+                }
+            };
+
+        let mut reader = self.reader.write();
+        *self.mmap_file.write() = None;
+        let compacted_file = OpenOptions::new()
+            .append(true)
+            .read(true)
+            .open(&compact_path)?;
+        *writer = BufWriter::with_capacity(self.buffer_size, compacted_file);
+        std::fs::rename(&compact_path, &self.file_path)?;
+        sync_parent_dir(&self.file_path)?;
+        *reader = File::open(&self.file_path)?;
+
+        self.index.relocate_many(&relocated);
+        self.current_offset.store(bytes_after, Ordering::SeqCst);
+        self.note_flushed();
+        self.sequence.compacted(marker);
+        self.remove_index_checkpoint()?;
+        self.setup_mmap()?;
+
+        Ok(CompactionReport {
+            live_entries: relocated.len() as u64,
+            bytes_before,
+            bytes_after,
+        })
+    }
+
+    fn write_compacted_log(
+        &self,
+        compact_path: &str,
+        marker: &SequenceMarker,
+        locations: &[(u64, EntryLocation)],
+    ) -> Result<(Vec<(u64, EntryLocation)>, u64)> {
+        let mut reader = BufReader::with_capacity(self.buffer_size, File::open(&self.file_path)?);
+        let compact_file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(compact_path)?;
+        let mut output = BufWriter::with_capacity(self.buffer_size, compact_file);
+        let mut relocated = Vec::with_capacity(locations.len());
+        let mut frame = Vec::new();
+        let mut read_position = 0u64;
+
+        let marker = marker.encode();
+        output.write_all(&(marker.len() as u32).to_le_bytes())?;
+        output.write_all(&marker)?;
+        let mut write_position = 4 + marker.len() as u64;
+
+        for (id, location) in locations {
+            let skip = i64::try_from(location.offset - read_position)
+                .map_err(|_| anyhow::anyhow!("Log offset out of range during compaction"))?;
+            reader.seek_relative(skip)?;
+            frame.resize(location.frame_len() as usize, 0);
+            reader.read_exact(&mut frame)?;
+            read_position = location.offset + location.frame_len();
+
+            output.write_all(&frame)?;
+            relocated.push((
+                *id,
+                EntryLocation {
+                    offset: write_position,
+                    size: location.size,
+                },
+            ));
+            write_position += location.frame_len();
+        }
+
+        output.flush()?;
+        output.get_ref().sync_all()?;
+        Ok((relocated, write_position))
+    }
+}
+
+#[cfg(unix)]
+pub(super) fn sync_parent_dir(file_path: &str) -> Result<()> {
+    if let Some(parent) = Path::new(file_path)
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(super) fn sync_parent_dir(_file_path: &str) -> Result<()> {
+    Ok(())
+}
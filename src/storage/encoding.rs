@@ -1,20 +1,43 @@
 use anyhow::Result;
+use crc::{Crc, CRC_32_ISCSI};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashSet;
 use std::sync::Arc;
 
 use crate::models::{LogEntry, Operation};
 use crate::storage::index::{CachedData, CachedEntry};
+use crate::storage::schema::LogSchema;
+use crate::storage::secondary::{IndexedFields, IndexedValues};
 use crate::storage::typed::{
-    decode_compact_typed_payload, decode_typed_payload, encode_compact_typed_payload, FieldCodec,
+    decode_compact_typed_payload, decode_typed_payload, encode_compact_typed_payload,
 };
 
 const JSON_ENTRY_MAGIC: &[u8; 4] = b"NYR1";
 const TYPED_ENTRY_MAGIC: &[u8; 4] = b"NYR2";
 const COMPACT_TYPED_ENTRY_MAGIC: &[u8; 4] = b"NYR3";
+/// Wraps an NYR1, NYR3, NYR5 or NYR6 frame with a CRC32C of the wrapped bytes.
+pub(super) const CHECKSUMMED_ENTRY_MAGIC: &[u8; 4] = b"NYR4";
+/// NYR3 and NYR1 with the schema version after the operation byte. Entries
+/// written before a model's first migration carry no version.
+const VERSIONED_TYPED_ENTRY_MAGIC: &[u8; 4] = b"NYR5";
+const VERSIONED_JSON_ENTRY_MAGIC: &[u8; 4] = b"NYR6";
+const CHECKSUM_HEADER_SIZE: usize = CHECKSUMMED_ENTRY_MAGIC.len() + 4;
+/// Every entry frame format, for telling frames from garbage during recovery.
+pub(super) const ENTRY_MAGICS: [&[u8; 4]; 6] = [
+    JSON_ENTRY_MAGIC,
+    TYPED_ENTRY_MAGIC,
+    COMPACT_TYPED_ENTRY_MAGIC,
+    CHECKSUMMED_ENTRY_MAGIC,
+    VERSIONED_TYPED_ENTRY_MAGIC,
+    VERSIONED_JSON_ENTRY_MAGIC,
+];
+pub(super) const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 const JSON_HEADER_SIZE: usize = JSON_ENTRY_MAGIC.len() + 8 + 1;
 const TYPED_HEADER_SIZE: usize = TYPED_ENTRY_MAGIC.len() + 8 + 1;
+const VERSIONED_HEADER_SIZE: usize = TYPED_HEADER_SIZE + 4;
+pub(crate) const INSERT_OPERATION: u8 = 0;
+pub(crate) const UPDATE_OPERATION: u8 = 1;
+pub(crate) const DELETE_OPERATION: u8 = 2;
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct RawEntry {
@@ -26,13 +49,14 @@ pub(crate) struct RawEntry {
 pub(crate) struct EncodedEntry {
     pub(crate) data: Arc<[u8]>,
     pub(crate) size: u32,
+    pub(crate) schema_version: u32,
     pub(crate) index_data: Option<IndexData>,
     pub(crate) cache_entry: CachedEntry,
 }
 
 pub(crate) struct IndexData {
     pub(crate) id: u64,
-    pub(crate) fields: Vec<(String, String)>,
+    pub(crate) values: IndexedValues,
 }
 
 #[derive(Clone, Copy)]
@@ -43,16 +67,14 @@ pub(crate) enum CacheMode {
 
 pub(crate) fn encode_entry(
     entry: &LogEntry<Value>,
-    indexed_fields: &HashSet<String>,
-    field_codecs: &[FieldCodec],
+    schema: &LogSchema,
     cache_mode: CacheMode,
 ) -> Result<EncodedEntry> {
     let core = encode_entry_core(
         entry.timestamp,
         operation_to_u8(&entry.operation),
         &entry.data,
-        indexed_fields,
-        field_codecs,
+        schema,
         false,
     )?;
     let EncodedCore {
@@ -69,10 +91,12 @@ pub(crate) fn encode_entry(
     Ok(EncodedEntry {
         data,
         size,
+        schema_version: schema.version(),
         index_data,
         cache_entry: CachedEntry {
             timestamp: entry.timestamp,
             operation,
+            schema_version: schema.version(),
             data: cache_data,
         },
     })
@@ -80,20 +104,12 @@ pub(crate) fn encode_entry(
 
 pub(crate) fn encode_owned_entry(
     entry: LogEntry<Value>,
-    indexed_fields: &HashSet<String>,
-    field_codecs: &[FieldCodec],
+    schema: &LogSchema,
 ) -> Result<EncodedEntry> {
     let timestamp = entry.timestamp;
     let operation = operation_to_u8(&entry.operation);
     let data = entry.data;
-    let core = encode_entry_core(
-        timestamp,
-        operation,
-        &data,
-        indexed_fields,
-        field_codecs,
-        false,
-    )?;
+    let core = encode_entry_core(timestamp, operation, &data, schema, false)?;
     let EncodedCore {
         data: encoded_data,
         size,
@@ -103,10 +119,12 @@ pub(crate) fn encode_owned_entry(
     Ok(EncodedEntry {
         data: Arc::from(encoded_data),
         size,
+        schema_version: schema.version(),
         index_data,
         cache_entry: CachedEntry {
             timestamp,
             operation,
+            schema_version: schema.version(),
             data: CachedData::Parsed(Arc::new(data)),
         },
     })
@@ -123,24 +141,50 @@ fn encode_entry_core(
     timestamp: u64,
     operation: u8,
     entry_data: &Value,
-    indexed_fields: &HashSet<String>,
-    field_codecs: &[FieldCodec],
+    schema: &LogSchema,
     needs_json_cache: bool,
 ) -> Result<EncodedCore> {
-    let typed_payload = encode_compact_typed_payload(entry_data, field_codecs);
+    let version = schema.version();
+    let typed_payload = encode_compact_typed_payload(entry_data, schema.codecs());
     let json_data = if needs_json_cache || typed_payload.is_none() {
         Some(serde_json::to_vec(entry_data)?)
     } else {
         None
     };
-    let data = if let Some(payload) = typed_payload {
-        encode_compact_typed_raw_entry(timestamp, operation, &payload)
-    } else {
-        let json = json_data
-            .as_deref()
-            .ok_or_else(|| anyhow::anyhow!("Missing JSON fallback payload"))?;
-        encode_json_raw_entry(timestamp, operation, json)
+    let payload_len = typed_payload
+        .as_ref()
+        .or(json_data.as_ref())
+        .map_or(0, Vec::len);
+    let mut data = Vec::with_capacity(CHECKSUM_HEADER_SIZE + VERSIONED_HEADER_SIZE + payload_len);
+    data.extend_from_slice(CHECKSUMMED_ENTRY_MAGIC);
+    data.extend_from_slice(&[0; 4]);
+    let (magic, payload) = match (typed_payload.as_deref(), version) {
+        (Some(payload), 1) => (COMPACT_TYPED_ENTRY_MAGIC, payload),
+        (Some(payload), _) => (VERSIONED_TYPED_ENTRY_MAGIC, payload),
+        (None, version) => {
+            let json = json_data
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("Missing JSON fallback payload"))?;
+            let magic = if version == 1 {
+                JSON_ENTRY_MAGIC
+            } else {
+                VERSIONED_JSON_ENTRY_MAGIC
+            };
+            (magic, json)
+        }
     };
+    if version == 1 {
+        write_raw_entry(&mut data, magic, timestamp, operation, payload);
+    } else {
+        data.extend_from_slice(magic);
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data.push(operation);
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(payload);
+    }
+    let checksum = CRC32C.checksum(&data[CHECKSUM_HEADER_SIZE..]);
+    data[CHECKSUMMED_ENTRY_MAGIC.len()..CHECKSUM_HEADER_SIZE]
+        .copy_from_slice(&checksum.to_le_bytes());
     let size = u32::try_from(data.len())
         .map_err(|_| anyhow::anyhow!("Serialized entry is larger than u32::MAX"))?;
 
@@ -148,84 +192,176 @@ fn encode_entry_core(
         data,
         size,
         operation,
-        index_data: build_index_data(entry_data, indexed_fields),
+        index_data: build_index_data(entry_data, &schema.indexed_fields),
     })
 }
 
-pub(crate) fn decode_raw_entry(data: &[u8], field_codecs: &[FieldCodec]) -> Result<RawEntry> {
+/// Decodes an entry with the codecs of the schema version it was written
+/// under and brings its data up to the current version.
+pub(crate) fn decode_raw_entry(data: &[u8], schema: &LogSchema) -> Result<RawEntry> {
+    if data.starts_with(CHECKSUMMED_ENTRY_MAGIC) {
+        let inner = verify_checksum(data)?;
+        if inner.starts_with(CHECKSUMMED_ENTRY_MAGIC) {
+            return Err(anyhow::anyhow!("Nested checksummed log entry"));
+        }
+        return decode_raw_entry(inner, schema);
+    }
     if data.starts_with(JSON_ENTRY_MAGIC) {
-        return decode_json_raw_entry(data);
+        return decode_json_raw_entry(data, JSON_HEADER_SIZE, 1, schema);
     }
     if data.starts_with(TYPED_ENTRY_MAGIC) {
-        return decode_typed_raw_entry(data, field_codecs);
+        return decode_typed_raw_entry(data, schema);
     }
     if data.starts_with(COMPACT_TYPED_ENTRY_MAGIC) {
-        return decode_compact_typed_raw_entry(data, field_codecs);
+        return decode_compact_typed_raw_entry(data, TYPED_HEADER_SIZE, 1, schema);
+    }
+    if data.starts_with(VERSIONED_TYPED_ENTRY_MAGIC) {
+        let version = decode_version(data)?;
+        return decode_compact_typed_raw_entry(data, VERSIONED_HEADER_SIZE, version, schema);
     }
-    bincode::deserialize(data).map_err(Into::into)
+    if data.starts_with(VERSIONED_JSON_ENTRY_MAGIC) {
+        let version = decode_version(data)?;
+        return decode_json_raw_entry(data, VERSIONED_HEADER_SIZE, version, schema);
+    }
+    let mut raw_entry: RawEntry = bincode::deserialize(data)?;
+    raw_entry.data = schema.upgrade_json(1, raw_entry.data)?;
+    Ok(raw_entry)
 }
 
-pub(super) fn encode_json_raw_entry(timestamp: u64, operation: u8, json_data: &[u8]) -> Vec<u8> {
-    let mut data = Vec::with_capacity(JSON_HEADER_SIZE + json_data.len());
-    data.extend_from_slice(JSON_ENTRY_MAGIC);
+/// Reads only an entry's timestamp, which needs no field codecs.
+pub(super) fn decode_entry_timestamp(data: &[u8]) -> Result<u64> {
+    Ok(decode_entry_header(data)?.0)
+}
+
+/// Reads only an entry's timestamp and operation.
+pub(super) fn decode_entry_header(data: &[u8]) -> Result<(u64, u8)> {
+    if data.starts_with(CHECKSUMMED_ENTRY_MAGIC) {
+        let inner = verify_checksum(data)?;
+        if inner.starts_with(CHECKSUMMED_ENTRY_MAGIC) {
+            return Err(anyhow::anyhow!("Nested checksummed log entry"));
+        }
+        return decode_entry_header(inner);
+    }
+    if data.starts_with(JSON_ENTRY_MAGIC) {
+        return decode_header(data, JSON_HEADER_SIZE);
+    }
+    if data.starts_with(TYPED_ENTRY_MAGIC) || data.starts_with(COMPACT_TYPED_ENTRY_MAGIC) {
+        return decode_header(data, TYPED_HEADER_SIZE);
+    }
+    if data.starts_with(VERSIONED_TYPED_ENTRY_MAGIC) || data.starts_with(VERSIONED_JSON_ENTRY_MAGIC)
+    {
+        return decode_header(data, VERSIONED_HEADER_SIZE);
+    }
+    let raw_entry: RawEntry = bincode::deserialize(data)?;
+    Ok((raw_entry.timestamp, raw_entry.operation))
+}
+
+fn verify_checksum(data: &[u8]) -> Result<&[u8]> {
+    if data.len() < CHECKSUM_HEADER_SIZE {
+        return Err(anyhow::anyhow!("Corrupt checksummed log entry header"));
+    }
+    let stored_bytes: [u8; 4] = data[CHECKSUMMED_ENTRY_MAGIC.len()..CHECKSUM_HEADER_SIZE]
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Corrupt log entry checksum"))?;
+    let inner = &data[CHECKSUM_HEADER_SIZE..];
+    if u32::from_le_bytes(stored_bytes) != CRC32C.checksum(inner) {
+        return Err(anyhow::anyhow!("Log entry checksum mismatch"));
+    }
+    Ok(inner)
+}
+
+fn write_raw_entry(
+    data: &mut Vec<u8>,
+    magic: &[u8; 4],
+    timestamp: u64,
+    operation: u8,
+    payload: &[u8],
+) {
+    data.extend_from_slice(magic);
     data.extend_from_slice(&timestamp.to_le_bytes());
     data.push(operation);
-    data.extend_from_slice(json_data);
+    data.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+pub(super) fn encode_json_raw_entry(timestamp: u64, operation: u8, json_data: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(JSON_HEADER_SIZE + json_data.len());
+    write_raw_entry(&mut data, JSON_ENTRY_MAGIC, timestamp, operation, json_data);
     data
 }
 
 #[cfg(test)]
 pub(super) fn encode_typed_raw_entry(timestamp: u64, operation: u8, payload: &[u8]) -> Vec<u8> {
     let mut data = Vec::with_capacity(TYPED_HEADER_SIZE + payload.len());
-    data.extend_from_slice(TYPED_ENTRY_MAGIC);
-    data.extend_from_slice(&timestamp.to_le_bytes());
-    data.push(operation);
-    data.extend_from_slice(payload);
+    write_raw_entry(&mut data, TYPED_ENTRY_MAGIC, timestamp, operation, payload);
     data
 }
 
+#[cfg(test)]
 pub(super) fn encode_compact_typed_raw_entry(
     timestamp: u64,
     operation: u8,
     payload: &[u8],
 ) -> Vec<u8> {
     let mut data = Vec::with_capacity(TYPED_HEADER_SIZE + payload.len());
-    data.extend_from_slice(COMPACT_TYPED_ENTRY_MAGIC);
-    data.extend_from_slice(&timestamp.to_le_bytes());
-    data.push(operation);
-    data.extend_from_slice(payload);
+    write_raw_entry(
+        &mut data,
+        COMPACT_TYPED_ENTRY_MAGIC,
+        timestamp,
+        operation,
+        payload,
+    );
     data
 }
 
-fn decode_json_raw_entry(data: &[u8]) -> Result<RawEntry> {
-    let (timestamp, operation) = decode_header(data, JSON_HEADER_SIZE)?;
+fn decode_json_raw_entry(
+    data: &[u8],
+    header_size: usize,
+    version: u32,
+    schema: &LogSchema,
+) -> Result<RawEntry> {
+    let (timestamp, operation) = decode_header(data, header_size)?;
     Ok(RawEntry {
         timestamp,
         operation,
-        data: data[JSON_HEADER_SIZE..].to_vec(),
+        data: schema.upgrade_json(version, data[header_size..].to_vec())?,
     })
 }
 
-fn decode_typed_raw_entry(data: &[u8], field_codecs: &[FieldCodec]) -> Result<RawEntry> {
+fn decode_typed_raw_entry(data: &[u8], schema: &LogSchema) -> Result<RawEntry> {
     let (timestamp, operation) = decode_header(data, TYPED_HEADER_SIZE)?;
-    let json_data = decode_typed_payload(&data[TYPED_HEADER_SIZE..], field_codecs)?;
+    let mut row = decode_typed_payload(&data[TYPED_HEADER_SIZE..], schema.codecs_for(1)?)?;
+    schema.upgrade(1, &mut row);
     Ok(RawEntry {
         timestamp,
         operation,
-        data: json_data,
+        data: serde_json::to_vec(&row)?,
     })
 }
 
-fn decode_compact_typed_raw_entry(data: &[u8], field_codecs: &[FieldCodec]) -> Result<RawEntry> {
-    let (timestamp, operation) = decode_header(data, TYPED_HEADER_SIZE)?;
-    let json_data = decode_compact_typed_payload(&data[TYPED_HEADER_SIZE..], field_codecs)?;
+fn decode_compact_typed_raw_entry(
+    data: &[u8],
+    header_size: usize,
+    version: u32,
+    schema: &LogSchema,
+) -> Result<RawEntry> {
+    let (timestamp, operation) = decode_header(data, header_size)?;
+    let mut row = decode_compact_typed_payload(&data[header_size..], schema.codecs_for(version)?)?;
+    schema.upgrade(version, &mut row);
     Ok(RawEntry {
         timestamp,
         operation,
-        data: json_data,
+        data: serde_json::to_vec(&row)?,
     })
 }
 
+fn decode_version(data: &[u8]) -> Result<u32> {
+    data.get(TYPED_HEADER_SIZE..VERSIONED_HEADER_SIZE)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| anyhow::anyhow!("Corrupt log entry header"))
+}
+
 fn decode_header(data: &[u8], header_size: usize) -> Result<(u64, u8)> {
     if data.len() < header_size {
         return Err(anyhow::anyhow!("Corrupt log entry header"));
@@ -242,36 +378,36 @@ fn decode_header(data: &[u8], header_size: usize) -> Result<(u64, u8)> {
 
 fn operation_to_u8(operation: &Operation) -> u8 {
     match operation {
-        Operation::Insert => 0,
-        Operation::Update => 1,
-        Operation::Delete => 2,
+        Operation::Insert => INSERT_OPERATION,
+        Operation::Update => UPDATE_OPERATION,
+        Operation::Delete => DELETE_OPERATION,
     }
 }
 
 pub(crate) fn operation_from_u8(operation: u8) -> Result<Operation> {
     match operation {
-        0 => Ok(Operation::Insert),
-        1 => Ok(Operation::Update),
-        2 => Ok(Operation::Delete),
+        INSERT_OPERATION => Ok(Operation::Insert),
+        UPDATE_OPERATION => Ok(Operation::Update),
+        DELETE_OPERATION => Ok(Operation::Delete),
         invalid => Err(anyhow::anyhow!("Invalid log operation byte: {}", invalid)),
     }
 }
 
-fn build_index_data(data: &Value, indexed_fields: &HashSet<String>) -> Option<IndexData> {
+pub(crate) fn build_index_data(data: &Value, indexed_fields: &IndexedFields) -> Option<IndexData> {
     let id = data.get("id").and_then(|value| value.as_u64())?;
     if indexed_fields.is_empty() {
         return Some(IndexData {
             id,
-            fields: Vec::new(),
+            values: IndexedValues::default(),
         });
     }
 
-    let fields = data
+    let hashed = data
         .as_object()
         .map(|object| {
             object
                 .iter()
-                .filter(|(field, _)| indexed_fields.contains(field.as_str()))
+                .filter(|(field, _)| indexed_fields.hashed.contains(field.as_str()))
                 .map(|(field, value)| {
                     let value_string = value
                         .as_str()
@@ -282,6 +418,17 @@ fn build_index_data(data: &Value, indexed_fields: &HashSet<String>) -> Option<In
                 .collect()
         })
         .unwrap_or_default();
+    let ordered = indexed_fields
+        .ordered
+        .iter()
+        .filter_map(|(field, kind)| {
+            let key = kind.key(data.get(field)?)?;
+            Some((field.clone(), key))
+        })
+        .collect();
 
-    Some(IndexData { id, fields })
+    Some(IndexData {
+        id,
+        values: IndexedValues { hashed, ordered },
+    })
 }
@@ -1,15 +1,17 @@
 use super::encoding::{
-    decode_raw_entry, encode_compact_typed_raw_entry, encode_json_raw_entry,
+    decode_raw_entry, encode_compact_typed_raw_entry, encode_json_raw_entry, encode_owned_entry,
     encode_typed_raw_entry, RawEntry,
 };
-use super::typed::{encode_compact_typed_payload, encode_typed_payload, field_codecs_from_schema};
-use crate::config::{ModelField, ModelSchema};
+use super::schema::{LogSchema, SchemaChange, SchemaVersion};
+use super::typed::{encode_compact_typed_payload, encode_typed_payload};
+use crate::config::{ConflictPolicy, ModelField, ModelSchema};
+use crate::models::{LogEntry, Operation};
 use serde_json::{json, Value};
 
 #[test]
 fn decodes_json_raw_entry_format() -> anyhow::Result<()> {
     let encoded = encode_json_raw_entry(123, 0, br#"{"id":1}"#);
-    let decoded = decode_raw_entry(&encoded, &[])?;
+    let decoded = decode_raw_entry(&encoded, &empty_schema())?;
 
     assert_eq!(decoded.timestamp, 123);
     assert_eq!(decoded.operation, 0);
@@ -19,17 +21,17 @@ fn decodes_json_raw_entry_format() -> anyhow::Result<()> {
 
 #[test]
 fn decodes_typed_raw_entry_format() -> anyhow::Result<()> {
-    let codecs = field_codecs_from_schema(&test_schema());
+    let schema = LogSchema::initial(&test_schema());
     let value = json!({
         "id": 7,
         "email": "a@nyro.local",
         "hash_password": "hash_7",
         "created_at": 99
     });
-    let payload = encode_typed_payload(&value, &codecs)
+    let payload = encode_typed_payload(&value, schema.codecs())
         .ok_or_else(|| anyhow::anyhow!("expected typed payload"))?;
     let encoded = encode_typed_raw_entry(456, 0, &payload);
-    let decoded = decode_raw_entry(&encoded, &codecs)?;
+    let decoded = decode_raw_entry(&encoded, &schema)?;
     let decoded_value: Value = serde_json::from_slice(&decoded.data)?;
 
     assert_eq!(decoded.timestamp, 456);
@@ -40,17 +42,17 @@ fn decodes_typed_raw_entry_format() -> anyhow::Result<()> {
 
 #[test]
 fn decodes_compact_typed_raw_entry_format() -> anyhow::Result<()> {
-    let codecs = field_codecs_from_schema(&test_schema());
+    let schema = LogSchema::initial(&test_schema());
     let value = json!({
         "id": 8,
         "email": "compact@nyro.local",
         "hash_password": "hash_8",
         "created_at": 100
     });
-    let payload = encode_compact_typed_payload(&value, &codecs)
+    let payload = encode_compact_typed_payload(&value, schema.codecs())
         .ok_or_else(|| anyhow::anyhow!("expected compact typed payload"))?;
     let encoded = encode_compact_typed_raw_entry(457, 0, &payload);
-    let decoded = decode_raw_entry(&encoded, &codecs)?;
+    let decoded = decode_raw_entry(&encoded, &schema)?;
     let decoded_value: Value = serde_json::from_slice(&decoded.data)?;
 
     assert_eq!(decoded.timestamp, 457);
@@ -66,7 +68,7 @@ fn decodes_legacy_bincode_raw_entry_format() -> anyhow::Result<()> {
         operation: 1,
         data: br#"{"id":2}"#.to_vec(),
     })?;
-    let decoded = decode_raw_entry(&encoded, &[])?;
+    let decoded = decode_raw_entry(&encoded, &empty_schema())?;
 
     assert_eq!(decoded.timestamp, 789);
     assert_eq!(decoded.operation, 1);
@@ -74,6 +76,124 @@ fn decodes_legacy_bincode_raw_entry_format() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn checksummed_entries_round_trip_and_detect_corruption() -> anyhow::Result<()> {
+    let schema = LogSchema::initial(&test_schema());
+    let empty = empty_schema();
+    for (data, schema) in [
+        (
+            json!({
+                "id": 9,
+                "email": "crc@nyro.local",
+                "hash_password": "hash_9",
+                "created_at": 101
+            }),
+            &schema,
+        ),
+        (json!({ "id": 9 }), &empty),
+    ] {
+        let encoded = encode_owned_entry(
+            LogEntry {
+                timestamp: 458,
+                operation: Operation::Delete,
+                data: data.clone(),
+            },
+            schema,
+        )?;
+        assert!(encoded.data.starts_with(b"NYR4"));
+
+        let decoded = decode_raw_entry(&encoded.data, schema)?;
+        let decoded_value: Value = serde_json::from_slice(&decoded.data)?;
+        assert_eq!(decoded.timestamp, 458);
+        assert_eq!(decoded.operation, 2);
+        assert_eq!(decoded_value, data);
+
+        let mut corrupted = encoded.data.to_vec();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0x01;
+        assert!(decode_raw_entry(&corrupted, schema).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn older_versions_decode_through_their_own_codecs() -> anyhow::Result<()> {
+    let initial = LogSchema::initial(&test_schema());
+    let entry = |data: Value| LogEntry {
+        timestamp: 459,
+        operation: Operation::Insert,
+        data,
+    };
+    let old = encode_owned_entry(
+        entry(json!({
+            "id": 10,
+            "email": "old@nyro.local",
+            "hash_password": "hash_10",
+            "created_at": 102
+        })),
+        &initial,
+    )?;
+
+    let mut fields = test_schema().fields;
+    fields.remove(2);
+    fields[2].field_type = "f64".to_string();
+    fields.push(field("plan", "string"));
+    let mut versions = initial.versions().to_vec();
+    versions.push(SchemaVersion {
+        version: 2,
+        change: Some(SchemaChange::DropField {
+            name: "hash_password".to_string(),
+        }),
+        fields: vec![
+            field("id", "u64"),
+            field("email", "string"),
+            field("created_at", "u64"),
+        ],
+    });
+    versions.push(SchemaVersion {
+        version: 3,
+        change: Some(SchemaChange::RetypeField {
+            name: "created_at".to_string(),
+            field_type: "f64".to_string(),
+        }),
+        fields: fields[..3].to_vec(),
+    });
+    versions.push(SchemaVersion {
+        version: 4,
+        change: Some(SchemaChange::AddField {
+            name: "plan".to_string(),
+            field_type: "string".to_string(),
+            required: true,
+            default: json!("free"),
+        }),
+        fields,
+    });
+    let migrated = LogSchema::new(versions)?;
+
+    let decoded = decode_raw_entry(&old.data, &migrated)?;
+    let decoded_value: Value = serde_json::from_slice(&decoded.data)?;
+    assert_eq!(
+        decoded_value,
+        json!({ "id": 10, "email": "old@nyro.local", "created_at": 102.0, "plan": "free" })
+    );
+
+    let value = json!({ "id": 11, "email": "new@nyro.local", "created_at": 1.5, "plan": "pro" });
+    let new = encode_owned_entry(entry(value.clone()), &migrated)?;
+    assert_eq!(new.schema_version, 4);
+    let decoded = decode_raw_entry(&new.data, &migrated)?;
+    assert_eq!(serde_json::from_slice::<Value>(&decoded.data)?, value);
+    assert!(decode_raw_entry(&new.data, &initial).is_err());
+    Ok(())
+}
+
+fn empty_schema() -> LogSchema {
+    LogSchema::initial(&ModelSchema {
+        fields: Vec::new(),
+        on_conflict: ConflictPolicy::default(),
+        cache_bytes: None,
+    })
+}
+
 fn test_schema() -> ModelSchema {
     ModelSchema {
         fields: vec![
@@ -82,6 +202,8 @@ fn test_schema() -> ModelSchema {
             field("hash_password", "string"),
             field("created_at", "u64"),
         ],
+        on_conflict: ConflictPolicy::default(),
+        cache_bytes: None,
     }
 }
 
@@ -91,5 +213,6 @@ fn field(name: &str, field_type: &str) -> ModelField {
         field_type: field_type.to_string(),
         required: true,
         indexed: false,
+        index: None,
     }
 }
@@ -1,8 +1,12 @@
 use dashmap::DashMap;
 use parking_lot::RwLock;
 use serde_json::Value;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
+use super::row_cache::RowCache;
+
 const DENSE_GROWTH_SLACK: u64 = 1_000_000;
 const DENSE_RESIZE_CHUNK: usize = 8192;
 
@@ -16,6 +20,8 @@ pub(crate) struct EntryLocation {
 pub(crate) struct CachedEntry {
     pub(crate) timestamp: u64,
     pub(crate) operation: u8,
+    /// Schema version of `Json` and `Parsed` data.
+    pub(crate) schema_version: u32,
     pub(crate) data: CachedData,
 }
 
@@ -29,12 +35,28 @@ pub(crate) enum CachedData {
 #[derive(Clone)]
 pub(crate) struct IndexedEntry {
     pub(crate) location: EntryLocation,
-    pub(crate) cache: CachedEntry,
+    /// `None` once the row cache evicted the row; it is then read from the
+    /// log.
+    pub(crate) cache: Option<CachedEntry>,
+}
+
+impl EntryLocation {
+    pub(crate) fn frame_len(self) -> u64 {
+        4 + self.size as u64
+    }
 }
 
 pub(crate) struct PrimaryIndex {
     dense: RwLock<Vec<Option<IndexedEntry>>>,
     sparse: DashMap<u64, IndexedEntry>,
+    /// The keys of `sparse` in order, so pages of ids need no sort.
+    sparse_ids: RwLock<BTreeSet<u64>>,
+    live_bytes: AtomicU64,
+    /// Bounds the cached rows; without it every row stays cached.
+    row_cache: Option<RowCache>,
+    /// End of the log bytes known to be in the file. Rows after it are never
+    /// evicted, as they could not be read back.
+    flushed_offset: AtomicU64,
 }
 
 impl PrimaryIndex {
@@ -42,41 +64,198 @@ impl PrimaryIndex {
         Self {
             dense: RwLock::new(Vec::new()),
             sparse: DashMap::new(),
+            sparse_ids: RwLock::new(BTreeSet::new()),
+            live_bytes: AtomicU64::new(0),
+            row_cache: None,
+            flushed_offset: AtomicU64::new(0),
+        }
+    }
+
+    /// An index caching rows worth at most `budget` log bytes, or every row
+    /// when `budget` is 0.
+    pub(crate) fn with_row_cache(budget: u64) -> Self {
+        Self {
+            row_cache: (budget > 0).then(|| RowCache::new(budget)),
+            ..Self::new()
         }
     }
 
     pub(crate) fn clear(&self) {
         self.dense.write().clear();
         self.sparse.clear();
+        self.sparse_ids.write().clear();
+        self.live_bytes.store(0, Ordering::Release);
+        if let Some(row_cache) = &self.row_cache {
+            row_cache.clear();
+        }
+    }
+
+    pub(crate) fn is_bounded(&self) -> bool {
+        self.row_cache.is_some()
+    }
+
+    /// Log bytes of the cached rows, when the cache is bounded.
+    pub(crate) fn cached_bytes(&self) -> Option<u64> {
+        self.row_cache.as_ref().map(RowCache::used_bytes)
+    }
+
+    pub(crate) fn flushed_to(&self, offset: u64) {
+        self.flushed_offset.store(offset, Ordering::Release);
+    }
+
+    /// Counts a newly indexed row in the row cache and evicts rows over the
+    /// budget.
+    fn track_cached(&self, rows: &[(u64, u64, bool)]) {
+        let Some(row_cache) = &self.row_cache else {
+            return;
+        };
+        for &(id, bytes, cached) in rows {
+            if cached {
+                row_cache.admit(id, bytes);
+            } else {
+                row_cache.forget(id);
+            }
+        }
+        self.evict();
+    }
+
+    fn evict(&self) {
+        let Some(row_cache) = &self.row_cache else {
+            return;
+        };
+        let flushed_offset = self.flushed_offset.load(Ordering::Acquire);
+        let flushed =
+            |location: EntryLocation| location.offset + location.frame_len() <= flushed_offset;
+        let victims = row_cache.victims(|id| self.location(id).is_some_and(flushed));
+        if victims.is_empty() {
+            return;
+        }
+        let mut dense = self.dense.write();
+        for id in victims {
+            // Admitted again since it was picked.
+            if row_cache.is_resident(id) {
+                continue;
+            }
+            let dense_entry = usize::try_from(id)
+                .ok()
+                .and_then(|index| dense.get_mut(index))
+                .and_then(Option::as_mut);
+            // A writer may have stored a newer row that is still buffered
+            // and not yet admitted; reads of it must stay in memory.
+            if let Some(entry) = dense_entry {
+                if flushed(entry.location) {
+                    entry.cache = None;
+                }
+            } else if let Some(mut entry) = self.sparse.get_mut(&id) {
+                if flushed(entry.location) {
+                    entry.cache = None;
+                }
+            }
+        }
+    }
+
+    fn location(&self, id: u64) -> Option<EntryLocation> {
+        let dense_location = usize::try_from(id).ok().and_then(|index| {
+            self.dense
+                .read()
+                .get(index)
+                .and_then(|entry| entry.as_ref().map(|entry| entry.location))
+        });
+        dense_location.or_else(|| self.sparse.get(&id).map(|entry| entry.location))
+    }
+
+    /// Caches a row read back from the log, unless it was rewritten or moved
+    /// since `location` was read.
+    pub(crate) fn recache(&self, id: u64, location: EntryLocation, cache: CachedEntry) {
+        let mut dense = self.dense.write();
+        let dense_entry = usize::try_from(id)
+            .ok()
+            .and_then(|index| dense.get_mut(index))
+            .and_then(Option::as_mut);
+        let recached = match dense_entry {
+            Some(entry) => restore_cache(entry, location, cache),
+            None => match self.sparse.get_mut(&id) {
+                Some(mut entry) => restore_cache(&mut entry, location, cache),
+                None => false,
+            },
+        };
+        drop(dense);
+        if recached {
+            self.track_cached(&[(id, location.size as u64, true)]);
+        }
+    }
+
+    /// Bytes of log frames still referenced by the index.
+    pub(crate) fn live_bytes(&self) -> u64 {
+        self.live_bytes.load(Ordering::Acquire)
+    }
+
+    fn track_replaced(&self, inserted: EntryLocation, previous: Option<&IndexedEntry>) {
+        self.live_bytes
+            .fetch_add(inserted.frame_len(), Ordering::AcqRel);
+        if let Some(previous) = previous {
+            self.live_bytes
+                .fetch_sub(previous.location.frame_len(), Ordering::AcqRel);
+        }
     }
 
     pub(crate) fn insert(&self, id: u64, entry: IndexedEntry) {
+        let cached = (id, entry.location.size as u64, entry.cache.is_some());
+        self.insert_entry(id, entry);
+        self.track_cached(&[cached]);
+    }
+
+    fn insert_entry(&self, id: u64, entry: IndexedEntry) {
+        let location = entry.location;
         let mut dense = self.dense.write();
         let dense_len = dense.len() as u64;
         if id <= dense_len.saturating_add(DENSE_GROWTH_SLACK) {
             let Ok(index) = usize::try_from(id) else {
                 drop(dense);
-                self.sparse.insert(id, entry);
+                self.insert_sparse(id, entry);
                 return;
             };
             if index >= dense.len() {
                 let next_len = (index + 1).saturating_add(DENSE_RESIZE_CHUNK);
                 dense.resize_with(next_len, || None);
             }
-            dense[index] = Some(entry);
+            let previous = dense[index].replace(entry);
             drop(dense);
-            self.sparse.remove(&id);
+            let previous = previous.or_else(|| self.remove_sparse(id));
+            self.track_replaced(location, previous.as_ref());
             return;
         }
 
         drop(dense);
-        self.sparse.insert(id, entry);
+        let previous = self.insert_sparse(id, entry);
+        self.track_replaced(location, previous.as_ref());
+    }
+
+    fn insert_sparse(&self, id: u64, entry: IndexedEntry) -> Option<IndexedEntry> {
+        self.sparse_ids.write().insert(id);
+        self.sparse.insert(id, entry)
+    }
+
+    fn remove_sparse(&self, id: u64) -> Option<IndexedEntry> {
+        let removed = self.sparse.remove(&id).map(|(_, entry)| entry);
+        if removed.is_some() {
+            self.sparse_ids.write().remove(&id);
+        }
+        removed
     }
 
     pub(crate) fn insert_many(&self, entries: Vec<(u64, IndexedEntry)>) {
         if entries.is_empty() {
             return;
         }
+        let cached: Vec<(u64, u64, bool)> = if self.row_cache.is_some() {
+            entries
+                .iter()
+                .map(|(id, entry)| (*id, entry.location.size as u64, entry.cache.is_some()))
+                .collect()
+        } else {
+            Vec::new()
+        };
 
         let mut dense_entries = Vec::with_capacity(entries.len());
         let mut sparse_entries = Vec::new();
@@ -101,17 +280,70 @@ impl PrimaryIndex {
                 dense.resize_with(max_index.saturating_add(DENSE_RESIZE_CHUNK + 1), || None);
             }
             for (id, index, entry) in dense_entries {
-                dense[index] = Some(entry);
-                self.sparse.remove(&id);
+                let location = entry.location;
+                let previous = dense[index]
+                    .replace(entry)
+                    .or_else(|| self.remove_sparse(id));
+                self.track_replaced(location, previous.as_ref());
             }
         }
 
         for (id, entry) in sparse_entries {
-            self.sparse.insert(id, entry);
+            let location = entry.location;
+            let previous = self.insert_sparse(id, entry);
+            self.track_replaced(location, previous.as_ref());
         }
+        self.track_cached(&cached);
+    }
+
+    /// Points existing entries at new log offsets after the log was rewritten.
+    pub(crate) fn relocate_many(&self, locations: &[(u64, EntryLocation)]) {
+        let mut dense = self.dense.write();
+        for (id, location) in locations {
+            let dense_entry = usize::try_from(*id)
+                .ok()
+                .and_then(|index| dense.get_mut(index))
+                .and_then(Option::as_mut);
+            if let Some(entry) = dense_entry {
+                entry.location = *location;
+            } else if let Some(mut entry) = self.sparse.get_mut(id) {
+                entry.location = *location;
+            }
+        }
+        drop(dense);
+        let live_bytes = locations
+            .iter()
+            .map(|(_, location)| location.frame_len())
+            .sum();
+        self.live_bytes.store(live_bytes, Ordering::Release);
+    }
+
+    pub(crate) fn remove(&self, id: u64) -> Option<IndexedEntry> {
+        let dense_entry = usize::try_from(id)
+            .ok()
+            .and_then(|index| self.dense.write().get_mut(index).and_then(Option::take));
+        let removed = dense_entry.or_else(|| self.remove_sparse(id));
+        if let Some(entry) = &removed {
+            self.live_bytes
+                .fetch_sub(entry.location.frame_len(), Ordering::AcqRel);
+            if let Some(row_cache) = &self.row_cache {
+                row_cache.forget(id);
+            }
+        }
+        removed
     }
 
     pub(crate) fn get(&self, id: u64) -> Option<IndexedEntry> {
+        let entry = self.lookup(id);
+        if let (Some(row_cache), Some(entry)) = (&self.row_cache, &entry) {
+            if entry.cache.is_some() {
+                row_cache.touch(id);
+            }
+        }
+        entry
+    }
+
+    fn lookup(&self, id: u64) -> Option<IndexedEntry> {
         let Ok(index) = usize::try_from(id) else {
             return self.sparse.get(&id).map(|entry| entry.clone());
         };
@@ -123,6 +355,32 @@ impl PrimaryIndex {
         self.sparse.get(&id).map(|entry| entry.clone())
     }
 
+    pub(crate) fn locations(&self) -> Vec<(u64, EntryLocation)> {
+        let mut locations = self
+            .dense
+            .read()
+            .iter()
+            .enumerate()
+            .filter_map(|(id, entry)| entry.as_ref().map(|entry| (id as u64, entry.location)))
+            .collect::<Vec<_>>();
+        locations.extend(
+            self.sparse
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().location)),
+        );
+        locations
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        let dense = self
+            .dense
+            .read()
+            .iter()
+            .filter(|entry| entry.is_some())
+            .count();
+        dense + self.sparse.len()
+    }
+
     pub(crate) fn ids(&self) -> Vec<u64> {
         let dense_ids = self
             .dense
@@ -136,4 +394,41 @@ impl PrimaryIndex {
         ids.extend(self.sparse.iter().map(|entry| *entry.key()));
         ids
     }
+
+    /// Up to `limit` live ids greater than `after`, in ascending order. Both
+    /// halves of the index are walked in order, so a page costs `limit`
+    /// rows, not the size of the model.
+    pub(crate) fn ids_after(&self, after: Option<u64>, limit: usize) -> Vec<u64> {
+        let start = match after {
+            Some(u64::MAX) => return Vec::new(),
+            Some(id) => id + 1,
+            None => 0,
+        };
+        let mut ids = match usize::try_from(start) {
+            Ok(start) => self
+                .dense
+                .read()
+                .get(start..)
+                .unwrap_or_default()
+                .iter()
+                .zip(start as u64..)
+                .filter_map(|(entry, id)| entry.as_ref().map(|_| id))
+                .take(limit)
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        ids.extend(self.sparse_ids.read().range(start..).take(limit).copied());
+        ids.sort_unstable();
+        ids.dedup();
+        ids.truncate(limit);
+        ids
+    }
+}
+
+fn restore_cache(entry: &mut IndexedEntry, location: EntryLocation, cache: CachedEntry) -> bool {
+    if entry.cache.is_some() || entry.location.offset != location.offset {
+        return false;
+    }
+    entry.cache = Some(cache);
+    true
 }
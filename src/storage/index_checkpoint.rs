@@ -0,0 +1,335 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use super::compaction::sync_parent_dir;
+use super::encoding::{decode_entry_header, CRC32C};
+use super::index::{CachedData, CachedEntry, EntryLocation, IndexedEntry};
+use super::ordered::OrderedKey;
+use super::schema::LogSchema;
+use super::sequence::{SequenceMarker, SEQUENCE_MARKER_MAGIC, SEQUENCE_MARKER_SIZE};
+use super::LogStorage;
+
+/// Header of an index checkpoint: magic, then CRC32C of the bincode body.
+const INDEX_CHECKPOINT_MAGIC: &[u8; 4] = b"NYI2";
+const INDEX_CHECKPOINT_HEADER_SIZE: usize = INDEX_CHECKPOINT_MAGIC.len() + 4;
+/// Log bytes before the covered offset whose checksum is kept, to tell a log
+/// that was cut or swapped since.
+const TAIL_CHECKSUM_BYTES: u64 = 4096;
+const LOAD_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The ids under each value of one index.
+type Buckets<K> = Vec<(K, Vec<u64>)>;
+
+/// The indexes of a log as of `offset`. Startup loads it and replays only
+/// the frames after `offset`.
+#[derive(Serialize, Deserialize)]
+struct IndexCheckpoint {
+    offset: u64,
+    tail_checksum: u32,
+    generation: SequenceMarker,
+    last_seq: u64,
+    seq_checkpoints: Vec<(u64, u64)>,
+    schema_version: u32,
+    hashed_fields: Vec<String>,
+    ordered_fields: Vec<String>,
+    rows: Vec<CheckpointRow>,
+    hashed: Vec<(String, Buckets<String>)>,
+    ordered: Vec<(String, Buckets<OrderedKey>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointRow {
+    id: u64,
+    offset: u64,
+    size: u32,
+}
+
+pub(super) fn index_checkpoint_path(log_path: &str) -> String {
+    format!(
+        "{}.index",
+        log_path.strip_suffix(".log").unwrap_or(log_path)
+    )
+}
+
+impl LogStorage {
+    /// Log bytes covered by the newest index checkpoint.
+    pub fn checkpointed_bytes(&self) -> u64 {
+        self.checkpointed_bytes.load(Ordering::Acquire)
+    }
+
+    /// Writes the indexes to `<model>.index` unless nothing was logged since
+    /// the last checkpoint or an index is still being filled. The indexes are
+    /// copied under the writer lock and written after it is released.
+    pub fn checkpoint_indexes(&self) -> Result<bool> {
+        let _checkpointing = self.checkpoint_lock.lock();
+        let checkpoint = {
+            let mut writer = self.file.write();
+            let offset = self.current_offset.load(Ordering::Acquire);
+            if offset == self.checkpointed_bytes() || !self.index_builds.is_empty() {
+                return Ok(false);
+            }
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+            self.note_flushed();
+            self.capture_index_checkpoint(offset)?
+        };
+
+        let body = bincode::serialize(&checkpoint)?;
+        let path = index_checkpoint_path(&self.file_path);
+        let temporary = format!("{}.tmp", path);
+        let mut file = File::create(&temporary)?;
+        file.write_all(INDEX_CHECKPOINT_MAGIC)?;
+        file.write_all(&CRC32C.checksum(&body).to_le_bytes())?;
+        file.write_all(&body)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, &path)?;
+        sync_parent_dir(&path)?;
+        self.checkpointed_bytes
+            .store(checkpoint.offset, Ordering::Release);
+        Ok(true)
+    }
+
+    /// Callers hold the writer lock.
+    fn capture_index_checkpoint(&self, offset: u64) -> Result<IndexCheckpoint> {
+        let schema = self.schema();
+        let (last_seq, generation, seq_checkpoints) = self.sequence.state();
+        let (hashed_fields, ordered_fields) = indexed_field_names(&schema);
+        let rows = self
+            .index
+            .locations()
+            .into_iter()
+            .map(|(id, location)| CheckpointRow {
+                id,
+                offset: location.offset,
+                size: location.size,
+            })
+            .collect();
+        let hashed = self
+            .secondary_indices
+            .iter()
+            .map(|field_idx| {
+                let buckets = field_idx
+                    .iter()
+                    .map(|bucket| (bucket.key().clone(), bucket.value().clone()))
+                    .collect();
+                (field_idx.key().clone(), buckets)
+            })
+            .collect();
+        let ordered = self
+            .ordered_indices
+            .iter()
+            .map(|index| (index.key().clone(), index.buckets()))
+            .collect();
+        Ok(IndexCheckpoint {
+            offset,
+            tail_checksum: tail_checksum(&mut File::open(&self.file_path)?, offset)?,
+            generation,
+            last_seq,
+            seq_checkpoints,
+            schema_version: schema.version(),
+            hashed_fields,
+            ordered_fields,
+            rows,
+            hashed,
+            ordered,
+        })
+    }
+
+    /// Fills the cleared indexes from the checkpoint of this log and returns
+    /// the offset replay resumes from, or 0 without a checkpoint. A checkpoint
+    /// that does not match the log or the schema is an error, after which the
+    /// caller clears the indexes again.
+    pub(super) fn load_index_checkpoint(&self, file: &mut File, file_len: u64) -> Result<u64> {
+        let path = index_checkpoint_path(&self.file_path);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(error) => return Err(error.into()),
+        };
+        if bytes.len() < INDEX_CHECKPOINT_HEADER_SIZE || !bytes.starts_with(INDEX_CHECKPOINT_MAGIC)
+        {
+            return Err(anyhow::anyhow!("{} is not an index checkpoint", path));
+        }
+        let stored_checksum = u32::from_le_bytes(bytes[4..8].try_into()?);
+        let body = &bytes[INDEX_CHECKPOINT_HEADER_SIZE..];
+        if stored_checksum != CRC32C.checksum(body) {
+            return Err(anyhow::anyhow!("{} checksum mismatch", path));
+        }
+        let checkpoint: IndexCheckpoint = bincode::deserialize(body)?;
+
+        let schema = self.schema();
+        let (hashed_fields, ordered_fields) = indexed_field_names(&schema);
+        if checkpoint.schema_version != schema.version()
+            || checkpoint.hashed_fields != hashed_fields
+            || checkpoint.ordered_fields != ordered_fields
+        {
+            return Err(anyhow::anyhow!("indexed fields changed since {}", path));
+        }
+        if checkpoint.offset > file_len
+            || checkpoint.tail_checksum != tail_checksum(file, checkpoint.offset)?
+            || checkpoint.generation != log_generation(file)?
+        {
+            return Err(anyhow::anyhow!("{} does not match the log", path));
+        }
+
+        let mut rows = checkpoint.rows;
+        rows.sort_unstable_by_key(|row| row.offset);
+        if self.index.is_bounded() {
+            self.load_cold_rows(&path, rows, checkpoint.offset)?;
+        } else {
+            self.load_cached_rows(&path, file, rows, checkpoint.offset)?;
+        }
+
+        for (field, buckets) in checkpoint.hashed {
+            let field_idx = self.secondary_indices.entry(field.clone()).or_default();
+            for (value, ids) in buckets {
+                for &id in &ids {
+                    let mut values = self.indexed_values.entry(id).or_default();
+                    values.hashed.push((field.clone(), value.clone()));
+                }
+                field_idx.insert(value, ids);
+            }
+        }
+        for (field, buckets) in checkpoint.ordered {
+            let index = self
+                .ordered_indices
+                .get(&field)
+                .ok_or_else(|| anyhow::anyhow!("{} has no ordered index for '{}'", path, field))?;
+            for (key, ids) in &buckets {
+                for &id in ids {
+                    let mut values = self.indexed_values.entry(id).or_default();
+                    values.ordered.push((field.clone(), key.clone()));
+                }
+            }
+            index.load(buckets);
+        }
+        self.sequence.restore(
+            checkpoint.last_seq,
+            checkpoint.generation,
+            checkpoint.seq_checkpoints,
+        );
+        self.checkpointed_bytes
+            .store(checkpoint.offset, Ordering::Release);
+        Ok(checkpoint.offset)
+    }
+
+    /// Indexes checkpointed rows without reading them; the row cache reads
+    /// them on first use.
+    fn load_cold_rows(&self, path: &str, rows: Vec<CheckpointRow>, covered: u64) -> Result<()> {
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in rows {
+            let location = EntryLocation {
+                offset: row.offset,
+                size: row.size,
+            };
+            if row.offset + location.frame_len() > covered {
+                return Err(anyhow::anyhow!("{} has a row outside the log", path));
+            }
+            entries.push((
+                row.id,
+                IndexedEntry {
+                    location,
+                    cache: None,
+                },
+            ));
+        }
+        self.index.insert_many(entries);
+        Ok(())
+    }
+
+    /// Indexes checkpointed rows with their frames, read in log order.
+    fn load_cached_rows(
+        &self,
+        path: &str,
+        file: &mut File,
+        rows: Vec<CheckpointRow>,
+        covered: u64,
+    ) -> Result<()> {
+        let schema_version = self.schema().version();
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::with_capacity(LOAD_BUFFER_SIZE, &mut *file);
+        let mut read_position = 0u64;
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in rows {
+            let location = EntryLocation {
+                offset: row.offset,
+                size: row.size,
+            };
+            if row.offset < read_position || row.offset + location.frame_len() > covered {
+                return Err(anyhow::anyhow!("{} has a row outside the log", path));
+            }
+            reader.seek_relative((row.offset - read_position) as i64)?;
+            let mut frame = vec![0u8; location.frame_len() as usize];
+            reader.read_exact(&mut frame)?;
+            read_position = row.offset + location.frame_len();
+            let (timestamp, operation) = decode_entry_header(&frame[4..])?;
+            entries.push((
+                row.id,
+                IndexedEntry {
+                    location,
+                    cache: Some(CachedEntry {
+                        timestamp,
+                        operation,
+                        schema_version,
+                        data: CachedData::Encoded(Arc::from(&frame[4..])),
+                    }),
+                },
+            ));
+        }
+        self.index.insert_many(entries);
+        Ok(())
+    }
+
+    /// Drops the checkpoint of a log whose offsets or schema it no longer
+    /// matches.
+    pub(super) fn remove_index_checkpoint(&self) -> Result<()> {
+        self.checkpointed_bytes.store(0, Ordering::Release);
+        match std::fs::remove_file(index_checkpoint_path(&self.file_path)) {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Hashed and ordered index fields, in a stable order.
+fn indexed_field_names(schema: &LogSchema) -> (Vec<String>, Vec<String>) {
+    let mut hashed: Vec<String> = schema.indexed_fields.hashed.iter().cloned().collect();
+    hashed.sort();
+    let ordered = schema
+        .indexed_fields
+        .ordered
+        .iter()
+        .map(|(field, _)| field.clone())
+        .collect();
+    (hashed, ordered)
+}
+
+fn tail_checksum(file: &mut File, offset: u64) -> Result<u32> {
+    let start = offset.saturating_sub(TAIL_CHECKSUM_BYTES);
+    let mut tail = vec![0u8; (offset - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut tail)?;
+    Ok(CRC32C.checksum(&tail))
+}
+
+/// The sequence marker a compacted log opens with.
+fn log_generation(file: &mut File) -> Result<SequenceMarker> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut size_bytes = [0u8; 4];
+    if file.read_exact(&mut size_bytes).is_err()
+        || u32::from_le_bytes(size_bytes) as usize != SEQUENCE_MARKER_SIZE
+    {
+        return Ok(SequenceMarker::default());
+    }
+    let mut frame = [0u8; SEQUENCE_MARKER_SIZE];
+    file.read_exact(&mut frame)?;
+    if frame.starts_with(SEQUENCE_MARKER_MAGIC) {
+        return SequenceMarker::decode(&frame);
+    }
+    Ok(SequenceMarker::default())
+}
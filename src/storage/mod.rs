@@ -1,20 +1,30 @@
+mod backfill;
 #[doc(hidden)]
 pub mod benchmark;
+mod compaction;
 mod encoding;
 #[cfg(test)]
 mod encoding_tests;
 mod index;
+mod index_checkpoint;
+mod ordered;
 mod rebuild;
+mod replication;
+mod row_cache;
+mod schema;
+mod secondary;
+mod sequence;
+mod snapshot;
+mod transaction;
 mod typed;
 mod writer;
 
 use anyhow::Result;
-use index::{CachedData, IndexedEntry, PrimaryIndex};
+use index::{CachedData, CachedEntry, IndexedEntry, PrimaryIndex};
 use memmap2::MmapOptions;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::Deserialize;
 use serde_json::Value;
-use std::collections::HashSet;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
@@ -23,25 +33,57 @@ use std::{
     io::{BufWriter, Write},
 };
 
+pub use backfill::IndexBuildProgress;
+pub use compaction::CompactionReport;
+pub use ordered::RangeQuery;
+pub use replication::{LogChunk, ReadOnlyError};
+pub use schema::{SchemaChange, SchemaVersion};
+pub use sequence::{SequenceMarker, SequencedEntry};
+pub(crate) use snapshot::{copy_log_until, FrozenLog};
+pub use snapshot::{LogCut, LogSnapshot};
+pub(crate) use transaction::{
+    read_transaction_records, transaction_log_path, write_transaction_records, StagedFrame,
+    TransactionLog, TRANSACTION_LOG_FILE,
+};
+
 use crate::config::{LoggingConfig, ModelSchema, StorageConfig};
 use crate::models::LogEntry;
 use crate::utils::logger::Logger;
 
+use backfill::IndexBuild;
 use dashmap::DashMap;
-use encoding::{decode_raw_entry, operation_from_u8};
-use std::sync::atomic::AtomicU64;
-use typed::{field_codecs_from_schema, FieldCodec};
+use encoding::{decode_entry_header, decode_raw_entry, operation_from_u8};
+use ordered::OrderedIndex;
+use schema::LogSchema;
+use secondary::IndexedValues;
+use sequence::SequenceState;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 pub struct LogStorage {
     file: Arc<RwLock<BufWriter<File>>>,
-    mmap_file: Option<memmap2::Mmap>,
+    mmap_file: RwLock<Option<memmap2::Mmap>>,
+    /// Reads rows the row cache evicted. Swapped with the log, under its
+    /// write lock, before any location changes.
+    reader: RwLock<File>,
+    enable_mmap: bool,
+    buffer_size: usize,
     sync_on_append: bool,
-    field_codecs: Vec<FieldCodec>,
-    indexed_fields: HashSet<String>,
+    /// Swapped whole by a migration, under the writer lock.
+    schema: RwLock<Arc<LogSchema>>,
     index: Arc<PrimaryIndex>,
     pub secondary_indices: Arc<DashMap<String, DashMap<String, Vec<u64>>>>,
+    ordered_indices: DashMap<String, OrderedIndex>,
+    indexed_values: DashMap<u64, IndexedValues>,
+    /// Indexes added while the log was open and still being filled.
+    index_builds: DashMap<String, Arc<IndexBuild>>,
+    sequence: SequenceState,
+    /// Serializes index checkpoints.
+    checkpoint_lock: Mutex<()>,
+    checkpointed_bytes: AtomicU64,
     pub file_path: String,
     pub current_offset: Arc<AtomicU64>,
+    /// Set on replicas, where only replicated frames are appended.
+    read_only: AtomicBool,
 }
 
 impl LogStorage {
@@ -51,7 +93,7 @@ impl LogStorage {
         log_config: &LoggingConfig,
         schema: &ModelSchema,
     ) -> Result<Self> {
-        let file_path = format!("{}/{}.log", config.data_dir, model_name);
+        let file_path = Self::log_path(&config.data_dir, model_name);
         std::fs::create_dir_all(&config.data_dir).map_err(|e| {
             Logger::error_with_config(
                 log_config,
@@ -59,6 +101,7 @@ impl LogStorage {
             );
             e
         })?;
+        compaction::remove_stale_compaction(&file_path)?;
 
         let file = OpenOptions::new()
             .create(true)
@@ -74,31 +117,43 @@ impl LogStorage {
             })?;
 
         let writer = BufWriter::with_capacity(config.buffer_size, file);
+        let log_schema = schema::load_log_schema(&file_path, schema, log_config)?;
+        let ordered_indices = log_schema
+            .indexed_fields
+            .ordered
+            .iter()
+            .map(|(field, _)| (field.clone(), OrderedIndex::default()))
+            .collect();
 
-        let mut storage = Self {
+        let storage = Self {
             file: Arc::new(RwLock::new(writer)),
-            mmap_file: None,
+            mmap_file: RwLock::new(None),
+            reader: RwLock::new(File::open(&file_path)?),
+            enable_mmap: config.enable_mmap,
+            buffer_size: config.buffer_size,
             sync_on_append: config.sync_interval == 0,
-            field_codecs: field_codecs_from_schema(schema),
-            indexed_fields: schema
-                .fields
-                .iter()
-                .filter(|field| field.indexed && field.name != "id")
-                .map(|field| field.name.clone())
-                .collect(),
-            index: Arc::new(PrimaryIndex::new()),
+            schema: RwLock::new(Arc::new(log_schema)),
+            index: Arc::new(PrimaryIndex::with_row_cache(
+                schema.cache_bytes.unwrap_or(config.row_cache_bytes),
+            )),
             secondary_indices: Arc::new(DashMap::new()),
+            ordered_indices,
+            indexed_values: DashMap::new(),
+            index_builds: DashMap::new(),
+            sequence: SequenceState::default(),
+            checkpoint_lock: Mutex::new(()),
+            checkpointed_bytes: AtomicU64::new(0),
             file_path: file_path.clone(),
             current_offset: Arc::new(AtomicU64::new(0)),
+            read_only: AtomicBool::new(false),
         };
 
-        storage.rebuild_index()?;
-
-        if config.enable_mmap {
-            storage.setup_mmap()?;
-        }
+        storage.rebuild_index(config.strict_recovery, log_config)?;
+        storage.setup_mmap()?;
 
         let file_ref = Arc::downgrade(&storage.file);
+        let index_ref = Arc::downgrade(&storage.index);
+        let current_offset = Arc::clone(&storage.current_offset);
         let sync_log_config = log_config.clone();
         let sync_interval = config.sync_interval;
         if sync_interval > 0 {
@@ -109,17 +164,18 @@ impl LogStorage {
                             tokio::time::interval(Duration::from_millis(sync_interval));
                         loop {
                             interval.tick().await;
-                            let Some(file_clone) = file_ref.upgrade() else {
+                            let (Some(file_clone), Some(index)) =
+                                (file_ref.upgrade(), index_ref.upgrade())
+                            else {
                                 break;
                             };
                             let mut writer = file_clone.write();
-                            if let Err(e) =
-                                writer.flush().and_then(|_| writer.get_ref().sync_data())
-                            {
-                                Logger::error_with_config(
+                            match writer.flush().and_then(|_| writer.get_ref().sync_data()) {
+                                Ok(()) => index.flushed_to(current_offset.load(Ordering::Acquire)),
+                                Err(e) => Logger::error_with_config(
                                     &sync_log_config,
                                     &format!("Failed to flush storage buffer: {}", e),
-                                );
+                                ),
                             }
                         }
                     });
@@ -162,30 +218,64 @@ impl LogStorage {
         Ok(())
     }
 
-    fn setup_mmap(&mut self) -> Result<()> {
+    pub fn log_path(data_dir: &str, model_name: &str) -> String {
+        format!("{}/{}.log", data_dir, model_name)
+    }
+
+    /// Deletes the log of a dropped model with its schema versions, index
+    /// checkpoint and any unfinished compaction.
+    pub fn remove_files(data_dir: &str, model_name: &str) -> Result<()> {
+        let file_path = Self::log_path(data_dir, model_name);
+        compaction::remove_stale_compaction(&file_path)?;
+        for path in [
+            schema::schema_path(&file_path),
+            index_checkpoint::index_checkpoint_path(&file_path),
+            file_path,
+        ] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn setup_mmap(&self) -> Result<()> {
+        if !self.enable_mmap {
+            return Ok(());
+        }
+        let mut mmap_file = self.mmap_file.write();
+        *mmap_file = None;
         if Path::new(&self.file_path).exists() {
             let file = File::open(&self.file_path)?;
             if file.metadata()?.len() > 0 {
-                self.mmap_file = Some(unsafe { MmapOptions::new().map(&file)? });
+                *mmap_file = Some(unsafe { MmapOptions::new().map(&file)? });
             }
         }
         Ok(())
     }
 
     pub fn get<T: for<'de> Deserialize<'de>>(&self, id: u64) -> Result<Option<LogEntry<T>>> {
+        // Hold the reader and mmap guards before reading the location so
+        // compaction cannot swap the file between the two.
+        let reader = self.reader.read();
+        let mmap_file = self.mmap_file.read();
         if let Some(indexed_entry) = self.index.get(id) {
             let location = indexed_entry.location;
-            let raw_entry = if let Some(ref mmap) = self.mmap_file {
+            let raw_entry = if let Some(ref mmap) = *mmap_file {
                 let start = location.offset as usize;
                 let end = start + 4 + location.size as usize;
                 if end <= mmap.len() {
                     let data = &mmap[start + 4..end];
-                    decode_raw_entry(data, &self.field_codecs)?
+                    decode_raw_entry(data, &self.schema())?
                 } else {
-                    return self.decode_cached_entry(indexed_entry);
+                    let cache = self.cached_entry(&reader, id, indexed_entry)?;
+                    return self.decode_cached_entry(cache);
                 }
             } else {
-                return self.decode_cached_entry(indexed_entry);
+                let cache = self.cached_entry(&reader, id, indexed_entry)?;
+                return self.decode_cached_entry(cache);
             };
 
             let data: T = serde_json::from_slice(&raw_entry.data)?;
@@ -201,41 +291,143 @@ impl LogStorage {
         }
     }
 
+    pub(crate) fn schema(&self) -> Arc<LogSchema> {
+        Arc::clone(&self.schema.read())
+    }
+
+    /// Every schema version of this log, oldest first.
+    pub fn schema_versions(&self) -> Vec<SchemaVersion> {
+        self.schema().versions().to_vec()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.index.get(id).is_some()
+    }
+
     pub fn get_value(&self, id: u64) -> Result<Option<Value>> {
+        Ok(self.get_value_with_offset(id)?.map(|(_, value)| value))
+    }
+
+    /// The row with the log offset of its frame, which changes whenever the
+    /// row is written or the log compacted.
+    pub fn get_value_with_offset(&self, id: u64) -> Result<Option<(u64, Value)>> {
+        let reader = self.reader.read();
         self.index
             .get(id)
-            .map(|entry| match &entry.cache.data {
-                CachedData::Json(data) => serde_json::from_slice(data).map_err(Into::into),
-                CachedData::Encoded(data) => {
-                    let raw_entry = decode_raw_entry(data, &self.field_codecs)?;
-                    serde_json::from_slice(&raw_entry.data).map_err(Into::into)
-                }
-                CachedData::Parsed(data) => Ok((**data).clone()),
+            .map(|entry| {
+                let offset = entry.location.offset;
+                let cache = self.cached_entry(&reader, id, entry)?;
+                Ok((offset, self.cached_value(&cache)?))
             })
             .transpose()
     }
 
+    /// Log bytes of the rows held by a bounded row cache.
+    pub fn cached_bytes(&self) -> Option<u64> {
+        self.index.cached_bytes()
+    }
+
+    /// Marks everything written so far as readable from the file. Callers
+    /// hold the writer lock and have just flushed it.
+    pub(super) fn note_flushed(&self) {
+        self.index
+            .flushed_to(self.current_offset.load(Ordering::Acquire));
+    }
+
+    /// The cached row of `entry`, read back from the log and cached again
+    /// if the row cache evicted it. `reader` is the guarded read handle.
+    fn cached_entry(&self, reader: &File, id: u64, entry: IndexedEntry) -> Result<CachedEntry> {
+        if let Some(cache) = entry.cache {
+            return Ok(cache);
+        }
+        let location = entry.location;
+        let mut data = vec![0u8; location.size as usize];
+        read_exact_at(reader, &mut data, location.offset + 4)?;
+        let (timestamp, operation) = decode_entry_header(&data)?;
+        let cache = CachedEntry {
+            timestamp,
+            operation,
+            schema_version: self.schema().version(),
+            data: CachedData::Encoded(Arc::from(data)),
+        };
+        self.index.recache(id, location, cache.clone());
+        Ok(cache)
+    }
+
+    /// The row held by `cache`, read under the current schema version.
+    fn cached_value(&self, cache: &CachedEntry) -> Result<Value> {
+        let schema = self.schema();
+        let mut value = match &cache.data {
+            CachedData::Json(data) => serde_json::from_slice(data)?,
+            CachedData::Encoded(data) => {
+                let raw_entry = decode_raw_entry(data, &schema)?;
+                return serde_json::from_slice(&raw_entry.data).map_err(Into::into);
+            }
+            CachedData::Parsed(data) => (**data).clone(),
+        };
+        if let Value::Object(row) = &mut value {
+            schema.upgrade(cache.schema_version, row);
+        }
+        Ok(value)
+    }
+
     fn decode_cached_entry<T: for<'de> Deserialize<'de>>(
         &self,
-        indexed_entry: IndexedEntry,
+        cache: CachedEntry,
     ) -> Result<Option<LogEntry<T>>> {
-        let data = match &indexed_entry.cache.data {
-            CachedData::Json(data) => serde_json::from_slice(data)?,
-            CachedData::Encoded(data) => {
-                let raw_entry = decode_raw_entry(data, &self.field_codecs)?;
-                serde_json::from_slice(&raw_entry.data)?
+        let data = match &cache.data {
+            CachedData::Json(data) if self.schema().is_current(cache.schema_version) => {
+                serde_json::from_slice(data)?
             }
-            CachedData::Parsed(data) => serde_json::from_value((**data).clone())?,
+            _ => serde_json::from_value(self.cached_value(&cache)?)?,
         };
-        let operation = operation_from_u8(indexed_entry.cache.operation)?;
+        let operation = operation_from_u8(cache.operation)?;
 
         Ok(Some(LogEntry {
-            timestamp: indexed_entry.cache.timestamp,
+            timestamp: cache.timestamp,
             operation,
             data,
         }))
     }
 
+    /// Rows with ids greater than `after` in id order, plus the cursor for the
+    /// next page if more rows follow.
+    pub fn page(&self, after: Option<u64>, limit: usize) -> Result<(Vec<Value>, Option<u64>)> {
+        let limit = limit.max(1);
+        let mut ids = self.index.ids_after(after, limit + 1);
+        let next_after = if ids.len() > limit {
+            ids.truncate(limit);
+            ids.last().copied()
+        } else {
+            None
+        };
+        let mut rows = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(entry) = self.get::<Value>(id)? {
+                rows.push(entry.data);
+            }
+        }
+        Ok((rows, next_after))
+    }
+
+    /// Visits every live row without collecting them first.
+    pub fn scan(&self, mut visit: impl FnMut(Value)) -> Result<()> {
+        for id in self.index.ids() {
+            if let Some(entry) = self.get::<Value>(id)? {
+                visit(entry.data);
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_all<T: for<'de> Deserialize<'de>>(&self) -> Result<Vec<LogEntry<T>>> {
         let mut results = Vec::new();
         for id in self.index.ids() {
@@ -246,3 +438,27 @@ impl LogStorage {
         Ok(results)
     }
 }
+
+#[cfg(unix)]
+pub(super) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(super) fn read_exact_at(
+    file: &File,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,362 @@
+use anyhow::Result;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use super::LogStorage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OrderedKind {
+    Unsigned,
+    Signed,
+    Float,
+    Text,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum OrderedKey {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    Text(String),
+}
+
+/// Bounds for a range query over one field. Operands are coerced to the
+/// field's type, so numeric fields also accept numeric strings.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RangeQuery {
+    pub gt: Option<Value>,
+    pub gte: Option<Value>,
+    pub lt: Option<Value>,
+    pub lte: Option<Value>,
+    pub between: Option<(Value, Value)>,
+    pub prefix: Option<String>,
+}
+
+pub(crate) struct KeyRange {
+    lower: Bound<OrderedKey>,
+    upper: Bound<OrderedKey>,
+    prefix: Option<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct OrderedIndex {
+    entries: RwLock<BTreeMap<OrderedKey, Vec<u64>>>,
+}
+
+impl OrderedKind {
+    pub(crate) fn from_field_type(field_type: &str) -> Option<Self> {
+        match field_type {
+            "u64" | "u32" => Some(Self::Unsigned),
+            "i64" => Some(Self::Signed),
+            "f64" => Some(Self::Float),
+            "string" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn key(self, value: &Value) -> Option<OrderedKey> {
+        match (self, value) {
+            (Self::Unsigned, Value::Number(number)) => number.as_u64().map(OrderedKey::Unsigned),
+            (Self::Signed, Value::Number(number)) => number.as_i64().map(OrderedKey::Signed),
+            (Self::Float, Value::Number(number)) => number.as_f64().map(OrderedKey::Float),
+            (Self::Unsigned, Value::String(text)) => text.parse().ok().map(OrderedKey::Unsigned),
+            (Self::Signed, Value::String(text)) => text.parse().ok().map(OrderedKey::Signed),
+            (Self::Float, Value::String(text)) => text.parse().ok().map(OrderedKey::Float),
+            (Self::Text, Value::String(text)) => Some(OrderedKey::Text(text.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl OrderedKey {
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Unsigned(_) => 0,
+            Self::Signed(_) => 1,
+            Self::Float(_) => 2,
+            Self::Text(_) => 3,
+        }
+    }
+}
+
+impl From<&OrderedKey> for Value {
+    fn from(key: &OrderedKey) -> Self {
+        match key {
+            OrderedKey::Unsigned(value) => Value::from(*value),
+            OrderedKey::Signed(value) => Value::from(*value),
+            OrderedKey::Float(value) => Value::from(*value),
+            OrderedKey::Text(value) => Value::from(value.as_str()),
+        }
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Unsigned(left), Self::Unsigned(right)) => left.cmp(right),
+            (Self::Signed(left), Self::Signed(right)) => left.cmp(right),
+            (Self::Float(left), Self::Float(right)) => left.total_cmp(right),
+            (Self::Text(left), Self::Text(right)) => left.cmp(right),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl RangeQuery {
+    pub fn equal_to(value: Value) -> Self {
+        Self {
+            gte: Some(value.clone()),
+            lte: Some(value),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn resolve(&self, field: &str, kind: OrderedKind) -> Result<KeyRange> {
+        let key = |value: &Value| {
+            kind.key(value).ok_or_else(|| {
+                anyhow::anyhow!("Invalid range operand for field '{}': {}", field, value)
+            })
+        };
+        let lower = single_bound(
+            field,
+            "lower",
+            [
+                self.gt
+                    .as_ref()
+                    .map(|value| key(value).map(Bound::Excluded)),
+                self.gte
+                    .as_ref()
+                    .map(|value| key(value).map(Bound::Included)),
+                self.between
+                    .as_ref()
+                    .map(|(low, _)| key(low).map(Bound::Included)),
+            ],
+        )?;
+        let upper = single_bound(
+            field,
+            "upper",
+            [
+                self.lt
+                    .as_ref()
+                    .map(|value| key(value).map(Bound::Excluded)),
+                self.lte
+                    .as_ref()
+                    .map(|value| key(value).map(Bound::Included)),
+                self.between
+                    .as_ref()
+                    .map(|(_, high)| key(high).map(Bound::Included)),
+            ],
+        )?;
+
+        if self.prefix.is_some() && kind != OrderedKind::Text {
+            return Err(anyhow::anyhow!(
+                "Prefix queries need a string field, '{}' is not one",
+                field
+            ));
+        }
+        if self.prefix.is_none()
+            && matches!(lower, Bound::Unbounded)
+            && matches!(upper, Bound::Unbounded)
+        {
+            return Err(anyhow::anyhow!(
+                "Range query on '{}' needs one of gt, gte, lt, lte, between or prefix",
+                field
+            ));
+        }
+
+        let lower = match (&self.prefix, lower) {
+            (Some(prefix), Bound::Unbounded) => Bound::Included(OrderedKey::Text(prefix.clone())),
+            (_, lower) => lower,
+        };
+        Ok(KeyRange {
+            lower,
+            upper,
+            prefix: self.prefix.clone(),
+        })
+    }
+}
+
+fn single_bound<const N: usize>(
+    field: &str,
+    side: &str,
+    candidates: [Option<Result<Bound<OrderedKey>>>; N],
+) -> Result<Bound<OrderedKey>> {
+    let mut bound = Bound::Unbounded;
+    for candidate in candidates.into_iter().flatten() {
+        if !matches!(bound, Bound::Unbounded) {
+            return Err(anyhow::anyhow!(
+                "Range query on '{}' has more than one {} bound",
+                field,
+                side
+            ));
+        }
+        bound = candidate?;
+    }
+    Ok(bound)
+}
+
+impl KeyRange {
+    pub(crate) fn contains(&self, key: &OrderedKey) -> bool {
+        let above_lower = match &self.lower {
+            Bound::Included(lower) => key >= lower,
+            Bound::Excluded(lower) => key > lower,
+            Bound::Unbounded => true,
+        };
+        let below_upper = match &self.upper {
+            Bound::Included(upper) => key <= upper,
+            Bound::Excluded(upper) => key < upper,
+            Bound::Unbounded => true,
+        };
+        let has_prefix = match (&self.prefix, key) {
+            (Some(prefix), OrderedKey::Text(text)) => text.starts_with(prefix.as_str()),
+            (Some(_), _) => false,
+            (None, _) => true,
+        };
+        above_lower && below_upper && has_prefix
+    }
+
+    /// `BTreeMap::range` panics on inverted bounds, so those are answered here.
+    fn is_empty(&self) -> bool {
+        match (&self.lower, &self.upper) {
+            (Bound::Included(lower), Bound::Included(upper)) => lower > upper,
+            (Bound::Included(lower), Bound::Excluded(upper))
+            | (Bound::Excluded(lower), Bound::Included(upper))
+            | (Bound::Excluded(lower), Bound::Excluded(upper)) => lower >= upper,
+            _ => false,
+        }
+    }
+}
+
+impl OrderedIndex {
+    pub(crate) fn insert(&self, key: OrderedKey, id: u64) {
+        self.entries.write().entry(key).or_default().push(id);
+    }
+
+    pub(crate) fn remove(&self, key: &OrderedKey, id: u64) {
+        let mut entries = self.entries.write();
+        if let Some(ids) = entries.get_mut(key) {
+            ids.retain(|indexed_id| *indexed_id != id);
+            if ids.is_empty() {
+                entries.remove(key);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.write().clear();
+    }
+
+    pub(crate) fn buckets(&self) -> Vec<(OrderedKey, Vec<u64>)> {
+        self.entries
+            .read()
+            .iter()
+            .map(|(key, ids)| (key.clone(), ids.clone()))
+            .collect()
+    }
+
+    pub(crate) fn load(&self, buckets: Vec<(OrderedKey, Vec<u64>)>) {
+        *self.entries.write() = buckets.into_iter().collect();
+    }
+
+    pub(crate) fn bucket_sizes(&self) -> Vec<(Value, usize)> {
+        self.entries
+            .read()
+            .iter()
+            .map(|(key, ids)| (Value::from(key), ids.len()))
+            .collect()
+    }
+
+    pub(crate) fn ids(&self, range: &KeyRange) -> Vec<u64> {
+        if range.is_empty() {
+            return Vec::new();
+        }
+
+        let entries = self.entries.read();
+        let mut ids = Vec::new();
+        for (key, bucket) in entries.range((range.lower.clone(), range.upper.clone())) {
+            if let Some(prefix) = &range.prefix {
+                match key {
+                    OrderedKey::Text(text) if text.starts_with(prefix.as_str()) => {}
+                    OrderedKey::Text(text) if text.as_str() > prefix.as_str() => break,
+                    _ => continue,
+                }
+            }
+            ids.extend_from_slice(bucket);
+        }
+        ids
+    }
+}
+
+impl LogStorage {
+    /// Whether `field` has an ordered index that is complete.
+    pub fn has_ordered_index(&self, field: &str) -> bool {
+        self.ordered_indices.contains_key(field) && self.index_ready(field)
+    }
+
+    /// Ids matching `query` in the field's ordered index, or `None` if the
+    /// field has no complete one or the operands do not fit its type.
+    pub fn ordered_index_ids(&self, field: &str, query: &RangeQuery) -> Option<Vec<u64>> {
+        if !self.index_ready(field) {
+            return None;
+        }
+        let index = self.ordered_indices.get(field)?;
+        let kind = self.schema().field_kinds.get(field).copied()?;
+        let range = query.resolve(field, kind).ok()?;
+        Some(index.ids(&range))
+    }
+
+    /// Answers from the field's ordered index when it has one and falls back
+    /// to a full scan otherwise.
+    pub fn query_range(&self, field: &str, query: &RangeQuery) -> Result<Vec<Value>> {
+        let kind = self
+            .schema()
+            .field_kinds
+            .get(field)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Field '{}' does not support range queries", field))?;
+        let range = query.resolve(field, kind)?;
+
+        let index = self
+            .ordered_indices
+            .get(field)
+            .filter(|_| self.index_ready(field));
+        if let Some(index) = index {
+            let mut results = Vec::new();
+            for id in index.ids(&range) {
+                if let Some(value) = self.get_value(id)? {
+                    results.push(value);
+                }
+            }
+            return Ok(results);
+        }
+
+        Ok(self
+            .get_all::<Value>()?
+            .into_iter()
+            .map(|entry| entry.data)
+            .filter(|data| {
+                data.get(field)
+                    .and_then(|value| kind.key(value))
+                    .is_some_and(|key| range.contains(&key))
+            })
+            .collect())
+    }
+}
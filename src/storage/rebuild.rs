@@ -1,79 +1,384 @@
 use anyhow::Result;
 use serde_json::Value;
-use std::io::Read;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::sync::atomic::Ordering;
 
-use crate::storage::encoding::decode_raw_entry;
+use crate::config::LoggingConfig;
+
+use crate::storage::encoding::{
+    build_index_data, decode_raw_entry, CHECKSUMMED_ENTRY_MAGIC, CRC32C, DELETE_OPERATION,
+    ENTRY_MAGICS,
+};
 use crate::storage::index::{CachedData, CachedEntry, EntryLocation, IndexedEntry};
-use crate::storage::LogStorage;
+use crate::storage::sequence::{SequenceMarker, SEQUENCE_MARKER_MAGIC};
+use crate::storage::transaction::{
+    committed_transactions, split_transaction_frame, TRANSACTION_FRAME_MAGIC,
+};
+use crate::storage::{read_exact_at, LogStorage};
+use crate::utils::logger::Logger;
+
+const REBUILD_BUFFER_SIZE: usize = 1024 * 1024;
 
 impl LogStorage {
-    pub(super) fn rebuild_index(&self) -> Result<()> {
+    /// Replays the log into the in-memory indexes, starting after the index
+    /// checkpoint when one matches the log. An incomplete or corrupt final
+    /// frame is treated as a torn write and truncated. Corruption followed by
+    /// intact frames is refused when `strict` is set and otherwise skipped up
+    /// to the next intact frame, since a corrupt size prefix cannot be
+    /// trusted to find it.
+    pub(super) fn rebuild_index(&self, strict: bool, log_config: &LoggingConfig) -> Result<()> {
         if !Path::new(&self.file_path).exists() {
             return Ok(());
         }
 
-        let mut file = std::fs::File::open(&self.file_path)?;
+        let mut file = File::open(&self.file_path)?;
+        let file_len = file.metadata()?.len();
         self.current_offset.store(0, Ordering::Release);
-        self.index.clear();
-        self.secondary_indices.clear();
+        self.clear_indexes();
+        self.index.flushed_to(file_len);
+        let checkpoint_offset = match self.load_index_checkpoint(&mut file, file_len) {
+            Ok(offset) => offset,
+            Err(error) => {
+                Logger::warn_with_config(
+                    log_config,
+                    &format!("Ignoring index checkpoint of {}: {}", self.file_path, error),
+                );
+                self.clear_indexes();
+                0
+            }
+        };
+        if checkpoint_offset > 0 {
+            Logger::info_with_config(
+                log_config,
+                &format!(
+                    "Loaded index checkpoint of {}; replaying {} bytes after it",
+                    self.file_path,
+                    file_len - checkpoint_offset
+                ),
+            );
+        }
+        file.seek(SeekFrom::Start(checkpoint_offset))?;
+        let mut reader = BufReader::with_capacity(REBUILD_BUFFER_SIZE, file);
 
-        loop {
-            let offset = self.current_offset.load(Ordering::Acquire);
+        let mut offset = checkpoint_offset;
+        let mut buffer = Vec::new();
+        let mut committed = None;
+        while offset < file_len {
+            let remaining = file_len - offset;
+            if remaining < 4 {
+                self.truncate_torn_tail(offset, file_len, log_config)?;
+                break;
+            }
             let mut size_bytes = [0u8; 4];
-            match file.read_exact(&mut size_bytes) {
-                Ok(_) => self.rebuild_entry_indexes(&mut file, offset, size_bytes)?,
-                Err(_) => break,
+            reader.read_exact(&mut size_bytes)?;
+            let size = u32::from_le_bytes(size_bytes);
+            let frame_len = 4 + size as u64;
+            if frame_len > remaining {
+                let Some(next) = self.next_intact_frame(offset + 1, file_len)? else {
+                    // Frames of the older formats carry no checksum, so they
+                    // cannot be resumed from; keep them rather than truncate.
+                    if self.next_fitting_frame(offset + 1, file_len)?.is_some() {
+                        return Err(anyhow::anyhow!(
+                            "Corrupt length at offset {} in {}; the frames after it have no checksum to resume from",
+                            offset,
+                            self.file_path
+                        ));
+                    }
+                    self.truncate_torn_tail(offset, file_len, log_config)?;
+                    break;
+                };
+                if strict {
+                    return Err(anyhow::anyhow!(
+                        "Corrupt length at offset {} in {}",
+                        offset,
+                        self.file_path
+                    ));
+                }
+                self.warn_skipped(offset, next, "length", log_config);
+                reader.seek(SeekFrom::Start(next))?;
+                offset = next;
+                continue;
             }
+            buffer.resize(size as usize, 0);
+            reader.read_exact(&mut buffer)?;
+
+            let seq_before = self.sequence.last_seq();
+            self.sequence.note_checkpoint(seq_before, offset);
+            if let Err(error) = self.rebuild_frame(offset, size, &buffer, &mut committed) {
+                if frame_len == remaining || is_zero_filled(&mut reader)? {
+                    self.truncate_torn_tail(offset, file_len, log_config)?;
+                    break;
+                }
+                if strict {
+                    return Err(anyhow::anyhow!(
+                        "Corrupt log entry at offset {} in {}: {}",
+                        offset,
+                        self.file_path,
+                        error
+                    ));
+                }
+                // The size prefix is outside the checksum, so the frame after
+                // it is only trusted when intact. Frames of the older formats
+                // carry no checksum and are reached by the size alone.
+                let next = if self.is_intact_frame_at(offset + frame_len, file_len)? {
+                    offset + frame_len
+                } else {
+                    self.next_intact_frame(offset + 1, file_len)?
+                        .unwrap_or(offset + frame_len)
+                };
+                Logger::warn_with_config(
+                    log_config,
+                    &format!(
+                        "Skipping corrupt log entry at offset {} in {}: {}",
+                        offset, self.file_path, error
+                    ),
+                );
+                if next != offset + frame_len {
+                    self.warn_skipped(offset, next, "entry", log_config);
+                }
+                reader.seek(SeekFrom::Start(next))?;
+                offset = next;
+                continue;
+            }
+            offset += frame_len;
         }
+
+        self.current_offset.store(offset, Ordering::SeqCst);
         Ok(())
     }
 
-    fn rebuild_entry_indexes(
+    /// The offset of the first frame at or after `from` whose checksum
+    /// matches, found by scanning for frame magics.
+    fn next_intact_frame(&self, from: u64, file_len: u64) -> Result<Option<u64>> {
+        self.next_frame(
+            from,
+            file_len,
+            |magic| magic == CHECKSUMMED_ENTRY_MAGIC || magic == TRANSACTION_FRAME_MAGIC,
+            |offset| self.is_intact_frame_at(offset, file_len),
+        )
+    }
+
+    /// The offset of the first frame of any format at or after `from` whose
+    /// size fits in the file. Anything but zeros or the rest of a torn frame
+    /// usually holds one.
+    fn next_fitting_frame(&self, from: u64, file_len: u64) -> Result<Option<u64>> {
+        self.next_frame(
+            from,
+            file_len,
+            |magic| {
+                ENTRY_MAGICS.iter().any(|entry_magic| magic == *entry_magic)
+                    || magic == TRANSACTION_FRAME_MAGIC
+                    || magic == SEQUENCE_MARKER_MAGIC
+            },
+            |offset| {
+                let file = File::open(&self.file_path)?;
+                let mut size_bytes = [0u8; 4];
+                read_exact_at(&file, &mut size_bytes, offset)?;
+                let size = u32::from_le_bytes(size_bytes) as u64;
+                Ok(size >= 4 && offset + 4 + size <= file_len)
+            },
+        )
+    }
+
+    /// Scans from `from` for a frame whose magic passes `is_magic` and whose
+    /// offset passes `accept`.
+    fn next_frame(
+        &self,
+        from: u64,
+        file_len: u64,
+        is_magic: impl Fn(&[u8]) -> bool,
+        mut accept: impl FnMut(u64) -> Result<bool>,
+    ) -> Result<Option<u64>> {
+        let mut file = File::open(&self.file_path)?;
+        // A candidate frame starts 4 bytes before its magic.
+        let mut position = from + 4;
+        file.seek(SeekFrom::Start(position))?;
+        let mut chunk = vec![0u8; REBUILD_BUFFER_SIZE];
+        while position + 4 <= file_len {
+            let read = (file_len - position).min(REBUILD_BUFFER_SIZE as u64) as usize;
+            file.read_exact(&mut chunk[..read])?;
+            for start in 0..read.saturating_sub(3) {
+                if !is_magic(&chunk[start..start + 4]) {
+                    continue;
+                }
+                let frame_offset = position + start as u64 - 4;
+                if accept(frame_offset)? {
+                    return Ok(Some(frame_offset));
+                }
+            }
+            if position + (read as u64) >= file_len {
+                break;
+            }
+            // Magics split across chunks are found in the next one.
+            position += read as u64 - 3;
+            file.seek(SeekFrom::Start(position))?;
+        }
+        Ok(None)
+    }
+
+    fn is_intact_frame_at(&self, offset: u64, file_len: u64) -> Result<bool> {
+        if offset + 4 > file_len {
+            return Ok(false);
+        }
+        let file = File::open(&self.file_path)?;
+        let mut size_bytes = [0u8; 4];
+        read_exact_at(&file, &mut size_bytes, offset)?;
+        let size = u32::from_le_bytes(size_bytes) as u64;
+        if size < 8 || offset + 4 + size > file_len {
+            return Ok(false);
+        }
+        let mut payload = vec![0u8; size as usize];
+        read_exact_at(&file, &mut payload, offset + 4)?;
+        Ok(is_intact_frame(&payload))
+    }
+
+    fn warn_skipped(&self, offset: u64, next: u64, what: &str, log_config: &LoggingConfig) {
+        Logger::warn_with_config(
+            log_config,
+            &format!(
+                "Skipping {} bytes after a corrupt {} at offset {} in {}",
+                next - offset,
+                what,
+                offset,
+                self.file_path
+            ),
+        );
+    }
+
+    fn clear_indexes(&self) {
+        self.index.clear();
+        self.secondary_indices.clear();
+        for index in self.ordered_indices.iter() {
+            index.clear();
+        }
+        self.indexed_values.clear();
+        self.sequence.reset();
+        self.checkpointed_bytes.store(0, Ordering::Release);
+    }
+
+    fn truncate_torn_tail(
         &self,
-        file: &mut std::fs::File,
         offset: u64,
-        size_bytes: [u8; 4],
+        file_len: u64,
+        log_config: &LoggingConfig,
     ) -> Result<()> {
-        let size = u32::from_le_bytes(size_bytes);
-        let mut buffer = vec![0u8; size as usize];
-        file.read_exact(&mut buffer)?;
+        Logger::warn_with_config(
+            log_config,
+            &format!(
+                "Truncating torn tail of {}: {} bytes after offset {}",
+                self.file_path,
+                file_len - offset,
+                offset
+            ),
+        );
+        let file = OpenOptions::new().write(true).open(&self.file_path)?;
+        file.set_len(offset)?;
+        file.sync_all()?;
+        Ok(())
+    }
 
-        let raw_entry = decode_raw_entry(&buffer, &self.field_codecs)?;
-        let data: Value = serde_json::from_slice(&raw_entry.data)?;
-        if let Some(id) = data.get("id").and_then(|value| value.as_u64()) {
-            self.index.insert(
-                id,
-                IndexedEntry {
-                    location: EntryLocation { offset, size },
-                    cache: CachedEntry {
-                        timestamp: raw_entry.timestamp,
-                        operation: raw_entry.operation,
-                        data: CachedData::Json(std::sync::Arc::from(raw_entry.data)),
-                    },
-                },
-            );
-            self.rebuild_secondary_indexes(id, &data);
+    /// Transaction frames are replayed only if the shared transaction log
+    /// holds their commit record; the log is read on the first one met. A
+    /// sequence marker restores numbering after a compaction.
+    fn rebuild_frame(
+        &self,
+        offset: u64,
+        size: u32,
+        buffer: &[u8],
+        committed: &mut Option<HashSet<u64>>,
+    ) -> Result<()> {
+        if buffer.starts_with(SEQUENCE_MARKER_MAGIC) {
+            self.sequence.apply_marker(SequenceMarker::decode(buffer)?);
+            return Ok(());
+        }
+        if !buffer.starts_with(TRANSACTION_FRAME_MAGIC) {
+            return self.rebuild_entry_indexes(offset, size, buffer);
         }
 
-        self.current_offset
-            .fetch_add(4 + size as u64, Ordering::SeqCst);
+        let (transaction_id, inner_frames) = split_transaction_frame(buffer)?;
+        let committed = match committed {
+            Some(committed) => committed,
+            None => {
+                let data_dir = Path::new(&self.file_path)
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."));
+                committed.insert(committed_transactions(data_dir)?)
+            }
+        };
+        if !committed.contains(&transaction_id) {
+            return Ok(());
+        }
+        for (inner_offset, inner_size, inner_frame) in inner_frames {
+            self.rebuild_entry_indexes(offset + inner_offset, inner_size, inner_frame)?;
+        }
         Ok(())
     }
 
-    fn rebuild_secondary_indexes(&self, id: u64, data: &Value) {
-        if let Some(obj) = data.as_object() {
-            for (field, value) in obj {
-                if self.indexed_fields.contains(field.as_str()) {
-                    let value_str = value
-                        .as_str()
-                        .map(str::to_owned)
-                        .unwrap_or_else(|| value.to_string());
-                    let field_idx = self.secondary_indices.entry(field.clone()).or_default();
-                    field_idx.entry(value_str).or_default().push(id);
+    fn rebuild_entry_indexes(&self, offset: u64, size: u32, buffer: &[u8]) -> Result<()> {
+        let schema = self.schema();
+        let raw_entry = decode_raw_entry(buffer, &schema)?;
+        self.sequence.replayed();
+        let data: Value = serde_json::from_slice(&raw_entry.data)?;
+        if let Some(id) = data.get("id").and_then(|value| value.as_u64()) {
+            match raw_entry.operation {
+                DELETE_OPERATION => {
+                    self.remove_secondary_indexes(id);
+                    self.index.remove(id);
+                }
+                operation => {
+                    self.index.insert(
+                        id,
+                        IndexedEntry {
+                            location: EntryLocation { offset, size },
+                            cache: Some(CachedEntry {
+                                timestamp: raw_entry.timestamp,
+                                operation,
+                                schema_version: schema.version(),
+                                data: CachedData::Json(std::sync::Arc::from(raw_entry.data)),
+                            }),
+                        },
+                    );
+                    self.rebuild_secondary_indexes(id, &data);
                 }
             }
         }
+        Ok(())
+    }
+
+    fn rebuild_secondary_indexes(&self, id: u64, data: &Value) {
+        if let Some(index_data) = build_index_data(data, &self.schema().indexed_fields) {
+            self.reindex_secondary(id, index_data.values);
+        }
+    }
+}
+
+/// Whether `payload` is an entry or transaction frame whose CRC32C matches.
+/// Both keep the checksum of the bytes after it right after the magic.
+fn is_intact_frame(payload: &[u8]) -> bool {
+    if payload.len() < 8
+        || !(payload.starts_with(CHECKSUMMED_ENTRY_MAGIC)
+            || payload.starts_with(TRANSACTION_FRAME_MAGIC))
+    {
+        return false;
+    }
+    let stored = u32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    stored == CRC32C.checksum(&payload[8..])
+}
+
+/// Pre-allocated but unwritten blocks read back as zeros after a crash, so a
+/// corrupt frame followed only by zeros is still a torn tail.
+fn is_zero_filled(reader: &mut impl Read) -> Result<bool> {
+    let mut chunk = [0u8; 8192];
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        if chunk[..read].iter().any(|byte| *byte != 0) {
+            return Ok(false);
+        }
     }
 }
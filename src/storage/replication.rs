@@ -0,0 +1,290 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::config::LoggingConfig;
+use crate::models::LogEntry;
+
+use super::compaction::sync_parent_dir;
+use super::encoding::{build_index_data, decode_raw_entry, EncodedEntry, RawEntry};
+use super::index::{CachedData, CachedEntry};
+use super::sequence::{SequenceMarker, SEQUENCE_MARKER_MAGIC};
+use super::transaction::{
+    committed_transactions, split_transaction_frame, TRANSACTION_FRAME_MAGIC,
+};
+use super::LogStorage;
+
+/// Whole frames of a model log starting at `offset`, read together with the
+/// log end and the compaction they belong to.
+#[derive(Debug, Clone, Default)]
+pub struct LogChunk {
+    /// Epoch of the primary that served the chunk.
+    pub epoch: u64,
+    pub offset: u64,
+    pub end: u64,
+    pub generation: SequenceMarker,
+    /// Schema version of the log when the chunk was read; every frame in it
+    /// decodes with that version's history.
+    pub schema_version: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Returned by writes on a node that replicates from a primary.
+#[derive(Debug)]
+pub struct ReadOnlyError;
+
+impl std::fmt::Display for ReadOnlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "This node is a read-only replica; send writes to the primary"
+        )
+    }
+}
+
+impl std::error::Error for ReadOnlyError {}
+
+/// A replicated entry decoded ahead of [`LogStorage::apply_log_chunk`].
+pub(crate) struct ReplicatedEntry {
+    offset: u64,
+    encoded: EncodedEntry,
+    pub(crate) entry: LogEntry<Value>,
+}
+
+impl LogStorage {
+    pub(crate) fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Release);
+    }
+
+    /// Replicas only change their logs through replication.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    pub(super) fn ensure_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Err(ReadOnlyError.into());
+        }
+        Ok(())
+    }
+
+    /// Reads whole frames from `offset`, stopping before `max_bytes` is
+    /// exceeded unless a single frame is larger. An offset past the end comes
+    /// back empty, for the caller to notice from `end`.
+    pub fn read_log_chunk(&self, offset: u64, max_bytes: usize) -> Result<LogChunk> {
+        let (end, generation, schema_version, file) = {
+            let mut writer = self.file.write();
+            writer.flush()?;
+            self.note_flushed();
+            (
+                self.current_offset.load(Ordering::Acquire),
+                self.sequence_marker(),
+                self.schema().version(),
+                File::open(&self.file_path)?,
+            )
+        };
+        let mut chunk = LogChunk {
+            epoch: 0,
+            offset,
+            end,
+            generation,
+            schema_version,
+            bytes: Vec::new(),
+        };
+        if offset >= end {
+            return Ok(chunk);
+        }
+
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut position = offset;
+        while position + 4 <= end {
+            let mut size_bytes = [0u8; 4];
+            reader.read_exact(&mut size_bytes)?;
+            let frame_len = 4 + u32::from_le_bytes(size_bytes) as u64;
+            if position + frame_len > end
+                || (!chunk.bytes.is_empty()
+                    && chunk.bytes.len() as u64 + frame_len > max_bytes as u64)
+            {
+                break;
+            }
+            chunk.bytes.extend_from_slice(&size_bytes);
+            let start = chunk.bytes.len();
+            chunk.bytes.resize(start + frame_len as usize - 4, 0);
+            reader.read_exact(&mut chunk.bytes[start..])?;
+            position += frame_len;
+        }
+        Ok(chunk)
+    }
+
+    /// Decodes the entries of frames shipped from a primary. Transaction
+    /// frames count only once their commit record has been replicated, and
+    /// frames that don't decode are kept in the log but not indexed, as a
+    /// lenient rebuild would.
+    pub(crate) fn decode_log_chunk(
+        &self,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<Vec<ReplicatedEntry>> {
+        let mut entries = Vec::new();
+        let mut committed: Option<HashSet<u64>> = None;
+        let mut position = 0usize;
+        while position < bytes.len() {
+            let size = bytes
+                .get(position..position + 4)
+                .map(|size| u32::from_le_bytes(size.try_into().unwrap_or_default()) as usize)
+                .ok_or_else(|| anyhow::anyhow!("Replicated chunk ends inside a frame"))?;
+            let frame = bytes
+                .get(position + 4..position + 4 + size)
+                .ok_or_else(|| anyhow::anyhow!("Replicated chunk ends inside a frame"))?;
+            let frame_offset = offset + position as u64;
+            position += 4 + size;
+
+            if frame.starts_with(SEQUENCE_MARKER_MAGIC) {
+                continue;
+            }
+            if !frame.starts_with(TRANSACTION_FRAME_MAGIC) {
+                entries.extend(self.decode_replicated_entry(frame_offset, frame));
+                continue;
+            }
+            let Ok((transaction_id, inner_frames)) = split_transaction_frame(frame) else {
+                continue;
+            };
+            let committed = match &mut committed {
+                Some(committed) => committed,
+                None => {
+                    let data_dir = Path::new(&self.file_path)
+                        .parent()
+                        .unwrap_or_else(|| Path::new("."));
+                    committed.insert(committed_transactions(data_dir)?)
+                }
+            };
+            if !committed.contains(&transaction_id) {
+                continue;
+            }
+            for (inner_offset, _, inner_frame) in inner_frames {
+                entries
+                    .extend(self.decode_replicated_entry(frame_offset + inner_offset, inner_frame));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn decode_replicated_entry(&self, offset: u64, frame: &[u8]) -> Option<ReplicatedEntry> {
+        let schema = self.schema();
+        let RawEntry {
+            timestamp,
+            operation,
+            data,
+        } = decode_raw_entry(frame, &schema).ok()?;
+        let value: Value = serde_json::from_slice(&data).ok()?;
+        let entry = LogEntry {
+            timestamp,
+            operation: super::encoding::operation_from_u8(operation).ok()?,
+            data: value,
+        };
+        let data: Arc<[u8]> = Arc::from(frame);
+        Some(ReplicatedEntry {
+            offset,
+            encoded: EncodedEntry {
+                size: data.len() as u32,
+                schema_version: schema.version(),
+                index_data: build_index_data(&entry.data, &schema.indexed_fields),
+                cache_entry: CachedEntry {
+                    timestamp,
+                    operation,
+                    schema_version: schema.version(),
+                    data: CachedData::Encoded(Arc::clone(&data)),
+                },
+                data,
+            },
+            entry,
+        })
+    }
+
+    /// Appends the frames of `chunk`, which must start at the end of the
+    /// local log, and indexes `entries` decoded from them. A chunk served
+    /// under an epoch older than the local `epoch` may hold writes the
+    /// current primary never made, so it is refused. Returns the sequence
+    /// number of the first entry.
+    pub(crate) fn apply_log_chunk(
+        &self,
+        chunk: &LogChunk,
+        epoch: u64,
+        entries: Vec<ReplicatedEntry>,
+    ) -> Result<u64> {
+        if chunk.epoch < epoch {
+            return Err(anyhow::anyhow!(
+                "Replicated chunk is from epoch {} but the local epoch is {}",
+                chunk.epoch,
+                epoch
+            ));
+        }
+        let (offset, bytes) = (chunk.offset, chunk.bytes.as_slice());
+        let mut writer = self.file.write();
+        let local_end = self.current_offset.load(Ordering::Acquire);
+        if local_end != offset {
+            return Err(anyhow::anyhow!(
+                "Replicated chunk starts at {} but the local log ends at {}",
+                offset,
+                local_end
+            ));
+        }
+        if offset == 0 && bytes.len() >= 4 {
+            let size = u32::from_le_bytes(bytes[..4].try_into()?) as usize;
+            if let Some(marker) = bytes.get(4..4 + size) {
+                if marker.starts_with(SEQUENCE_MARKER_MAGIC) {
+                    self.sequence.apply_marker(SequenceMarker::decode(marker)?);
+                }
+            }
+        }
+        writer.write_all(bytes)?;
+        writer.flush()?;
+        if self.sync_on_append {
+            writer.get_ref().sync_data()?;
+        }
+        self.current_offset
+            .store(offset + bytes.len() as u64, Ordering::SeqCst);
+        self.note_flushed();
+        let count = entries.len() as u64;
+        for replicated in &entries {
+            self.insert_indexes(replicated.offset, &replicated.encoded);
+        }
+        Ok(self.sequence.advance(offset, count))
+    }
+
+    /// Swaps in a complete copy of the primary's log, after the primary
+    /// compacted it or the local log no longer lines up with it.
+    pub(crate) fn replace_log(
+        &self,
+        replacement: &Path,
+        strict: bool,
+        log_config: &LoggingConfig,
+    ) -> Result<()> {
+        let mut writer = self.file.write();
+        let mut reader = self.reader.write();
+        *self.mmap_file.write() = None;
+        std::fs::rename(replacement, &self.file_path)?;
+        sync_parent_dir(&self.file_path)?;
+        *reader = File::open(&self.file_path)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .read(true)
+            .open(&self.file_path)?;
+        let replaced = std::mem::replace(
+            &mut *writer,
+            BufWriter::with_capacity(self.buffer_size, file),
+        );
+        // Anything still buffered belongs to the log that was replaced.
+        let _ = replaced.into_parts();
+        self.remove_index_checkpoint()?;
+        self.rebuild_index(strict, log_config)?;
+        self.setup_mmap()?;
+        Ok(())
+    }
+}
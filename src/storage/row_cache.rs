@@ -0,0 +1,121 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Which rows of a primary index keep their data in memory, within a budget
+/// of log frame bytes. Rows are evicted in CLOCK order: a sweep passes over
+/// rows read since the previous sweep once before evicting them.
+pub(crate) struct RowCache {
+    budget: u64,
+    used: AtomicU64,
+    resident: DashMap<u64, Resident>,
+    /// Resident ids in sweep order. Ids evicted or removed meanwhile are
+    /// dropped when the sweep reaches them.
+    ring: Mutex<VecDeque<u64>>,
+}
+
+struct Resident {
+    bytes: u64,
+    referenced: AtomicBool,
+}
+
+impl RowCache {
+    pub(crate) fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            used: AtomicU64::new(0),
+            resident: DashMap::new(),
+            ring: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn used_bytes(&self) -> u64 {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn is_resident(&self, id: u64) -> bool {
+        self.resident.contains_key(&id)
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut ring = self.ring.lock();
+        ring.clear();
+        self.resident.clear();
+        self.used.store(0, Ordering::Release);
+    }
+
+    /// Counts the row `id` as cached with `bytes` of data.
+    pub(crate) fn admit(&self, id: u64, bytes: u64) {
+        // The shard lock is released before the ring is locked; sweeps take
+        // them the other way round.
+        let newly_resident = match self.resident.entry(id) {
+            Entry::Occupied(mut resident) => {
+                let previous = std::mem::replace(&mut resident.get_mut().bytes, bytes);
+                resident.get().referenced.store(true, Ordering::Relaxed);
+                self.used.fetch_sub(previous, Ordering::AcqRel);
+                false
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(Resident {
+                    bytes,
+                    referenced: AtomicBool::new(false),
+                });
+                true
+            }
+        };
+        self.used.fetch_add(bytes, Ordering::AcqRel);
+        if newly_resident {
+            let mut ring = self.ring.lock();
+            ring.push_back(id);
+            if ring.len() > 2 * self.resident.len() + 1024 {
+                let mut seen = HashSet::with_capacity(self.resident.len());
+                ring.retain(|id| self.resident.contains_key(id) && seen.insert(*id));
+            }
+        }
+    }
+
+    pub(crate) fn touch(&self, id: u64) {
+        if let Some(resident) = self.resident.get(&id) {
+            resident.referenced.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn forget(&self, id: u64) {
+        if let Some((_, resident)) = self.resident.remove(&id) {
+            self.used.fetch_sub(resident.bytes, Ordering::AcqRel);
+        }
+    }
+
+    /// Picks rows to evict until the cached bytes fit the budget. Rows that
+    /// `evictable` refuses stay cached until a later sweep.
+    pub(crate) fn victims(&self, evictable: impl Fn(u64) -> bool) -> Vec<u64> {
+        let mut victims = Vec::new();
+        if self.used_bytes() <= self.budget {
+            return victims;
+        }
+        let mut ring = self.ring.lock();
+        // Two passes give every referenced row its second chance.
+        let mut remaining = 2 * ring.len();
+        while self.used_bytes() > self.budget && remaining > 0 {
+            remaining -= 1;
+            let Some(id) = ring.pop_front() else {
+                break;
+            };
+            let keep = match self.resident.get(&id) {
+                None => continue,
+                Some(resident) => resident.referenced.swap(false, Ordering::Relaxed),
+            };
+            if keep || !evictable(id) {
+                ring.push_back(id);
+                continue;
+            }
+            if let Some((_, resident)) = self.resident.remove(&id) {
+                self.used.fetch_sub(resident.bytes, Ordering::AcqRel);
+                victims.push(id);
+            }
+        }
+        victims
+    }
+}
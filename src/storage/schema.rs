@@ -0,0 +1,380 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::config::{IndexKind, LoggingConfig, ModelField, ModelSchema};
+use crate::utils::logger::Logger;
+
+use super::compaction::sync_parent_dir;
+use super::ordered::{OrderedIndex, OrderedKind};
+use super::secondary::IndexedFields;
+use super::typed::{field_codecs_from_fields, FieldCodec};
+use super::LogStorage;
+
+/// One version of a model's fields. Every version after the first records
+/// the change that produced it, which is how older rows are read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaVersion {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change: Option<SchemaChange>,
+    pub fields: Vec<ModelField>,
+}
+
+/// A change to a model's fields that leaves its log untouched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SchemaChange {
+    /// Older rows read `default`, or go without the field when it is null.
+    AddField {
+        name: String,
+        #[serde(rename = "type")]
+        field_type: String,
+        #[serde(default)]
+        required: bool,
+        #[serde(default)]
+        default: Value,
+    },
+    DropField {
+        name: String,
+    },
+    /// Widens a numeric field; older integers are read as floats after a
+    /// change to `f64`.
+    RetypeField {
+        name: String,
+        #[serde(rename = "type")]
+        field_type: String,
+    },
+    /// Indexes a field; rows logged earlier are indexed in the background.
+    AddIndex {
+        name: String,
+        #[serde(default)]
+        kind: IndexKind,
+    },
+    DropIndex {
+        name: String,
+    },
+}
+
+impl SchemaChange {
+    fn upgrade(&self, row: &mut Map<String, Value>) {
+        match self {
+            Self::AddField { name, default, .. } => {
+                if !default.is_null() && !row.contains_key(name) {
+                    row.insert(name.clone(), default.clone());
+                }
+            }
+            Self::DropField { name } => {
+                row.remove(name);
+            }
+            Self::RetypeField { name, field_type } if field_type == "f64" => {
+                if let Some(value) = row.get_mut(name).filter(|value| !value.is_f64()) {
+                    if let Some(number) = value.as_f64().and_then(Number::from_f64) {
+                        *value = Value::Number(number);
+                    }
+                }
+            }
+            Self::RetypeField { .. } | Self::AddIndex { .. } | Self::DropIndex { .. } => {}
+        }
+    }
+
+    fn changes_index(&self) -> bool {
+        matches!(self, Self::AddIndex { .. } | Self::DropIndex { .. })
+    }
+}
+
+/// Every schema version of a log, with the codecs that read entries written
+/// under each. Entries without a version stamp belong to version 1.
+pub(crate) struct LogSchema {
+    versions: Vec<SchemaVersion>,
+    codecs: Vec<Vec<FieldCodec>>,
+    pub(crate) indexed_fields: IndexedFields,
+    pub(crate) field_kinds: HashMap<String, OrderedKind>,
+}
+
+impl LogSchema {
+    pub(crate) fn new(versions: Vec<SchemaVersion>) -> Result<Self> {
+        if versions.is_empty() {
+            return Err(anyhow::anyhow!("A schema needs at least one version"));
+        }
+        for (position, version) in versions.iter().enumerate() {
+            if version.version as usize != position + 1 {
+                return Err(anyhow::anyhow!(
+                    "Schema version {} is out of order",
+                    version.version
+                ));
+            }
+        }
+        Ok(Self::build(versions))
+    }
+
+    /// A log that has only ever had `schema`.
+    pub(crate) fn initial(schema: &ModelSchema) -> Self {
+        Self::build(vec![SchemaVersion {
+            version: 1,
+            change: None,
+            fields: schema.fields.clone(),
+        }])
+    }
+
+    fn build(versions: Vec<SchemaVersion>) -> Self {
+        let current = &versions[versions.len() - 1].fields;
+        let indexed_fields = IndexedFields::from_fields(current);
+        let field_kinds = current
+            .iter()
+            .filter_map(|field| {
+                let kind = OrderedKind::from_field_type(&field.field_type)?;
+                Some((field.name.clone(), kind))
+            })
+            .collect();
+        Self {
+            codecs: versions
+                .iter()
+                .map(|version| field_codecs_from_fields(&version.fields))
+                .collect(),
+            versions,
+            indexed_fields,
+            field_kinds,
+        }
+    }
+
+    pub(crate) fn version(&self) -> u32 {
+        self.versions.len() as u32
+    }
+
+    pub(crate) fn versions(&self) -> &[SchemaVersion] {
+        &self.versions
+    }
+
+    pub(crate) fn fields(&self) -> &[ModelField] {
+        &self.versions[self.versions.len() - 1].fields
+    }
+
+    pub(crate) fn codecs(&self) -> &[FieldCodec] {
+        &self.codecs[self.codecs.len() - 1]
+    }
+
+    pub(crate) fn codecs_for(&self, version: u32) -> Result<&[FieldCodec]> {
+        version
+            .checked_sub(1)
+            .and_then(|position| self.codecs.get(position as usize))
+            .map(Vec::as_slice)
+            .ok_or_else(|| anyhow::anyhow!("Unknown schema version {}", version))
+    }
+
+    pub(crate) fn is_current(&self, version: u32) -> bool {
+        version == self.version()
+    }
+
+    /// Brings a row written under `version` up to the current version.
+    pub(crate) fn upgrade(&self, version: u32, row: &mut Map<String, Value>) {
+        for later in self.versions.iter().skip(version as usize) {
+            if let Some(change) = &later.change {
+                change.upgrade(row);
+            }
+        }
+    }
+
+    /// [`LogSchema::upgrade`] for a row in JSON text.
+    pub(crate) fn upgrade_json(&self, version: u32, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_current(version) {
+            return Ok(data);
+        }
+        let mut row: Map<String, Value> = serde_json::from_slice(&data)?;
+        self.upgrade(version, &mut row);
+        serde_json::to_vec(&row).map_err(Into::into)
+    }
+}
+
+/// Where the versions of the log at `log_path` are kept once it has more
+/// than one.
+pub(super) fn schema_path(log_path: &str) -> String {
+    format!(
+        "{}.schema.json",
+        log_path.strip_suffix(".log").unwrap_or(log_path)
+    )
+}
+
+/// The persisted versions of a log, or `schema` alone before its first
+/// migration. Index settings of the newest version follow `schema` as long
+/// as its fields still line up with it and no index was added or dropped
+/// through a migration.
+pub(super) fn load_log_schema(
+    log_path: &str,
+    schema: &ModelSchema,
+    log_config: &LoggingConfig,
+) -> Result<LogSchema> {
+    let path = schema_path(log_path);
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(LogSchema::initial(schema));
+        }
+        Err(error) => return Err(error.into()),
+    };
+    let mut versions: Vec<SchemaVersion> = serde_json::from_slice(&bytes)
+        .map_err(|error| anyhow::anyhow!("Failed to parse {}: {}", path, error))?;
+    let indexes_migrated = versions.iter().any(|version| {
+        version
+            .change
+            .as_ref()
+            .is_some_and(SchemaChange::changes_index)
+    });
+    if let Some(current) = versions.last_mut() {
+        if same_layout(&current.fields, &schema.fields) {
+            if !indexes_migrated {
+                current.fields = schema.fields.clone();
+            } else if !same_indexes(&current.fields, &schema.fields) {
+                Logger::warn_with_config(
+                    log_config,
+                    &format!(
+                        "Indexes configured for {} differ from its schema version {}; using the indexes in {}",
+                        log_path, current.version, path
+                    ),
+                );
+            }
+        } else {
+            Logger::warn_with_config(
+                log_config,
+                &format!(
+                    "Fields configured for {} differ from its schema version {}; using the fields in {}",
+                    log_path, current.version, path
+                ),
+            );
+        }
+    }
+    LogSchema::new(versions)
+}
+
+pub(super) fn save_versions(log_path: &str, versions: &[SchemaVersion]) -> Result<()> {
+    let path = schema_path(log_path);
+    let temporary = format!("{}.tmp", path);
+    let mut file = std::fs::File::create(&temporary)?;
+    file.write_all(&serde_json::to_vec_pretty(versions)?)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, &path)?;
+    sync_parent_dir(&path)
+}
+
+fn same_indexes(left: &[ModelField], right: &[ModelField]) -> bool {
+    left.iter()
+        .zip(right)
+        .all(|(left, right)| left.index_kind() == right.index_kind())
+}
+
+/// Fields that encode the same way: equal names and types in equal order.
+fn same_layout(left: &[ModelField], right: &[ModelField]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .all(|(left, right)| left.name == right.name && left.field_type == right.field_type)
+}
+
+impl LogStorage {
+    /// Makes `fields` the next schema version, reached from the current one
+    /// by `change`. Logged rows keep their encoding and are upgraded when
+    /// read. Returns every version.
+    pub fn add_schema_version(
+        &self,
+        change: SchemaChange,
+        fields: Vec<ModelField>,
+    ) -> Result<Vec<SchemaVersion>> {
+        self.ensure_writable()?;
+        let mut writer = self.file.write();
+        writer.flush()?;
+        let mut versions = self.schema().versions().to_vec();
+        versions.push(SchemaVersion {
+            version: versions.len() as u32 + 1,
+            change: Some(change),
+            fields,
+        });
+        self.replace_schema(versions)?;
+        Ok(self.schema_versions())
+    }
+
+    /// Takes the versions of a primary, which must extend the local ones.
+    /// Returns whether anything changed.
+    pub fn adopt_schema_versions(&self, versions: Vec<SchemaVersion>) -> Result<bool> {
+        let _writer = self.file.write();
+        let current = self.schema();
+        if versions.len() <= current.versions().len() {
+            return Ok(false);
+        }
+        let diverged = current
+            .versions()
+            .iter()
+            .zip(&versions)
+            .any(|(local, primary)| local.change != primary.change);
+        if diverged {
+            return Err(anyhow::anyhow!(
+                "Schema history of {} differs from the primary's",
+                self.file_path
+            ));
+        }
+        self.replace_schema(versions)?;
+        Ok(true)
+    }
+
+    /// Persists `versions` and switches to them. Callers hold the writer lock,
+    /// so every later write indexes newly indexed fields; the rows before
+    /// them wait for [`LogStorage::backfill_indexes`].
+    fn replace_schema(&self, versions: Vec<SchemaVersion>) -> Result<()> {
+        let schema = LogSchema::new(versions)?;
+        save_versions(&self.file_path, schema.versions())?;
+        self.remove_index_checkpoint()?;
+        let previous = std::mem::replace(&mut *self.schema.write(), Arc::new(schema));
+        let current = self.schema();
+
+        let mut removed = HashSet::new();
+        for field in &previous.indexed_fields.hashed {
+            if !current.indexed_fields.hashed.contains(field) {
+                self.secondary_indices.remove(field);
+                removed.insert(field.clone());
+            }
+        }
+        for (field, _) in &previous.indexed_fields.ordered {
+            if !current
+                .indexed_fields
+                .ordered
+                .iter()
+                .any(|(name, _)| name == field)
+            {
+                self.ordered_indices.remove(field);
+                removed.insert(field.clone());
+            }
+        }
+        if !removed.is_empty() {
+            for field in &removed {
+                self.index_builds.remove(field);
+            }
+            self.indexed_values.retain(|_, values| {
+                values.hashed.retain(|(field, _)| !removed.contains(field));
+                values.ordered.retain(|(field, _)| !removed.contains(field));
+                !values.is_empty()
+            });
+        }
+
+        for field in &current.indexed_fields.hashed {
+            if !previous.indexed_fields.hashed.contains(field) {
+                self.start_index_build(field);
+            }
+        }
+        for (field, _) in &current.indexed_fields.ordered {
+            if !previous
+                .indexed_fields
+                .ordered
+                .iter()
+                .any(|(name, _)| name == field)
+            {
+                self.ordered_indices
+                    .insert(field.clone(), OrderedIndex::default());
+                self.start_index_build(field);
+            }
+        }
+        Ok(())
+    }
+}
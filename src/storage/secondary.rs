@@ -0,0 +1,173 @@
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::config::{IndexKind, ModelField};
+
+use super::ordered::{OrderedKey, OrderedKind};
+use super::LogStorage;
+
+/// The fields of a model that carry a secondary index, split by index kind.
+#[derive(Default)]
+pub(crate) struct IndexedFields {
+    pub(crate) hashed: HashSet<String>,
+    pub(crate) ordered: Vec<(String, OrderedKind)>,
+}
+
+/// The values an id is currently indexed under.
+#[derive(Clone, Default)]
+pub(crate) struct IndexedValues {
+    pub(crate) hashed: Vec<(String, String)>,
+    pub(crate) ordered: Vec<(String, OrderedKey)>,
+}
+
+impl IndexedFields {
+    pub(crate) fn from_fields(fields: &[ModelField]) -> Self {
+        let mut indexed_fields = Self::default();
+        for field in fields.iter().filter(|field| field.name != "id") {
+            match field.index_kind() {
+                Some(IndexKind::Hash) => {
+                    indexed_fields.hashed.insert(field.name.clone());
+                }
+                Some(IndexKind::Ordered) => {
+                    if let Some(kind) = OrderedKind::from_field_type(&field.field_type) {
+                        indexed_fields.ordered.push((field.name.clone(), kind));
+                    }
+                }
+                None => {}
+            }
+        }
+        indexed_fields
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hashed.is_empty() && self.ordered.is_empty()
+    }
+}
+
+impl IndexedValues {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hashed.is_empty() && self.ordered.is_empty()
+    }
+}
+
+impl LogStorage {
+    /// Moves `id` out of the buckets of its previously indexed values and into
+    /// the buckets for `values`. Unchanged values are left alone, so repeated
+    /// writes of the same value never duplicate the id inside a bucket.
+    pub(super) fn reindex_secondary(&self, id: u64, values: IndexedValues) {
+        if self.schema().indexed_fields.is_empty() {
+            return;
+        }
+
+        let previous = self
+            .indexed_values
+            .get(&id)
+            .map(|values| values.clone())
+            .unwrap_or_default();
+        for (field, value) in &previous.hashed {
+            if !contains_value(&values.hashed, field, value) {
+                self.remove_from_bucket(field, value, id);
+            }
+        }
+        for (field, value) in &values.hashed {
+            if !contains_value(&previous.hashed, field, value) {
+                let field_idx = self.secondary_indices.entry(field.clone()).or_default();
+                field_idx.entry(value.clone()).or_default().push(id);
+            }
+        }
+        for (field, key) in &previous.ordered {
+            if !contains_value(&values.ordered, field, key) {
+                if let Some(index) = self.ordered_indices.get(field) {
+                    index.remove(key, id);
+                }
+            }
+        }
+        for (field, key) in &values.ordered {
+            if !contains_value(&previous.ordered, field, key) {
+                if let Some(index) = self.ordered_indices.get(field) {
+                    index.insert(key.clone(), id);
+                }
+            }
+        }
+
+        if values.is_empty() {
+            self.indexed_values.remove(&id);
+        } else {
+            self.indexed_values.insert(id, values);
+        }
+    }
+
+    /// Ids in the hash index bucket for `value`, or `None` if `field` has no
+    /// complete hash index.
+    pub fn hash_index_ids(&self, field: &str, value: &str) -> Option<Vec<u64>> {
+        if !self.schema().indexed_fields.hashed.contains(field) || !self.index_ready(field) {
+            return None;
+        }
+        let ids = self
+            .secondary_indices
+            .get(field)
+            .and_then(|field_idx| field_idx.get(value).map(|ids| ids.clone()))
+            .unwrap_or_default();
+        Some(ids)
+    }
+
+    /// Row counts per distinct value of an indexed field, read from the index
+    /// buckets. `None` if `field` has no secondary index.
+    pub fn index_bucket_sizes(&self, field: &str) -> Option<Vec<(Value, usize)>> {
+        if !self.index_ready(field) {
+            return None;
+        }
+        if let Some(index) = self.ordered_indices.get(field) {
+            return Some(index.bucket_sizes());
+        }
+        if !self.schema().indexed_fields.hashed.contains(field) {
+            return None;
+        }
+        // Hash buckets are keyed by the raw string or the JSON text of the value.
+        let is_text = self.schema().field_kinds.get(field) == Some(&OrderedKind::Text);
+        let Some(field_idx) = self.secondary_indices.get(field) else {
+            return Some(Vec::new());
+        };
+        let sizes = field_idx
+            .iter()
+            .map(|bucket| {
+                let key = if is_text {
+                    Value::from(bucket.key().as_str())
+                } else {
+                    serde_json::from_str(bucket.key())
+                        .unwrap_or_else(|_| Value::from(bucket.key().as_str()))
+                };
+                (key, bucket.value().len())
+            })
+            .collect();
+        Some(sizes)
+    }
+
+    pub(super) fn remove_secondary_indexes(&self, id: u64) {
+        if let Some((_, previous)) = self.indexed_values.remove(&id) {
+            for (field, value) in &previous.hashed {
+                self.remove_from_bucket(field, value, id);
+            }
+            for (field, key) in &previous.ordered {
+                if let Some(index) = self.ordered_indices.get(field) {
+                    index.remove(key, id);
+                }
+            }
+        }
+    }
+
+    fn remove_from_bucket(&self, field: &str, value: &str, id: u64) {
+        if let Some(field_idx) = self.secondary_indices.get(field) {
+            field_idx.remove_if_mut(value, |_, ids| {
+                ids.retain(|indexed_id| *indexed_id != id);
+                ids.is_empty()
+            });
+        }
+    }
+}
+
+fn contains_value<T: PartialEq>(values: &[(String, T)], field: &str, value: &T) -> bool {
+    values
+        .iter()
+        .any(|(indexed_field, indexed_value)| indexed_field == field && indexed_value == value)
+}
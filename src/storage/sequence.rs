@@ -0,0 +1,308 @@
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::models::LogEntry;
+
+use super::encoding::{decode_raw_entry, operation_from_u8, RawEntry, CRC32C};
+use super::transaction::{
+    committed_transactions, split_transaction_frame, TRANSACTION_FRAME_MAGIC,
+};
+use super::LogStorage;
+
+/// First frame of a compacted log: magic, CRC32C of the rest, `base`,
+/// `compacted_through` and `compacted_at`.
+pub(super) const SEQUENCE_MARKER_MAGIC: &[u8; 4] = b"NYS1";
+pub(super) const SEQUENCE_MARKER_SIZE: usize = SEQUENCE_MARKER_MAGIC.len() + 4 + 3 * 8;
+const CHECKPOINT_INTERVAL: u64 = 4096;
+const SCAN_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Where numbering resumes after a compaction. The entries kept by the
+/// compaction are numbered from `base + 1`; the history up to
+/// `compacted_through` can no longer be replayed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SequenceMarker {
+    pub base: u64,
+    pub compacted_through: u64,
+    /// UNIX millis of the compaction.
+    pub compacted_at: u64,
+}
+
+/// A log entry with its sequence number.
+#[derive(Clone, Debug)]
+pub struct SequencedEntry {
+    pub seq: u64,
+    pub entry: LogEntry<Value>,
+}
+
+/// Every entry gets the next sequence number when it is written, or replayed
+/// on open. Sparse checkpoints map numbers back to frame offsets so reads of
+/// recent entries don't scan the whole log.
+#[derive(Default)]
+pub(super) struct SequenceState {
+    last_seq: AtomicU64,
+    marker: RwLock<SequenceMarker>,
+    checkpoints: Mutex<Vec<(u64, u64)>>,
+}
+
+impl SequenceMarker {
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(SEQUENCE_MARKER_SIZE);
+        payload.extend_from_slice(SEQUENCE_MARKER_MAGIC);
+        payload.extend_from_slice(&[0; 4]);
+        payload.extend_from_slice(&self.base.to_le_bytes());
+        payload.extend_from_slice(&self.compacted_through.to_le_bytes());
+        payload.extend_from_slice(&self.compacted_at.to_le_bytes());
+        let checksum = CRC32C.checksum(&payload[8..]);
+        payload[4..8].copy_from_slice(&checksum.to_le_bytes());
+        payload
+    }
+
+    pub(super) fn decode(payload: &[u8]) -> Result<Self> {
+        if payload.len() != SEQUENCE_MARKER_SIZE {
+            return Err(anyhow::anyhow!("Corrupt sequence marker"));
+        }
+        let stored_checksum = u32::from_le_bytes(payload[4..8].try_into()?);
+        if stored_checksum != CRC32C.checksum(&payload[8..]) {
+            return Err(anyhow::anyhow!("Sequence marker checksum mismatch"));
+        }
+        Ok(Self {
+            base: u64::from_le_bytes(payload[8..16].try_into()?),
+            compacted_through: u64::from_le_bytes(payload[16..24].try_into()?),
+            compacted_at: u64::from_le_bytes(payload[24..32].try_into()?),
+        })
+    }
+}
+
+impl SequenceState {
+    pub(super) fn reset(&self) {
+        self.last_seq.store(0, Ordering::SeqCst);
+        *self.marker.write() = SequenceMarker::default();
+        self.checkpoints.lock().clear();
+    }
+
+    /// `(last_seq, marker, checkpoints)`, for an index checkpoint.
+    pub(super) fn state(&self) -> (u64, SequenceMarker, Vec<(u64, u64)>) {
+        (
+            self.last_seq(),
+            *self.marker.read(),
+            self.checkpoints.lock().clone(),
+        )
+    }
+
+    pub(super) fn restore(
+        &self,
+        last_seq: u64,
+        marker: SequenceMarker,
+        checkpoints: Vec<(u64, u64)>,
+    ) {
+        self.last_seq.store(last_seq, Ordering::SeqCst);
+        *self.marker.write() = marker;
+        *self.checkpoints.lock() = checkpoints;
+    }
+
+    pub(super) fn apply_marker(&self, marker: SequenceMarker) {
+        self.last_seq.store(marker.base, Ordering::SeqCst);
+        *self.marker.write() = marker;
+    }
+
+    /// After a compaction the old checkpoints point into the replaced file.
+    pub(super) fn compacted(&self, marker: SequenceMarker) {
+        *self.marker.write() = marker;
+        self.checkpoints.lock().clear();
+    }
+
+    /// Log bytes taken by the marker frame, which is not garbage.
+    pub(super) fn marker_bytes(&self) -> u64 {
+        if *self.marker.read() == SequenceMarker::default() {
+            0
+        } else {
+            4 + SEQUENCE_MARKER_SIZE as u64
+        }
+    }
+
+    pub(super) fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Acquire)
+    }
+
+    pub(super) fn replayed(&self) {
+        self.last_seq.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Numbers `count` entries whose frames start at `offset` and returns the
+    /// first number. Callers hold the writer lock, so numbers follow log order.
+    pub(super) fn advance(&self, offset: u64, count: u64) -> u64 {
+        let seq_before = self.last_seq.fetch_add(count, Ordering::AcqRel);
+        self.note_checkpoint(seq_before, offset);
+        seq_before + 1
+    }
+
+    pub(super) fn note_checkpoint(&self, seq_before: u64, offset: u64) {
+        let mut checkpoints = self.checkpoints.lock();
+        let due = checkpoints
+            .last()
+            .map_or(seq_before >= CHECKPOINT_INTERVAL, |(seq, _)| {
+                seq_before >= seq + CHECKPOINT_INTERVAL
+            });
+        if due {
+            checkpoints.push((seq_before, offset));
+        }
+    }
+
+    /// The latest checkpoint at or before `after_seq`, as `(seq, offset)`.
+    fn start_for(&self, after_seq: u64) -> (u64, u64) {
+        let checkpoints = self.checkpoints.lock();
+        let position = checkpoints.partition_point(|(seq, _)| *seq <= after_seq);
+        position
+            .checked_sub(1)
+            .map(|position| checkpoints[position])
+            .unwrap_or((0, 0))
+    }
+}
+
+impl LogStorage {
+    /// Sequence number of the newest entry in the log.
+    pub fn last_seq(&self) -> u64 {
+        self.sequence.last_seq()
+    }
+
+    pub fn sequence_marker(&self) -> SequenceMarker {
+        *self.sequence.marker.read()
+    }
+
+    /// Entries with `after_seq < seq <= until_seq`, oldest first.
+    pub fn read_entries(
+        &self,
+        after_seq: u64,
+        until_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<SequencedEntry>> {
+        let mut entries = Vec::new();
+        if after_seq >= until_seq || limit == 0 {
+            return Ok(entries);
+        }
+        self.scan_sequenced(after_seq, |seq, raw_entry| {
+            if seq > until_seq {
+                return Ok(false);
+            }
+            if seq > after_seq {
+                entries.push(SequencedEntry {
+                    seq,
+                    entry: log_entry(raw_entry)?,
+                });
+            }
+            Ok(entries.len() < limit)
+        })?;
+        Ok(entries)
+    }
+
+    /// Sequence number of the first entry written at or after `timestamp`.
+    pub fn first_seq_since(&self, timestamp: u64, until_seq: u64) -> Result<Option<u64>> {
+        let mut found = None;
+        self.scan_sequenced(0, |seq, raw_entry| {
+            if seq > until_seq {
+                return Ok(false);
+            }
+            if raw_entry.timestamp >= timestamp {
+                found = Some(seq);
+                return Ok(false);
+            }
+            Ok(true)
+        })?;
+        Ok(found)
+    }
+
+    /// Walks entries from the checkpoint before `after_seq`, numbering them
+    /// the way `rebuild_index` does. `visit` returns whether to continue.
+    fn scan_sequenced(
+        &self,
+        after_seq: u64,
+        mut visit: impl FnMut(u64, RawEntry) -> Result<bool>,
+    ) -> Result<()> {
+        // Under the writer lock the open handle, end offset and checkpoint all
+        // describe the same file, even if a compaction swaps it afterwards.
+        let (end, (mut seq, start), file) = {
+            let mut writer = self.file.write();
+            writer.flush()?;
+            (
+                self.current_offset.load(Ordering::Acquire),
+                self.sequence.start_for(after_seq),
+                File::open(&self.file_path)?,
+            )
+        };
+        let schema = self.schema();
+        let mut reader = BufReader::with_capacity(SCAN_BUFFER_SIZE, file);
+        reader.seek(SeekFrom::Start(start))?;
+        let mut committed: Option<HashSet<u64>> = None;
+        let mut offset = start;
+        let mut buffer = Vec::new();
+
+        while offset + 4 <= end {
+            let mut size_bytes = [0u8; 4];
+            reader.read_exact(&mut size_bytes)?;
+            let size = u32::from_le_bytes(size_bytes) as u64;
+            if offset + 4 + size > end {
+                break;
+            }
+            buffer.resize(size as usize, 0);
+            reader.read_exact(&mut buffer)?;
+            offset += 4 + size;
+
+            if buffer.starts_with(SEQUENCE_MARKER_MAGIC) {
+                if let Ok(marker) = SequenceMarker::decode(&buffer) {
+                    seq = marker.base;
+                }
+                continue;
+            }
+            if buffer.starts_with(TRANSACTION_FRAME_MAGIC) {
+                let Ok((transaction_id, inner_frames)) = split_transaction_frame(&buffer) else {
+                    continue;
+                };
+                let committed = match &mut committed {
+                    Some(committed) => committed,
+                    None => {
+                        let data_dir = Path::new(&self.file_path)
+                            .parent()
+                            .unwrap_or_else(|| Path::new("."));
+                        committed.insert(committed_transactions(data_dir)?)
+                    }
+                };
+                if !committed.contains(&transaction_id) {
+                    continue;
+                }
+                for (_, _, inner_frame) in inner_frames {
+                    let Ok(raw_entry) = decode_raw_entry(inner_frame, &schema) else {
+                        continue;
+                    };
+                    seq += 1;
+                    if !visit(seq, raw_entry)? {
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+            let Ok(raw_entry) = decode_raw_entry(&buffer, &schema) else {
+                continue;
+            };
+            seq += 1;
+            if !visit(seq, raw_entry)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn log_entry(raw_entry: RawEntry) -> Result<LogEntry<Value>> {
+    Ok(LogEntry {
+        timestamp: raw_entry.timestamp,
+        operation: operation_from_u8(raw_entry.operation)?,
+        data: serde_json::from_slice(&raw_entry.data)?,
+    })
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use parking_lot::RwLockWriteGuard;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::atomic::Ordering;
+
+use super::encoding::decode_entry_timestamp;
+use super::sequence::{SequenceMarker, SEQUENCE_MARKER_MAGIC};
+use super::transaction::{split_transaction_frame, TRANSACTION_FRAME_MAGIC};
+use super::LogStorage;
+
+/// A model log with its writer blocked and flushed, so its offset and
+/// sequence number stay put until the guard is dropped.
+pub(crate) struct FrozenLog<'a> {
+    storage: &'a LogStorage,
+    _writer: RwLockWriteGuard<'a, BufWriter<File>>,
+}
+
+/// The first `len` bytes of a model log as of a [`FrozenLog`].
+pub struct LogSnapshot {
+    pub file: File,
+    pub len: u64,
+    pub last_seq: u64,
+}
+
+impl LogStorage {
+    pub(crate) fn freeze(&self) -> Result<FrozenLog<'_>> {
+        let mut writer = self.file.write();
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        Ok(FrozenLog {
+            storage: self,
+            _writer: writer,
+        })
+    }
+}
+
+impl FrozenLog<'_> {
+    /// The handle is opened while frozen, so a compaction swapping the file
+    /// afterwards does not change what it reads.
+    pub(crate) fn snapshot(&self) -> Result<LogSnapshot> {
+        Ok(LogSnapshot {
+            file: File::open(&self.storage.file_path)?,
+            len: self.storage.current_offset.load(Ordering::Acquire),
+            last_seq: self.storage.last_seq(),
+        })
+    }
+}
+
+/// What a point-in-time copy of a model log kept and dropped.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogCut {
+    pub kept_entries: u64,
+    pub dropped_entries: u64,
+}
+
+/// Copies the frames of a model log whose entries were written at or before
+/// `until` (UNIX millis). Transaction frames are kept or dropped whole, and
+/// frames that can't be read are copied unchanged for the rebuild to judge.
+pub(crate) fn copy_log_until(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    until: u64,
+) -> Result<LogCut> {
+    let mut cut = LogCut::default();
+    let mut buffer = Vec::new();
+    loop {
+        let mut size_bytes = [0u8; 4];
+        match reader.read_exact(&mut size_bytes) {
+            Ok(()) => {}
+            // A torn tail would be truncated on open anyway.
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error.into()),
+        }
+        buffer.resize(u32::from_le_bytes(size_bytes) as usize, 0);
+        match reader.read_exact(&mut buffer) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error.into()),
+        }
+
+        let keep = if buffer.starts_with(SEQUENCE_MARKER_MAGIC) {
+            if let Ok(marker) = SequenceMarker::decode(&buffer) {
+                if marker.compacted_at > until {
+                    return Err(anyhow::anyhow!(
+                        "The log was compacted at {}, after {}; older history is gone",
+                        marker.compacted_at,
+                        until
+                    ));
+                }
+            }
+            true
+        } else {
+            match frame_timestamps(&buffer) {
+                Some(timestamps) => {
+                    let count = timestamps.len() as u64;
+                    if timestamps.into_iter().all(|timestamp| timestamp <= until) {
+                        cut.kept_entries += count;
+                        true
+                    } else {
+                        cut.dropped_entries += count;
+                        false
+                    }
+                }
+                None => true,
+            }
+        };
+        if keep {
+            writer.write_all(&size_bytes)?;
+            writer.write_all(&buffer)?;
+        }
+    }
+    writer.flush()?;
+    Ok(cut)
+}
+
+fn frame_timestamps(frame: &[u8]) -> Option<Vec<u64>> {
+    if !frame.starts_with(TRANSACTION_FRAME_MAGIC) {
+        return decode_entry_timestamp(frame)
+            .ok()
+            .map(|timestamp| vec![timestamp]);
+    }
+    let (_, inner_frames) = split_transaction_frame(frame).ok()?;
+    inner_frames
+        .into_iter()
+        .map(|(_, _, inner_frame)| decode_entry_timestamp(inner_frame).ok())
+        .collect()
+}
@@ -0,0 +1,298 @@
+use anyhow::Result;
+use parking_lot::{Mutex, RwLockWriteGuard};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::models::LogEntry;
+
+use super::encoding::{EncodedEntry, CRC32C};
+use super::LogStorage;
+
+/// Groups the entries one transaction writes to a model into a single frame:
+/// magic, CRC32C of the rest, transaction id, then ordinary inner frames.
+pub(super) const TRANSACTION_FRAME_MAGIC: &[u8; 4] = b"NYT1";
+const TRANSACTION_HEADER_SIZE: usize = TRANSACTION_FRAME_MAGIC.len() + 4 + 8;
+pub(crate) const TRANSACTION_LOG_FILE: &str = "transactions.txlog";
+const BEGIN_RECORD: u8 = 1;
+const COMMIT_RECORD: u8 = 2;
+const RECORD_SIZE: usize = 1 + 8 + 4;
+
+/// Shared record of transaction ids. Frames of a transaction are only
+/// replayed once its commit record is durable here.
+pub(crate) struct TransactionLog {
+    file: Mutex<File>,
+    next_id: AtomicU64,
+}
+
+/// The frame a transaction wrote to one model, kept unpublished until the
+/// transaction commits.
+pub(crate) struct StagedFrame<'a> {
+    storage: &'a LogStorage,
+    writer: RwLockWriteGuard<'a, BufWriter<File>>,
+    start_offset: u64,
+    entries: Vec<(u64, EncodedEntry)>,
+}
+
+pub(crate) fn transaction_log_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(TRANSACTION_LOG_FILE)
+}
+
+impl TransactionLog {
+    pub(crate) fn open(data_dir: &str) -> Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        let path = transaction_log_path(Path::new(data_dir));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let records = read_records(&bytes);
+        let valid_len = (records.len() * RECORD_SIZE) as u64;
+        if valid_len < bytes.len() as u64 {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        let next_id = records.iter().map(|(_, id)| id + 1).max().unwrap_or(1);
+
+        Ok(Self {
+            file: Mutex::new(file),
+            next_id: AtomicU64::new(next_id),
+        })
+    }
+
+    /// Reserves an id. The begin record is synced first so a crash can never
+    /// hand the id of an orphaned frame to a later transaction.
+    pub(crate) fn begin(&self) -> Result<u64> {
+        let mut file = self.file.lock();
+        let id = self.next_id.fetch_add(1, Ordering::AcqRel);
+        write_record(&mut file, BEGIN_RECORD, id)?;
+        Ok(id)
+    }
+
+    pub(crate) fn commit(&self, id: u64) -> Result<()> {
+        let mut file = self.file.lock();
+        write_record(&mut file, COMMIT_RECORD, id)
+    }
+}
+
+fn write_record(file: &mut File, kind: u8, id: u64) -> Result<()> {
+    let mut record = [0u8; RECORD_SIZE];
+    record[0] = kind;
+    record[1..9].copy_from_slice(&id.to_le_bytes());
+    let checksum = CRC32C.checksum(&record[..9]);
+    record[9..].copy_from_slice(&checksum.to_le_bytes());
+    file.write_all(&record)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Reads records up to the first torn or corrupt one.
+fn read_records(bytes: &[u8]) -> Vec<(u8, u64)> {
+    bytes
+        .chunks_exact(RECORD_SIZE)
+        .map_while(|record| {
+            let checksum = u32::from_le_bytes(record[9..].try_into().ok()?);
+            if checksum != CRC32C.checksum(&record[..9]) {
+                return None;
+            }
+            let id = u64::from_le_bytes(record[1..9].try_into().ok()?);
+            Some((record[0], id))
+        })
+        .collect()
+}
+
+pub(super) fn committed_transactions(data_dir: &Path) -> Result<HashSet<u64>> {
+    let bytes = match std::fs::read(transaction_log_path(data_dir)) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(error) => return Err(error.into()),
+    };
+    Ok(read_records(&bytes)
+        .into_iter()
+        .filter(|(kind, _)| *kind == COMMIT_RECORD)
+        .map(|(_, id)| id)
+        .collect())
+}
+
+/// The whole records of the transaction log after `offset`, for a replica,
+/// with the length of the valid log.
+pub(crate) fn read_transaction_records(data_dir: &Path, offset: u64) -> Result<(Vec<u8>, u64)> {
+    let bytes = match std::fs::read(transaction_log_path(data_dir)) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(error) => return Err(error.into()),
+    };
+    let valid_len = read_records(&bytes).len() * RECORD_SIZE;
+    let start = (offset as usize).min(valid_len);
+    Ok((bytes[start..valid_len].to_vec(), valid_len as u64))
+}
+
+/// Cuts the transaction log to `offset` and appends records replicated from
+/// a primary.
+pub(crate) fn write_transaction_records(
+    data_dir: &Path,
+    offset: u64,
+    records: &[u8],
+) -> Result<()> {
+    if read_records(records).len() * RECORD_SIZE != records.len() {
+        return Err(anyhow::anyhow!(
+            "Replicated transaction records are corrupt"
+        ));
+    }
+    std::fs::create_dir_all(data_dir)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(transaction_log_path(data_dir))?;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(records)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// `(offset, size, payload)`, with the offset relative to the outer frame.
+pub(super) type InnerFrame<'a> = (u64, u32, &'a [u8]);
+
+/// Splits a transaction frame body into its id and inner frames.
+pub(super) fn split_transaction_frame(frame: &[u8]) -> Result<(u64, Vec<InnerFrame<'_>>)> {
+    if frame.len() < TRANSACTION_HEADER_SIZE {
+        return Err(anyhow::anyhow!("Corrupt transaction frame header"));
+    }
+    let stored_checksum = u32::from_le_bytes(frame[4..8].try_into()?);
+    if stored_checksum != CRC32C.checksum(&frame[8..]) {
+        return Err(anyhow::anyhow!("Transaction frame checksum mismatch"));
+    }
+    let id = u64::from_le_bytes(frame[8..16].try_into()?);
+
+    let mut inner_frames = Vec::new();
+    let mut position = TRANSACTION_HEADER_SIZE;
+    while position < frame.len() {
+        let size_bytes = frame
+            .get(position..position + 4)
+            .ok_or_else(|| anyhow::anyhow!("Truncated frame inside transaction {}", id))?;
+        let size = u32::from_le_bytes(size_bytes.try_into()?);
+        let payload = frame
+            .get(position + 4..position + 4 + size as usize)
+            .ok_or_else(|| anyhow::anyhow!("Truncated frame inside transaction {}", id))?;
+        // +4 for the outer size prefix that precedes `frame` in the log.
+        inner_frames.push((4 + position as u64, size, payload));
+        position += 4 + size as usize;
+    }
+    Ok((id, inner_frames))
+}
+
+impl LogStorage {
+    pub(crate) fn encode_transaction_entries(
+        &self,
+        entries: &[LogEntry<Value>],
+    ) -> Result<Vec<EncodedEntry>> {
+        self.encode_entry_slice(entries)
+    }
+
+    /// Takes this model's writer lock for a transaction. Rows read while the
+    /// frame is held cannot change underneath the transaction. Earlier
+    /// buffered appends are flushed so a rollback only cuts this frame.
+    pub(crate) fn lock_for_transaction(&self) -> Result<StagedFrame<'_>> {
+        self.ensure_writable()?;
+        let mut writer = self.file.write();
+        writer.flush()?;
+        self.note_flushed();
+        let start_offset = self.current_offset.load(Ordering::Acquire);
+        Ok(StagedFrame {
+            storage: self,
+            writer,
+            start_offset,
+            entries: Vec::new(),
+        })
+    }
+}
+
+impl StagedFrame<'_> {
+    /// Writes and syncs the transaction frame. Nothing is indexed until
+    /// [`StagedFrame::publish`].
+    pub(crate) fn write(
+        &mut self,
+        transaction_id: u64,
+        encoded_entries: Vec<EncodedEntry>,
+    ) -> Result<()> {
+        for entry in &encoded_entries {
+            self.storage.ensure_schema_version(entry.schema_version)?;
+        }
+        let mut frame = Vec::with_capacity(
+            TRANSACTION_HEADER_SIZE
+                + encoded_entries
+                    .iter()
+                    .map(|entry| 4 + entry.size as usize)
+                    .sum::<usize>(),
+        );
+        frame.extend_from_slice(TRANSACTION_FRAME_MAGIC);
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(&transaction_id.to_le_bytes());
+        for entry in &encoded_entries {
+            frame.extend_from_slice(&entry.size.to_le_bytes());
+            frame.extend_from_slice(&entry.data);
+        }
+        let checksum = CRC32C.checksum(&frame[8..]);
+        frame[4..8].copy_from_slice(&checksum.to_le_bytes());
+        let frame_size = u32::try_from(frame.len())
+            .map_err(|_| anyhow::anyhow!("Transaction frame is larger than u32::MAX"))?;
+
+        self.writer.write_all(&frame_size.to_le_bytes())?;
+        self.writer.write_all(&frame)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+
+        let mut offset = self.start_offset + 4 + TRANSACTION_HEADER_SIZE as u64;
+        for entry in encoded_entries {
+            let entry_offset = offset;
+            offset += 4 + entry.size as u64;
+            self.entries.push((entry_offset, entry));
+        }
+        self.storage
+            .current_offset
+            .store(self.start_offset + 4 + frame_size as u64, Ordering::SeqCst);
+        self.storage.note_flushed();
+        Ok(())
+    }
+
+    /// Indexes the entries and returns the sequence number of the first.
+    pub(crate) fn publish(self) -> u64 {
+        for (offset, entry) in &self.entries {
+            self.storage.insert_indexes(*offset, entry);
+        }
+        self.storage
+            .sequence
+            .advance(self.start_offset, self.entries.len() as u64)
+    }
+
+    /// Cuts the log back to where this frame started, dropping anything still
+    /// buffered. The mmap never covers bytes past the start of a new frame.
+    pub(crate) fn rollback(mut self) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.storage.file_path)?;
+        file.set_len(self.start_offset)?;
+        file.sync_all()?;
+        let discarded = std::mem::replace(
+            &mut *self.writer,
+            BufWriter::with_capacity(self.storage.buffer_size, file),
+        );
+        // Buffered bytes belong to the aborted frame and must not be flushed.
+        let _ = discarded.into_parts();
+        self.storage
+            .current_offset
+            .store(self.start_offset, Ordering::SeqCst);
+        self.storage.note_flushed();
+        Ok(())
+    }
+}
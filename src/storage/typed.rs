@@ -1,7 +1,7 @@
 use anyhow::Result;
 use serde_json::{Map, Number, Value};
 
-use crate::config::ModelSchema;
+use crate::config::ModelField;
 
 #[derive(Clone)]
 pub(crate) struct FieldCodec {
@@ -20,9 +20,8 @@ enum FieldType {
     Json,
 }
 
-pub(crate) fn field_codecs_from_schema(schema: &ModelSchema) -> Vec<FieldCodec> {
-    schema
-        .fields
+pub(crate) fn field_codecs_from_fields(fields: &[ModelField]) -> Vec<FieldCodec> {
+    fields
         .iter()
         .map(|field| FieldCodec {
             name: field.name.clone(),
@@ -91,21 +90,24 @@ fn encode_payload<const COMPACT_STRINGS: bool>(
     Some(payload)
 }
 
-pub(crate) fn decode_typed_payload(data: &[u8], field_codecs: &[FieldCodec]) -> Result<Vec<u8>> {
+pub(crate) fn decode_typed_payload(
+    data: &[u8],
+    field_codecs: &[FieldCodec],
+) -> Result<Map<String, Value>> {
     decode_payload::<false>(data, field_codecs)
 }
 
 pub(crate) fn decode_compact_typed_payload(
     data: &[u8],
     field_codecs: &[FieldCodec],
-) -> Result<Vec<u8>> {
+) -> Result<Map<String, Value>> {
     decode_payload::<true>(data, field_codecs)
 }
 
 fn decode_payload<const COMPACT_STRINGS: bool>(
     data: &[u8],
     field_codecs: &[FieldCodec],
-) -> Result<Vec<u8>> {
+) -> Result<Map<String, Value>> {
     let mut cursor = 0;
     let mut object = Map::with_capacity(field_codecs.len());
 
@@ -135,7 +137,7 @@ fn decode_payload<const COMPACT_STRINGS: bool>(
     if cursor != data.len() {
         return Err(anyhow::anyhow!("Typed log entry has trailing bytes"));
     }
-    serde_json::to_vec(&Value::Object(object)).map_err(Into::into)
+    Ok(object)
 }
 
 fn read_u8(data: &[u8], cursor: &mut usize) -> Result<u8> {
@@ -1,65 +1,181 @@
 use anyhow::Result;
 use rayon::prelude::*;
 use serde_json::Value;
-use std::io::Write;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 
 use crate::models::LogEntry;
 
-use super::encoding::{self, CacheMode, EncodedEntry};
+use super::encoding::{self, CacheMode, EncodedEntry, DELETE_OPERATION};
 use super::index::{EntryLocation, IndexedEntry};
+use super::secondary::IndexedValues;
 use super::LogStorage;
 
 const PARALLEL_ENCODE_THRESHOLD: usize = 16_384;
 
 impl LogStorage {
-    pub fn append(&self, entry: &LogEntry<Value>) -> Result<()> {
-        let encoded_entry = encoding::encode_entry(
-            entry,
-            &self.indexed_fields,
-            &self.field_codecs,
-            CacheMode::ParsedValue,
-        )?;
+    /// Appends `entry` and returns its sequence number.
+    pub fn append(&self, entry: &LogEntry<Value>) -> Result<u64> {
+        let encoded_entry = encoding::encode_entry(entry, &self.schema(), CacheMode::ParsedValue)?;
         self.append_encoded_entry(encoded_entry)
     }
 
-    pub fn append_owned(&self, entry: LogEntry<Value>) -> Result<()> {
-        let encoded_entry =
-            encoding::encode_owned_entry(entry, &self.indexed_fields, &self.field_codecs)?;
+    pub fn append_owned(&self, entry: LogEntry<Value>) -> Result<u64> {
+        let encoded_entry = encoding::encode_owned_entry(entry, &self.schema())?;
         self.append_encoded_entry(encoded_entry)
     }
 
-    pub fn append_entries(&self, entries: &[LogEntry<Value>]) -> Result<()> {
+    /// Appends `entries` in order and returns the sequence number of the
+    /// first; the rest follow consecutively.
+    pub fn append_entries(&self, entries: &[LogEntry<Value>]) -> Result<u64> {
         if entries.is_empty() {
-            return Ok(());
+            return Ok(self.last_seq() + 1);
         }
 
         let encoded_entries = self.encode_entry_slice(entries)?;
         self.append_encoded_entries(encoded_entries)
     }
 
-    pub fn append_entries_owned(&self, entries: Vec<LogEntry<Value>>) -> Result<()> {
+    pub fn append_entries_owned(&self, entries: Vec<LogEntry<Value>>) -> Result<u64> {
         if entries.is_empty() {
-            return Ok(());
+            return Ok(self.last_seq() + 1);
         }
 
         let encoded_entries = self.encode_entry_slice(&entries)?;
         self.append_encoded_entries(encoded_entries)
     }
 
-    pub fn append_many(&self, entries: &[&LogEntry<Value>]) -> Result<()> {
+    pub fn append_many(&self, entries: &[&LogEntry<Value>]) -> Result<u64> {
         if entries.is_empty() {
-            return Ok(());
+            return Ok(self.last_seq() + 1);
         }
 
         let encoded_entries = self.encode_entry_refs(entries)?;
         self.append_encoded_entries(encoded_entries)
     }
 
-    fn append_encoded_entries(&self, encoded_entries: Vec<EncodedEntry>) -> Result<()> {
+    /// Appends `entry` only if its id is not indexed yet. The check runs under
+    /// the writer lock, so concurrent inserts of one id cannot both succeed.
+    /// Returns the sequence number, or `None` if the id was present.
+    pub fn append_owned_if_absent(&self, entry: LogEntry<Value>) -> Result<Option<u64>> {
+        let encoded_entry = encoding::encode_owned_entry(entry, &self.schema())?;
+        let mut file = self.file.write();
+        if self.is_indexed(&encoded_entry) {
+            return Ok(None);
+        }
+        self.write_encoded_entry(&mut file, encoded_entry).map(Some)
+    }
+
+    /// Appends `entry` only if its id is indexed and, with `expected_offset`,
+    /// its frame is still the one at that offset. The check runs under the
+    /// writer lock, so an update cannot follow a concurrent delete and bring
+    /// the row back on replay. Returns the sequence number, or `None` if the
+    /// check failed.
+    pub fn append_if_present(
+        &self,
+        entry: &LogEntry<Value>,
+        expected_offset: Option<u64>,
+    ) -> Result<Option<u64>> {
+        Ok(self
+            .replace_if_present(entry, expected_offset, false)?
+            .map(|(seq, _)| seq))
+    }
+
+    /// [`Self::append_if_present`] that, with `read_previous`, also returns
+    /// the row it replaced, read under the same writer lock.
+    pub fn replace_if_present(
+        &self,
+        entry: &LogEntry<Value>,
+        expected_offset: Option<u64>,
+        read_previous: bool,
+    ) -> Result<Option<(u64, Option<Value>)>> {
+        let encoded_entry = encoding::encode_entry(entry, &self.schema(), CacheMode::ParsedValue)?;
+        let Some(id) = encoded_entry
+            .index_data
+            .as_ref()
+            .map(|index_data| index_data.id)
+        else {
+            return Err(anyhow::anyhow!("Entry has no 'id'"));
+        };
+        let mut file = self.file.write();
+        let present = self.index.get(id).is_some_and(|indexed| {
+            expected_offset.is_none_or(|offset| indexed.location.offset == offset)
+        });
+        if !present {
+            return Ok(None);
+        }
+        let previous = if read_previous {
+            self.get_value(id)?
+        } else {
+            None
+        };
+        let seq = self.write_encoded_entry(&mut file, encoded_entry)?;
+        Ok(Some((seq, previous)))
+    }
+
+    /// Appends the entries whose ids are not indexed yet and returns the
+    /// sequence number of the first appended one with the positions of the
+    /// skipped ones. A repeated id inside `entries` is skipped after its first
+    /// occurrence.
+    pub fn append_entries_if_absent(
+        &self,
+        entries: &[LogEntry<Value>],
+    ) -> Result<(u64, Vec<usize>)> {
+        if entries.is_empty() {
+            return Ok((self.last_seq() + 1, Vec::new()));
+        }
+
+        let encoded_entries = self.encode_entry_slice(entries)?;
+        let mut file = self.file.write();
+        let mut batch_ids = HashSet::with_capacity(encoded_entries.len());
+        let mut skipped = Vec::new();
+        let mut accepted = Vec::with_capacity(encoded_entries.len());
+        for (position, encoded_entry) in encoded_entries.into_iter().enumerate() {
+            let new_in_batch = encoded_entry
+                .index_data
+                .as_ref()
+                .is_none_or(|index_data| batch_ids.insert(index_data.id));
+            if new_in_batch && !self.is_indexed(&encoded_entry) {
+                accepted.push(encoded_entry);
+            } else {
+                skipped.push(position);
+            }
+        }
+        let first_seq = self.write_encoded_entries(&mut file, accepted)?;
+        Ok((first_seq, skipped))
+    }
+
+    fn is_indexed(&self, encoded_entry: &EncodedEntry) -> bool {
+        encoded_entry
+            .index_data
+            .as_ref()
+            .is_some_and(|index_data| self.index.get(index_data.id).is_some())
+    }
+
+    fn append_encoded_entries(&self, encoded_entries: Vec<EncodedEntry>) -> Result<u64> {
         let mut file = self.file.write();
-        let mut offset = self
+        self.write_encoded_entries(&mut file, encoded_entries)
+    }
+
+    fn write_encoded_entries(
+        &self,
+        file: &mut BufWriter<File>,
+        encoded_entries: Vec<EncodedEntry>,
+    ) -> Result<u64> {
+        self.ensure_writable()?;
+        for encoded_entry in &encoded_entries {
+            self.ensure_schema_version(encoded_entry.schema_version)?;
+        }
+        if encoded_entries.is_empty() {
+            return Ok(self.last_seq() + 1);
+        }
+
+        let start_offset = self
             .current_offset
             .load(std::sync::atomic::Ordering::Acquire);
+        let mut offset = start_offset;
+        let count = encoded_entries.len() as u64;
         let mut primary_entries = Vec::with_capacity(encoded_entries.len());
         let mut secondary_entries = Vec::new();
 
@@ -82,13 +198,26 @@ impl LogStorage {
 
         self.current_offset
             .store(offset, std::sync::atomic::Ordering::SeqCst);
+        if self.sync_on_append {
+            self.note_flushed();
+        }
         self.publish_prepared_indexes(primary_entries, secondary_entries);
 
-        Ok(())
+        Ok(self.sequence.advance(start_offset, count))
     }
 
-    fn append_encoded_entry(&self, encoded_entry: EncodedEntry) -> Result<()> {
+    fn append_encoded_entry(&self, encoded_entry: EncodedEntry) -> Result<u64> {
         let mut file = self.file.write();
+        self.write_encoded_entry(&mut file, encoded_entry)
+    }
+
+    fn write_encoded_entry(
+        &self,
+        file: &mut BufWriter<File>,
+        encoded_entry: EncodedEntry,
+    ) -> Result<u64> {
+        self.ensure_writable()?;
+        self.ensure_schema_version(encoded_entry.schema_version)?;
         let offset = self
             .current_offset
             .load(std::sync::atomic::Ordering::Acquire);
@@ -104,70 +233,66 @@ impl LogStorage {
             offset + 4 + entry_size as u64,
             std::sync::atomic::Ordering::SeqCst,
         );
-        drop(file);
-
+        if self.sync_on_append {
+            self.note_flushed();
+        }
         self.insert_indexes(offset, &encoded_entry);
-        Ok(())
+
+        Ok(self.sequence.advance(offset, 1))
     }
 
-    fn encode_entry_slice(&self, entries: &[LogEntry<Value>]) -> Result<Vec<EncodedEntry>> {
+    /// Entries are encoded before the writer lock is taken, so a migration
+    /// may have finished in between.
+    pub(super) fn ensure_schema_version(&self, version: u32) -> Result<()> {
+        if self.schema().is_current(version) {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "The schema of {} changed while the write was prepared; retry",
+            self.file_path
+        ))
+    }
+
+    pub(super) fn encode_entry_slice(
+        &self,
+        entries: &[LogEntry<Value>],
+    ) -> Result<Vec<EncodedEntry>> {
+        let schema = self.schema();
         if entries.len() >= PARALLEL_ENCODE_THRESHOLD {
             return entries
                 .par_iter()
-                .map(|entry| {
-                    encoding::encode_entry(
-                        entry,
-                        &self.indexed_fields,
-                        &self.field_codecs,
-                        CacheMode::EncodedFrame,
-                    )
-                })
+                .map(|entry| encoding::encode_entry(entry, &schema, CacheMode::EncodedFrame))
                 .collect::<Result<Vec<_>>>();
         }
 
         entries
             .iter()
-            .map(|entry| {
-                encoding::encode_entry(
-                    entry,
-                    &self.indexed_fields,
-                    &self.field_codecs,
-                    CacheMode::EncodedFrame,
-                )
-            })
+            .map(|entry| encoding::encode_entry(entry, &schema, CacheMode::EncodedFrame))
             .collect::<Result<Vec<_>>>()
     }
 
     fn encode_entry_refs(&self, entries: &[&LogEntry<Value>]) -> Result<Vec<EncodedEntry>> {
+        let schema = self.schema();
         if entries.len() >= PARALLEL_ENCODE_THRESHOLD {
             return entries
                 .par_iter()
-                .map(|entry| {
-                    encoding::encode_entry(
-                        entry,
-                        &self.indexed_fields,
-                        &self.field_codecs,
-                        CacheMode::EncodedFrame,
-                    )
-                })
+                .map(|entry| encoding::encode_entry(entry, &schema, CacheMode::EncodedFrame))
                 .collect::<Result<Vec<_>>>();
         }
 
         entries
             .iter()
-            .map(|entry| {
-                encoding::encode_entry(
-                    entry,
-                    &self.indexed_fields,
-                    &self.field_codecs,
-                    CacheMode::EncodedFrame,
-                )
-            })
+            .map(|entry| encoding::encode_entry(entry, &schema, CacheMode::EncodedFrame))
             .collect::<Result<Vec<_>>>()
     }
 
-    fn insert_indexes(&self, offset: u64, encoded_entry: &EncodedEntry) {
+    pub(super) fn insert_indexes(&self, offset: u64, encoded_entry: &EncodedEntry) {
         if let Some(index_data) = &encoded_entry.index_data {
+            if encoded_entry.cache_entry.operation == DELETE_OPERATION {
+                self.remove_secondary_indexes(index_data.id);
+                self.index.remove(index_data.id);
+                return;
+            }
             self.index.insert(
                 index_data.id,
                 IndexedEntry {
@@ -175,16 +300,10 @@ impl LogStorage {
                         offset,
                         size: encoded_entry.size,
                     },
-                    cache: encoded_entry.cache_entry.clone(),
+                    cache: Some(encoded_entry.cache_entry.clone()),
                 },
             );
-            for (field, value) in &index_data.fields {
-                let field_idx = self.secondary_indices.entry(field.clone()).or_default();
-                field_idx
-                    .entry(value.clone())
-                    .or_default()
-                    .push(index_data.id);
-            }
+            self.reindex_secondary(index_data.id, index_data.values.clone());
         }
     }
 
@@ -192,7 +311,7 @@ impl LogStorage {
         offset: u64,
         encoded_entry: EncodedEntry,
         primary_entries: &mut Vec<(u64, IndexedEntry)>,
-        secondary_entries: &mut Vec<(String, String, u64)>,
+        secondary_entries: &mut Vec<(u64, IndexedValues)>,
     ) {
         let Some(index_data) = encoded_entry.index_data else {
             return;
@@ -204,23 +323,22 @@ impl LogStorage {
                     offset,
                     size: encoded_entry.size,
                 },
-                cache: encoded_entry.cache_entry,
+                cache: Some(encoded_entry.cache_entry),
             },
         ));
-        for (field, value) in index_data.fields {
-            secondary_entries.push((field, value, index_data.id));
+        if !index_data.values.is_empty() {
+            secondary_entries.push((index_data.id, index_data.values));
         }
     }
 
     fn publish_prepared_indexes(
         &self,
         primary_entries: Vec<(u64, IndexedEntry)>,
-        secondary_entries: Vec<(String, String, u64)>,
+        secondary_entries: Vec<(u64, IndexedValues)>,
     ) {
         self.index.insert_many(primary_entries);
-        for (field, value, id) in secondary_entries {
-            let field_idx = self.secondary_indices.entry(field).or_default();
-            field_idx.entry(value).or_default().push(id);
+        for (id, values) in secondary_entries {
+            self.reindex_secondary(id, values);
         }
     }
 }
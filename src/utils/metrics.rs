@@ -9,6 +9,8 @@ pub struct Metrics {
     pub total_inserts: AtomicU64,
     pub total_gets: AtomicU64,
     pub total_queries: AtomicU64,
+    pub total_updates: AtomicU64,
+    pub total_deletes: AtomicU64,
     pub start_time: Instant,
     pub insert_times: Arc<RwLock<Vec<Duration>>>,
     pub get_times: Arc<RwLock<Vec<Duration>>>,
@@ -22,6 +24,8 @@ impl Metrics {
             total_inserts: AtomicU64::new(0),
             total_gets: AtomicU64::new(0),
             total_queries: AtomicU64::new(0),
+            total_updates: AtomicU64::new(0),
+            total_deletes: AtomicU64::new(0),
             start_time: Instant::now(),
             insert_times: Arc::new(RwLock::new(Vec::with_capacity(config.max_samples))),
             get_times: Arc::new(RwLock::new(Vec::with_capacity(config.max_samples))),
@@ -73,11 +77,27 @@ impl Metrics {
         self.total_queries.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn record_update(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.total_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_delete(&self) {
+        if !self.enabled {
+            return;
+        }
+        self.total_deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn get_stats(&self) -> MetricsReport {
         let uptime = self.start_time.elapsed();
         let total_inserts = self.total_inserts.load(Ordering::Relaxed);
         let total_gets = self.total_gets.load(Ordering::Relaxed);
         let total_queries = self.total_queries.load(Ordering::Relaxed);
+        let total_updates = self.total_updates.load(Ordering::Relaxed);
+        let total_deletes = self.total_deletes.load(Ordering::Relaxed);
 
         let insert_times = self
             .insert_times
@@ -116,10 +136,16 @@ impl Metrics {
 
         MetricsReport {
             uptime_secs: uptime.as_secs_f64(),
-            total_operations: total_inserts + total_gets + total_queries,
+            total_operations: total_inserts
+                + total_gets
+                + total_queries
+                + total_updates
+                + total_deletes,
             total_inserts,
             total_gets,
             total_queries,
+            total_updates,
+            total_deletes,
             inserts_per_sec: if uptime.as_secs_f64() > 0.0 {
                 total_inserts as f64 / uptime.as_secs_f64()
             } else {
@@ -133,6 +159,9 @@ impl Metrics {
             avg_insert_latency_ns: avg_insert_latency,
             avg_get_latency_ns: avg_get_latency,
             p99_insert_latency_ns: p99_insert,
+            replication_lag_bytes: None,
+            replication_lag_ms: None,
+            index_builds: Vec::new(),
         }
     }
 }
@@ -144,9 +173,26 @@ pub struct MetricsReport {
     pub total_inserts: u64,
     pub total_gets: u64,
     pub total_queries: u64,
+    pub total_updates: u64,
+    pub total_deletes: u64,
     pub inserts_per_sec: f64,
     pub gets_per_sec: f64,
     pub avg_insert_latency_ns: f64,
     pub avg_get_latency_ns: f64,
     pub p99_insert_latency_ns: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replication_lag_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replication_lag_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub index_builds: Vec<IndexBuildReport>,
+}
+
+/// A secondary index still being filled from the rows logged before it.
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexBuildReport {
+    pub model: String,
+    pub field: String,
+    pub indexed_rows: u64,
+    pub total_rows: u64,
 }
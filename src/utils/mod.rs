@@ -1,3 +1,4 @@
 pub mod benchmark;
 pub mod logger;
 pub mod metrics;
+pub mod tar;
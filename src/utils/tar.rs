@@ -0,0 +1,140 @@
+//! Minimal ustar archives holding regular files only, enough to carry a
+//! backup in one file without pulling in an archive crate.
+
+use anyhow::Result;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const BLOCK_SIZE: usize = 512;
+const MAX_PATH_LEN: usize = 99;
+/// The largest size that fits the 11 octal digits of a ustar header.
+const MAX_FILE_SIZE: u64 = 0o77777777777;
+
+pub struct TarWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Appends a file of exactly `size` bytes read from `data`.
+    pub fn append(&mut self, path: &str, size: u64, data: impl Read) -> Result<()> {
+        self.inner.write_all(&header(path, size)?)?;
+        let copied = io::copy(&mut data.take(size), &mut self.inner)?;
+        if copied != size {
+            return Err(anyhow::anyhow!(
+                "'{}' ended after {} of {} bytes",
+                path,
+                copied,
+                size
+            ));
+        }
+        let padding = (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE;
+        self.inner.write_all(&[0u8; BLOCK_SIZE][..padding])?;
+        Ok(())
+    }
+
+    /// Writes the end-of-archive marker and hands back the writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.write_all(&[0u8; BLOCK_SIZE * 2])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// A file inside an archive: `size` bytes starting at `offset`.
+#[derive(Debug, Clone)]
+pub struct TarEntry {
+    pub path: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Lists the regular files of a ustar archive, skipping everything else.
+pub fn read_index(mut reader: impl Read + Seek) -> Result<Vec<TarEntry>> {
+    let mut entries = Vec::new();
+    let mut header = [0u8; BLOCK_SIZE];
+    let mut offset = 0u64;
+    loop {
+        reader.seek(SeekFrom::Start(offset))?;
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| anyhow::anyhow!("Archive ends without an end marker"))?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(entries);
+        }
+        let stored_checksum = parse_octal(&header[148..156])?;
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|&byte| u64::from(byte)).sum();
+        if checksum != stored_checksum {
+            return Err(anyhow::anyhow!(
+                "Corrupt archive header at offset {}",
+                offset
+            ));
+        }
+
+        let size = parse_octal(&header[124..136])?;
+        if matches!(header[156], b'0' | 0) {
+            let mut path = field_str(&header[..100])?.to_string();
+            let prefix = field_str(&header[345..500])?;
+            if !prefix.is_empty() {
+                path = format!("{}/{}", prefix, path);
+            }
+            entries.push(TarEntry {
+                path,
+                offset: offset + BLOCK_SIZE as u64,
+                size,
+            });
+        }
+        offset += BLOCK_SIZE as u64 + size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64;
+    }
+}
+
+fn field_str(field: &[u8]) -> Result<&str> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    std::str::from_utf8(&field[..end]).map_err(|_| anyhow::anyhow!("Archive path is not UTF-8"))
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let digits = field_str(field)?.trim();
+    u64::from_str_radix(digits, 8)
+        .map_err(|_| anyhow::anyhow!("Invalid number '{}' in archive header", digits))
+}
+
+fn header(path: &str, size: u64) -> Result<[u8; BLOCK_SIZE]> {
+    if path.is_empty() || path.len() > MAX_PATH_LEN {
+        return Err(anyhow::anyhow!(
+            "Archive path '{}' must be 1 to {} bytes",
+            path,
+            MAX_PATH_LEN
+        ));
+    }
+    if size > MAX_FILE_SIZE {
+        return Err(anyhow::anyhow!(
+            "'{}' is too large for a tar archive ({} bytes)",
+            path,
+            size
+        ));
+    }
+
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}